@@ -0,0 +1,59 @@
+//! Records what `build_info` reports about the build: the git commit, when it was built and the
+//! webauthn-rs version from the lock file.
+
+use std::{
+    env, fs,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!("cargo:rerun-if-env-changed=WEBAUTHN_TINY_GIT_HASH");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    // Builds from a tarball (e.g. nix) have no repository and pass the commit in the environment.
+    let git_hash = env::var("WEBAUTHN_TINY_GIT_HASH").ok().or_else(|| {
+        if Path::new(".git/HEAD").exists() {
+            println!("cargo:rerun-if-changed=.git/HEAD");
+            println!("cargo:rerun-if-changed=.git/refs");
+        }
+        let output = Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    if let Some(git_hash) = git_hash.filter(|hash| !hash.is_empty()) {
+        println!("cargo:rustc-env=WEBAUTHN_TINY_GIT_HASH={git_hash}");
+    }
+
+    // Reproducible builds set the date to that of the sources.
+    let build_time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("time went backwards")
+                .as_secs()
+        });
+    println!("cargo:rustc-env=WEBAUTHN_TINY_BUILD_TIME={build_time}");
+
+    let lock_file = fs::read_to_string("Cargo.lock").expect("could not read Cargo.lock");
+    let webauthn_rs_version = lock_file
+        .split("[[package]]")
+        .find(|package| package.contains("\nname = \"webauthn-rs\"\n"))
+        .and_then(|package| {
+            package
+                .lines()
+                .find_map(|line| line.strip_prefix("version = \""))
+        })
+        .and_then(|version| version.strip_suffix('"'))
+        .expect("webauthn-rs is missing from Cargo.lock");
+    println!("cargo:rustc-env=WEBAUTHN_TINY_WEBAUTHN_RS_VERSION={webauthn_rs_version}");
+}
@@ -12,7 +12,7 @@
       git-hooks,
     }:
     {
-      overlays.default = (_: prev: { webauthn-tiny = prev.callPackage ./package.nix { }; });
+      overlays.default = (_: prev: { webauthn-tiny = prev.callPackage ./package.nix { gitHash = self.rev or null; }; });
       nixosModules.default = {
         nixpkgs.overlays = [ self.overlays.default ];
         imports = [ ./module.nix ];
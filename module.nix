@@ -7,6 +7,9 @@
 with lib;
 let
   cfg = config.services.webauthn-tiny;
+  settingsFormat = pkgs.formats.json { };
+  configFile = settingsFormat.generate("webauthn-tiny.json", cfg.settings);
+  seedFile = settingsFormat.generate("webauthn-tiny-seed.json", cfg.seed);
   passwordFile =
     if (cfg.basicAuthFile != null) then
       cfg.basicAuthFile
@@ -60,7 +63,16 @@ in
         default = null;
         description = ''
           The path to a file containing a session secret (64 or more bytes).
-          You can use `openssl rand -hex 64` to generate a session secret.
+          You can use `webauthn-tiny generate-secret` (or `openssl rand -hex 64`)
+          to generate a session secret.
+        '';
+      };
+      sessionSecretEncryptedFile = mkOption {
+        type = types.nullOr types.path;
+        default = null;
+        description = ''
+          The path to a session secret encrypted with `systemd-creds encrypt
+          --name=session-secret`, used instead of `sessionSecretFile`.
         '';
       };
       relyingParty = {
@@ -90,6 +102,71 @@ in
           example = [ "https://subdomain.mywebsite.com" ];
         };
       };
+      settings = mkOption {
+        type = settingsFormat.type;
+        default = { };
+        description = ''
+          Contents of the JSON config file. See the README for available
+          settings.
+        '';
+        example = {
+          relatedOrigins = [ "https://myotherwebsite.com" ];
+        };
+      };
+      allowedAlgorithms = mkOption {
+        type = types.listOf types.str;
+        default = [ ];
+        description = ''
+          COSE algorithms that newly registered credentials may use, by name
+          or COSE identifier. An empty list allows all algorithms.
+        '';
+        example = [
+          "ES256"
+          "EdDSA"
+        ];
+      };
+      credentialMaxAgeDays = mkOption {
+        type = types.nullOr types.ints.positive;
+        default = null;
+        description = ''
+          Days after which users are asked to register a replacement for the
+          credential they authenticated with. Null disables credential expiry.
+        '';
+        example = 365;
+      };
+      seed = mkOption {
+        type = types.nullOr settingsFormat.type;
+        default = null;
+        description = ''
+          Users to reconcile into the database at every start. See the README
+          for the format.
+        '';
+        example = {
+          prune = true;
+          users.myuser.groups = [ "admins" ];
+        };
+      };
+      maxUsers = mkOption {
+        type = types.nullOr types.ints.positive;
+        default = null;
+        description = ''
+          Maximum number of users. Once reached, usernames that have not logged
+          in before are refused. Null allows any number of users.
+        '';
+        example = 50;
+      };
+      pam.enable = mkEnableOption ''
+        verifying sessions for PAM on /run/webauthn-tiny/pam.sock, for use with
+        `webauthn-tiny pam-verify`
+      '';
+      adminAddress = mkOption {
+        type = types.str;
+        default = "[::1]:8081";
+        description = ''
+          The address to serve the admin API and metrics on, either a socket
+          address or unix:<path>.
+        '';
+      };
       nginx = {
         enable = mkEnableOption "nginx support";
         virtualHost = mkOption {
@@ -148,8 +225,9 @@ in
             locations."/" = {
               proxyPass = "http://[::1]:8080";
               extraConfig = ''
-                proxy_set_header Host            $host;
-                proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
+                proxy_set_header Host              $host;
+                proxy_set_header X-Forwarded-For   $proxy_add_x_forwarded_for;
+                proxy_set_header X-Forwarded-Proto $scheme;
               '';
             };
           };
@@ -162,19 +240,38 @@ in
       environment.WEBAUTHN_TINY_LOG = "info";
       serviceConfig = {
         StateDirectory = "webauthn-tiny";
+        RuntimeDirectory = mkIf cfg.pam.enable "webauthn-tiny";
         LoadCredential = [
           "password-file:${passwordFile}"
-          "session-secret-file:${sessionSecretFile}"
-        ];
+        ]
+        ++ optional (cfg.sessionSecretEncryptedFile == null) "session-secret:${sessionSecretFile}";
+        LoadCredentialEncrypted = optional (
+          cfg.sessionSecretEncryptedFile != null
+        ) "session-secret:${cfg.sessionSecretEncryptedFile}";
         ExecStart = escapeShellArgs (
           [
             (lib.getExe pkgs.webauthn-tiny)
             "--rp-id=${cfg.relyingParty.id}"
             "--rp-origin=${cfg.relyingParty.origin}"
             "--password-file=\${CREDENTIALS_DIRECTORY}/password-file"
-            "--session-secret-file=\${CREDENTIALS_DIRECTORY}/session-secret-file"
+            "--session-secret-credential=session-secret"
+            "--config-file=${configFile}"
+            "--admin-address=${cfg.adminAddress}"
+          ]
+          # Only reachable through nginx, which appends the client address to X-Forwarded-For.
+          ++ optionals cfg.nginx.enable [
+            "--address=[::1]:8080"
+            "--real-ip-header=x-forwarded-for"
+            "--trusted-proxy-hops=1"
           ]
           ++ (map (origin: "--extra-allowed-origin=${origin}") cfg.relyingParty.extraAllowedOrigins)
+          ++ (map (alg: "--allowed-algorithm=${alg}") cfg.allowedAlgorithms)
+          ++ optional (
+            cfg.credentialMaxAgeDays != null
+          ) "--credential-max-age-days=${toString cfg.credentialMaxAgeDays}"
+          ++ optional (cfg.maxUsers != null) "--max-users=${toString cfg.maxUsers}"
+          ++ optional (cfg.seed != null) "--seed-file=${seedFile}"
+          ++ optional cfg.pam.enable "--pam-socket=/run/webauthn-tiny/pam.sock"
         );
         CapabilityBoundingSet = [ ];
         DeviceAllow = [ ];
@@ -195,7 +292,8 @@ in
         RestrictAddressFamilies = [
           "AF_INET"
           "AF_INET6"
-        ];
+        ]
+        ++ optional (cfg.pam.enable || hasPrefix "unix:" cfg.adminAddress) "AF_UNIX";
         RestrictNamespaces = true;
         RestrictRealtime = true;
         RestrictSUIDSGID = true;
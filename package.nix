@@ -6,6 +6,8 @@
   openssl,
   sqlite,
   clippy,
+  # Reported by `/api/version` and the `build_info` metric.
+  gitHash ? null,
 }:
 let
   # TODO(jared): use `finalAttrs` once buildRustPackage supports it
@@ -18,11 +20,13 @@ let
       fileset = lib.fileset.unions [
         ./Cargo.toml
         ./Cargo.lock
+        ./build.rs
         ./templates
         ./src
       ];
     };
     cargoLock.lockFile = ./Cargo.lock;
+    env = lib.optionalAttrs (gitHash != null) { WEBAUTHN_TINY_GIT_HASH = gitHash; };
     strictDeps = true;
     nativeCheckInputs = [
       clippy
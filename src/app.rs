@@ -1,15 +1,46 @@
+use crate::{
+    audit::{AuditLogKey, SignedFields},
+    locale,
+    metadata::{cred_protect, is_hybrid, AuthenticatorInfo, PasskeyInfo},
+    policy::{MaxUsers, UserCreationPolicy},
+    reconcile::Reconciliation,
+    seed::Seed,
+    throttle, timing,
+    username::UsernameNormalization,
+};
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
-use libsqlite3_sys::ErrorCode::ConstraintViolation;
-use rusqlite::Error::{QueryReturnedNoRows, SqliteFailure};
+use base64::{engine::general_purpose, Engine as _};
+use libsqlite3_sys::{
+    ErrorCode::{
+        CannotOpen, ConstraintViolation, DatabaseBusy, DatabaseCorrupt, DatabaseLocked, DiskFull,
+        NotADatabase, ReadOnly, SystemIoFailure,
+    },
+    SQLITE_CONSTRAINT_PRIMARYKEY, SQLITE_CONSTRAINT_UNIQUE,
+};
+use metrics::counter;
+use rusqlite::{
+    Error::{QueryReturnedNoRows, SqliteFailure},
+    TransactionBehavior,
+};
 use serde::{Deserialize, Serialize};
-use std::{fmt::Display, sync::Arc};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::sync::RwLock;
 use tokio_rusqlite::Connection;
+use tracing::{debug, error, warn};
 use webauthn_rs::prelude::{AuthenticationResult, CredentialID, Passkey, Uuid};
+use webauthn_rs_proto::{
+    AuthenticatorAttachment, AuthenticatorTransport, COSEAlgorithm, CredentialProtectionPolicy,
+};
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct CredentialState {
@@ -17,58 +48,132 @@ pub struct CredentialState {
     pub credentials: Vec<Passkey>,
 }
 
-#[derive(Debug, Copy, Clone, Default)]
+/// Errors of `App` and the handlers using it, which `From<AppError> for StatusCode` maps to HTTP.
+/// Database errors are logged where they are converted, as only their kind is kept.
+#[derive(Debug, Copy, Clone, Default, thiserror::Error)]
 pub enum AppError {
+    #[error("user info is missing")]
     MissingUserInfo,
+    #[error("invalid username or password")]
+    InvalidPassword,
+    #[error("user not found")]
     UserNotFound,
+    #[error("user is deactivated")]
+    UserDeactivated,
+    #[error("new users cannot be created")]
+    UserCreationDenied,
+    #[error("this instance is read-only")]
+    ReadOnly,
+    #[error("ceremony timed out, please try again")]
+    CeremonyTimedOut,
+    #[error("credential or authenticator model is blocked")]
+    CredentialBlocked,
+    #[error("captcha verification failed")]
+    CaptchaFailed,
+    #[error("kiosk mode is not available to this user")]
+    NotKioskOperator,
+    #[error("credential not found")]
     CredentialNotFound,
+    #[error("unknown error")]
     BadUrl,
+    #[error("unknown error")]
     OriginNotAllowed,
+    #[error("incorrect credential used")]
     MismatchingCredential,
+    #[error("credential already exists")]
     DuplicateCredential,
+    #[error("bad input")]
     BadInput,
+    #[error("credential algorithm is not allowed")]
+    AlgorithmNotAllowed,
+    #[error("authenticator does not require user verification for the credential")]
+    CredentialProtectionRequired,
+    #[error("valid proof-of-work is required")]
+    ProofOfWorkRequired,
+    /// See `LoginThrottle`.
+    #[error("too many failed logins, please try again later")]
+    TooManyFailures,
+    #[error("recovery link is invalid, expired or already used")]
+    InvalidRecoveryToken,
+    #[error("impersonation link is invalid, expired or already used")]
+    InvalidImpersonationToken,
+    /// The session was issued to an admin impersonating the user, see
+    /// `App::issue_impersonation`.
+    #[error("not allowed while impersonating a user")]
+    Impersonating,
+    #[error("could not find data")]
     EntityNotFound,
+    #[error("session is invalid")]
     BadSession,
+    #[error("webauthn process failed")]
     WebauthnFailed,
     #[default]
+    #[error("unknown error")]
     UnknownError,
+    #[error("unknown error")]
     NoUserCredentials,
+    #[error("credential is awaiting approval by an admin")]
+    CredentialPending,
+    #[error("a credential has to be enrolled to log in")]
+    EnrollmentRequired,
+    #[error("a fresh webauthn assertion is required, see /api/step-up")]
+    StepUpRequired,
+    #[error("alias is already taken")]
+    DuplicateAlias,
+    #[error("alias not found")]
+    AliasNotFound,
+    #[error("the server is overloaded, please try again")]
+    Overloaded,
+    #[error("the server is low on disk space, please try again later")]
+    LowDiskSpace,
+    /// A unique constraint was violated, e.g. by a name that is taken already.
+    #[error("conflicts with existing data")]
+    Conflict,
+    /// The database stayed locked by another connection (e.g. a maintenance subcommand)
+    /// through all retries, see `BUSY_RETRIES`.
+    #[error("the database is busy, please try again")]
+    Busy,
+    #[error("the database is corrupt")]
+    Corrupt,
+    /// The database file could not be read or written.
+    #[error("could not access the database")]
+    Io,
+    /// Accessing the database failed repeatedly, see `DatabaseHealth`.
+    #[error("the database is unavailable, please try again later")]
+    DatabaseUnavailable,
+    /// The instance is being drained, see `Drain`.
+    #[error("the server is shutting down, please try again")]
+    Draining,
+    /// See `ForwardedHttps`.
+    #[error("the server has to be reached over HTTPS")]
+    PlaintextCeremony,
 }
 
-impl Display for AppError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let msg = match self {
-            AppError::MissingUserInfo => "user info is missing",
-            AppError::BadInput => "bad input",
-            AppError::EntityNotFound => "could not find data",
-            AppError::BadSession => "session is invalid",
-            AppError::DuplicateCredential => "credential already exists",
-            AppError::MismatchingCredential => "incorrect credential used",
-            AppError::CredentialNotFound => "credential not found",
-            AppError::WebauthnFailed => "webauthn process failed",
-            AppError::UserNotFound => "user not found",
-            _ => "unknown error",
-        };
-        write!(f, "{msg}")
-    }
-}
-
-impl std::error::Error for AppError {}
-
 #[derive(Serialize)]
 struct AppErrorResponse {
     error: String,
 }
 
+/// The error is kept in the response's extensions, so that `render_error_pages` can show it on
+/// an error page instead of the JSON body. Throttled logins carry `Retry-After`, which also
+/// tells them apart from the other `429 Too Many Requests`, a missing proof-of-work.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
+        let mut response = (
             StatusCode::from(self),
             Json(AppErrorResponse {
                 error: self.to_string(),
             }),
         )
-            .into_response()
+            .into_response();
+        if let AppError::TooManyFailures = self {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(throttle::FAILURE_TTL.as_secs()),
+            );
+        }
+        response.extensions_mut().insert(self);
+        response
     }
 }
 
@@ -77,9 +182,37 @@ impl From<AppError> for StatusCode {
         eprintln!("{:#?}", error);
         match error {
             AppError::BadInput => StatusCode::BAD_REQUEST,
+            AppError::InvalidPassword => StatusCode::UNAUTHORIZED,
+            AppError::AlgorithmNotAllowed => StatusCode::BAD_REQUEST,
+            AppError::CredentialProtectionRequired => StatusCode::BAD_REQUEST,
+            AppError::ProofOfWorkRequired => StatusCode::TOO_MANY_REQUESTS,
+            AppError::TooManyFailures => StatusCode::TOO_MANY_REQUESTS,
+            AppError::InvalidRecoveryToken => StatusCode::UNAUTHORIZED,
+            AppError::InvalidImpersonationToken => StatusCode::UNAUTHORIZED,
+            AppError::Impersonating => StatusCode::FORBIDDEN,
             AppError::UserNotFound => StatusCode::NOT_FOUND,
+            AppError::UserDeactivated => StatusCode::FORBIDDEN,
+            AppError::UserCreationDenied => StatusCode::FORBIDDEN,
+            AppError::ReadOnly => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::CeremonyTimedOut => StatusCode::REQUEST_TIMEOUT,
+            AppError::CredentialBlocked => StatusCode::FORBIDDEN,
+            AppError::CaptchaFailed => StatusCode::FORBIDDEN,
+            AppError::NotKioskOperator => StatusCode::FORBIDDEN,
             AppError::CredentialNotFound => StatusCode::NOT_FOUND,
+            AppError::DuplicateCredential => StatusCode::CONFLICT,
             AppError::NoUserCredentials => StatusCode::NO_CONTENT,
+            AppError::CredentialPending => StatusCode::FORBIDDEN,
+            AppError::EnrollmentRequired => StatusCode::FORBIDDEN,
+            AppError::StepUpRequired => StatusCode::FORBIDDEN,
+            AppError::DuplicateAlias => StatusCode::CONFLICT,
+            AppError::AliasNotFound => StatusCode::NOT_FOUND,
+            AppError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::LowDiskSpace => StatusCode::INSUFFICIENT_STORAGE,
+            AppError::Conflict => StatusCode::CONFLICT,
+            AppError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::DatabaseUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Draining => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::PlaintextCeremony => StatusCode::FORBIDDEN,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -93,14 +226,34 @@ impl From<tower_sessions::session::Error> for AppError {
 
 impl From<rusqlite::Error> for AppError {
     fn from(error: rusqlite::Error) -> Self {
-        match error {
+        let app_error = match &error {
             SqliteFailure(err, _) => match err.code {
+                ConstraintViolation
+                    if matches!(
+                        err.extended_code,
+                        SQLITE_CONSTRAINT_UNIQUE | SQLITE_CONSTRAINT_PRIMARYKEY
+                    ) =>
+                {
+                    AppError::Conflict
+                }
                 ConstraintViolation => AppError::BadInput,
+                DiskFull => AppError::LowDiskSpace,
+                DatabaseBusy | DatabaseLocked => AppError::Busy,
+                DatabaseCorrupt | NotADatabase => AppError::Corrupt,
+                SystemIoFailure | CannotOpen => AppError::Io,
+                ReadOnly => AppError::ReadOnly,
                 _ => AppError::UnknownError,
             },
-            QueryReturnedNoRows => AppError::EntityNotFound,
+            QueryReturnedNoRows => return AppError::EntityNotFound,
             _ => AppError::UnknownError,
+        };
+        match app_error {
+            AppError::Corrupt | AppError::Io | AppError::UnknownError => {
+                error!("database error: {error}")
+            }
+            _ => debug!("database error: {error}"),
         }
+        app_error
     }
 }
 
@@ -125,22 +278,309 @@ impl From<uuid::Error> for AppError {
     }
 }
 
+/// Schema changes applied after the initial tables are created. Each entry runs exactly once,
+/// tracked by sqlite's `user_version` pragma, so new migrations must only ever be appended.
+const MIGRATIONS: &[&str] = &[
+    r#"alter table credentials add column algorithm integer"#,
+    r#"alter table credentials add column aaguid text;
+       create index credentials_aaguid on credentials(aaguid)"#,
+    r#"alter table users add column active integer not null default 1"#,
+    // Existing credentials are treated as if they were created when this migration ran.
+    r#"alter table credentials add column created_at integer;
+       update credentials set created_at = cast(strftime('%s', 'now') as integer)"#,
+    r#"create table recovery_tokens (
+         id text primary key not null,
+         user uuid not null,
+         expires_at integer not null,
+         used_at integer,
+         foreign key(user) references users(id)
+       );
+       create table audit_log (
+         id integer primary key,
+         at integer not null,
+         event text not null,
+         username text,
+         detail text
+       )"#,
+    r#"create table blocklist (
+         id integer primary key,
+         aaguid text unique,
+         cred_id json unique,
+         reason text,
+         created_at integer not null,
+         check ((aaguid is null) != (cred_id is null))
+       )"#,
+    // Passkeys serialize their credential ID as base64url already, the replacements only
+    // normalize IDs stored in another base64 variant.
+    r#"alter table credentials add column handle text;
+       update credentials
+         set handle = rtrim(replace(replace(value->>'$.cred.cred_id', '+', '-'), '/', '_'), '=');
+       create unique index credentials_handle on credentials(handle)"#,
+    r#"create table user_groups (
+         user uuid not null,
+         name text not null,
+         primary key(user, name),
+         foreign key(user) references users(id)
+       );
+       create table recovery_codes (
+         user uuid not null,
+         hash text not null,
+         used_at integer,
+         primary key(user, hash),
+         foreign key(user) references users(id)
+       )"#,
+    r#"alter table credentials add column min_pin_length integer"#,
+    r#"create table pending_credentials (
+         id integer primary key,
+         user uuid not null,
+         name text not null,
+         value json not null,
+         algorithm integer not null,
+         aaguid text,
+         min_pin_length integer,
+         handle text unique not null,
+         created_at integer not null,
+         foreign key(user) references users(id)
+       )"#,
+    r#"alter table users add column enrollment_started_at integer"#,
+    r#"create table user_settings (
+         user uuid not null,
+         key text not null,
+         value json not null,
+         primary key(user, key),
+         foreign key(user) references users(id)
+       )"#,
+    r#"create table pending_ceremonies (
+         id text primary key,
+         kind text not null,
+         state json not null,
+         expires_at integer not null
+       )"#,
+    r#"create table user_aliases (
+         alias text primary key,
+         user uuid not null,
+         foreign key(user) references users(id)
+       )"#,
+    r#"alter table users add column password_hash text"#,
+    // Tags go away with their credential however it is deleted.
+    r#"create table credential_tags (
+         credential text not null,
+         name text not null,
+         primary key(credential, name)
+       );
+       create trigger credential_tags_delete after delete on credentials begin
+         delete from credential_tags where credential = old.handle;
+       end"#,
+    r#"alter table credentials add column attachment text;
+       alter table credentials add column last_used_at integer;
+       alter table credentials add column last_attachment text;
+       alter table credentials add column uses integer not null default 0;
+       alter table credentials add column hybrid_uses integer not null default 0;
+       alter table pending_credentials add column attachment text"#,
+    r#"alter table audit_log add column mac text;
+       create table audit_log_head (
+         id integer primary key check (id = 1),
+         last_id integer not null,
+         mac text not null
+       )"#,
+    // Uses are recorded one by one so that old ones can be purged. Existing counts are carried
+    // over at the time of the last use, and the counters on credentials are no longer written.
+    r#"create table credential_uses (
+         credential text not null,
+         at integer not null,
+         attachment text,
+         hybrid integer not null
+       );
+       create index credential_uses_credential on credential_uses(credential, at);
+       create index credential_uses_at on credential_uses(at);
+       create trigger credential_uses_delete after delete on credentials begin
+         delete from credential_uses where credential = old.handle;
+       end;
+       insert into credential_uses (credential, at, attachment, hybrid)
+         with recursive n(i) as (
+           select 1 union all select i + 1 from n where i < (select max(uses) from credentials)
+         )
+         select c.handle, c.last_used_at, c.last_attachment, n.i <= c.hybrid_uses
+         from credentials c join n on n.i <= c.uses
+         where c.last_used_at is not null;
+       create table audit_log_anchor (
+         id integer primary key check (id = 1),
+         last_id integer not null,
+         mac text not null,
+         anchor_mac text not null
+       )"#,
+    r#"create index audit_log_username on audit_log(username, id)"#,
+    r#"create table metadata (
+         key text primary key not null,
+         value text not null
+       )"#,
+    // Filled for existing credentials by the migrate-credentials subcommand.
+    r#"alter table credentials add column transports json;
+       alter table credentials add column backup_eligible boolean;
+       alter table credentials add column backup_state boolean"#,
+    r#"create index credentials_user on credentials(user)"#,
+    // Only hashes of the challenges are kept, see `App::consume_challenge`.
+    r#"create table consumed_challenges (
+         challenge text primary key,
+         expires_at integer not null
+       )"#,
+    // Only hashes of the tokens are kept, see `App::issue_impersonation`.
+    r#"create table impersonations (
+         id text primary key not null,
+         user uuid not null,
+         operator text not null,
+         expires_at integer not null,
+         used_at integer,
+         foreign key(user) references users(id)
+       )"#,
+];
+
+/// How often a statement that found the database locked by another connection (e.g. a
+/// maintenance subcommand, or the session store in another file) is retried, waiting
+/// `BUSY_BACKOFF` before the first retry and twice as long before every further one.
+const BUSY_RETRIES: u32 = 5;
+const BUSY_BACKOFF: Duration = Duration::from_millis(10);
+
+fn is_busy<R>(result: &tokio_rusqlite::Result<R>) -> bool {
+    matches!(
+        result,
+        Err(tokio_rusqlite::Error::Rusqlite(SqliteFailure(err, _)))
+            if matches!(err.code, DatabaseBusy | DatabaseLocked)
+    )
+}
+
+/// Tables with rows belonging to a user, which have to be emptied before the user is deleted.
+const USER_TABLES: [&str; 8] = [
+    "credentials",
+    "pending_credentials",
+    "recovery_tokens",
+    "impersonations",
+    "user_settings",
+    "recovery_codes",
+    "user_groups",
+    "user_aliases",
+];
+
+/// Limits on what users can store with `replace_user_settings`, which is meant for a handful of
+/// preferences rather than arbitrary data.
+const MAX_USER_SETTINGS: usize = 32;
+
+/// Limits on the tags of a credential (see `set_credential_tags`), which are short labels such
+/// as `work` or `backup`.
+const MAX_CREDENTIAL_TAGS: usize = 8;
+const MAX_CREDENTIAL_TAG_LEN: usize = 32;
+const MAX_USER_SETTING_KEY_LEN: usize = 64;
+const MAX_USER_SETTING_VALUE_LEN: usize = 4096;
+
+/// Condition matching credentials (aliased as `c`) that are on the blocklist, either by their
+/// authenticator's AAGUID or by their credential ID.
+const CREDENTIAL_IS_BLOCKED: &str = r#"exists(
+    select 1 from blocklist b
+    where b.aaguid = c.aaguid or b.cred_id = c.value->'$.cred.cred_id'
+)"#;
+
+/// The public identifier of a credential: its credential ID, base64url encoded without padding.
+/// It is stored along with the credential so that it does not depend on how a passkey happens to
+/// serialize its ID.
+pub fn credential_handle(cred_id: &CredentialID) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(cred_id)
+}
+
+/// Maps what clients send as a credential identifier to its handle. Besides handles, this
+/// accepts credential IDs in the other base64 variants that were used as identifiers before
+/// handles existed. Those are deprecated; each use is logged and counted in
+/// `legacy_credential_ids`, so that they can be refused once the counter stays at zero.
+fn normalize_credential_handle(handle: &str) -> String {
+    match serde_json::from_value::<CredentialID>(serde_json::Value::String(handle.to_string())) {
+        Ok(cred_id) => {
+            let normalized = credential_handle(&cred_id);
+            if normalized != handle {
+                warn!("credential {normalized} was identified by its deprecated base64 ID");
+                counter!("legacy_credential_ids").increment(1);
+            }
+            normalized
+        }
+        Err(_) => handle.to_string(),
+    }
+}
+
+/// Applies the `migrations` the database has not seen yet. Each one is recorded in
+/// `user_version` in the same transaction as its changes, so that a failing migration (or the
+/// process dying) leaves the database at the version before it instead of half-way through.
+fn apply_migrations(conn: &mut rusqlite::Connection, migrations: &[&str]) -> rusqlite::Result<()> {
+    let version: usize = conn.query_row("pragma user_version", [], |row| row.get(0))?;
+    for (i, migration) in migrations.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
 pub struct App {
     db: Connection,
+    user_creation_policy: Arc<dyn UserCreationPolicy>,
+    username_normalization: UsernameNormalization,
+    /// Entries are only signed when this is set, see `App::verify_audit_log`.
+    audit_log_key: Option<Arc<AuditLogKey>>,
 }
 
 pub type SharedAppState = Arc<RwLock<App>>;
 
 #[derive(Clone, Debug)]
 pub struct CredentialWithName {
+    pub handle: String,
     pub name: String,
+    pub algorithm: COSEAlgorithm,
     pub credential: Passkey,
+    /// Blocked credentials are kept so that their owner can see what needs replacing, but they
+    /// cannot be used to authenticate.
+    pub blocked: bool,
+    pub min_pin_length: Option<u32>,
+    /// Labels the owner gave the credential, sorted.
+    pub tags: Vec<String>,
+    /// How the credential was attached to the device it was registered from.
+    pub attachment: Option<AuthenticatorAttachment>,
+    pub usage: CredentialUsage,
+    /// Unix timestamp (in seconds) of when the credential was registered, unknown for ones
+    /// registered before this was recorded.
+    pub created_at: Option<u64>,
+    /// The model of the authenticator, or of the passkey provider (e.g. a password manager).
+    pub aaguid: Option<Uuid>,
+}
+
+/// How a credential has been used since usage started being recorded, or within the usage
+/// retention window.
+#[derive(Serialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct CredentialUsage {
+    pub uses: u64,
+    /// Authentications from another device than the one it lives on, over the hybrid transport
+    /// (formerly caBLE), e.g. with a phone scanning a QR code shown by a laptop.
+    pub hybrid_uses: u64,
+    /// Unix timestamp (in seconds).
+    pub last_used_at: Option<u64>,
+    pub last_attachment: Option<AuthenticatorAttachment>,
+}
+
+/// Attachments are stored by their name in the WebAuthn spec.
+fn attachment_name(attachment: Option<AuthenticatorAttachment>) -> Option<&'static str> {
+    attachment.map(|attachment| match attachment {
+        AuthenticatorAttachment::Platform => "platform",
+        AuthenticatorAttachment::CrossPlatform => "cross-platform",
+    })
+}
+
+fn parse_attachment(name: Option<String>) -> Option<AuthenticatorAttachment> {
+    serde_json::from_value(serde_json::Value::String(name?)).ok()
 }
 
 #[derive(Default, Debug, Clone)]
 pub struct UserWithCredentials {
     pub id: Uuid,
     pub username: String,
+    /// Deactivated users keep their credentials but cannot log in, register or pass validation.
+    pub active: bool,
     pub credentials: Vec<CredentialWithName>,
 }
 
@@ -150,38 +590,620 @@ impl UserWithCredentials {
     }
 }
 
+/// A credential along with its owner, as listed in the admin API.
+#[derive(Serialize, Debug, Clone)]
+pub struct CredentialSummary {
+    pub username: String,
+    pub name: String,
+    pub handle: String,
+    /// Deprecated in favor of `handle`.
+    pub id: CredentialID,
+    pub aaguid: Option<Uuid>,
+    pub algorithm: COSEAlgorithm,
+    /// Unix timestamp (in seconds) of when the credential was registered.
+    pub created_at: u64,
+    pub blocked: bool,
+    pub cred_protect: Option<CredentialProtectionPolicy>,
+    pub min_pin_length: Option<u32>,
+    /// Unknown (like the flags) for credentials registered before these were recorded, until the
+    /// migrate-credentials subcommand fills them in.
+    pub transports: Option<Vec<AuthenticatorTransport>>,
+    pub backup_eligible: Option<bool>,
+    pub backup_state: Option<bool>,
+}
+
+/// What `App::migrate_credentials` did to one credential.
+#[derive(Debug, Clone)]
+pub struct CredentialMigration {
+    pub username: String,
+    pub name: String,
+    /// The columns that were filled, or why the stored passkey could not be parsed.
+    pub result: Result<Vec<&'static str>, String>,
+}
+
+/// What an admin blocked, in response to e.g. a vulnerability in an authenticator model.
+#[derive(Debug, Clone)]
+pub enum BlockedItem {
+    Aaguid(Uuid),
+    Credential(CredentialID),
+}
+
+/// An entry of the blocklist, as listed in the admin API.
+#[derive(Serialize, Debug, Clone)]
+pub struct BlocklistEntry {
+    pub id: i64,
+    pub aaguid: Option<Uuid>,
+    pub credential_id: Option<CredentialID>,
+    pub reason: Option<String>,
+    /// Unix timestamp (in seconds).
+    pub created_at: u64,
+}
+
+/// A registered credential that cannot be used until an admin approves it.
+#[derive(Serialize, Debug, Clone)]
+pub struct PendingCredential {
+    pub id: i64,
+    pub username: String,
+    pub name: String,
+    pub handle: String,
+    pub aaguid: Option<Uuid>,
+    pub algorithm: COSEAlgorithm,
+    pub min_pin_length: Option<u32>,
+    /// Unix timestamp (in seconds) of when the credential was registered.
+    pub created_at: u64,
+}
+
+/// A user, as listed in the admin API.
+#[derive(Serialize, Debug, Clone)]
+pub struct UserSummary {
+    pub username: String,
+    pub active: bool,
+    pub credentials: usize,
+    pub groups: Vec<String>,
+    /// Other usernames that log in as this user, see `App::add_user_alias`.
+    pub aliases: Vec<String>,
+}
+
+/// Which users `App::search_users` returns. Unset fields match any user.
+#[derive(Debug, Clone, Default)]
+pub struct UserFilter {
+    /// The start of the username or of one of the user's aliases.
+    pub search: Option<String>,
+    pub has_credentials: Option<bool>,
+    /// Whether the user is deactivated, see `App::set_user_active`.
+    pub locked: Option<bool>,
+}
+
+/// Everything stored about a user, as exported for them. Secrets (password and recovery code
+/// hashes, recovery links) are only described.
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct UserExport {
+    pub username: String,
+    pub active: bool,
+    pub password_set: bool,
+    /// Unix timestamp (in seconds).
+    pub enrollment_started_at: Option<u64>,
+    pub groups: Vec<String>,
+    pub aliases: Vec<String>,
+    pub settings: BTreeMap<String, serde_json::Value>,
+    pub credentials: Vec<CredentialExport>,
+    pub pending_credentials: Vec<PendingCredential>,
+    pub recovery_codes: RecoveryCodesExport,
+    pub recovery_links: Vec<RecoveryLinkExport>,
+    pub audit_events: Vec<AuditEvent>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CredentialExport {
+    #[serde(flatten)]
+    pub summary: CredentialSummary,
+    pub tags: Vec<String>,
+    pub attachment: Option<AuthenticatorAttachment>,
+    pub usage: CredentialUsage,
+}
+
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct RecoveryCodesExport {
+    pub remaining: usize,
+    /// Unix timestamps (in seconds) of when codes were used.
+    pub used_at: Vec<u64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RecoveryLinkExport {
+    /// Unix timestamps (in seconds).
+    pub expires_at: u64,
+    pub used_at: Option<u64>,
+}
+
+/// The outcome of checking the audit log against its MACs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditLogVerification {
+    Intact {
+        entries: usize,
+    },
+    /// The entry was recorded without being signed, or its MAC was removed.
+    Unsigned {
+        id: i64,
+    },
+    /// The entry was modified, or entries before it were removed.
+    Modified {
+        id: i64,
+    },
+    /// Entries after `after` (or all entries) were removed.
+    Truncated {
+        after: Option<i64>,
+    },
+}
+
+impl Display for AuditLogVerification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Intact { entries } => write!(f, "all {entries} entries are intact"),
+            Self::Unsigned { id } => write!(f, "entry {id} is not signed"),
+            Self::Modified { id } => {
+                write!(
+                    f,
+                    "entry {id} was modified, or entries before it were removed"
+                )
+            }
+            Self::Truncated { after: Some(id) } => write!(f, "entries after {id} were removed"),
+            Self::Truncated { after: None } => write!(f, "all entries were removed"),
+        }
+    }
+}
+
+/// A security relevant event, as recorded in the audit log.
+#[derive(Serialize, Debug, Clone)]
+pub struct AuditEvent {
+    pub id: i64,
+    /// Unix timestamp (in seconds).
+    pub at: u64,
+    pub event: String,
+    pub username: Option<String>,
+    pub detail: Option<String>,
+}
+
+fn record_event(
+    conn: &rusqlite::Connection,
+    event: &str,
+    username: Option<&str>,
+    detail: Option<&str>,
+) -> Result<(), AppError> {
+    conn.execute(
+        r#"insert into audit_log (at, event, username, detail)
+           values (cast(strftime('%s', 'now') as integer), ?1, ?2, ?3)"#,
+        (event, username, detail),
+    )?;
+    Ok(())
+}
+
+/// Deletes a user and everything belonging to them, except for their audit log entries. Returns
+/// the number of credentials that were deleted.
+fn delete_user(conn: &rusqlite::Connection, username: &str) -> Result<usize, AppError> {
+    let user_id = user_id(conn, username)?;
+    let credentials = conn.execute(r#"delete from credentials where user = ?1"#, (&user_id,))?;
+    for table in USER_TABLES {
+        conn.execute(
+            &format!(r#"delete from {table} where user = ?1"#),
+            (&user_id,),
+        )?;
+    }
+    conn.execute(r#"delete from users where id = ?1"#, (&user_id,))?;
+    Ok(credentials)
+}
+
+/// Signs the audit log entries after the entry with ID `after`, or if not given, those recorded
+/// since the log was last signed, and moves the head of the log to the last entry.
+fn sign_audit_log(
+    conn: &rusqlite::Connection,
+    key: &AuditLogKey,
+    after: Option<i64>,
+) -> Result<(), AppError> {
+    let head = match conn.query_row(r#"select last_id from audit_log_head"#, [], |row| {
+        row.get::<_, i64>(0)
+    }) {
+        Err(QueryReturnedNoRows) => 0,
+        result => result?,
+    };
+    let after = after.unwrap_or(i64::MAX).min(head);
+
+    // The entry before may have been purged, in which case the chain continues from the anchor.
+    let mut previous = match conn.query_row(
+        r#"select mac from (
+             select id, mac from audit_log where id <= ?1
+             union all
+             select last_id, mac from audit_log_anchor where last_id <= ?1
+           )
+           order by id desc
+           limit 1"#,
+        (after,),
+        |row| row.get::<_, Option<String>>(0),
+    ) {
+        Err(QueryReturnedNoRows) => None,
+        result => result?,
+    }
+    .unwrap_or_default();
+
+    let entries = conn
+        .prepare(
+            r#"select id, at, event, username, detail from audit_log
+               where id > ?1
+               order by id"#,
+        )?
+        .query_map((after,), |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, u64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<String>>(4)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some(last_id) = entries.last().map(|entry| entry.0) else {
+        return Ok(());
+    };
+
+    for (id, at, event, username, detail) in entries {
+        previous = key.entry_mac(
+            &previous,
+            SignedFields {
+                id,
+                at,
+                event: &event,
+                username: username.as_deref(),
+                detail: detail.as_deref(),
+            },
+        );
+        conn.execute(
+            r#"update audit_log set mac = ?1 where id = ?2"#,
+            (&previous, id),
+        )?;
+    }
+
+    conn.execute(
+        r#"insert or replace into audit_log_head (id, last_id, mac) values (1, ?1, ?2)"#,
+        (last_id, key.head_mac(last_id, &previous)),
+    )?;
+    Ok(())
+}
+
+/// A credential serialized for storage, so that the passkey does not have to be moved to the
+/// database thread.
+struct NewCredential {
+    cred_id: String,
+    handle: String,
+    value: String,
+    algorithm: i32,
+    aaguid: Option<String>,
+    min_pin_length: Option<u32>,
+    attachment: Option<&'static str>,
+    transports: Option<String>,
+    backup_eligible: bool,
+    backup_state: bool,
+}
+
+impl NewCredential {
+    fn new(credential: &Passkey, info: AuthenticatorInfo) -> Result<Self, AppError> {
+        let passkey_info = PasskeyInfo::of(credential);
+        Ok(Self {
+            cred_id: serde_json::to_string(credential.cred_id())?,
+            handle: credential_handle(credential.cred_id()),
+            value: serde_json::to_string(credential)?,
+            algorithm: passkey_info.algorithm as i32,
+            aaguid: info
+                .aaguid
+                .or(passkey_info.aaguid)
+                .map(|aaguid| aaguid.to_string()),
+            min_pin_length: info.min_pin_length,
+            attachment: attachment_name(info.attachment),
+            transports: passkey_info
+                .transports
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?,
+            backup_eligible: passkey_info.backup_eligible,
+            backup_state: passkey_info.backup_state,
+        })
+    }
+
+    /// Refuses credentials that are already registered (or awaiting approval) or blocked.
+    fn check(&self, conn: &rusqlite::Connection) -> Result<(), AppError> {
+        let exists: bool = conn.query_row(
+            r#"select exists(select 1 from credentials where value->'$.cred.cred_id' = ?1)
+                 or exists(select 1 from pending_credentials where handle = ?2)"#,
+            (&self.cred_id, &self.handle),
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Err(AppError::DuplicateCredential);
+        }
+
+        let blocked: bool = conn.query_row(
+            r#"select exists(select 1 from blocklist where aaguid = ?1 or cred_id = json(?2))"#,
+            (&self.aaguid, &self.cred_id),
+            |row| row.get(0),
+        )?;
+        if blocked {
+            return Err(AppError::CredentialBlocked);
+        }
+
+        Ok(())
+    }
+
+    fn insert(
+        self,
+        conn: &rusqlite::Connection,
+        username: &str,
+        name: String,
+    ) -> Result<(), AppError> {
+        self.check(conn)?;
+
+        let user_id = user_id(conn, username)?;
+        conn.execute(
+            r#"insert into credentials
+                 (name, user, value, algorithm, aaguid, created_at, handle, min_pin_length,
+                  attachment, transports, backup_eligible, backup_state)
+               values (?1, ?2, json(?3), ?4, ?5, cast(strftime('%s', 'now') as integer), ?6, ?7,
+                       ?8, json(?9), ?10, ?11)"#,
+            (
+                name,
+                user_id,
+                self.value,
+                self.algorithm,
+                self.aaguid,
+                self.handle,
+                self.min_pin_length,
+                self.attachment,
+                self.transports,
+                self.backup_eligible,
+                self.backup_state,
+            ),
+        )?;
+
+        Ok(())
+    }
+
+    /// Like `insert`, but the credential cannot be used until an admin approves it.
+    fn insert_pending(
+        self,
+        conn: &rusqlite::Connection,
+        username: &str,
+        name: String,
+    ) -> Result<i64, AppError> {
+        self.check(conn)?;
+
+        let user_id = user_id(conn, username)?;
+        conn.execute(
+            r#"insert into pending_credentials
+                 (name, user, value, algorithm, aaguid, created_at, handle, min_pin_length,
+                  attachment)
+               values (?1, ?2, json(?3), ?4, ?5, cast(strftime('%s', 'now') as integer), ?6, ?7,
+                       ?8)"#,
+            (
+                name,
+                user_id,
+                self.value,
+                self.algorithm,
+                self.aaguid,
+                self.handle,
+                self.min_pin_length,
+                self.attachment,
+            ),
+        )?;
+
+        Ok(conn.last_insert_rowid())
+    }
+}
+
+/// Recovery codes are only stored hashed, so that seed files (e.g. in the nix store) do not
+/// contain them.
+pub fn recovery_code_hash(code: &str) -> String {
+    sha256_hex(code.trim())
+}
+
+fn sha256_hex(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn user_id(conn: &rusqlite::Connection, username: &str) -> Result<String, AppError> {
+    match conn.query_row(
+        r#"select id from users where username = ?1"#,
+        (username,),
+        |row| row.get::<_, String>(0),
+    ) {
+        Err(QueryReturnedNoRows) => Err(AppError::UserNotFound),
+        result => Ok(result?),
+    }
+}
+
 impl App {
     pub fn new(db: Connection) -> Self {
-        Self { db }
+        Self {
+            db,
+            user_creation_policy: Arc::new(MaxUsers(None)),
+            username_normalization: UsernameNormalization::default(),
+            audit_log_key: None,
+        }
+    }
+
+    pub fn with_user_creation_policy(mut self, policy: Arc<dyn UserCreationPolicy>) -> Self {
+        self.user_creation_policy = policy;
+        self
+    }
+
+    pub fn with_username_normalization(mut self, normalization: UsernameNormalization) -> Self {
+        self.username_normalization = normalization;
+        self
+    }
+
+    pub fn with_audit_log_key(mut self, key: AuditLogKey) -> Self {
+        self.audit_log_key = Some(Arc::new(key));
+        self
+    }
+
+    /// Runs `function` on the database thread, attributing the time spent to the `db` phase of
+    /// the current request.
+    async fn call<F, R>(&self, function: F) -> tokio_rusqlite::Result<R>
+    where
+        F: FnMut(&mut rusqlite::Connection) -> tokio_rusqlite::Result<R> + 'static + Send,
+        R: Send + 'static,
+    {
+        let function = Arc::new(Mutex::new(function));
+        let mut retries = 0;
+        loop {
+            let function = function.clone();
+            let result = timing::measure(
+                "db",
+                self.db
+                    .call(move |conn| (function.lock().expect("database call panicked"))(conn)),
+            )
+            .await;
+            if retries == BUSY_RETRIES || !is_busy(&result) {
+                return result;
+            }
+            counter!("database_busy_retries").increment(1);
+            tokio::time::sleep(BUSY_BACKOFF * 2u32.pow(retries)).await;
+            retries += 1;
+        }
+    }
+
+    /// Runs `function` inside a transaction on the database thread. The transaction is committed
+    /// if `function` succeeds and rolled back otherwise. Transactions take the write lock up
+    /// front so that a read followed by a write cannot race with another writer. Audit log
+    /// entries recorded by `function` are signed before the transaction is committed.
+    async fn transaction<F, R>(&self, function: F) -> Result<R, AppError>
+    where
+        F: FnOnce(&rusqlite::Transaction) -> Result<R, AppError> + 'static + Send,
+        R: Send + 'static,
+    {
+        let key = self.audit_log_key.clone();
+        // Only taking the write lock is retried by `call`, which happens before `function` runs.
+        let mut function = Some(function);
+        self.call(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let function = function.take().expect("transaction ran twice");
+            Ok(function(&tx).and_then(|result| {
+                if let Some(key) = key.as_ref() {
+                    sign_audit_log(&tx, key, None)?;
+                }
+                tx.commit()?;
+                Ok(result)
+            }))
+        })
+        .await?
     }
 
     pub async fn init(&self) -> Result<(), AppError> {
-        self.db
-            .call(|conn| {
-                conn.execute(
-                    r#"create table if not exists users (
+        self.call(|conn| {
+            conn.execute(
+                r#"create table if not exists users (
                          id uuid primary key not null,
                          username text not null unique
                        )"#,
-                    [],
-                )?;
+                [],
+            )?;
 
-                conn.execute(
-                    r#"create table if not exists credentials (
+            conn.execute(
+                r#"create table if not exists credentials (
                          name text not null,
                          user uuid not null,
                          value json not null,
                          foreign key(user) references users(id),
                          unique(name, user)
                        )"#,
-                    [],
+                [],
+            )?;
+
+            apply_migrations(conn, MIGRATIONS)?;
+
+            Ok(())
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Whether the database has all migrations applied. Databases opened read-only (e.g. a
+    /// replica) cannot be migrated, so they must already match this version.
+    pub async fn schema_is_current(&self) -> Result<bool, AppError> {
+        let version: usize = self
+            .call(|conn| Ok(conn.query_row("pragma user_version", [], |row| row.get(0))?))
+            .await?;
+
+        Ok(version == MIGRATIONS.len())
+    }
+
+    /// The RP ID recorded by `record_rp_id`, which the credentials in the database are scoped to.
+    pub async fn stored_rp_id(&self) -> Result<Option<String>, AppError> {
+        Ok(self
+            .call(|conn| {
+                Ok(
+                    match conn.query_row(
+                        r#"select value from metadata where key = 'rp_id'"#,
+                        [],
+                        |row| row.get::<_, String>(0),
+                    ) {
+                        Err(QueryReturnedNoRows) => None,
+                        result => Some(result?),
+                    },
+                )
+            })
+            .await?)
+    }
+
+    /// Records the RP ID the server runs with, along with an audit log entry if it replaces
+    /// another one.
+    pub async fn record_rp_id(&self, rp_id: String) -> Result<(), AppError> {
+        self.transaction(move |tx| {
+            let previous = match tx.query_row(
+                r#"select value from metadata where key = 'rp_id'"#,
+                [],
+                |row| row.get::<_, String>(0),
+            ) {
+                Err(QueryReturnedNoRows) => None,
+                result => Some(result?),
+            };
+            if previous.as_ref() == Some(&rp_id) {
+                return Ok(());
+            }
+            tx.execute(
+                r#"insert or replace into metadata (key, value) values ('rp_id', ?1)"#,
+                (&rp_id,),
+            )?;
+            if let Some(previous) = previous {
+                record_event(
+                    tx,
+                    "rp_id_changed",
+                    None,
+                    Some(&format!("{previous} -> {rp_id}")),
                 )?;
+            }
+            Ok(())
+        })
+        .await
+    }
 
-                Ok(())
+    /// The number of credentials of all users, including blocked ones.
+    /// Reads from the database, for `DatabaseHealth` to tell whether it is accessible.
+    pub async fn ping(&self) -> Result<(), AppError> {
+        Ok(self
+            .call(|conn| {
+                Ok(conn.query_row(r#"select count(*) from sqlite_master"#, [], |_| Ok(()))?)
             })
-            .await?;
+            .await?)
+    }
 
-        Ok(())
+    pub async fn credential_count(&self) -> Result<usize, AppError> {
+        Ok(self
+            .call(|conn| {
+                Ok(conn.query_row(r#"select count(*) from credentials"#, [], |row| row.get(0))?)
+            })
+            .await?)
     }
 
     pub async fn get_user_with_credentials(
@@ -191,24 +1213,53 @@ impl App {
         let username_ = username.clone();
 
         let users = self
-            .db
             .call(move |conn| {
                 Ok(conn
-                    .prepare(
-                        r#"select u.id, u.username, c.name, c.value
+                    .prepare(&format!(
+                        r#"select u.id, u.username, c.name, c.value, c.algorithm, u.active,
+                             {CREDENTIAL_IS_BLOCKED}, c.handle, c.min_pin_length,
+                             (select json_group_array(t.name) from credential_tags t
+                              where t.credential = c.handle),
+                             c.attachment,
+                             (select count(*) from credential_uses s
+                              where s.credential = c.handle),
+                             (select sum(s.hybrid) from credential_uses s
+                              where s.credential = c.handle),
+                             (select max(s.at) from credential_uses s
+                              where s.credential = c.handle),
+                             (select s.attachment from credential_uses s
+                              where s.credential = c.handle
+                              order by s.at desc, s.rowid desc
+                              limit 1),
+                             c.created_at, c.aaguid
                            from users u
                            left join credentials c on u.id = c.user
-                           where username = ?1"#,
-                    )?
+                           where username = ?1"#
+                    ))?
                     .query_map((&username_,), |row| {
                         Ok((
                             row.get::<_, String>(0)?,
                             row.get::<_, String>(1)?,
                             row.get::<_, Option<String>>(2)?,
                             row.get::<_, Option<String>>(3)?,
+                            row.get::<_, Option<i32>>(4)?,
+                            row.get::<_, bool>(5)?,
+                            row.get::<_, bool>(6)?,
+                            row.get::<_, Option<String>>(7)?,
+                            row.get::<_, Option<u32>>(8)?,
+                            row.get::<_, Option<String>>(9)?,
+                            row.get::<_, Option<String>>(10)?,
+                            CredentialUsage {
+                                uses: row.get::<_, Option<u64>>(11)?.unwrap_or_default(),
+                                hybrid_uses: row.get::<_, Option<u64>>(12)?.unwrap_or_default(),
+                                last_used_at: row.get(13)?,
+                                last_attachment: parse_attachment(row.get(14)?),
+                            },
+                            row.get::<_, Option<u64>>(15)?,
+                            row.get::<_, Option<String>>(16)?,
                         ))
                     })?
-                    .filter_map(|v| if let Ok(v_ok) = v { Some(v_ok) } else { None })
+                    .filter_map(|v| v.ok())
                     .fold(Vec::new(), |mut accumulator, current| {
                         accumulator.push(current);
                         accumulator
@@ -223,17 +1274,36 @@ impl App {
                     user.id = id;
                 }
 
-                if u.2.is_some() && u.3.is_some() {
-                    if let Ok(passkey) =
-                        serde_json::from_str::<Passkey>(&u.3.expect("is_some guard"))
-                    {
+                if let (Some(name), Some(value), Some(handle)) = (u.2, u.3, u.7) {
+                    if let Ok(passkey) = serde_json::from_str::<Passkey>(&value) {
+                        // Credentials registered before the algorithm column existed fall back
+                        // to what is stored in the passkey itself.
+                        let algorithm =
+                            u.4.and_then(|alg| COSEAlgorithm::try_from(alg as i128).ok())
+                                .unwrap_or(*passkey.cred_algorithm());
                         user.credentials.push(CredentialWithName {
-                            name: u.2.expect("is_some guard"),
+                            handle,
+                            name,
+                            algorithm,
                             credential: passkey,
+                            blocked: u.6,
+                            min_pin_length: u.8,
+                            tags: {
+                                let mut tags: Vec<String> =
+                                    u.9.and_then(|tags| serde_json::from_str(&tags).ok())
+                                        .unwrap_or_default();
+                                tags.sort();
+                                tags
+                            },
+                            attachment: parse_attachment(u.10),
+                            usage: u.11,
+                            created_at: u.12,
+                            aaguid: u.13.and_then(|aaguid| Uuid::parse_str(&aaguid).ok()),
                         });
                     }
                 }
                 user.username = u.1;
+                user.active = u.5;
                 user
             });
 
@@ -241,173 +1311,3917 @@ impl App {
             return Ok::<_, AppError>(user);
         }
 
+        // Another request may have created the user in the meantime, in which case the existing
+        // row is returned.
+        let policy = self.user_creation_policy.clone();
         let new_user = self
-            .db
-            .call(move |conn| {
-                Ok(conn.query_row(
-                    r#"insert into users (id, username)
-                       values (?1, ?2)
-                       returning id, username"#,
-                    (&Uuid::new_v4().to_string(), &username),
-                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
-                ))
+            .transaction(move |tx| {
+                let exists: bool = tx.query_row(
+                    r#"select exists(select 1 from users where username = ?1)"#,
+                    (&username,),
+                    |row| row.get(0),
+                )?;
+                if !exists {
+                    let existing_users: usize =
+                        tx.query_row(r#"select count(*) from users"#, [], |row| row.get(0))?;
+                    if !policy.allow_user_creation(&username, existing_users) {
+                        return Err(AppError::UserCreationDenied);
+                    }
+                    tx.execute(
+                        r#"insert into users (id, username) values (?1, ?2)"#,
+                        (&Uuid::new_v4().to_string(), &username),
+                    )?;
+                }
+                Ok(tx.query_row(
+                    r#"select id, username, active from users where username = ?1"#,
+                    (&username,),
+                    |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, bool>(2)?,
+                        ))
+                    },
+                )?)
             })
-            .await??;
+            .await?;
 
         Ok::<_, AppError>(UserWithCredentials {
             id: Uuid::from_slice(&new_user.0.as_bytes()[..16])?,
             username: new_user.1,
+            active: new_user.2,
             credentials: vec![],
         })
     }
 
-    pub async fn add_credential(
-        &self,
-        username: String,
-        credential_name: String,
-        credential: &Passkey,
-    ) -> Result<(), AppError> {
-        let Ok(cred_val) = serde_json::to_string(&credential) else {
-            return Err(AppError::UnknownError);
-        };
+    /// Whether an existing user is allowed to use the service. Unknown users are not active.
+    pub async fn user_is_active(&self, username: String) -> Result<bool, AppError> {
+        let active = self
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    r#"select active from users where username = ?1"#,
+                    (&username,),
+                    |row| row.get::<_, bool>(0),
+                ))
+            })
+            .await?;
 
-        let n_added = self
-            .db
-            .call(|conn| {
+        match active {
+            Err(QueryReturnedNoRows) => Ok(false),
+            active => Ok(active?),
+        }
+    }
+
+    pub async fn set_user_active(&self, username: String, active: bool) -> Result<(), AppError> {
+        let n_updated = self
+            .call(move |conn| {
                 Ok(conn.execute(
-                    r#"insert into credentials (name, user, value)
-                       values (?1, (select id from users where username = ?2), json(?3))"#,
-                    (credential_name, username, cred_val),
+                    r#"update users set active = ?1 where username = ?2"#,
+                    (active, &username),
                 ))
             })
             .await??;
 
-        if n_added != 1 {
+        if n_updated == 0 {
             Err(AppError::UserNotFound)
         } else {
-            Ok::<_, AppError>(())
+            Ok(())
         }
     }
 
-    pub async fn update_credential(
-        &self,
-        auth_result: AuthenticationResult,
-    ) -> Result<(), AppError> {
-        let cred_id = serde_json::to_string(auth_result.cred_id())?;
+    pub async fn list_users(&self) -> Result<Vec<UserSummary>, AppError> {
+        self.search_users(UserFilter::default(), None, None).await
+    }
 
-        let cred_json = self
-            .db
+    /// Users matching `filter` in order of their username, starting after the username `after`.
+    /// Every condition is answered from an index, so that pages stay cheap with many users.
+    pub async fn search_users(
+        &self,
+        filter: UserFilter,
+        after: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<UserSummary>, AppError> {
+        // Usernames are stored normalized, aliases too.
+        let search = filter.search.map(|search| self.normalize_username(&search));
+        Ok(self
             .call(move |conn| {
-                Ok(conn.query_row(
-                    r#"select value from credentials
-                       where value->'$.cred.cred_id' = ?1"#,
-                    (cred_id,),
-                    |row| row.get::<_, String>(0),
-                ))
+                Ok(conn
+                    .prepare(
+                        // Prefixes are matched as ranges, which (unlike `like`) can use the
+                        // indexes on usernames and aliases.
+                        r#"select u.username, u.active,
+                             (select count(*) from credentials c where c.user = u.id),
+                             (select json_group_array(g.name) from user_groups g
+                              where g.user = u.id),
+                             (select json_group_array(a.alias) from user_aliases a
+                              where a.user = u.id)
+                           from users u
+                           where (?1 is null or u.id in (
+                               select id from users
+                               where username >= ?1 and username < ?1 || char(1114111)
+                               union
+                               select user from user_aliases
+                               where alias >= ?1 and alias < ?1 || char(1114111)))
+                             and (?2 is null
+                               or exists(select 1 from credentials c where c.user = u.id) = ?2)
+                             and (?3 is null or u.active = not ?3)
+                             and (?4 is null or u.username > ?4)
+                           order by u.username
+                           limit coalesce(?5, -1)"#,
+                    )?
+                    .query_map(
+                        (
+                            &search,
+                            filter.has_credentials,
+                            filter.locked,
+                            &after,
+                            limit,
+                        ),
+                        |row| {
+                            let mut groups: Vec<String> =
+                                serde_json::from_str(&row.get::<_, String>(3)?).unwrap_or_default();
+                            groups.sort();
+                            let mut aliases: Vec<String> =
+                                serde_json::from_str(&row.get::<_, String>(4)?).unwrap_or_default();
+                            aliases.sort();
+                            Ok(UserSummary {
+                                username: row.get(0)?,
+                                active: row.get(1)?,
+                                credentials: row.get(2)?,
+                                groups,
+                                aliases,
+                            })
+                        },
+                    )?
+                    .collect::<Result<Vec<_>, _>>())
             })
-            .await??;
+            .await??)
+    }
 
-        let mut passkey = serde_json::from_str::<Passkey>(&cred_json)?;
-        if passkey.update_credential(&auth_result).is_none() {
-            return Err(AppError::MismatchingCredential);
-        }
+    pub fn normalize_username(&self, name: &str) -> String {
+        self.username_normalization.normalize(name)
+    }
+
+    /// The username that a name given by a client (e.g. when logging in) refers to: the name is
+    /// normalized, and then resolved to the user it is an alias of, if any.
+    pub async fn canonical_username(&self, name: &str) -> Result<String, AppError> {
+        let name = self.normalize_username(name);
+        let username = self
+            .call({
+                let name = name.clone();
+                move |conn| {
+                    Ok(conn.query_row(
+                        r#"select u.username from user_aliases a
+                           join users u on u.id = a.user
+                           where a.alias = ?1"#,
+                        (&name,),
+                        |row| row.get::<_, String>(0),
+                    ))
+                }
+            })
+            .await?;
 
-        let cred_id = serde_json::to_string(passkey.cred_id())?;
+        match username {
+            Err(QueryReturnedNoRows) => Ok(name),
+            username => Ok(username?),
+        }
+    }
 
-        let cred_json = serde_json::to_string(&passkey)?;
+    /// Lets `alias` log in as `username`, sharing their account and credentials, e.g. when
+    /// proxies send different spellings of the same user. Aliases cannot be usernames of their
+    /// own.
+    pub async fn add_user_alias(&self, username: String, alias: String) -> Result<(), AppError> {
+        let alias = self.normalize_username(&alias);
+        if alias.is_empty() || alias.contains(':') {
+            return Err(AppError::BadInput);
+        }
 
-        _ = self
-            .db
-            .call(move |conn| {
-                Ok(conn.execute(
-                    r#"update credentials set value = ?1
-                       where value->'$.cred.cred_id' = ?2"#,
-                    (cred_json, cred_id),
-                ))
-            })
-            .await??;
+        self.transaction(move |tx| {
+            let user_id = user_id(tx, &username)?;
+            let taken: bool = tx.query_row(
+                r#"select exists(select 1 from users where username = ?1)
+                     or exists(select 1 from user_aliases where alias = ?1)"#,
+                (&alias,),
+                |row| row.get(0),
+            )?;
+            if taken {
+                return Err(AppError::DuplicateAlias);
+            }
+            tx.execute(
+                r#"insert into user_aliases (alias, user) values (?1, ?2)"#,
+                (&alias, &user_id),
+            )?;
+            record_event(tx, "alias_added", Some(&username), Some(&alias))
+        })
+        .await
+    }
 
-        Ok(())
+    pub async fn remove_user_alias(&self, username: String, alias: String) -> Result<(), AppError> {
+        self.transaction(move |tx| {
+            let user_id = user_id(tx, &username)?;
+            if tx.execute(
+                r#"delete from user_aliases where alias = ?1 and user = ?2"#,
+                (&alias, &user_id),
+            )? != 1
+            {
+                return Err(AppError::AliasNotFound);
+            }
+            record_event(tx, "alias_removed", Some(&username), Some(&alias))
+        })
+        .await
     }
 
-    pub async fn delete_credential(&self, cred_id: CredentialID) -> Result<(), AppError> {
-        let cred_id = serde_json::to_string(&cred_id)?;
+    /// Sets the argon2 hash of the password `username` may log in with when they are missing
+    /// from the password file (see `Policy::database_passwords`), or removes it.
+    pub async fn set_password_hash(
+        &self,
+        username: String,
+        hash: Option<String>,
+    ) -> Result<(), AppError> {
+        self.transaction(move |tx| {
+            let user_id = user_id(tx, &username)?;
+            tx.execute(
+                r#"update users set password_hash = ?1 where id = ?2"#,
+                (&hash, &user_id),
+            )?;
+            let event = if hash.is_some() {
+                "password_set"
+            } else {
+                "password_removed"
+            };
+            record_event(tx, event, Some(&username), None)
+        })
+        .await
+    }
 
-        let n_deleted = self
-            .db
+    pub async fn password_hash(&self, username: String) -> Result<Option<String>, AppError> {
+        let hash = self
             .call(move |conn| {
-                Ok(conn.execute(
-                    r#"delete from credentials where value->'$.cred.cred_id' = ?1"#,
-                    (&cred_id,),
+                Ok(conn.query_row(
+                    r#"select password_hash from users where username = ?1"#,
+                    (&username,),
+                    |row| row.get::<_, Option<String>>(0),
                 ))
             })
-            .await??;
+            .await?;
 
-        if n_deleted != 1 {
-            Err(AppError::CredentialNotFound)
-        } else {
-            Ok::<_, AppError>(())
+        match hash {
+            Err(QueryReturnedNoRows) => Ok(None),
+            hash => Ok(hash?),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
+    /// Existing users whose usernames are not normalized, grouped by the username they are
+    /// looked up as now. They have to be renamed to it (see `rename_user`), which merges users
+    /// sharing a normalized username.
+    pub async fn unnormalized_usernames(&self) -> Result<BTreeMap<String, Vec<String>>, AppError> {
+        if !self.username_normalization.is_enabled() {
+            return Ok(BTreeMap::new());
+        }
+        let usernames = self
+            .call(|conn| {
+                Ok(conn
+                    .prepare(r#"select username from users order by username"#)?
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>())
+            })
+            .await??;
+        Ok(self.username_normalization.unnormalized(usernames))
+    }
+
+    pub async fn add_credential(
+        &self,
+        username: String,
+        credential_name: String,
+        credential: &Passkey,
+        info: AuthenticatorInfo,
+    ) -> Result<(), AppError> {
+        let credential = NewCredential::new(credential, info)?;
+
+        self.transaction(move |tx| {
+            credential.insert(tx, &username, credential_name.clone())?;
+            record_event(
+                tx,
+                "credential_registered",
+                Some(&username),
+                Some(&credential_name),
+            )
+        })
+        .await
+    }
+
+    /// Registers a credential for `username` on behalf of `operator`, e.g. at an IT desk.
+    pub async fn add_kiosk_credential(
+        &self,
+        operator: String,
+        username: String,
+        credential_name: String,
+        credential: &Passkey,
+        info: AuthenticatorInfo,
+    ) -> Result<(), AppError> {
+        let credential = NewCredential::new(credential, info)?;
+
+        self.transaction(move |tx| {
+            let detail = format!("{credential_name} enrolled by {operator}");
+            credential.insert(tx, &username, credential_name)?;
+            record_event(tx, "kiosk_enrolled", Some(&username), Some(&detail))
+        })
+        .await
+    }
+
+    /// Starts the grace period within which a user without credentials may skip enrolling one,
+    /// returning when it started. The grace period only ever starts once per user.
+    pub async fn start_enrollment(&self, username: String) -> Result<u64, AppError> {
+        self.transaction(move |tx| {
+            let started_at: Option<u64> = match tx.query_row(
+                r#"select enrollment_started_at from users where username = ?1"#,
+                (&username,),
+                |row| row.get(0),
+            ) {
+                Err(QueryReturnedNoRows) => return Err(AppError::UserNotFound),
+                result => result?,
+            };
+            if let Some(started_at) = started_at {
+                return Ok(started_at);
+            }
+
+            let started_at = tx.query_row(
+                r#"update users set enrollment_started_at = cast(strftime('%s', 'now') as integer)
+                   where username = ?1
+                   returning enrollment_started_at"#,
+                (&username,),
+                |row| row.get(0),
+            )?;
+            record_event(tx, "enrollment_started", Some(&username), None)?;
+            Ok(started_at)
+        })
+        .await
+    }
+
+    /// All settings of a user, keyed by their name.
+    pub async fn user_settings(
+        &self,
+        username: String,
+    ) -> Result<BTreeMap<String, serde_json::Value>, AppError> {
+        let rows = self
+            .call(move |conn| {
+                Ok(conn
+                    .prepare(
+                        r#"select s.key, json(s.value) from user_settings s
+                           join users u on u.id = s.user
+                           where u.username = ?1"#,
+                    )?
+                    .query_map((&username,), |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>())
+            })
+            .await??;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, serde_json::from_str(&value).ok()?)))
+            .collect())
+    }
+
+    /// Replaces all settings of a user, refusing anything beyond the `MAX_USER_SETTING*` limits
+    /// and timezones or locales that timestamps cannot be rendered for (see `locale`).
+    pub async fn replace_user_settings(
+        &self,
+        username: String,
+        settings: BTreeMap<String, serde_json::Value>,
+    ) -> Result<(), AppError> {
+        if settings.len() > MAX_USER_SETTINGS || !locale::valid_settings(&settings) {
+            return Err(AppError::BadInput);
+        }
+        let settings = settings
+            .into_iter()
+            .map(|(key, value)| {
+                let value = serde_json::to_string(&value)?;
+                if key.is_empty()
+                    || key.len() > MAX_USER_SETTING_KEY_LEN
+                    || value.len() > MAX_USER_SETTING_VALUE_LEN
+                {
+                    return Err(AppError::BadInput);
+                }
+                Ok((key, value))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.transaction(move |tx| {
+            let user_id = user_id(tx, &username)?;
+            tx.execute(r#"delete from user_settings where user = ?1"#, (&user_id,))?;
+            for (key, value) in settings {
+                tx.execute(
+                    r#"insert into user_settings (user, key, value) values (?1, ?2, json(?3))"#,
+                    (&user_id, key, value),
+                )?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Stores the state of a ceremony between its start and end under an opaque ID, which is all
+    /// the session has to hold on to.
+    pub async fn insert_ceremony(
+        &self,
+        id: String,
+        kind: String,
+        state: String,
+        expires_at: u64,
+    ) -> Result<(), AppError> {
+        self.call(move |conn| {
+            conn.execute(
+                r#"insert into pending_ceremonies (id, kind, state, expires_at)
+                   values (?1, ?2, json(?3), ?4)"#,
+                (&id, &kind, &state, expires_at),
+            )?;
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Removes a ceremony of the given kind, returning its state and when it expires, so that
+    /// each challenge can only be answered once.
+    pub async fn take_ceremony(
+        &self,
+        id: String,
+        kind: String,
+    ) -> Result<Option<(String, u64)>, AppError> {
+        let ceremony = self
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    r#"delete from pending_ceremonies where id = ?1 and kind = ?2
+                       returning json(state), expires_at"#,
+                    (&id, &kind),
+                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?)),
+                ))
+            })
+            .await?;
+
+        match ceremony {
+            Err(QueryReturnedNoRows) => Ok(None),
+            ceremony => Ok(Some(ceremony?)),
+        }
+    }
+
+    /// Deletes ceremonies that were started but never finished before they expired, along with
+    /// the consumed challenges that no ceremony could be answered with anymore.
+    pub async fn delete_expired_ceremonies(&self, now: u64) -> Result<usize, AppError> {
+        Ok(self
+            .call(move |conn| {
+                let ceremonies = conn.execute(
+                    r#"delete from pending_ceremonies where expires_at <= ?1"#,
+                    (now,),
+                )?;
+                conn.execute(
+                    r#"delete from consumed_challenges where expires_at <= ?1"#,
+                    (now,),
+                )?;
+                Ok(ceremonies)
+            })
+            .await?)
+    }
+
+    /// Marks the challenge of an assertion as answered until it expires, returning false if it
+    /// was answered already. Unlike taking the ceremony, this holds across sessions, e.g. when
+    /// the state of a ceremony leaked into another one.
+    pub async fn consume_challenge(
+        &self,
+        challenge: String,
+        expires_at: u64,
+    ) -> Result<bool, AppError> {
+        let challenge = sha256_hex(&challenge);
+        Ok(self
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"insert into consumed_challenges (challenge, expires_at) values (?1, ?2)
+                       on conflict (challenge) do nothing"#,
+                    (&challenge, expires_at),
+                )?)
+            })
+            .await?
+            == 1)
+    }
+
+    /// Registers a credential that only becomes usable once an admin approves it, returning the
+    /// ID of the pending credential.
+    pub async fn add_pending_credential(
+        &self,
+        username: String,
+        credential_name: String,
+        credential: &Passkey,
+        info: AuthenticatorInfo,
+    ) -> Result<i64, AppError> {
+        let credential = NewCredential::new(credential, info)?;
+
+        self.transaction(move |tx| {
+            let id = credential.insert_pending(tx, &username, credential_name.clone())?;
+            record_event(
+                tx,
+                "credential_pending",
+                Some(&username),
+                Some(&credential_name),
+            )?;
+            Ok(id)
+        })
+        .await
+    }
+
+    /// Lists credentials awaiting approval, optionally only those of one user.
+    pub async fn list_pending_credentials(
+        &self,
+        username: Option<String>,
+    ) -> Result<Vec<PendingCredential>, AppError> {
+        let rows = self
+            .call(move |conn| {
+                Ok(conn
+                    .prepare(
+                        r#"select p.id, u.username, p.name, p.handle, p.aaguid, p.algorithm,
+                                  p.min_pin_length, p.created_at
+                           from pending_credentials p
+                           join users u on u.id = p.user
+                           where ?1 is null or u.username = ?1
+                           order by p.id"#,
+                    )?
+                    .query_map((&username,), |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(2)?,
+                            row.get::<_, String>(3)?,
+                            row.get::<_, Option<String>>(4)?,
+                            row.get::<_, i32>(5)?,
+                            row.get::<_, Option<u32>>(6)?,
+                            row.get::<_, u64>(7)?,
+                        ))
+                    })?
+                    .collect::<Result<Vec<_>, _>>())
+            })
+            .await??;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(
+                |(id, username, name, handle, aaguid, algorithm, min_pin_length, created_at)| {
+                    Some(PendingCredential {
+                        id,
+                        username,
+                        name,
+                        handle,
+                        aaguid: aaguid.and_then(|aaguid| Uuid::parse_str(&aaguid).ok()),
+                        algorithm: COSEAlgorithm::try_from(algorithm as i128).ok()?,
+                        min_pin_length,
+                        created_at,
+                    })
+                },
+            )
+            .collect())
+    }
+
+    /// Makes a pending credential usable. The blocklist is checked again, since it may have
+    /// changed while the credential was waiting.
+    pub async fn approve_pending_credential(&self, id: i64) -> Result<(), AppError> {
+        self.transaction(move |tx| {
+            let (username, name, value, info) = match tx.query_row(
+                r#"select u.username, p.name, p.value, p.aaguid, p.min_pin_length, p.attachment
+                   from pending_credentials p
+                   join users u on u.id = p.user
+                   where p.id = ?1"#,
+                (id,),
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        AuthenticatorInfo {
+                            aaguid: row
+                                .get::<_, Option<String>>(3)?
+                                .and_then(|aaguid| Uuid::parse_str(&aaguid).ok()),
+                            min_pin_length: row.get(4)?,
+                            attachment: parse_attachment(row.get(5)?),
+                        },
+                    ))
+                },
+            ) {
+                Err(QueryReturnedNoRows) => return Err(AppError::CredentialNotFound),
+                result => result?,
+            };
+            let credential = NewCredential::new(&serde_json::from_str(&value)?, info)?;
+
+            tx.execute(r#"delete from pending_credentials where id = ?1"#, (id,))?;
+            credential.insert(tx, &username, name.clone())?;
+            record_event(tx, "credential_approved", Some(&username), Some(&name))
+        })
+        .await
+    }
+
+    /// Discards a pending credential without ever making it usable.
+    pub async fn reject_pending_credential(&self, id: i64) -> Result<(), AppError> {
+        self.transaction(move |tx| {
+            let (username, name) = match tx.query_row(
+                r#"select u.username, p.name
+                   from pending_credentials p
+                   join users u on u.id = p.user
+                   where p.id = ?1"#,
+                (id,),
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            ) {
+                Err(QueryReturnedNoRows) => return Err(AppError::CredentialNotFound),
+                result => result?,
+            };
+            tx.execute(r#"delete from pending_credentials where id = ?1"#, (id,))?;
+            record_event(tx, "credential_rejected", Some(&username), Some(&name))
+        })
+        .await
+    }
+
+    pub async fn user_in_group(&self, username: String, group: String) -> Result<bool, AppError> {
+        Ok(self
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    r#"select exists(
+                         select 1 from user_groups g
+                         join users u on u.id = g.user
+                         where u.username = ?1 and g.name = ?2
+                       )"#,
+                    (&username, &group),
+                    |row| row.get::<_, bool>(0),
+                ))
+            })
+            .await??)
+    }
+
+    pub async fn user_groups(&self, username: String) -> Result<Vec<String>, AppError> {
+        Ok(self
+            .call(move |conn| {
+                Ok(conn
+                    .prepare(
+                        r#"select g.name from user_groups g
+                           join users u on u.id = g.user
+                           where u.username = ?1
+                           order by g.name"#,
+                    )?
+                    .query_map((&username,), |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>())
+            })
+            .await??)
+    }
+
+    /// Records a recovery token that was issued to `username`.
+    pub async fn issue_recovery_token(
+        &self,
+        username: String,
+        id: String,
+        expires_at: u64,
+    ) -> Result<(), AppError> {
+        self.transaction(move |tx| {
+            let user_id = user_id(tx, &username)?;
+            tx.execute(
+                r#"insert into recovery_tokens (id, user, expires_at) values (?1, ?2, ?3)"#,
+                (&id, &user_id, expires_at),
+            )?;
+            record_event(tx, "recovery_issued", Some(&username), None)
+        })
+        .await
+    }
+
+    /// Whether the recovery token belongs to `username` and can still be redeemed.
+    pub async fn recovery_token_is_valid(
+        &self,
+        id: String,
+        username: String,
+    ) -> Result<bool, AppError> {
+        Ok(self
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    r#"select exists(
+                         select 1 from recovery_tokens t
+                         join users u on u.id = t.user
+                         where t.id = ?1 and u.username = ?2 and t.used_at is null
+                           and t.expires_at > cast(strftime('%s', 'now') as integer)
+                       )"#,
+                    (&id, &username),
+                    |row| row.get::<_, bool>(0),
+                ))
+            })
+            .await??)
+    }
+
+    /// Marks the recovery token as used and replaces all credentials of `username` with the new
+    /// one, returning how many credentials were revoked.
+    pub async fn redeem_recovery_token(
+        &self,
+        id: String,
+        username: String,
+        credential_name: String,
+        credential: &Passkey,
+        info: AuthenticatorInfo,
+    ) -> Result<usize, AppError> {
+        let credential = NewCredential::new(credential, info)?;
+
+        self.transaction(move |tx| {
+            let user_id = user_id(tx, &username)?;
+            let n_used = tx.execute(
+                r#"update recovery_tokens set used_at = cast(strftime('%s', 'now') as integer)
+                   where id = ?1 and user = ?2 and used_at is null
+                     and expires_at > cast(strftime('%s', 'now') as integer)"#,
+                (&id, &user_id),
+            )?;
+            if n_used != 1 {
+                return Err(AppError::InvalidRecoveryToken);
+            }
+
+            let n_revoked =
+                tx.execute(r#"delete from credentials where user = ?1"#, (&user_id,))?;
+            credential.insert(tx, &username, credential_name)?;
+            record_event(
+                tx,
+                "recovery_redeemed",
+                Some(&username),
+                Some(&format!("revoked {n_revoked} credentials")),
+            )?;
+
+            Ok(n_revoked)
+        })
+        .await
+    }
+
+    /// Records an impersonation link issued for `username` by `operator`, who gave `reason` for
+    /// it. Only the hash of `token` is stored, so that the database cannot be used to log in as
+    /// anyone.
+    pub async fn issue_impersonation(
+        &self,
+        username: String,
+        token: String,
+        operator: String,
+        reason: Option<String>,
+        expires_at: u64,
+    ) -> Result<(), AppError> {
+        let id = sha256_hex(&token);
+        self.transaction(move |tx| {
+            let user_id = user_id(tx, &username)?;
+            tx.execute(
+                r#"insert into impersonations (id, user, operator, expires_at)
+                   values (?1, ?2, ?3, ?4)"#,
+                (&id, &user_id, &operator, expires_at),
+            )?;
+            let detail = match reason {
+                Some(reason) => format!("by {operator}: {reason}"),
+                None => format!("by {operator}"),
+            };
+            record_event(tx, "impersonation_issued", Some(&username), Some(&detail))
+        })
+        .await
+    }
+
+    /// Marks the impersonation link with `token` as used, returning the user to log in as and
+    /// the operator who issued the link.
+    pub async fn redeem_impersonation(&self, token: String) -> Result<(String, String), AppError> {
+        let id = sha256_hex(&token);
+        self.transaction(move |tx| {
+            let redeemed = tx.query_row(
+                r#"update impersonations set used_at = cast(strftime('%s', 'now') as integer)
+                   where id = ?1 and used_at is null
+                     and expires_at > cast(strftime('%s', 'now') as integer)
+                   returning (select username from users where id = user), operator"#,
+                (&id,),
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            );
+            let (username, operator) = match redeemed {
+                Err(QueryReturnedNoRows) => return Err(AppError::InvalidImpersonationToken),
+                redeemed => redeemed?,
+            };
+            record_event(
+                tx,
+                "impersonation_started",
+                Some(&username),
+                Some(&format!("by {operator}")),
+            )?;
+            Ok((username, operator))
+        })
+        .await
+    }
+
+    /// Uses up one of the user's recovery codes in exchange for a recovery token with the given
+    /// `id`, which is then redeemed like one from a recovery link.
+    pub async fn exchange_recovery_code(
+        &self,
+        username: String,
+        code: String,
+        id: String,
+        expires_at: u64,
+    ) -> Result<(), AppError> {
+        let hash = recovery_code_hash(&code);
+
+        self.transaction(move |tx| {
+            let user_id = match user_id(tx, &username) {
+                Err(AppError::UserNotFound) => return Err(AppError::InvalidRecoveryToken),
+                user_id => user_id?,
+            };
+            let n_used = tx.execute(
+                r#"update recovery_codes set used_at = cast(strftime('%s', 'now') as integer)
+                   where user = ?1 and hash = ?2 and used_at is null"#,
+                (&user_id, &hash),
+            )?;
+            if n_used != 1 {
+                record_event(tx, "recovery_code_rejected", Some(&username), None)?;
+                return Ok(Err(AppError::InvalidRecoveryToken));
+            }
+            tx.execute(
+                r#"insert into recovery_tokens (id, user, expires_at) values (?1, ?2, ?3)"#,
+                (&id, &user_id, expires_at),
+            )?;
+            record_event(tx, "recovery_code_used", Some(&username), None)?;
+            Ok(Ok(()))
+        })
+        .await?
+    }
+
+    /// Replaces all of the user's recovery codes, used or not, with the codes hashed as
+    /// `hashes` (see `recovery_code_hash`).
+    pub async fn replace_recovery_codes(
+        &self,
+        username: String,
+        hashes: Vec<String>,
+    ) -> Result<(), AppError> {
+        self.transaction(move |tx| {
+            let user_id = user_id(tx, &username)?;
+            tx.execute(r#"delete from recovery_codes where user = ?1"#, (&user_id,))?;
+            for hash in &hashes {
+                tx.execute(
+                    r#"insert or ignore into recovery_codes (user, hash) values (?1, ?2)"#,
+                    (&user_id, hash),
+                )?;
+            }
+            record_event(tx, "recovery_codes_regenerated", Some(&username), None)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Reconciles users with `seed`, returning how many users were created and pruned.
+    pub async fn apply_seed(&self, seed: Seed) -> Result<(usize, usize), AppError> {
+        self.transaction(move |tx| {
+            let mut created = 0;
+            for (username, user) in &seed.users {
+                created += tx.execute(
+                    r#"insert into users (id, username) values (?1, ?2)
+                       on conflict(username) do nothing"#,
+                    (&Uuid::new_v4().to_string(), username),
+                )?;
+                let user_id = user_id(tx, username)?;
+                tx.execute(
+                    r#"update users set active = ?2 where id = ?1"#,
+                    (&user_id, user.active),
+                )?;
+
+                if let Some(groups) = &user.groups {
+                    tx.execute(r#"delete from user_groups where user = ?1"#, (&user_id,))?;
+                    for group in groups {
+                        tx.execute(
+                            r#"insert or ignore into user_groups (user, name) values (?1, ?2)"#,
+                            (&user_id, group),
+                        )?;
+                    }
+                }
+
+                if let Some(hashes) = &user.recovery_codes {
+                    let hashes = hashes
+                        .iter()
+                        .map(|hash| hash.to_ascii_lowercase())
+                        .collect::<Vec<_>>();
+                    tx.execute(
+                        r#"delete from recovery_codes
+                           where user = ?1 and hash not in (select value from json_each(?2))"#,
+                        (&user_id, serde_json::to_string(&hashes)?),
+                    )?;
+                    for hash in hashes {
+                        tx.execute(
+                            r#"insert or ignore into recovery_codes (user, hash) values (?1, ?2)"#,
+                            (&user_id, &hash),
+                        )?;
+                    }
+                }
+            }
+
+            let mut pruned = 0;
+            if seed.prune {
+                let usernames = serde_json::to_string(&seed.users.keys().collect::<Vec<_>>())?;
+                for table in USER_TABLES {
+                    tx.execute(
+                        &format!(
+                            r#"delete from {table} where user in (
+                                 select id from users
+                                 where username not in (select value from json_each(?1))
+                               )"#
+                        ),
+                        (&usernames,),
+                    )?;
+                }
+                pruned = tx.execute(
+                    r#"delete from users where username not in (select value from json_each(?1))"#,
+                    (&usernames,),
+                )?;
+            }
+
+            record_event(
+                tx,
+                "seed_applied",
+                None,
+                Some(&format!("created {created} users, pruned {pruned} users")),
+            )?;
+            Ok((created, pruned))
+        })
+        .await
+    }
+
+    /// Returns the most recent audit log entries, newest first.
+    pub async fn audit_log(&self, limit: usize) -> Result<Vec<AuditEvent>, AppError> {
+        Ok(self
+            .call(move |conn| {
+                Ok(conn
+                    .prepare(
+                        r#"select id, at, event, username, detail from audit_log
+                           order by id desc
+                           limit ?1"#,
+                    )?
+                    .query_map((limit,), |row| {
+                        Ok(AuditEvent {
+                            id: row.get(0)?,
+                            at: row.get(1)?,
+                            event: row.get(2)?,
+                            username: row.get(3)?,
+                            detail: row.get(4)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>())
+            })
+            .await??)
+    }
+
+    /// Records an event that does not come with any other database change, e.g. a login.
+    pub async fn record_event(
+        &self,
+        event: &'static str,
+        username: Option<String>,
+        detail: Option<String>,
+    ) -> Result<(), AppError> {
+        self.transaction(move |tx| record_event(tx, event, username.as_deref(), detail.as_deref()))
+            .await
+    }
+
+    /// Checks that no audit log entry was modified or removed since it was signed with `key`.
+    pub async fn verify_audit_log(
+        &self,
+        key: AuditLogKey,
+    ) -> Result<AuditLogVerification, AppError> {
+        Ok(self
+            .call(move |conn| {
+                // A forged anchor leaves the chain to start from nothing, which the MAC of the
+                // first entry then does not match.
+                let mut previous = match conn.query_row(
+                    r#"select last_id, mac, anchor_mac from audit_log_anchor"#,
+                    [],
+                    |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(2)?,
+                        ))
+                    },
+                ) {
+                    Err(QueryReturnedNoRows) => String::new(),
+                    result => {
+                        let (anchor_id, anchor, anchor_mac) = result?;
+                        if anchor_mac == key.anchor_mac(anchor_id, &anchor) {
+                            anchor
+                        } else {
+                            String::new()
+                        }
+                    }
+                };
+
+                let mut entries = conn.prepare(
+                    r#"select id, at, event, username, detail, mac from audit_log order by id"#,
+                )?;
+                let mut rows = entries.query([])?;
+                let mut last_id = None;
+                let mut count = 0;
+                while let Some(row) = rows.next()? {
+                    let id = row.get(0)?;
+                    let Some(mac) = row.get::<_, Option<String>>(5)? else {
+                        return Ok(AuditLogVerification::Unsigned { id });
+                    };
+                    let (event, username, detail) = (
+                        row.get::<_, String>(2)?,
+                        row.get::<_, Option<String>>(3)?,
+                        row.get::<_, Option<String>>(4)?,
+                    );
+                    let fields = SignedFields {
+                        id,
+                        at: row.get(1)?,
+                        event: &event,
+                        username: username.as_deref(),
+                        detail: detail.as_deref(),
+                    };
+                    if mac != key.entry_mac(&previous, fields) {
+                        return Ok(AuditLogVerification::Modified { id });
+                    }
+                    previous = mac;
+                    last_id = Some(id);
+                    count += 1;
+                }
+
+                let head =
+                    match conn.query_row(r#"select last_id, mac from audit_log_head"#, [], |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                    }) {
+                        Err(QueryReturnedNoRows) => None,
+                        result => Some(result?),
+                    };
+                Ok(match (head, last_id) {
+                    (None, None) => AuditLogVerification::Intact { entries: 0 },
+                    (Some((head_id, head_mac)), Some(last_id))
+                        if head_id == last_id && head_mac == key.head_mac(last_id, &previous) =>
+                    {
+                        AuditLogVerification::Intact { entries: count }
+                    }
+                    (_, after) => AuditLogVerification::Truncated { after },
+                })
+            })
+            .await?)
+    }
+
+    /// Deletes audit log entries recorded before the unix timestamp `before`, keeping the MAC
+    /// of the last one as the anchor that the remaining entries are verified from. Returns the
+    /// number of deleted entries.
+    pub async fn purge_audit_log(&self, before: u64) -> Result<usize, AppError> {
+        let key = self.audit_log_key.clone();
+        self.transaction(move |tx| {
+            let (last_id, mac) = match tx.query_row(
+                r#"select id, mac from audit_log where at < ?1 order by id desc limit 1"#,
+                (before,),
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Option<String>>(1)?)),
+            ) {
+                Err(QueryReturnedNoRows) => return Ok(0),
+                result => result?,
+            };
+
+            let purged = tx.execute(r#"delete from audit_log where id <= ?1"#, (last_id,))?;
+            if let (Some(key), Some(mac)) = (&key, mac) {
+                tx.execute(
+                    r#"insert or replace into audit_log_anchor (id, last_id, mac, anchor_mac)
+                       values (1, ?1, ?2, ?3)"#,
+                    (last_id, &mac, key.anchor_mac(last_id, &mac)),
+                )?;
+            }
+            record_event(
+                tx,
+                "audit_log_purged",
+                None,
+                Some(&format!("purged {purged} entries")),
+            )?;
+            Ok(purged)
+        })
+        .await
+    }
+
+    /// Deletes the records of credential uses before the unix timestamp `before`, after which
+    /// usage stats only cover the time since. Returns the number of deleted records.
+    pub async fn purge_credential_uses(&self, before: u64) -> Result<usize, AppError> {
+        Ok(self
+            .call(move |conn| {
+                Ok(conn.execute(r#"delete from credential_uses where at < ?1"#, (before,)))
+            })
+            .await??)
+    }
+
+    /// The ID of the most recent audit log entry, or 0 if the log is empty.
+    pub async fn last_audit_event_id(&self) -> Result<i64, AppError> {
+        Ok(self
+            .call(|conn| {
+                Ok(
+                    conn.query_row(r#"select coalesce(max(id), 0) from audit_log"#, [], |row| {
+                        row.get(0)
+                    }),
+                )
+            })
+            .await??)
+    }
+
+    /// Audit log entries of `username` recorded before the entry with ID `before` (or all of
+    /// them), newest first.
+    pub async fn user_history(
+        &self,
+        username: String,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, AppError> {
+        Ok(self
+            .call(move |conn| {
+                Ok(conn
+                    .prepare(
+                        r#"select id, at, event, username, detail from audit_log
+                           where username = ?1 and (?2 is null or id < ?2)
+                           order by id desc
+                           limit ?3"#,
+                    )?
+                    .query_map((&username, before, limit), |row| {
+                        Ok(AuditEvent {
+                            id: row.get(0)?,
+                            at: row.get(1)?,
+                            event: row.get(2)?,
+                            username: row.get(3)?,
+                            detail: row.get(4)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>())
+            })
+            .await??)
+    }
+
+    /// Audit log entries recorded after the entry with the given ID, oldest first.
+    pub async fn audit_log_after(
+        &self,
+        after: i64,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, AppError> {
+        Ok(self
+            .call(move |conn| {
+                Ok(conn
+                    .prepare(
+                        r#"select id, at, event, username, detail from audit_log
+                           where id > ?1
+                           order by id
+                           limit ?2"#,
+                    )?
+                    .query_map((after, limit), |row| {
+                        Ok(AuditEvent {
+                            id: row.get(0)?,
+                            at: row.get(1)?,
+                            event: row.get(2)?,
+                            username: row.get(3)?,
+                            detail: row.get(4)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>())
+            })
+            .await??)
+    }
+
+    /// Records a successful authentication with a credential, along with the attachment the
+    /// browser reported for it, and stores its counter and backup state if they changed.
+    pub async fn update_credential(
+        &self,
+        auth_result: AuthenticationResult,
+        attachment: Option<AuthenticatorAttachment>,
+    ) -> Result<(), AppError> {
+        let cred_id = serde_json::to_string(auth_result.cred_id())?;
+
+        self.transaction(move |tx| {
+            let cred_json = tx.query_row(
+                r#"select value from credentials
+                   where value->'$.cred.cred_id' = ?1"#,
+                (&cred_id,),
+                |row| row.get::<_, String>(0),
+            )?;
+
+            let mut passkey = serde_json::from_str::<Passkey>(&cred_json)?;
+            if passkey.update_credential(&auth_result).is_none() {
+                return Err(AppError::MismatchingCredential);
+            }
+
+            tx.execute(
+                r#"update credentials set value = ?1, backup_state = ?3
+                   where value->'$.cred.cred_id' = ?2"#,
+                (
+                    serde_json::to_string(&passkey)?,
+                    &cred_id,
+                    PasskeyInfo::of(&passkey).backup_state,
+                ),
+            )?;
+            tx.execute(
+                r#"insert into credential_uses (credential, at, attachment, hybrid)
+                   values (?1, cast(strftime('%s', 'now') as integer), ?2, ?3)"#,
+                (
+                    credential_handle(passkey.cred_id()),
+                    attachment_name(attachment),
+                    is_hybrid(&passkey, attachment),
+                ),
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Unix timestamp (in seconds) of when the credential was registered.
+    pub async fn credential_created_at(&self, cred_id: &CredentialID) -> Result<u64, AppError> {
+        let cred_id = serde_json::to_string(cred_id)?;
+
+        let created_at = self
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    r#"select created_at from credentials
+                       where value->'$.cred.cred_id' = ?1"#,
+                    (&cred_id,),
+                    |row| row.get::<_, u64>(0),
+                ))
+            })
+            .await?;
+
+        match created_at {
+            Err(QueryReturnedNoRows) => Err(AppError::CredentialNotFound),
+            created_at => Ok(created_at?),
+        }
+    }
+
+    /// Deletes the credential identified by `handle`, if it belongs to `username`.
+    pub async fn delete_credential(&self, username: String, handle: &str) -> Result<(), AppError> {
+        let handle = normalize_credential_handle(handle);
+
+        self.transaction(move |tx| {
+            let name: String = match tx.query_row(
+                r#"delete from credentials
+                   where handle = ?2
+                     and user = (select id from users where username = ?1)
+                   returning name"#,
+                (&username, &handle),
+                |row| row.get(0),
+            ) {
+                Err(QueryReturnedNoRows) => return Err(AppError::CredentialNotFound),
+                name => name?,
+            };
+            record_event(tx, "credential_deleted", Some(&username), Some(&name))
+        })
+        .await
+    }
+
+    /// Replaces the tags of one of `username`'s credentials, returning them as stored: trimmed,
+    /// deduplicated and sorted.
+    pub async fn set_credential_tags(
+        &self,
+        username: String,
+        handle: &str,
+        tags: Vec<String>,
+    ) -> Result<Vec<String>, AppError> {
+        let handle = normalize_credential_handle(handle);
+        let tags = tags
+            .iter()
+            .map(|tag| tag.trim().to_string())
+            .collect::<BTreeSet<_>>();
+        if tags.len() > MAX_CREDENTIAL_TAGS
+            || tags
+                .iter()
+                .any(|tag| tag.is_empty() || tag.chars().count() > MAX_CREDENTIAL_TAG_LEN)
+        {
+            return Err(AppError::BadInput);
+        }
+
+        self.transaction(move |tx| {
+            let name = match tx.query_row(
+                r#"select c.name from credentials c
+                   join users u on u.id = c.user
+                   where c.handle = ?1 and u.username = ?2"#,
+                (&handle, &username),
+                |row| row.get::<_, String>(0),
+            ) {
+                Err(QueryReturnedNoRows) => return Err(AppError::CredentialNotFound),
+                result => result?,
+            };
+            tx.execute(
+                r#"delete from credential_tags where credential = ?1"#,
+                (&handle,),
+            )?;
+            for tag in tags.iter() {
+                tx.execute(
+                    r#"insert into credential_tags (credential, name) values (?1, ?2)"#,
+                    (&handle, tag),
+                )?;
+            }
+            let tags = tags.into_iter().collect::<Vec<_>>();
+            record_event(
+                tx,
+                "credential_tagged",
+                Some(&username),
+                Some(&format!("{name}: {}", tags.join(", "))),
+            )?;
+            Ok(tags)
+        })
+        .await
+    }
+
+    /// Looks up a credential of any user by its handle, returning the username, credential name
+    /// and the credential itself.
+    pub async fn find_credential(
+        &self,
+        handle: &str,
+    ) -> Result<(String, String, Passkey), AppError> {
+        let handle = normalize_credential_handle(handle);
+
+        let row = self
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    r#"select u.username, c.name, c.value
+                       from credentials c
+                       join users u on u.id = c.user
+                       where c.handle = ?1"#,
+                    (&handle,),
+                    |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(2)?,
+                        ))
+                    },
+                ))
+            })
+            .await?;
+
+        let (username, name, value) = match row {
+            Err(QueryReturnedNoRows) => return Err(AppError::CredentialNotFound),
+            row => row?,
+        };
+        Ok((username, name, serde_json::from_str::<Passkey>(&value)?))
+    }
+
+    /// Deletes every credential belonging to a user, returning how many were deleted.
+    pub async fn delete_user_credentials(&self, username: String) -> Result<usize, AppError> {
+        self.transaction(move |tx| {
+            let user_id = user_id(tx, &username)?;
+            Ok(tx.execute(r#"delete from credentials where user = ?1"#, (&user_id,))?)
+        })
+        .await
+    }
+
+    /// Compares the users with those of the authoritative source of users, `upstream`, and
+    /// reports, deactivates or deletes the users that are missing from it, depending on
+    /// `reconciliation`. Users are matched by their username or one of their aliases, and with
+    /// `database_passwords`, users with a password in the database are kept too. Returns the
+    /// missing users, or only those that were deactivated when deactivating.
+    pub async fn reconcile_users(
+        &self,
+        upstream: Vec<String>,
+        database_passwords: bool,
+        reconciliation: Reconciliation,
+    ) -> Result<Vec<String>, AppError> {
+        let upstream = serde_json::to_string(&upstream)?;
+        self.transaction(move |tx| {
+            let missing = tx
+                .prepare(
+                    r#"select u.username from users u
+                       where u.username not in (select value from json_each(?1))
+                         and not exists (
+                           select 1 from user_aliases a
+                           where a.user = u.id and a.alias in (select value from json_each(?1))
+                         )
+                         and not (?2 and u.password_hash is not null)
+                         and (?3 or u.active)
+                       order by u.username"#,
+                )?
+                .query_map(
+                    (
+                        &upstream,
+                        database_passwords,
+                        reconciliation != Reconciliation::Deactivate,
+                    ),
+                    |row| row.get::<_, String>(0),
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            for username in &missing {
+                match reconciliation {
+                    Reconciliation::Report => {}
+                    Reconciliation::Deactivate => {
+                        tx.execute(
+                            r#"update users set active = 0 where username = ?1"#,
+                            (username,),
+                        )?;
+                        record_event(
+                            tx,
+                            "user_deactivated",
+                            Some(username),
+                            Some("missing upstream"),
+                        )?;
+                    }
+                    Reconciliation::Delete => {
+                        let credentials = delete_user(tx, username)?;
+                        record_event(
+                            tx,
+                            "user_deleted",
+                            Some(username),
+                            Some(&format!(
+                                "missing upstream, deleted {credentials} credentials"
+                            )),
+                        )?;
+                    }
+                }
+            }
+            Ok(missing)
+        })
+        .await
+    }
+
+    /// Deletes a user along with everything belonging to them, returning the number of
+    /// credentials that were deleted. Their audit log entries are kept.
+    pub async fn delete_user(&self, username: String) -> Result<usize, AppError> {
+        self.transaction(move |tx| {
+            let credentials = delete_user(tx, &username)?;
+            record_event(
+                tx,
+                "user_deleted",
+                Some(&username),
+                Some(&format!("deleted {credentials} credentials")),
+            )?;
+            Ok(credentials)
+        })
+        .await
+    }
+
+    /// Deletes a user like `delete_user`, and also removes their username and the details of
+    /// their entries from the audit log, so that nothing about them is left. The rewritten
+    /// entries are signed again. Returns the number of audit log entries that were anonymized.
+    pub async fn erase_user(&self, username: String) -> Result<usize, AppError> {
+        let key = self.audit_log_key.clone();
+        self.transaction(move |tx| {
+            let credentials = delete_user(tx, &username)?;
+
+            let first_erased: Option<i64> = tx.query_row(
+                r#"select min(id) from audit_log where username = ?1"#,
+                (&username,),
+                |row| row.get(0),
+            )?;
+            let entries = tx.execute(
+                r#"update audit_log set username = null, detail = null where username = ?1"#,
+                (&username,),
+            )?;
+            if let (Some(key), Some(first_erased)) = (&key, first_erased) {
+                sign_audit_log(tx, key, Some(first_erased - 1))?;
+            }
+
+            record_event(
+                tx,
+                "user_erased",
+                None,
+                Some(&format!(
+                    "deleted {credentials} credentials, anonymized {entries} audit log entries"
+                )),
+            )?;
+            Ok(entries)
+        })
+        .await
+    }
+
+    /// Deletes everything in the database but the metadata recorded at startup (e.g. the RP ID),
+    /// leaving an empty one like at the first start, for `--test-mode`.
+    pub async fn reset(&self) -> Result<(), AppError> {
+        self.transaction(|tx| {
+            tx.execute_batch("pragma defer_foreign_keys = on")?;
+            let tables = tx
+                .prepare(
+                    r#"select name from sqlite_schema
+                       where type = 'table' and name not like 'sqlite_%' and name != 'metadata'"#,
+                )?
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            for table in tables {
+                tx.execute(&format!(r#"delete from "{table}""#), [])?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Everything stored about a user, for them to take with them.
+    pub async fn export_user(&self, username: String) -> Result<UserExport, AppError> {
+        let export = self
+            .call({
+                let username = username.clone();
+                move |conn| {
+                    let (active, password_set, enrollment_started_at, aliases) = match conn
+                        .query_row(
+                            r#"select active, password_hash is not null, enrollment_started_at,
+                                 (select json_group_array(a.alias) from user_aliases a
+                                  where a.user = u.id)
+                               from users u
+                               where username = ?1"#,
+                            (&username,),
+                            |row| {
+                                Ok((
+                                    row.get::<_, bool>(0)?,
+                                    row.get::<_, bool>(1)?,
+                                    row.get::<_, Option<u64>>(2)?,
+                                    row.get::<_, String>(3)?,
+                                ))
+                            },
+                        ) {
+                        Err(QueryReturnedNoRows) => return Ok(Err(AppError::UserNotFound)),
+                        result => result?,
+                    };
+                    let mut aliases: Vec<String> =
+                        serde_json::from_str(&aliases).unwrap_or_default();
+                    aliases.sort();
+
+                    let recovery_codes = conn
+                        .prepare(
+                            r#"select r.used_at from recovery_codes r
+                               join users u on u.id = r.user
+                               where u.username = ?1
+                               order by r.used_at"#,
+                        )?
+                        .query_map((&username,), |row| row.get::<_, Option<u64>>(0))?
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let recovery_links = conn
+                        .prepare(
+                            r#"select r.expires_at, r.used_at from recovery_tokens r
+                               join users u on u.id = r.user
+                               where u.username = ?1
+                               order by r.expires_at"#,
+                        )?
+                        .query_map((&username,), |row| {
+                            Ok(RecoveryLinkExport {
+                                expires_at: row.get(0)?,
+                                used_at: row.get(1)?,
+                            })
+                        })?
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let audit_events = conn
+                        .prepare(
+                            r#"select id, at, event, username, detail from audit_log
+                               where username = ?1
+                               order by id"#,
+                        )?
+                        .query_map((&username,), |row| {
+                            Ok(AuditEvent {
+                                id: row.get(0)?,
+                                at: row.get(1)?,
+                                event: row.get(2)?,
+                                username: row.get(3)?,
+                                detail: row.get(4)?,
+                            })
+                        })?
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    Ok(Ok(UserExport {
+                        username: username.clone(),
+                        active,
+                        password_set,
+                        enrollment_started_at,
+                        aliases,
+                        recovery_codes: RecoveryCodesExport {
+                            remaining: recovery_codes.iter().filter(|used| used.is_none()).count(),
+                            used_at: recovery_codes.into_iter().flatten().collect(),
+                        },
+                        recovery_links,
+                        audit_events,
+                        ..Default::default()
+                    }))
+                }
+            })
+            .await??;
+
+        let user = self.get_user_with_credentials(username.clone()).await?;
+        let credentials = self
+            .credential_summaries(Some(username.clone()), None, None)
+            .await?
+            .into_iter()
+            .filter_map(|summary| {
+                let credential = user
+                    .credentials
+                    .iter()
+                    .find(|credential| credential.handle == summary.handle)?;
+                Some(CredentialExport {
+                    tags: credential.tags.clone(),
+                    attachment: credential.attachment,
+                    usage: credential.usage.clone(),
+                    summary,
+                })
+            })
+            .collect();
+
+        Ok(UserExport {
+            groups: self.user_groups(username.clone()).await?,
+            settings: self.user_settings(username.clone()).await?,
+            credentials,
+            pending_credentials: self.list_pending_credentials(Some(username)).await?,
+            ..export
+        })
+    }
+
+    /// Re-assigns all credentials of one user to another user, e.g. when merging accounts.
+    /// Nothing is moved if any of the credential names are already used by the target user.
+    pub async fn move_credentials(
+        &self,
+        from_username: String,
+        to_username: String,
+    ) -> Result<usize, AppError> {
+        if from_username == to_username {
+            return Err(AppError::BadInput);
+        }
+
+        self.transaction(move |tx| {
+            let from_id = user_id(tx, &from_username)?;
+            let to_id = user_id(tx, &to_username)?;
+
+            let name_conflict: bool = tx.query_row(
+                r#"select exists(
+                         select 1 from credentials a
+                         join credentials b on a.name = b.name
+                         where a.user = ?1 and b.user = ?2
+                       )"#,
+                (&from_id, &to_id),
+                |row| row.get(0),
+            )?;
+            if name_conflict {
+                return Err(AppError::DuplicateCredential);
+            }
+
+            Ok(tx.execute(
+                r#"update credentials set user = ?1 where user = ?2"#,
+                (&to_id, &from_id),
+            )?)
+        })
+        .await
+    }
+
+    /// Renames a user, e.g. after the upstream identity provider changed their username. If the
+    /// new username is already taken (typically because the user visited under it before being
+    /// renamed), the old user is merged into it. Credentials and audit log entries follow along,
+    /// while recovery links that were issued for the old username stop working. Sessions are
+    /// renamed by the session store, since they may live in a different database. Returns the
+    /// number of credentials that were re-pointed.
+    pub async fn rename_user(
+        &self,
+        from_username: String,
+        to_username: String,
+    ) -> Result<usize, AppError> {
+        if from_username == to_username {
+            return Err(AppError::BadInput);
+        }
+
+        let key = self.audit_log_key.clone();
+        self.transaction(move |tx| {
+            let from_id = user_id(tx, &from_username)?;
+
+            let n_credentials: usize = tx.query_row(
+                r#"select count(*) from credentials where user = ?1"#,
+                (&from_id,),
+                |row| row.get(0),
+            )?;
+
+            match user_id(tx, &to_username) {
+                Err(AppError::UserNotFound) => {
+                    tx.execute(
+                        r#"update users set username = ?1 where id = ?2"#,
+                        (&to_username, &from_id),
+                    )?;
+                }
+                Err(err) => return Err(err),
+                Ok(to_id) => {
+                    let name_conflict: bool = tx.query_row(
+                        r#"select exists(
+                             select 1 from credentials a
+                             join credentials b on a.name = b.name
+                             where a.user = ?1 and b.user = ?2
+                           )"#,
+                        (&from_id, &to_id),
+                        |row| row.get(0),
+                    )?;
+                    if name_conflict {
+                        return Err(AppError::DuplicateCredential);
+                    }
+
+                    tx.execute(
+                        r#"update credentials set user = ?1 where user = ?2"#,
+                        (&to_id, &from_id),
+                    )?;
+                    tx.execute(
+                        r#"update pending_credentials set user = ?1 where user = ?2"#,
+                        (&to_id, &from_id),
+                    )?;
+                    // Settings the merged user already has take precedence.
+                    tx.execute(
+                        r#"update or ignore user_settings set user = ?1 where user = ?2"#,
+                        (&to_id, &from_id),
+                    )?;
+                    tx.execute(r#"delete from user_settings where user = ?1"#, (&from_id,))?;
+                    tx.execute(
+                        r#"update user_aliases set user = ?1 where user = ?2"#,
+                        (&to_id, &from_id),
+                    )?;
+                    // Tokens reference the user that is about to be deleted.
+                    tx.execute(
+                        r#"update recovery_tokens set user = ?1 where user = ?2"#,
+                        (&to_id, &from_id),
+                    )?;
+                    tx.execute(r#"delete from impersonations where user = ?1"#, (&from_id,))?;
+                    tx.execute(r#"delete from users where id = ?1"#, (&from_id,))?;
+                }
+            }
+
+            // Rewritten entries are signed again, along with every entry after them.
+            let first_renamed: Option<i64> = tx.query_row(
+                r#"select min(id) from audit_log where username = ?1"#,
+                (&from_username,),
+                |row| row.get(0),
+            )?;
+            tx.execute(
+                r#"update audit_log set username = ?1 where username = ?2"#,
+                (&to_username, &from_username),
+            )?;
+            if let (Some(key), Some(first_renamed)) = (&key, first_renamed) {
+                sign_audit_log(tx, key, Some(first_renamed - 1))?;
+            }
+            record_event(
+                tx,
+                "user_renamed",
+                Some(&to_username),
+                Some(&format!("renamed from {from_username}")),
+            )?;
+
+            Ok(n_credentials)
+        })
+        .await
+    }
+
+    /// Lists all credentials, optionally only those created by authenticators with the given
+    /// AAGUID and/or registered before the unix timestamp `created_before`.
+    pub async fn list_credentials(
+        &self,
+        aaguid: Option<Uuid>,
+        created_before: Option<u64>,
+    ) -> Result<Vec<CredentialSummary>, AppError> {
+        self.credential_summaries(None, aaguid, created_before)
+            .await
+    }
+
+    async fn credential_summaries(
+        &self,
+        username: Option<String>,
+        aaguid: Option<Uuid>,
+        created_before: Option<u64>,
+    ) -> Result<Vec<CredentialSummary>, AppError> {
+        let aaguid = aaguid.map(|aaguid| aaguid.to_string());
+
+        let rows = self
+            .call(move |conn| {
+                Ok(conn
+                    .prepare(&format!(
+                        r#"select u.username, c.name, c.value, c.aaguid, c.created_at,
+                             {CREDENTIAL_IS_BLOCKED}, c.handle, c.min_pin_length, c.transports,
+                             c.backup_eligible, c.backup_state
+                           from credentials c
+                           join users u on u.id = c.user
+                           where (?1 is null or c.aaguid = ?1)
+                             and (?2 is null or c.created_at < ?2)
+                             and (?3 is null or u.username = ?3)
+                           order by u.username, c.name"#
+                    ))?
+                    .query_map((&aaguid, created_before, &username), |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(2)?,
+                            row.get::<_, Option<String>>(3)?,
+                            row.get::<_, u64>(4)?,
+                            row.get::<_, bool>(5)?,
+                            row.get::<_, String>(6)?,
+                            row.get::<_, Option<u32>>(7)?,
+                            (
+                                row.get::<_, Option<String>>(8)?,
+                                row.get::<_, Option<bool>>(9)?,
+                                row.get::<_, Option<bool>>(10)?,
+                            ),
+                        ))
+                    })?
+                    .filter_map(|v| v.ok())
+                    .collect::<Vec<_>>())
+            })
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(
+                |(
+                    username,
+                    name,
+                    value,
+                    aaguid,
+                    created_at,
+                    blocked,
+                    handle,
+                    min_pin_length,
+                    (transports, backup_eligible, backup_state),
+                )| {
+                    let passkey = serde_json::from_str::<Passkey>(&value).ok()?;
+                    Some(CredentialSummary {
+                        username,
+                        name,
+                        handle,
+                        id: passkey.cred_id().to_owned(),
+                        aaguid: aaguid.and_then(|aaguid| Uuid::parse_str(&aaguid).ok()),
+                        algorithm: *passkey.cred_algorithm(),
+                        created_at,
+                        blocked,
+                        cred_protect: cred_protect(&passkey),
+                        min_pin_length,
+                        transports: transports
+                            .and_then(|transports| serde_json::from_str(&transports).ok()),
+                        backup_eligible,
+                        backup_state,
+                    })
+                },
+            )
+            .collect())
+    }
+
+    /// Fills the columns holding what the stored passkeys record about their credential (see
+    /// `PasskeyInfo`) wherever they are empty, i.e. for credentials registered before a column
+    /// existed. Credentials whose passkey cannot be parsed are left as they are.
+    pub async fn migrate_credentials(&self) -> Result<Vec<CredentialMigration>, AppError> {
+        self.transaction(|tx| {
+            let rows = tx
+                .prepare(
+                    r#"select u.username, c.name, c.handle, c.value, c.algorithm is null,
+                         c.aaguid is null, c.transports is null, c.backup_eligible is null,
+                         c.backup_state is null
+                       from credentials c
+                       join users u on u.id = c.user
+                       order by u.username, c.name"#,
+                )?
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, String>(3)?,
+                        [
+                            ("algorithm", row.get::<_, bool>(4)?),
+                            ("aaguid", row.get(5)?),
+                            ("transports", row.get(6)?),
+                            ("backup_eligible", row.get(7)?),
+                            ("backup_state", row.get(8)?),
+                        ],
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let mut migrations = Vec::new();
+            for (username, name, handle, value, missing) in rows {
+                let info = match serde_json::from_str::<Passkey>(&value) {
+                    Ok(passkey) => PasskeyInfo::of(&passkey),
+                    Err(e) => {
+                        migrations.push(CredentialMigration {
+                            username,
+                            name,
+                            result: Err(format!("could not parse passkey: {e}")),
+                        });
+                        continue;
+                    }
+                };
+                tx.execute(
+                    r#"update credentials set
+                         algorithm = coalesce(algorithm, ?2),
+                         aaguid = coalesce(aaguid, ?3),
+                         transports = coalesce(transports, json(?4)),
+                         backup_eligible = coalesce(backup_eligible, ?5),
+                         backup_state = coalesce(backup_state, ?6)
+                       where handle = ?1"#,
+                    (
+                        &handle,
+                        info.algorithm as i32,
+                        info.aaguid.map(|aaguid| aaguid.to_string()),
+                        info.transports
+                            .as_ref()
+                            .map(serde_json::to_string)
+                            .transpose()?,
+                        info.backup_eligible,
+                        info.backup_state,
+                    ),
+                )?;
+                // Passkeys without attestation have no AAGUID to fill in, ones registered with
+                // old versions of webauthn-rs no transports.
+                let known = [
+                    true,
+                    info.aaguid.is_some(),
+                    info.transports.is_some(),
+                    true,
+                    true,
+                ];
+                let filled: Vec<_> = missing
+                    .into_iter()
+                    .zip(known)
+                    .filter(|((_, missing), known)| *missing && *known)
+                    .map(|((column, _), _)| column)
+                    .collect();
+                if !filled.is_empty() {
+                    let detail = format!("{name}: {}", filled.join(", "));
+                    record_event(tx, "credential_migrated", Some(&username), Some(&detail))?;
+                }
+                migrations.push(CredentialMigration {
+                    username,
+                    name,
+                    result: Ok(filled),
+                });
+            }
+            Ok(migrations)
+        })
+        .await
+    }
+
+    /// Blocks registering credentials matching `item`, and using existing ones. Returns the id of
+    /// the new entry and how many existing credentials it blocks.
+    pub async fn add_to_blocklist(
+        &self,
+        item: BlockedItem,
+        reason: Option<String>,
+    ) -> Result<(i64, usize), AppError> {
+        let (aaguid, cred_id, description) = match &item {
+            BlockedItem::Aaguid(aaguid) => {
+                (Some(aaguid.to_string()), None, format!("aaguid {aaguid}"))
+            }
+            BlockedItem::Credential(cred_id) => {
+                // serialized as a JSON string of the base64url encoded ID
+                let cred_id = serde_json::to_string(cred_id)?;
+                let description = format!("credential {}", cred_id.trim_matches('"'));
+                (None, Some(cred_id), description)
+            }
+        };
+
+        self.transaction(move |tx| {
+            tx.execute(
+                r#"insert into blocklist (aaguid, cred_id, reason, created_at)
+                   values (?1, json(?2), ?3, cast(strftime('%s', 'now') as integer))"#,
+                (&aaguid, &cred_id, &reason),
+            )?;
+            let id = tx.last_insert_rowid();
+
+            let n_blocked: usize = tx.query_row(
+                r#"select count(*) from credentials c
+                   where c.aaguid = ?1 or c.value->'$.cred.cred_id' = json(?2)"#,
+                (&aaguid, &cred_id),
+                |row| row.get(0),
+            )?;
+            record_event(
+                tx,
+                "blocklist_added",
+                None,
+                Some(&format!("{description}, blocking {n_blocked} credentials")),
+            )?;
+
+            Ok((id, n_blocked))
+        })
+        .await
+    }
+
+    pub async fn list_blocklist(&self) -> Result<Vec<BlocklistEntry>, AppError> {
+        let rows = self
+            .call(|conn| {
+                Ok(conn
+                    .prepare(
+                        r#"select id, aaguid, cred_id, reason, created_at from blocklist
+                           order by id"#,
+                    )?
+                    .query_map([], |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, Option<String>>(1)?,
+                            row.get::<_, Option<String>>(2)?,
+                            row.get::<_, Option<String>>(3)?,
+                            row.get::<_, u64>(4)?,
+                        ))
+                    })?
+                    .collect::<Result<Vec<_>, _>>())
+            })
+            .await??;
+
+        Ok(rows
+            .into_iter()
+            .map(|(id, aaguid, cred_id, reason, created_at)| BlocklistEntry {
+                id,
+                aaguid: aaguid.and_then(|aaguid| Uuid::parse_str(&aaguid).ok()),
+                credential_id: cred_id.and_then(|cred_id| serde_json::from_str(&cred_id).ok()),
+                reason,
+                created_at,
+            })
+            .collect())
+    }
+
+    /// Unblocks an entry of the blocklist, making the credentials it matched usable again.
+    pub async fn remove_from_blocklist(&self, id: i64) -> Result<(), AppError> {
+        self.transaction(move |tx| {
+            let n_deleted = tx.execute(r#"delete from blocklist where id = ?1"#, (id,))?;
+            if n_deleted == 0 {
+                return Err(AppError::EntityNotFound);
+            }
+            record_event(tx, "blocklist_removed", None, Some(&format!("entry {id}")))?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Deletes all credentials created by authenticators with the given AAGUID, returning how
+    /// many were deleted.
+    pub async fn delete_credentials_by_aaguid(&self, aaguid: Uuid) -> Result<usize, AppError> {
+        let aaguid = aaguid.to_string();
+
+        Ok(self
+            .call(move |conn| {
+                Ok(conn.execute(r#"delete from credentials where aaguid = ?1"#, (&aaguid,)))
+            })
+            .await??)
+    }
+}
+
+#[cfg(test)]
+mod tests {
     use std::time::Duration;
 
-    use super::*;
-    use tokio_rusqlite::Connection;
-    use webauthn_authenticator_rs::{prelude::Url, softtoken::SoftToken, WebauthnAuthenticator};
-    use webauthn_rs_core::WebauthnCore;
+    use super::*;
+    use crate::{seed::SeedUser, username::NormalizationStep};
+    use tokio_rusqlite::Connection;
+    use webauthn_authenticator_rs::{prelude::Url, softtoken::SoftToken, WebauthnAuthenticator};
+    use webauthn_rs::prelude::Credential;
+    use webauthn_rs_core::WebauthnCore;
+
+    async fn get_app_with_db() -> App {
+        let db = Connection::open(":memory:").await.unwrap();
+        let app = App::new(db);
+        app.init().await.unwrap();
+        app
+    }
+
+    #[test]
+    fn test_apply_migrations() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute("create table foo (a integer)", []).unwrap();
+        let version = |conn: &rusqlite::Connection| -> usize {
+            conn.query_row("pragma user_version", [], |row| row.get(0))
+                .unwrap()
+        };
+
+        let migrations = [
+            "alter table foo add column b integer",
+            "alter table foo add column c integer; alter table nope add column d integer",
+        ];
+        assert!(apply_migrations(&mut conn, &migrations).is_err());
+        // the failed migration is rolled back entirely, the one before it is kept
+        assert_eq!(version(&conn), 1);
+        assert!(conn.prepare("select c from foo").is_err());
+
+        let migrations = [migrations[0], "alter table foo add column c integer"];
+        apply_migrations(&mut conn, &migrations).unwrap();
+        assert_eq!(version(&conn), 2);
+        conn.prepare("select b, c from foo").unwrap();
+    }
+
+    #[test]
+    fn test_credential_handle() {
+        let cred_id = CredentialID::from(vec![0xfb, 0xff, 0x00, 0x3e, 0x01]);
+        let handle = credential_handle(&cred_id);
+        assert_eq!(handle, "-_8APgE");
+
+        [
+            handle.clone(),
+            general_purpose::URL_SAFE.encode(&cred_id),
+            general_purpose::STANDARD.encode(&cred_id),
+            general_purpose::STANDARD_NO_PAD.encode(&cred_id),
+        ]
+        .iter()
+        .for_each(|identifier| {
+            assert_eq!(
+                normalize_credential_handle(identifier),
+                handle,
+                "{identifier}"
+            );
+        });
+
+        // anything else is looked up as-is, and will not match a credential
+        assert_eq!(normalize_credential_handle("not base64!"), "not base64!");
+    }
+
+    #[tokio::test]
+    async fn test_init_is_idempotent() {
+        let app = get_app_with_db().await;
+        app.init().await.unwrap();
+        assert!(app.schema_is_current().await.unwrap());
+
+        let uninitialized = App::new(Connection::open(":memory:").await.unwrap());
+        assert!(!uninitialized.schema_is_current().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_get_user_with_credentials() {
+        let app = get_app_with_db().await;
+
+        assert_eq!(
+            app.db
+                .call(|conn| {
+                    let exists: usize = conn
+                        .query_row("select exists(select id from users)", [], |row| row.get(0))
+                        .unwrap();
+
+                    Ok(exists)
+                })
+                .await
+                .unwrap(),
+            0
+        );
+
+        app.get_user_with_credentials("foo_user".to_string())
+            .await
+            .unwrap(); // user is created if they do not exist
+
+        assert_eq!(
+            app.db
+                .call(|conn| {
+                    let exists: usize = conn
+                        .query_row("select exists(select id from users)", [], |row| row.get(0))
+                        .unwrap();
+
+                    Ok(exists)
+                })
+                .await
+                .unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_user_creation_policy() {
+        let app = get_app_with_db()
+            .await
+            .with_user_creation_policy(Arc::new(MaxUsers(Some(1))));
+
+        app.get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        // existing users are not affected by the limit
+        app.get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        assert!(matches!(
+            app.get_user_with_credentials("bar".to_string()).await,
+            Err(AppError::UserCreationDenied)
+        ));
+        assert_eq!(app.list_users().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_user_deactivation() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+
+        let user = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        assert!(user.active);
+        app.add_credential(
+            user.username.clone(),
+            "key".to_string(),
+            &register_passkey(&wan, &user),
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        app.set_user_active("foo".to_string(), false).await.unwrap();
+        assert!(!app.user_is_active("foo".to_string()).await.unwrap());
+        assert!(!app.user_is_active("nobody".to_string()).await.unwrap());
+
+        // credentials are kept while deactivated
+        let user = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        assert!(!user.active);
+        assert_eq!(user.credentials.len(), 1);
+
+        let users = app.list_users().await.unwrap();
+        assert_eq!(users.len(), 1);
+        assert!(!users[0].active);
+        assert_eq!(users[0].credentials, 1);
+
+        app.set_user_active("foo".to_string(), true).await.unwrap();
+        assert!(app.user_is_active("foo".to_string()).await.unwrap());
+        assert!(matches!(
+            app.set_user_active("nobody".to_string(), true).await,
+            Err(AppError::UserNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_search_users() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+        for username in ["alice", "bob", "carol", "dave"] {
+            app.get_user_with_credentials(username.to_string())
+                .await
+                .unwrap();
+        }
+        let bob = app
+            .get_user_with_credentials("bob".to_string())
+            .await
+            .unwrap();
+        app.add_credential(
+            bob.username.clone(),
+            "key".to_string(),
+            &register_passkey(&wan, &bob),
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+        app.add_user_alias("dave".to_string(), "alfred".to_string())
+            .await
+            .unwrap();
+        app.set_user_active("carol".to_string(), false)
+            .await
+            .unwrap();
+
+        let search = |filter: UserFilter, after: Option<&str>, limit| {
+            let (app, after) = (&app, after.map(str::to_string));
+            async move {
+                app.search_users(filter, after, limit)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|user| user.username)
+                    .collect::<Vec<_>>()
+            }
+        };
+        let filter = |search: Option<&str>, has_credentials, locked| UserFilter {
+            search: search.map(str::to_string),
+            has_credentials,
+            locked,
+        };
+
+        assert_eq!(
+            search(UserFilter::default(), None, None).await,
+            ["alice", "bob", "carol", "dave"]
+        );
+        // aliases are searched too
+        assert_eq!(
+            search(filter(Some("al"), None, None), None, None).await,
+            ["alice", "dave"]
+        );
+        assert!(search(filter(Some("x"), None, None), None, None)
+            .await
+            .is_empty());
+        assert_eq!(
+            search(filter(None, Some(true), None), None, None).await,
+            ["bob"]
+        );
+        assert_eq!(
+            search(filter(None, Some(false), Some(false)), None, None).await,
+            ["alice", "dave"]
+        );
+        assert_eq!(
+            search(filter(None, None, Some(true)), None, None).await,
+            ["carol"]
+        );
+
+        // pages continue after the last username of the previous one
+        assert_eq!(
+            search(UserFilter::default(), None, Some(2)).await,
+            ["alice", "bob"]
+        );
+        assert_eq!(
+            search(UserFilter::default(), Some("bob"), Some(2)).await,
+            ["carol", "dave"]
+        );
+        assert!(search(UserFilter::default(), Some("dave"), Some(2))
+            .await
+            .is_empty());
+
+        let plan = app
+            .call(|conn| {
+                Ok(conn
+                    .prepare(
+                        r#"explain query plan select 1 from users u
+                           where exists(select 1 from credentials c where c.user = u.id)"#,
+                    )?
+                    .query_map([], |row| row.get::<_, String>(3))?
+                    .collect::<Result<Vec<_>, _>>())
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            plan.iter().any(|step| step.contains("credentials_user")),
+            "{plan:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_credential() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+
+        let user = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        let passkey = register_passkey(&wan, &user);
+        app.add_credential(
+            user.username.clone(),
+            "key".to_string(),
+            &passkey,
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        let (username, name, found) = app
+            .find_credential(&credential_handle(passkey.cred_id()))
+            .await
+            .unwrap();
+        assert_eq!((username.as_str(), name.as_str()), ("foo", "key"));
+        assert_eq!(found.get_public_key(), passkey.get_public_key());
+        assert!(matches!(
+            app.find_credential("AAAA").await,
+            Err(AppError::CredentialNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_user_aliases() {
+        let app = get_app_with_db().await;
+        for username in ["jdoe", "other"] {
+            app.get_user_with_credentials(username.to_string())
+                .await
+                .unwrap();
+        }
+
+        app.add_user_alias("jdoe".to_string(), "j.doe@example.com".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            app.canonical_username("j.doe@example.com").await.unwrap(),
+            "jdoe"
+        );
+        assert_eq!(app.canonical_username("other").await.unwrap(), "other");
+        assert_eq!(
+            app.list_users().await.unwrap()[0].aliases,
+            vec!["j.doe@example.com".to_string()]
+        );
+
+        // aliases are unique, and cannot shadow usernames
+        for alias in ["j.doe@example.com", "other"] {
+            assert!(matches!(
+                app.add_user_alias("jdoe".to_string(), alias.to_string())
+                    .await,
+                Err(AppError::DuplicateAlias)
+            ));
+        }
+        assert!(matches!(
+            app.add_user_alias("nobody".to_string(), "nobody2".to_string())
+                .await,
+            Err(AppError::UserNotFound)
+        ));
+
+        // aliases follow the user when it is merged into another one
+        app.rename_user("jdoe".to_string(), "other".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            app.canonical_username("j.doe@example.com").await.unwrap(),
+            "other"
+        );
+
+        assert!(matches!(
+            app.remove_user_alias("jdoe".to_string(), "j.doe@example.com".to_string())
+                .await,
+            Err(AppError::UserNotFound)
+        ));
+        app.remove_user_alias("other".to_string(), "j.doe@example.com".to_string())
+            .await
+            .unwrap();
+        assert!(matches!(
+            app.remove_user_alias("other".to_string(), "j.doe@example.com".to_string())
+                .await,
+            Err(AppError::AliasNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_username_normalization() {
+        let app = get_app_with_db().await;
+        for username in ["JDoe", "jdoe@example.com", "alice"] {
+            app.get_user_with_credentials(username.to_string())
+                .await
+                .unwrap();
+        }
+        assert!(app.unnormalized_usernames().await.unwrap().is_empty());
+
+        let app = app.with_username_normalization(UsernameNormalization::new(&[
+            NormalizationStep::StripRealm,
+            NormalizationStep::Lowercase,
+        ]));
+        assert_eq!(app.canonical_username("ALICE").await.unwrap(), "alice");
+        assert_eq!(
+            app.unnormalized_usernames().await.unwrap(),
+            BTreeMap::from([(
+                "jdoe".to_string(),
+                vec!["JDoe".to_string(), "jdoe@example.com".to_string()]
+            )])
+        );
+
+        app.add_user_alias("alice".to_string(), "Al".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            app.canonical_username("al@example.com").await.unwrap(),
+            "alice"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_hash() {
+        let app = get_app_with_db().await;
+        app.get_user_with_credentials("alice".to_string())
+            .await
+            .unwrap();
+        assert_eq!(app.password_hash("alice".to_string()).await.unwrap(), None);
+        assert_eq!(app.password_hash("nobody".to_string()).await.unwrap(), None);
+
+        app.set_password_hash("alice".to_string(), Some("$argon2id$hash".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            app.password_hash("alice".to_string()).await.unwrap(),
+            Some("$argon2id$hash".to_string())
+        );
+        app.set_password_hash("alice".to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(app.password_hash("alice".to_string()).await.unwrap(), None);
+
+        assert!(matches!(
+            app.set_password_hash("nobody".to_string(), None).await,
+            Err(AppError::UserNotFound)
+        ));
+        assert_eq!(
+            app.audit_log(2)
+                .await
+                .unwrap()
+                .iter()
+                .map(|e| e.event.as_str())
+                .collect::<Vec<_>>(),
+            vec!["password_removed", "password_set"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_user() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+
+        let user = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        app.add_credential(
+            user.username.clone(),
+            "key".to_string(),
+            &register_passkey(&wan, &user),
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+        app.issue_recovery_token("foo".to_string(), "t1".to_string(), 4_102_444_800)
+            .await
+            .unwrap();
+        app.replace_user_settings(
+            "foo".to_string(),
+            BTreeMap::from([("language".to_string(), serde_json::Value::from("de"))]),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            app.delete_user("nobody".to_string()).await,
+            Err(AppError::UserNotFound)
+        ));
+        assert_eq!(app.delete_user("foo".to_string()).await.unwrap(), 1);
+        assert!(app.list_users().await.unwrap().is_empty());
+        assert_eq!(app.audit_log(1).await.unwrap()[0].event, "user_deleted");
+
+        // logging in again starts from scratch
+        let recreated = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        assert_ne!(recreated.id, user.id);
+        assert!(recreated.credentials.is_empty());
+        assert!(app
+            .user_settings("foo".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_users() {
+        let app = get_app_with_db().await;
+        for username in ["aliased", "foo", "gone", "local"] {
+            app.get_user_with_credentials(username.to_string())
+                .await
+                .unwrap();
+        }
+        app.add_user_alias("aliased".to_string(), "bar".to_string())
+            .await
+            .unwrap();
+        app.set_password_hash("local".to_string(), Some("hash".to_string()))
+            .await
+            .unwrap();
+        let upstream = || vec!["foo".to_string(), "bar".to_string()];
+
+        assert_eq!(
+            app.reconcile_users(upstream(), false, Reconciliation::Report)
+                .await
+                .unwrap(),
+            ["gone", "local"]
+        );
+        assert_eq!(app.list_users().await.unwrap().len(), 4);
+
+        assert_eq!(
+            app.reconcile_users(upstream(), true, Reconciliation::Deactivate)
+                .await
+                .unwrap(),
+            ["gone"]
+        );
+        assert_eq!(app.audit_log(1).await.unwrap()[0].event, "user_deactivated");
+        // only users that are still active are deactivated
+        assert!(app
+            .reconcile_users(upstream(), true, Reconciliation::Deactivate)
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            app.reconcile_users(upstream(), true, Reconciliation::Delete)
+                .await
+                .unwrap(),
+            ["gone"]
+        );
+        let usernames = app
+            .list_users()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|user| user.username)
+            .collect::<Vec<_>>();
+        assert_eq!(usernames, ["aliased", "foo", "local"]);
+    }
+
+    #[tokio::test]
+    async fn test_reset() {
+        let wan = new_webauthn();
+        let key = || AuditLogKey::new(b"secret");
+        let app = get_app_with_db().await.with_audit_log_key(key());
+
+        let user = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        app.add_credential(
+            user.username.clone(),
+            "key".to_string(),
+            &register_passkey(&wan, &user),
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        app.reset().await.unwrap();
+        assert!(app.list_users().await.unwrap().is_empty());
+        assert!(app.audit_log(10).await.unwrap().is_empty());
+        assert_eq!(
+            app.verify_audit_log(key()).await.unwrap(),
+            AuditLogVerification::Intact { entries: 0 }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_and_erase_user() {
+        let wan = new_webauthn();
+        let key = || AuditLogKey::new(b"secret");
+        let app = get_app_with_db().await.with_audit_log_key(key());
+
+        let user = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        app.add_credential(
+            user.username.clone(),
+            "key".to_string(),
+            &register_passkey(&wan, &user),
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+        app.add_user_alias("foo".to_string(), "foo@example.com".to_string())
+            .await
+            .unwrap();
+        app.issue_recovery_token("foo".to_string(), "t1".to_string(), 4_102_444_800)
+            .await
+            .unwrap();
+        app.replace_user_settings(
+            "foo".to_string(),
+            BTreeMap::from([("language".to_string(), serde_json::Value::from("de"))]),
+        )
+        .await
+        .unwrap();
+        app.record_event("authentication_succeeded", Some("bar".to_string()), None)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            app.export_user("nobody".to_string()).await,
+            Err(AppError::UserNotFound)
+        ));
+        let export = app.export_user("foo".to_string()).await.unwrap();
+        assert!(export.active);
+        assert_eq!(export.aliases, vec!["foo@example.com"]);
+        assert_eq!(export.settings["language"], "de");
+        assert_eq!(export.credentials.len(), 1);
+        assert_eq!(export.credentials[0].summary.name, "key");
+        assert_eq!(export.recovery_links.len(), 1);
+        assert!(export
+            .audit_events
+            .iter()
+            .all(|event| event.username.as_deref() == Some("foo")));
+        assert!(!export.audit_events.is_empty());
+
+        let erased = export.audit_events.len();
+        assert_eq!(app.erase_user("foo".to_string()).await.unwrap(), erased);
+        assert!(app.list_users().await.unwrap().is_empty());
+        let events = app.audit_log(100).await.unwrap();
+        assert_eq!(events[0].event, "user_erased");
+        assert!(events
+            .iter()
+            .all(|event| event.username.as_deref() != Some("foo")
+                && !event.detail.as_deref().unwrap_or_default().contains("foo")));
+        // other users' entries are kept
+        assert!(events
+            .iter()
+            .any(|event| event.username.as_deref() == Some("bar")));
+        assert!(matches!(
+            app.verify_audit_log(key()).await.unwrap(),
+            AuditLogVerification::Intact { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rename_user() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+
+        let user = app
+            .get_user_with_credentials("j.doe".to_string())
+            .await
+            .unwrap();
+        app.add_credential(
+            user.username.clone(),
+            "key".to_string(),
+            &register_passkey(&wan, &user),
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+        app.issue_recovery_token("j.doe".to_string(), "t1".to_string(), 4_102_444_800)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            app.rename_user("nobody".to_string(), "jdoe".to_string())
+                .await,
+            Err(AppError::UserNotFound)
+        ));
+        assert_eq!(
+            app.rename_user("j.doe".to_string(), "jdoe".to_string())
+                .await
+                .unwrap(),
+            1
+        );
+
+        let renamed = app
+            .get_user_with_credentials("jdoe".to_string())
+            .await
+            .unwrap();
+        assert_eq!(renamed.id, user.id);
+        assert_eq!(renamed.credentials.len(), 1);
+        assert!(!app
+            .recovery_token_is_valid("t1".to_string(), "j.doe".to_string())
+            .await
+            .unwrap());
+        assert!(app
+            .audit_log(10)
+            .await
+            .unwrap()
+            .iter()
+            .all(|event| event.username.as_deref() == Some("jdoe")));
+
+        // merging into a user that already exists
+        let other = app
+            .get_user_with_credentials("doe".to_string())
+            .await
+            .unwrap();
+        app.add_credential(
+            other.username.clone(),
+            "other key".to_string(),
+            &register_passkey(&wan, &other),
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            app.rename_user("jdoe".to_string(), "doe".to_string())
+                .await
+                .unwrap(),
+            1
+        );
+        let merged = app
+            .get_user_with_credentials("doe".to_string())
+            .await
+            .unwrap();
+        assert_eq!(merged.id, other.id);
+        assert_eq!(merged.credentials.len(), 2);
+        assert_eq!(app.list_users().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_apply_seed() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+
+        let stranger = app
+            .get_user_with_credentials("stranger".to_string())
+            .await
+            .unwrap();
+        app.add_credential(
+            stranger.username.clone(),
+            "key".to_string(),
+            &register_passkey(&wan, &stranger),
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        let seed = |prune: bool, codes: Vec<String>| Seed {
+            prune,
+            users: [
+                (
+                    "alice".to_string(),
+                    SeedUser {
+                        groups: Some(vec!["admins".to_string(), "users".to_string()]),
+                        recovery_codes: Some(codes),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "bob".to_string(),
+                    SeedUser {
+                        active: false,
+                        ..Default::default()
+                    },
+                ),
+            ]
+            .into(),
+        };
+
+        let codes = vec![recovery_code_hash("first"), recovery_code_hash("second")];
+        assert_eq!(
+            app.apply_seed(seed(false, codes.clone())).await.unwrap(),
+            (2, 0)
+        );
+        // applying the same seed again changes nothing
+        assert_eq!(
+            app.apply_seed(seed(false, codes.clone())).await.unwrap(),
+            (0, 0)
+        );
+
+        let users = app.list_users().await.unwrap();
+        assert_eq!(
+            users
+                .iter()
+                .map(|u| (u.username.as_str(), u.active, u.groups.len()))
+                .collect::<Vec<_>>(),
+            vec![("alice", true, 2), ("bob", false, 0), ("stranger", true, 0)]
+        );
+
+        // codes are single use and only valid for their user
+        assert!(matches!(
+            app.exchange_recovery_code("bob".into(), "first".into(), "t0".into(), 4_102_444_800)
+                .await,
+            Err(AppError::InvalidRecoveryToken)
+        ));
+        app.exchange_recovery_code("alice".into(), "first".into(), "t1".into(), 4_102_444_800)
+            .await
+            .unwrap();
+        assert!(app
+            .recovery_token_is_valid("t1".to_string(), "alice".to_string())
+            .await
+            .unwrap());
+        assert!(matches!(
+            app.exchange_recovery_code("alice".into(), "first".into(), "t2".into(), 4_102_444_800)
+                .await,
+            Err(AppError::InvalidRecoveryToken)
+        ));
+
+        // dropping a code from the seed revokes it, and pruning deletes unlisted users
+        assert_eq!(
+            app.apply_seed(seed(true, vec![recovery_code_hash("first")]))
+                .await
+                .unwrap(),
+            (0, 1)
+        );
+        assert!(matches!(
+            app.exchange_recovery_code("alice".into(), "second".into(), "t3".into(), 4_102_444_800)
+                .await,
+            Err(AppError::InvalidRecoveryToken)
+        ));
+        assert_eq!(app.list_users().await.unwrap().len(), 2);
+        assert!(app.list_credentials(None, None).await.unwrap().is_empty());
+
+        // regenerating replaces all codes of the user, used or not
+        app.replace_recovery_codes("alice".into(), vec![recovery_code_hash("third")])
+            .await
+            .unwrap();
+        assert!(matches!(
+            app.exchange_recovery_code("alice".into(), "first".into(), "t4".into(), 4_102_444_800)
+                .await,
+            Err(AppError::InvalidRecoveryToken)
+        ));
+        app.exchange_recovery_code("alice".into(), "third".into(), "t5".into(), 4_102_444_800)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_add_kiosk_credential() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+        app.apply_seed(Seed {
+            prune: false,
+            users: [(
+                "operator".to_string(),
+                SeedUser {
+                    groups: Some(vec!["it".to_string()]),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        })
+        .await
+        .unwrap();
+
+        assert!(app
+            .user_in_group("operator".to_string(), "it".to_string())
+            .await
+            .unwrap());
+        assert!(!app
+            .user_in_group("operator".to_string(), "admins".to_string())
+            .await
+            .unwrap());
+        assert!(!app
+            .user_in_group("nobody".to_string(), "it".to_string())
+            .await
+            .unwrap());
+        assert_eq!(
+            app.user_groups("operator".to_string()).await.unwrap(),
+            vec!["it".to_string()]
+        );
+
+        let user = app
+            .get_user_with_credentials("new_hire".to_string())
+            .await
+            .unwrap();
+        app.add_kiosk_credential(
+            "operator".to_string(),
+            user.username.clone(),
+            "Security key".to_string(),
+            &register_passkey(&wan, &user),
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        let user = app
+            .get_user_with_credentials("new_hire".to_string())
+            .await
+            .unwrap();
+        assert_eq!(user.credentials.len(), 1);
+        let event = &app.audit_log(1).await.unwrap()[0];
+        assert_eq!(event.event, "kiosk_enrolled");
+        assert_eq!(event.username.as_deref(), Some("new_hire"));
+        assert_eq!(
+            event.detail.as_deref(),
+            Some("Security key enrolled by operator")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_impersonation() {
+        let app = get_app_with_db().await;
+        app.get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            app.issue_impersonation(
+                "nobody".into(),
+                "t0".into(),
+                "admin".into(),
+                None,
+                4_102_444_800
+            )
+            .await,
+            Err(AppError::UserNotFound)
+        ));
+        app.issue_impersonation("foo".into(), "expired".into(), "admin".into(), None, 0)
+            .await
+            .unwrap();
+        app.issue_impersonation(
+            "foo".into(),
+            "t1".into(),
+            "admin".into(),
+            Some("ticket 42".into()),
+            4_102_444_800,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            app.audit_log(1).await.unwrap()[0].detail.as_deref(),
+            Some("by admin: ticket 42")
+        );
+
+        // links are single use, and only their hash is stored
+        assert!(matches!(
+            app.redeem_impersonation("expired".into()).await,
+            Err(AppError::InvalidImpersonationToken)
+        ));
+        assert_eq!(
+            app.redeem_impersonation("t1".into()).await.unwrap(),
+            ("foo".to_string(), "admin".to_string())
+        );
+        assert_eq!(
+            app.audit_log(1).await.unwrap()[0].event,
+            "impersonation_started"
+        );
+        assert!(matches!(
+            app.redeem_impersonation("t1".into()).await,
+            Err(AppError::InvalidImpersonationToken)
+        ));
+        assert!(matches!(
+            app.redeem_impersonation(sha256_hex("t1")).await,
+            Err(AppError::InvalidImpersonationToken)
+        ));
+
+        assert_eq!(app.delete_user("foo".to_string()).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_recovery_token() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+
+        let user = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        for name in ["lost key", "lost phone"] {
+            app.add_credential(
+                user.username.clone(),
+                name.to_string(),
+                &register_passkey(&wan, &user),
+                AuthenticatorInfo::default(),
+            )
+            .await
+            .unwrap();
+        }
+
+        assert!(matches!(
+            app.issue_recovery_token("nobody".to_string(), "t0".to_string(), 4_102_444_800)
+                .await,
+            Err(AppError::UserNotFound)
+        ));
+        app.issue_recovery_token("foo".to_string(), "expired".to_string(), 0)
+            .await
+            .unwrap();
+        app.issue_recovery_token("foo".to_string(), "t1".to_string(), 4_102_444_800)
+            .await
+            .unwrap();
+
+        assert!(!app
+            .recovery_token_is_valid("expired".to_string(), "foo".to_string())
+            .await
+            .unwrap());
+        assert!(!app
+            .recovery_token_is_valid("t1".to_string(), "bar".to_string())
+            .await
+            .unwrap());
+        assert!(app
+            .recovery_token_is_valid("t1".to_string(), "foo".to_string())
+            .await
+            .unwrap());
+
+        let new_key = register_passkey(&wan, &user);
+        assert!(matches!(
+            app.redeem_recovery_token(
+                "expired".to_string(),
+                "foo".to_string(),
+                "new key".to_string(),
+                &new_key,
+                AuthenticatorInfo::default(),
+            )
+            .await,
+            Err(AppError::InvalidRecoveryToken)
+        ));
+        assert_eq!(
+            app.redeem_recovery_token(
+                "t1".to_string(),
+                "foo".to_string(),
+                "new key".to_string(),
+                &new_key,
+                AuthenticatorInfo::default(),
+            )
+            .await
+            .unwrap(),
+            2
+        );
+
+        let user = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        assert_eq!(user.credentials.len(), 1);
+        assert_eq!(user.credentials[0].name, "new key");
+
+        // tokens are single-use
+        assert!(!app
+            .recovery_token_is_valid("t1".to_string(), "foo".to_string())
+            .await
+            .unwrap());
+        assert!(matches!(
+            app.redeem_recovery_token(
+                "t1".to_string(),
+                "foo".to_string(),
+                "another key".to_string(),
+                &register_passkey(&wan, &user),
+                AuthenticatorInfo::default(),
+            )
+            .await,
+            Err(AppError::InvalidRecoveryToken)
+        ));
+
+        let events: Vec<_> = app
+            .audit_log(10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| event.event)
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "recovery_redeemed",
+                "recovery_issued",
+                "recovery_issued",
+                "credential_registered",
+                "credential_registered"
+            ]
+        );
+    }
+
+    fn new_webauthn() -> WebauthnCore {
+        WebauthnCore::new_unsafe_experts_only(
+            "https://localhost:8080/auth",
+            "localhost",
+            vec![Url::parse("https://localhost:8080").unwrap()],
+            Duration::from_secs(1),
+            None,
+            None,
+        )
+    }
+
+    fn register_passkey(wan: &WebauthnCore, user: &UserWithCredentials) -> Passkey {
+        let (soft_token, _) = SoftToken::new(true).unwrap();
+        let mut wa = WebauthnAuthenticator::new(soft_token);
+
+        let (chal, reg_state) = wan
+            .generate_challenge_register(
+                wan.new_challenge_register_builder(
+                    &user.id.into_bytes(),
+                    &user.username,
+                    &user.username,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let r = wa
+            .do_registration(Url::parse("https://localhost:8080").unwrap(), chal)
+            .unwrap();
+
+        Passkey::from(wan.register_credential(&r, &reg_state, None).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_credential_tags() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+        let foo = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        app.add_credential(
+            foo.username.clone(),
+            "key".to_string(),
+            &register_passkey(&wan, &foo),
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+        let handle = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap()
+            .credentials[0]
+            .handle
+            .clone();
+
+        assert_eq!(
+            app.set_credential_tags(
+                "foo".to_string(),
+                &handle,
+                vec![
+                    " work".to_string(),
+                    "backup".to_string(),
+                    "work".to_string()
+                ],
+            )
+            .await
+            .unwrap(),
+            vec!["backup".to_string(), "work".to_string()]
+        );
+        assert_eq!(
+            app.get_user_with_credentials("foo".to_string())
+                .await
+                .unwrap()
+                .credentials[0]
+                .tags,
+            vec!["backup".to_string(), "work".to_string()]
+        );
+
+        assert!(matches!(
+            app.set_credential_tags("foo".to_string(), &handle, vec![" ".to_string()])
+                .await,
+            Err(AppError::BadInput)
+        ));
+        assert!(matches!(
+            app.set_credential_tags("bar".to_string(), &handle, vec![])
+                .await,
+            Err(AppError::CredentialNotFound)
+        ));
 
-    async fn get_app_with_db() -> App {
-        let db = Connection::open(":memory:").await.unwrap();
-        let app = App::new(db);
-        app.init().await.unwrap();
-        app
+        // tags are deleted along with their credential
+        app.delete_credential("foo".to_string(), &handle)
+            .await
+            .unwrap();
+        let tags: usize = app
+            .call(|conn| {
+                Ok(
+                    conn.query_row(r#"select count(*) from credential_tags"#, [], |row| {
+                        row.get(0)
+                    }),
+                )
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tags, 0);
     }
 
     #[tokio::test]
-    async fn test_init_is_idempotent() {
+    async fn test_migrate_credentials() {
+        let wan = new_webauthn();
         let app = get_app_with_db().await;
-        app.init().await.unwrap();
+        let foo = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        for name in ["broken", "key"] {
+            app.add_credential(
+                foo.username.clone(),
+                name.to_string(),
+                &register_passkey(&wan, &foo),
+                AuthenticatorInfo::default(),
+            )
+            .await
+            .unwrap();
+        }
+        let registered = app.list_credentials(None, None).await.unwrap().remove(1);
+        assert!(registered.transports.is_some());
+        assert_eq!(registered.backup_eligible, Some(false));
+        assert_eq!(registered.backup_state, Some(false));
+
+        // as if registered before the columns existed
+        app.call(|conn| {
+            Ok(conn.execute_batch(
+                r#"update credentials set transports = null, backup_eligible = null,
+                     backup_state = null;
+                   update credentials set value = '{}' where name = 'broken'"#,
+            ))
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            app.list_credentials(None, None).await.unwrap()[0].backup_state,
+            None
+        );
+
+        let migrations = app.migrate_credentials().await.unwrap();
+        assert_eq!(migrations.len(), 2);
+        assert_eq!(migrations[0].name, "broken");
+        assert!(migrations[0].result.is_err());
+        assert_eq!(migrations[1].name, "key");
+        assert_eq!(
+            migrations[1].result,
+            Ok(vec!["transports", "backup_eligible", "backup_state"])
+        );
+        let summary = app.list_credentials(None, None).await.unwrap().remove(0);
+        assert_eq!(summary.name, "key");
+        assert_eq!(summary.transports, registered.transports);
+        assert_eq!(summary.backup_eligible, Some(false));
+        assert_eq!(summary.backup_state, Some(false));
+        let events = app.audit_log(10).await.unwrap();
+        assert_eq!(events[0].event, "credential_migrated");
+        assert_eq!(
+            events[0].detail.as_deref(),
+            Some("key: transports, backup_eligible, backup_state")
+        );
+
+        // nothing is left to fill in
+        let migrations = app.migrate_credentials().await.unwrap();
+        assert_eq!(migrations[1].result, Ok(vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_bulk_credential_operations() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+        let aaguid = Uuid::new_v4();
+
+        let foo = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        let bar = app
+            .get_user_with_credentials("bar".to_string())
+            .await
+            .unwrap();
+        for (user, name, aaguid) in [
+            (&foo, "key", Some(aaguid)),
+            (&foo, "phone", None),
+            (&bar, "key", Some(aaguid)),
+        ] {
+            app.add_credential(
+                user.username.clone(),
+                name.to_string(),
+                &register_passkey(&wan, user),
+                AuthenticatorInfo {
+                    aaguid,
+                    min_pin_length: None,
+                    attachment: None,
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(app.list_credentials(None, None).await.unwrap().len(), 3);
+        let by_aaguid = app.list_credentials(Some(aaguid), None).await.unwrap();
+        assert_eq!(by_aaguid.len(), 2);
+        assert!(by_aaguid.iter().all(|c| c.aaguid == Some(aaguid)));
+
+        // both users have a credential named "key", so nothing is moved
+        assert!(matches!(
+            app.move_credentials("foo".to_string(), "bar".to_string())
+                .await,
+            Err(AppError::DuplicateCredential)
+        ));
+        assert!(matches!(
+            app.move_credentials("foo".to_string(), "nobody".to_string())
+                .await,
+            Err(AppError::UserNotFound)
+        ));
+        assert_eq!(
+            app.get_user_with_credentials("foo".to_string())
+                .await
+                .unwrap()
+                .credentials
+                .len(),
+            2
+        );
+
+        assert_eq!(app.delete_credentials_by_aaguid(aaguid).await.unwrap(), 2);
+        assert_eq!(
+            app.move_credentials("foo".to_string(), "bar".to_string())
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            app.get_user_with_credentials("bar".to_string())
+                .await
+                .unwrap()
+                .credentials[0]
+                .name,
+            "phone"
+        );
+
+        assert_eq!(
+            app.delete_user_credentials("bar".to_string())
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(app.list_credentials(None, None).await.unwrap().is_empty());
+        assert!(matches!(
+            app.delete_user_credentials("nobody".to_string()).await,
+            Err(AppError::UserNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_after() {
+        let app = get_app_with_db().await;
+        assert_eq!(app.last_audit_event_id().await.unwrap(), 0);
+
+        app.record_event("authentication_failed", Some("foo".to_string()), None)
+            .await
+            .unwrap();
+        let first = app.last_audit_event_id().await.unwrap();
+        app.record_event("authentication_succeeded", Some("foo".to_string()), None)
+            .await
+            .unwrap();
+        app.record_event("authentication_succeeded", Some("bar".to_string()), None)
+            .await
+            .unwrap();
+
+        let events = app.audit_log_after(first, 1).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, "authentication_succeeded");
+        assert_eq!(events[0].username.as_deref(), Some("foo"));
+
+        let events = app.audit_log_after(events[0].id, 100).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].username.as_deref(), Some("bar"));
+        assert!(app
+            .audit_log_after(events[0].id, 100)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_verify_audit_log() {
+        let key = || AuditLogKey::new(b"secret");
+        let app = get_app_with_db().await;
+        // entries recorded before signing was enabled are signed along with the next one
+        app.record_event("authentication_failed", Some("foo".to_string()), None)
+            .await
+            .unwrap();
+        let app = app.with_audit_log_key(key());
+        assert_eq!(
+            app.verify_audit_log(key()).await.unwrap(),
+            AuditLogVerification::Unsigned { id: 1 }
+        );
+        for _ in 0..3 {
+            app.record_event("authentication_succeeded", Some("foo".to_string()), None)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            app.verify_audit_log(key()).await.unwrap(),
+            AuditLogVerification::Intact { entries: 4 }
+        );
+        assert_eq!(
+            app.verify_audit_log(AuditLogKey::new(b"other"))
+                .await
+                .unwrap(),
+            AuditLogVerification::Modified { id: 1 }
+        );
+
+        // renaming rewrites entries, which are signed again
+        app.get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        app.rename_user("foo".to_string(), "bar".to_string())
+            .await
+            .unwrap();
+        let last = app.last_audit_event_id().await.unwrap();
+        assert_eq!(
+            app.verify_audit_log(key()).await.unwrap(),
+            AuditLogVerification::Intact {
+                entries: last as usize
+            }
+        );
+
+        let tamper = |sql: &'static str| app.db.call(move |conn| Ok(conn.execute_batch(sql)?));
+        tamper("update audit_log set detail = 'forged' where id = 2")
+            .await
+            .unwrap();
+        assert_eq!(
+            app.verify_audit_log(key()).await.unwrap(),
+            AuditLogVerification::Modified { id: 2 }
+        );
+        tamper("update audit_log set detail = null where id = 2")
+            .await
+            .unwrap();
+        tamper("delete from audit_log where id = (select max(id) from audit_log)")
+            .await
+            .unwrap();
+        assert_eq!(
+            app.verify_audit_log(key()).await.unwrap(),
+            AuditLogVerification::Truncated {
+                after: Some(last - 1)
+            }
+        );
+        tamper("delete from audit_log where id = 2").await.unwrap();
+        assert_eq!(
+            app.verify_audit_log(key()).await.unwrap(),
+            AuditLogVerification::Modified { id: 3 }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purge_audit_log() {
+        let key = || AuditLogKey::new(b"secret");
+        let app = get_app_with_db().await;
+        for _ in 0..3 {
+            app.record_event("authentication_succeeded", Some("foo".to_string()), None)
+                .await
+                .unwrap();
+        }
+        let tamper = |sql: &'static str| app.db.call(move |conn| Ok(conn.execute_batch(sql)?));
+        tamper("update audit_log set at = 1 where id <= 2")
+            .await
+            .unwrap();
+        let app = app.with_audit_log_key(key());
+        app.record_event("authentication_succeeded", Some("foo".to_string()), None)
+            .await
+            .unwrap();
+
+        assert_eq!(app.purge_audit_log(1).await.unwrap(), 0);
+        assert_eq!(app.purge_audit_log(2).await.unwrap(), 2);
+        // the remaining entries continue the chain from the last purged one
+        assert_eq!(
+            app.verify_audit_log(key()).await.unwrap(),
+            AuditLogVerification::Intact { entries: 3 }
+        );
+        let events = app.audit_log(1).await.unwrap();
+        assert_eq!(events[0].event, "audit_log_purged");
+
+        let tamper = |sql: &'static str| app.db.call(move |conn| Ok(conn.execute_batch(sql)?));
+        tamper("update audit_log_anchor set last_id = 3")
+            .await
+            .unwrap();
+        assert_eq!(
+            app.verify_audit_log(key()).await.unwrap(),
+            AuditLogVerification::Modified { id: 3 }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purge_credential_uses() {
+        let wan = new_webauthn();
+        let (soft_token, _) = SoftToken::new(true).unwrap();
+        let mut wa = WebauthnAuthenticator::new(soft_token);
+        let app = get_app_with_db().await;
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        let cred = register_credential(&wan, &mut wa, &user, None);
+        app.add_credential(
+            user.username,
+            "bar_credential".to_string(),
+            &Passkey::from(cred.clone()),
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+        for _ in 0..2 {
+            app.update_credential(authenticate(&wan, &mut wa, &cred), None)
+                .await
+                .unwrap();
+        }
+
+        // usage stats only cover uses that were not purged
+        assert_eq!(app.purge_credential_uses(0).await.unwrap(), 0);
+        assert_eq!(app.purge_credential_uses(i64::MAX as u64).await.unwrap(), 2);
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        assert_eq!(user.credentials[0].usage, CredentialUsage::default());
+    }
+
+    #[tokio::test]
+    async fn test_blocklist() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+        let aaguid = Uuid::new_v4();
+
+        let foo = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        let key = register_passkey(&wan, &foo);
+        let phone = register_passkey(&wan, &foo);
+        app.add_credential(
+            "foo".to_string(),
+            "key".to_string(),
+            &key,
+            AuthenticatorInfo {
+                aaguid: Some(aaguid),
+                min_pin_length: None,
+                attachment: None,
+            },
+        )
+        .await
+        .unwrap();
+        app.add_credential(
+            "foo".to_string(),
+            "phone".to_string(),
+            &phone,
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        let (aaguid_entry, count) = app
+            .add_to_blocklist(BlockedItem::Aaguid(aaguid), Some("CVE-1234".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        let (_, count) = app
+            .add_to_blocklist(BlockedItem::Credential(phone.cred_id().to_owned()), None)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(matches!(
+            app.add_to_blocklist(BlockedItem::Aaguid(aaguid), None)
+                .await,
+            Err(AppError::Conflict)
+        ));
+
+        let blocklist = app.list_blocklist().await.unwrap();
+        assert_eq!(blocklist.len(), 2);
+        assert_eq!(blocklist[0].aaguid, Some(aaguid));
+        assert_eq!(blocklist[0].reason.as_deref(), Some("CVE-1234"));
+        assert_eq!(blocklist[1].credential_id.as_ref(), Some(phone.cred_id()));
+
+        // blocked credentials are flagged, not deleted
+        let foo = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        assert_eq!(foo.credentials.len(), 2);
+        assert!(foo.credentials.iter().all(|c| c.blocked));
+        assert!(app
+            .list_credentials(None, None)
+            .await
+            .unwrap()
+            .iter()
+            .all(|c| c.blocked));
+
+        // new registrations from a blocked authenticator model are refused
+        assert!(matches!(
+            app.add_credential(
+                "foo".to_string(),
+                "other key".to_string(),
+                &register_passkey(&wan, &foo),
+                AuthenticatorInfo {
+                    aaguid: Some(aaguid),
+                    min_pin_length: None,
+                    attachment: None,
+                },
+            )
+            .await,
+            Err(AppError::CredentialBlocked)
+        ));
+
+        app.remove_from_blocklist(aaguid_entry).await.unwrap();
+        assert!(matches!(
+            app.remove_from_blocklist(aaguid_entry).await,
+            Err(AppError::EntityNotFound)
+        ));
+        let foo = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            foo.credentials
+                .iter()
+                .filter(|c| c.blocked)
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["phone"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rp_id() {
+        let app = get_app_with_db().await;
+        assert_eq!(app.stored_rp_id().await.unwrap(), None);
+
+        app.record_rp_id("example.com".to_string()).await.unwrap();
+        app.record_rp_id("example.com".to_string()).await.unwrap();
+        assert_eq!(
+            app.stored_rp_id().await.unwrap().as_deref(),
+            Some("example.com")
+        );
+        assert!(app.audit_log(10).await.unwrap().is_empty());
+
+        app.record_rp_id("auth.example.com".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            app.stored_rp_id().await.unwrap().as_deref(),
+            Some("auth.example.com")
+        );
+        let events = app.audit_log(10).await.unwrap();
+        assert_eq!(events[0].event, "rp_id_changed");
+        assert_eq!(
+            events[0].detail.as_deref(),
+            Some("example.com -> auth.example.com")
+        );
+
+        // survives resetting the database
+        app.reset().await.unwrap();
+        assert_eq!(
+            app.stored_rp_id().await.unwrap().as_deref(),
+            Some("auth.example.com")
+        );
+        assert_eq!(app.credential_count().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_busy_retry() {
+        let app = App::new(Connection::open(":memory:").await.unwrap());
+        let busy = || {
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(libsqlite3_sys::SQLITE_BUSY),
+                None,
+            )
+        };
+        assert!(matches!(AppError::from(busy()), AppError::Busy));
+        assert!(matches!(
+            AppError::from(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(libsqlite3_sys::SQLITE_CORRUPT),
+                None
+            )),
+            AppError::Corrupt
+        ));
+
+        // succeeds once the lock is released
+        let mut attempts = 0;
+        let result = app
+            .call(move |_| {
+                attempts += 1;
+                match attempts {
+                    1 | 2 => Err(busy().into()),
+                    _ => Ok(attempts),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+
+        // gives up eventually
+        let result: Result<(), AppError> = app
+            .call(move |_| Err(busy().into()))
+            .await
+            .map_err(Into::into);
+        assert!(matches!(result, Err(AppError::Busy)));
+
+        // errors after the write lock is taken are not retried
+        let mut attempts = 0;
+        let result: Result<(), AppError> = app
+            .transaction(move |_| {
+                attempts += 1;
+                assert_eq!(attempts, 1);
+                Err(AppError::Busy)
+            })
+            .await;
+        assert!(matches!(result, Err(AppError::Busy)));
+    }
+
+    #[tokio::test]
+    async fn test_user_settings() {
+        let app = get_app_with_db().await;
+        app.get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        assert!(app
+            .user_settings("foo".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+
+        let settings: BTreeMap<String, serde_json::Value> = serde_json::from_str(
+            r#"{"language": "de", "fontSize": 14, "defaultApp": {"url": "https://foo.com"}}"#,
+        )
+        .unwrap();
+        app.replace_user_settings("foo".to_string(), settings.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            app.user_settings("foo".to_string()).await.unwrap(),
+            settings
+        );
+
+        // replacing drops settings that are not given again
+        let settings: BTreeMap<String, serde_json::Value> =
+            serde_json::from_str(r#"{"language": "en"}"#).unwrap();
+        app.replace_user_settings("foo".to_string(), settings.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            app.user_settings("foo".to_string()).await.unwrap(),
+            settings
+        );
+
+        let too_long = BTreeMap::from([(
+            "notes".to_string(),
+            serde_json::Value::String("x".repeat(MAX_USER_SETTING_VALUE_LEN)),
+        )]);
+        assert!(matches!(
+            app.replace_user_settings("foo".to_string(), too_long).await,
+            Err(AppError::BadInput)
+        ));
+        let too_many = (0..=MAX_USER_SETTINGS)
+            .map(|i| (i.to_string(), serde_json::Value::Bool(true)))
+            .collect();
+        assert!(matches!(
+            app.replace_user_settings("foo".to_string(), too_many).await,
+            Err(AppError::BadInput)
+        ));
+        let bad_timezone = BTreeMap::from([(
+            locale::TIMEZONE_SETTING.to_string(),
+            serde_json::Value::String("Mars/Olympus_Mons".to_string()),
+        )]);
+        assert!(matches!(
+            app.replace_user_settings("foo".to_string(), bad_timezone)
+                .await,
+            Err(AppError::BadInput)
+        ));
+        assert_eq!(
+            app.user_settings("foo".to_string()).await.unwrap(),
+            settings
+        );
+        assert!(matches!(
+            app.replace_user_settings("bar".to_string(), BTreeMap::new())
+                .await,
+            Err(AppError::UserNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_start_enrollment() {
+        let app = get_app_with_db().await;
+        app.get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+
+        let started_at = app.start_enrollment("foo".to_string()).await.unwrap();
+        assert!(started_at > 0);
+        app.db
+            .call(|conn| {
+                conn.execute(
+                    "update users set enrollment_started_at = 1 where username = 'foo'",
+                    [],
+                )?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+        // the grace period is not restarted
+        assert_eq!(app.start_enrollment("foo".to_string()).await.unwrap(), 1);
+        assert!(matches!(
+            app.start_enrollment("bar".to_string()).await,
+            Err(AppError::UserNotFound)
+        ));
+        assert_eq!(
+            app.audit_log(10)
+                .await
+                .unwrap()
+                .iter()
+                .filter(|event| event.event == "enrollment_started")
+                .count(),
+            1
+        );
     }
 
     #[tokio::test]
-    async fn test_get_user_with_credentials() {
+    async fn test_pending_credentials() {
+        let wan = new_webauthn();
         let app = get_app_with_db().await;
 
-        assert_eq!(
-            app.db
-                .call(|conn| {
-                    let exists: usize = conn
-                        .query_row("select exists(select id from users)", [], |row| row.get(0))
-                        .unwrap();
+        let foo = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        let key = register_passkey(&wan, &foo);
+        let phone = register_passkey(&wan, &foo);
+        let key_id = app
+            .add_pending_credential(
+                "foo".to_string(),
+                "key".to_string(),
+                &key,
+                AuthenticatorInfo::default(),
+            )
+            .await
+            .unwrap();
+        let phone_id = app
+            .add_pending_credential(
+                "foo".to_string(),
+                "phone".to_string(),
+                &phone,
+                AuthenticatorInfo::default(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            app.add_credential(
+                "foo".to_string(),
+                "key again".to_string(),
+                &key,
+                AuthenticatorInfo::default(),
+            )
+            .await,
+            Err(AppError::DuplicateCredential)
+        ));
 
-                    Ok(exists)
-                })
-                .await
-                .unwrap(),
-            0
-        );
+        let pending = app
+            .list_pending_credentials(Some("foo".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].id, key_id);
+        assert_eq!(pending[0].handle, credential_handle(key.cred_id()));
+        assert!(app
+            .list_pending_credentials(Some("bar".to_string()))
+            .await
+            .unwrap()
+            .is_empty());
 
-        app.get_user_with_credentials("foo_user".to_string())
+        // pending credentials cannot be used until they are approved
+        let foo = app
+            .get_user_with_credentials("foo".to_string())
             .await
-            .unwrap(); // user is created if they do not exist
+            .unwrap();
+        assert!(foo.credentials.is_empty());
 
-        assert_eq!(
-            app.db
-                .call(|conn| {
-                    let exists: usize = conn
-                        .query_row("select exists(select id from users)", [], |row| row.get(0))
-                        .unwrap();
+        app.approve_pending_credential(key_id).await.unwrap();
+        app.reject_pending_credential(phone_id).await.unwrap();
+        assert!(matches!(
+            app.approve_pending_credential(phone_id).await,
+            Err(AppError::CredentialNotFound)
+        ));
+        assert!(app.list_pending_credentials(None).await.unwrap().is_empty());
 
-                    Ok(exists)
-                })
-                .await
-                .unwrap(),
-            1
-        );
+        let foo = app
+            .get_user_with_credentials("foo".to_string())
+            .await
+            .unwrap();
+        assert_eq!(foo.credentials.len(), 1);
+        assert_eq!(foo.credentials[0].name, "key");
+
+        // credentials blocked while waiting are not approved
+        app.add_pending_credential(
+            "foo".to_string(),
+            "phone".to_string(),
+            &phone,
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+        app.add_to_blocklist(BlockedItem::Credential(phone.cred_id().to_owned()), None)
+            .await
+            .unwrap();
+        let pending = app.list_pending_credentials(None).await.unwrap();
+        assert!(matches!(
+            app.approve_pending_credential(pending[0].id).await,
+            Err(AppError::CredentialBlocked)
+        ));
+        assert_eq!(app.list_pending_credentials(None).await.unwrap().len(), 1);
     }
 
     #[tokio::test]
@@ -453,6 +5267,7 @@ mod tests {
             user.username,
             "bar_credential".to_string(),
             &Passkey::from(cred.clone()),
+            AuthenticatorInfo::default(),
         )
         .await
         .unwrap();
@@ -466,7 +5281,292 @@ mod tests {
         // TODO(jared): test this
         // app.update_credential();
 
-        app.delete_credential(cred.cred_id).await.unwrap();
+        app.delete_credential("bar_user".to_string(), &credential_handle(&cred.cred_id))
+            .await
+            .unwrap();
+
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        assert!(user.credentials.is_empty());
+    }
+
+    /// Registers a credential of `wa` for `user`, as a phone would with `transports`.
+    fn register_credential(
+        wan: &WebauthnCore,
+        wa: &mut WebauthnAuthenticator<SoftToken>,
+        user: &UserWithCredentials,
+        transports: Option<Vec<AuthenticatorTransport>>,
+    ) -> Credential {
+        let (chal, reg_state) = wan
+            .generate_challenge_register(
+                wan.new_challenge_register_builder(
+                    &user.id.into_bytes(),
+                    &user.username,
+                    &user.username,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let r = wa
+            .do_registration(Url::parse("https://localhost:8080").unwrap(), chal)
+            .unwrap();
+        let mut cred = wan.register_credential(&r, &reg_state, None).unwrap();
+        cred.transports = transports;
+        cred
+    }
+
+    fn authenticate(
+        wan: &WebauthnCore,
+        wa: &mut WebauthnAuthenticator<SoftToken>,
+        cred: &Credential,
+    ) -> AuthenticationResult {
+        let (chal, auth_state) = wan
+            .generate_challenge_authenticate(
+                wan.new_challenge_authenticate_builder(vec![cred.clone()], None)
+                    .unwrap(),
+            )
+            .unwrap();
+        let r = wa
+            .do_authentication(Url::parse("https://localhost:8080").unwrap(), chal)
+            .unwrap();
+        wan.authenticate_credential(&r, &auth_state).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_credential_algorithm() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        app.add_credential(
+            user.username.clone(),
+            "bar_credential".to_string(),
+            &register_passkey(&wan, &user),
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            user.credentials[0].algorithm,
+            *user.credentials[0].credential.cred_algorithm()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_authenticator_info() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        app.add_credential(
+            user.username.clone(),
+            "bar_credential".to_string(),
+            &register_passkey(&wan, &user),
+            AuthenticatorInfo {
+                aaguid: None,
+                min_pin_length: Some(8),
+                attachment: Some(AuthenticatorAttachment::Platform),
+            },
+        )
+        .await
+        .unwrap();
+
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        assert_eq!(user.credentials[0].min_pin_length, Some(8));
+        assert_eq!(
+            user.credentials[0].attachment,
+            Some(AuthenticatorAttachment::Platform)
+        );
+        let summary = &app.list_credentials(None, None).await.unwrap()[0];
+        assert_eq!(summary.min_pin_length, Some(8));
+        // the test ceremony does not request credProtect
+        assert_eq!(summary.cred_protect, None);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_credential() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        let passkey = register_passkey(&wan, &user);
+        app.add_credential(
+            user.username,
+            "bar_credential".to_string(),
+            &passkey,
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        // registering the same credential again, even under another user, is rejected and does
+        // not leave a partial row behind
+        let other_user = app
+            .get_user_with_credentials("baz_user".to_string())
+            .await
+            .unwrap();
+        assert!(matches!(
+            app.add_credential(
+                other_user.username,
+                "baz_credential".to_string(),
+                &passkey,
+                AuthenticatorInfo::default(),
+            )
+            .await,
+            Err(AppError::DuplicateCredential)
+        ));
+        assert_eq!(app.list_credentials(None, None).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_list_credentials_created_before() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        let passkey = register_passkey(&wan, &user);
+        app.add_credential(
+            user.username,
+            "bar_credential".to_string(),
+            &passkey,
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        let created_at = app.credential_created_at(passkey.cred_id()).await.unwrap();
+        assert!(app
+            .list_credentials(None, Some(created_at))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            app.list_credentials(None, Some(created_at + 1))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_credential_usage() {
+        let wan = new_webauthn();
+        let (soft_token, _) = SoftToken::new(true).unwrap();
+        let mut wa = WebauthnAuthenticator::new(soft_token);
+        let app = get_app_with_db().await;
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        // as registered by a phone
+        let cred = register_credential(
+            &wan,
+            &mut wa,
+            &user,
+            Some(vec![
+                AuthenticatorTransport::Hybrid,
+                AuthenticatorTransport::Internal,
+            ]),
+        );
+        app.add_credential(
+            user.username,
+            "bar_credential".to_string(),
+            &Passkey::from(cred.clone()),
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        assert_eq!(user.credentials[0].usage, CredentialUsage::default());
+
+        // used from another device, over the hybrid transport
+        app.update_credential(
+            authenticate(&wan, &mut wa, &cred),
+            Some(AuthenticatorAttachment::CrossPlatform),
+        )
+        .await
+        .unwrap();
+        // used on the phone itself
+        app.update_credential(
+            authenticate(&wan, &mut wa, &cred),
+            Some(AuthenticatorAttachment::Platform),
+        )
+        .await
+        .unwrap();
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        let usage = &user.credentials[0].usage;
+        assert_eq!((usage.uses, usage.hybrid_uses), (2, 1));
+        assert_eq!(
+            usage.last_attachment,
+            Some(AuthenticatorAttachment::Platform)
+        );
+        assert!(usage.last_used_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_delete_credential() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        let passkey = register_passkey(&wan, &user);
+        app.add_credential(
+            user.username,
+            "bar_credential".to_string(),
+            &passkey,
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+        app.get_user_with_credentials("baz_user".to_string())
+            .await
+            .unwrap();
+
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        let handle = credential_handle(passkey.cred_id());
+        assert_eq!(user.credentials[0].handle, handle);
+
+        // only the owner can delete a credential
+        assert!(matches!(
+            app.delete_credential("baz_user".to_string(), &handle).await,
+            Err(AppError::CredentialNotFound)
+        ));
+
+        // the padded standard base64 form of the credential ID is still accepted
+        let legacy_id = general_purpose::STANDARD.encode(passkey.cred_id());
+        app.delete_credential("bar_user".to_string(), &legacy_id)
+            .await
+            .unwrap();
 
         let user = app
             .get_user_with_credentials("bar_user".to_string())
@@ -474,4 +5574,50 @@ mod tests {
             .unwrap();
         assert!(user.credentials.is_empty());
     }
+
+    #[tokio::test]
+    async fn test_user_history() {
+        let wan = new_webauthn();
+        let app = get_app_with_db().await;
+        let user = app
+            .get_user_with_credentials("bar_user".to_string())
+            .await
+            .unwrap();
+        let passkey = register_passkey(&wan, &user);
+        app.add_credential(
+            user.username,
+            "bar_credential".to_string(),
+            &passkey,
+            AuthenticatorInfo::default(),
+        )
+        .await
+        .unwrap();
+        app.delete_credential(
+            "bar_user".to_string(),
+            &credential_handle(passkey.cred_id()),
+        )
+        .await
+        .unwrap();
+
+        let history = app
+            .user_history("bar_user".to_string(), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(history[0].event, "credential_deleted");
+        assert_eq!(history.last().unwrap().event, "credential_registered");
+        assert!(history
+            .iter()
+            .all(|event| event.username.as_deref() == Some("bar_user")));
+        let older = app
+            .user_history("bar_user".to_string(), Some(history[0].id), 10)
+            .await
+            .unwrap();
+        assert_eq!(
+            older.iter().map(|event| event.id).collect::<Vec<_>>(),
+            history[1..]
+                .iter()
+                .map(|event| event.id)
+                .collect::<Vec<_>>()
+        );
+    }
 }
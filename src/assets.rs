@@ -0,0 +1,291 @@
+use crate::providers::{self, passkey_provider};
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+use tracing::trace;
+use uuid::Uuid;
+
+/// A file built into the binary. It is served at its plain path, which browsers revalidate on
+/// every use, and at a path containing its content hash, which they may cache forever. Assets
+/// that are only linked from the pages (e.g. provider icons) have no plain path.
+struct Asset {
+    path: Option<&'static str>,
+    hashed_path: String,
+    content_type: &'static str,
+    body: Bytes,
+    /// The content hash, quoted.
+    etag: String,
+}
+
+impl Asset {
+    /// The hash is inserted before the extension of `name`, e.g. `/assets/main.<hash>.js`.
+    fn new(
+        path: Option<&'static str>,
+        name: &str,
+        content_type: &'static str,
+        body: Bytes,
+    ) -> Self {
+        let hash: String = Sha256::digest(&body)[..8]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let hashed_path = match name.rsplit_once('.') {
+            Some((stem, extension)) => format!("/assets/{stem}.{hash}.{extension}"),
+            None => format!("/assets/{name}.{hash}"),
+        };
+        Self {
+            path,
+            hashed_path,
+            content_type,
+            body,
+            etag: format!("\"{hash}\""),
+        }
+    }
+}
+
+/// URLs of the built-in assets for the layout template, e.g. `{{ assets.main_js }}`.
+#[derive(Serialize, Debug, Clone)]
+pub struct AssetUrls {
+    pub main_js: String,
+    pub webauthn_js: String,
+    pub favicon: String,
+}
+
+/// A passkey provider as shown on the credentials page, see `providers`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProviderInfo {
+    pub name: &'static str,
+    /// The URL of its icon.
+    pub icon: String,
+}
+
+pub struct Assets {
+    /// `--path-prefix`, which the paths are routed under but URLs have to include.
+    path_prefix: String,
+    main_js: Asset,
+    webauthn_js: Asset,
+    favicon: Asset,
+    /// Keyed by the file name in `providers::ICONS`.
+    provider_icons: Vec<(&'static str, Asset)>,
+}
+
+static ASSETS: OnceLock<Assets> = OnceLock::new();
+
+/// Hashes the assets for serving under `path_prefix`. `main` calls this before routing the hashed
+/// paths; without it, the assets are hashed for serving at the root on first use.
+pub fn init(path_prefix: &str) {
+    if ASSETS.set(Assets::new(path_prefix)).is_err() {
+        panic!("assets were hashed before their path prefix was set");
+    }
+}
+
+pub fn assets() -> &'static Assets {
+    ASSETS.get_or_init(|| Assets::new(""))
+}
+
+impl Assets {
+    fn new(path_prefix: &str) -> Self {
+        let webauthn_js = Asset::new(
+            Some("/assets/webauthn.js"),
+            "webauthn.js",
+            "text/javascript",
+            include_str!("webauthn.js")
+                .replace("__VERSION__", env!("CARGO_PKG_VERSION"))
+                .replace("__PATH_PREFIX__", path_prefix)
+                .into(),
+        );
+        // main.js has to import the hashed webauthn.js, or it would be cached forever along with
+        // whatever webauthn.js was current when it was first loaded.
+        let main_js = Asset::new(
+            Some("/main.js"),
+            "main.js",
+            "text/javascript",
+            include_str!("main.js")
+                .replace(
+                    "\"/assets/webauthn.js\"",
+                    &format!("\"{path_prefix}{}\"", webauthn_js.hashed_path),
+                )
+                .into(),
+        );
+        let favicon = Asset::new(
+            Some("/favicon.ico"),
+            "favicon.svg",
+            "image/svg+xml",
+            Bytes::from_static(include_bytes!("favicon.svg")),
+        );
+        let provider_icons = providers::ICONS
+            .iter()
+            .map(|(name, body)| {
+                (
+                    *name,
+                    Asset::new(None, name, "image/svg+xml", Bytes::from_static(body)),
+                )
+            })
+            .collect();
+        Self {
+            path_prefix: path_prefix.to_string(),
+            main_js,
+            webauthn_js,
+            favicon,
+            provider_icons,
+        }
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Asset> {
+        [&self.main_js, &self.webauthn_js, &self.favicon]
+            .into_iter()
+            .chain(self.provider_icons.iter().map(|(_, asset)| asset))
+    }
+
+    fn url(&self, asset: &Asset) -> String {
+        format!("{}{}", self.path_prefix, asset.hashed_path)
+    }
+
+    pub fn urls(&self) -> AssetUrls {
+        AssetUrls {
+            main_js: self.url(&self.main_js),
+            webauthn_js: self.url(&self.webauthn_js),
+            favicon: self.url(&self.favicon),
+        }
+    }
+
+    /// The provider of passkeys whose authenticator reports `aaguid`, if it is a known one.
+    pub fn provider(&self, aaguid: &Uuid) -> Option<ProviderInfo> {
+        let provider = passkey_provider(aaguid)?;
+        let (_, icon) = self
+            .provider_icons
+            .iter()
+            .find(|(name, _)| *name == provider.icon)?;
+        Some(ProviderInfo {
+            name: provider.name,
+            icon: self.url(icon),
+        })
+    }
+
+    /// The hashed paths, to be routed to `asset_handler`. The plain paths are routed
+    /// separately, as `/favicon.ico` and `/main.js` belong to a single-page app if one is served.
+    pub fn hashed_paths(&self) -> Vec<&str> {
+        self.all().map(|asset| asset.hashed_path.as_str()).collect()
+    }
+
+    /// Answers a request for one of the assets, with `304 Not Modified` if the browser's copy
+    /// is still current.
+    pub fn response(&self, path: &str, headers: &HeaderMap) -> Option<Response> {
+        let (asset, hashed) = self.all().find_map(|asset| {
+            if asset.path == Some(path) {
+                Some((asset, false))
+            } else if asset.hashed_path == path {
+                Some((asset, true))
+            } else {
+                None
+            }
+        })?;
+
+        let builder = Response::builder()
+            .header(header::ETAG, &asset.etag)
+            .header(
+                header::CACHE_CONTROL,
+                if hashed {
+                    "public, max-age=31536000, immutable"
+                } else {
+                    "no-cache"
+                },
+            );
+
+        if headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.split(',').any(|tag| tag.trim() == asset.etag))
+        {
+            return Some(
+                builder
+                    .status(StatusCode::NOT_MODIFIED)
+                    .body(Body::empty())
+                    .expect("could not build response"),
+            );
+        }
+
+        Some(
+            builder
+                .header(header::CONTENT_TYPE, asset.content_type)
+                .body(Body::from(asset.body.clone()))
+                .expect("could not build response"),
+        )
+    }
+}
+
+pub async fn asset_handler(uri: Uri, headers: HeaderMap) -> Response {
+    trace!("asset_handler");
+
+    assets()
+        .response(uri.path(), &headers)
+        .unwrap_or_else(|| StatusCode::NOT_FOUND.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::to_bytes, http::HeaderValue};
+
+    #[tokio::test]
+    async fn test_assets() {
+        let assets = Assets::new("");
+        let urls = assets.urls();
+        assert!(urls.webauthn_js.starts_with("/assets/webauthn."));
+        assert!(urls.webauthn_js.ends_with(".js"));
+        assert!(urls.favicon.ends_with(".svg"));
+        assert!(assets.hashed_paths().contains(&urls.favicon.as_str()));
+
+        let response = assets.response(&urls.main_js, &HeaderMap::new()).unwrap();
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "public, max-age=31536000, immutable"
+        );
+        let etag = response.headers()[header::ETAG].clone();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(&format!("\"{}\"", urls.webauthn_js)));
+        assert!(!body.contains("\"/assets/webauthn.js\""));
+
+        let response = assets.response("/main.js", &HeaderMap::new()).unwrap();
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-cache");
+        assert_eq!(response.headers()[header::ETAG], etag);
+
+        let headers = HeaderMap::from_iter([(header::IF_NONE_MATCH, etag)]);
+        assert_eq!(
+            assets.response("/main.js", &headers).unwrap().status(),
+            StatusCode::NOT_MODIFIED
+        );
+        let headers =
+            HeaderMap::from_iter([(header::IF_NONE_MATCH, HeaderValue::from_static("\"old\""))]);
+        assert_eq!(
+            assets.response("/main.js", &headers).unwrap().status(),
+            StatusCode::OK
+        );
+        assert!(assets.response("/assets/other.js", &headers).is_none());
+
+        let provider = assets
+            .provider(&Uuid::parse_str("bada5566-a7aa-401f-bd96-45619a55120d").unwrap())
+            .unwrap();
+        assert_eq!(provider.name, "1Password");
+        let response = assets.response(&provider.icon, &HeaderMap::new()).unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/svg+xml");
+
+        // under a prefix, URLs include it but the routed paths do not
+        let assets = Assets::new("/auth");
+        let urls = assets.urls();
+        assert!(urls.main_js.starts_with("/auth/assets/main."));
+        assert!(assets.hashed_paths()[0].starts_with("/assets/main."));
+        let response = assets.response(assets.hashed_paths()[0], &HeaderMap::new());
+        let body = to_bytes(response.unwrap().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(&format!("\"{}\"", urls.webauthn_js)));
+    }
+}
@@ -0,0 +1,113 @@
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// Prefix mixed into every MAC so that audit log entries cannot be confused with anything else
+/// signed using the session secret.
+const DOMAIN: &[u8] = b"webauthn-tiny audit log\0";
+
+/// An entry of the audit log as it is signed, see `AuditLogKey::entry_mac`.
+#[derive(Debug, Clone, Copy)]
+pub struct SignedFields<'a> {
+    pub id: i64,
+    pub at: u64,
+    pub event: &'a str,
+    pub username: Option<&'a str>,
+    pub detail: Option<&'a str>,
+}
+
+/// Chains audit log entries together, so that modifying or removing an entry breaks the MAC of
+/// every entry after it. The MAC of the last entry is signed once more as the head of the log,
+/// which is what removing entries from the end breaks, and when old entries are purged the MAC
+/// of the last purged one is signed as the anchor the chain continues from.
+pub struct AuditLogKey {
+    /// The session secret, zeroed when the key is dropped.
+    key: Zeroizing<Vec<u8>>,
+}
+
+impl AuditLogKey {
+    pub fn new(key: &[u8]) -> Self {
+        Self {
+            key: Zeroizing::new(key.to_vec()),
+        }
+    }
+
+    fn mac(&self, kind: &str, payload: serde_json::Value) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("hmac accepts keys of any length");
+        mac.update(DOMAIN);
+        mac.update(kind.as_bytes());
+        mac.update(b"\0");
+        mac.update(payload.to_string().as_bytes());
+        general_purpose::URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    }
+
+    /// The MAC of an entry following the entry with MAC `previous`, which is empty for the
+    /// first entry. Fields are encoded as a JSON array so that they cannot run into each other.
+    pub fn entry_mac(&self, previous: &str, entry: SignedFields) -> String {
+        self.mac(
+            "entry",
+            serde_json::json!([
+                previous,
+                entry.id,
+                entry.at,
+                entry.event,
+                entry.username,
+                entry.detail
+            ]),
+        )
+    }
+
+    pub fn head_mac(&self, last_id: i64, last_mac: &str) -> String {
+        self.mac("head", serde_json::json!([last_id, last_mac]))
+    }
+
+    /// Signs the MAC of the last purged entry, which the remaining entries are verified from.
+    pub fn anchor_mac(&self, last_id: i64, last_mac: &str) -> String {
+        self.mac("anchor", serde_json::json!([last_id, last_mac]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_mac() {
+        let key = AuditLogKey::new(b"secret");
+        let entry = SignedFields {
+            id: 1,
+            at: 1700000000,
+            event: "user_created",
+            username: Some("alice"),
+            detail: None,
+        };
+        let mac = key.entry_mac("", entry);
+        assert_eq!(mac, key.entry_mac("", entry));
+
+        assert_ne!(mac, key.entry_mac("x", entry));
+        assert_ne!(mac, AuditLogKey::new(b"other").entry_mac("", entry));
+        // moving text between fields changes the MAC
+        assert_ne!(
+            key.entry_mac(
+                "",
+                SignedFields {
+                    username: Some("alic"),
+                    detail: Some("e"),
+                    ..entry
+                }
+            ),
+            key.entry_mac(
+                "",
+                SignedFields {
+                    username: Some("alice"),
+                    detail: Some(""),
+                    ..entry
+                }
+            )
+        );
+        assert_ne!(key.head_mac(1, &mac), mac);
+        assert_ne!(key.head_mac(1, &mac), key.anchor_mac(1, &mac));
+    }
+}
@@ -0,0 +1,80 @@
+use metrics::gauge;
+use serde::Serialize;
+use tower_sessions::cookie::time::OffsetDateTime;
+
+/// What was built, as recorded by `build.rs`, for telling instances of a fleet apart.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// `None` for builds outside of a git checkout without `WEBAUTHN_TINY_GIT_HASH`.
+    pub git_hash: Option<&'static str>,
+    /// The UTC date of the build, or of the sources with `SOURCE_DATE_EPOCH`.
+    pub build_date: String,
+    /// Optional parts of the server that this build includes.
+    pub features: Vec<&'static str>,
+    pub webauthn_rs_version: &'static str,
+}
+
+impl BuildInfo {
+    pub fn new() -> Self {
+        let build_time = env!("WEBAUTHN_TINY_BUILD_TIME")
+            .parse()
+            .expect("build.rs records a unix timestamp");
+        let build_date = OffsetDateTime::from_unix_timestamp(build_time)
+            .expect("build time is in range")
+            .date()
+            .to_string();
+
+        let mut features = Vec::new();
+        if cfg!(target_os = "linux") {
+            // `--sandbox` is only available on Linux.
+            features.push("sandbox");
+        }
+        if cfg!(debug_assertions) {
+            // Debug builds always check WebAuthn payloads, see `--strict-validation`.
+            features.push("debug");
+        }
+
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: option_env!("WEBAUTHN_TINY_GIT_HASH"),
+            build_date,
+            features,
+            webauthn_rs_version: env!("WEBAUTHN_TINY_WEBAUTHN_RS_VERSION"),
+        }
+    }
+
+    /// Sets the `build_info` gauge, which is always 1 and describes the build in its labels.
+    pub fn record_metric(&self) {
+        gauge!(
+            "build_info",
+            "version" => self.version,
+            "git_hash" => self.git_hash.unwrap_or_default(),
+            "build_date" => self.build_date.clone(),
+            "features" => self.features.join(","),
+            "webauthn_rs_version" => self.webauthn_rs_version,
+        )
+        .set(1);
+    }
+}
+
+impl Default for BuildInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info() {
+        let info = BuildInfo::new();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.build_date.len(), "2024-01-01".len());
+        assert!(info.webauthn_rs_version.starts_with("0.5."));
+        assert_eq!(info.features.contains(&"debug"), cfg!(debug_assertions));
+    }
+}
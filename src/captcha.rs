@@ -0,0 +1,197 @@
+use crate::{
+    config::{CaptchaConfig, CaptchaProvider},
+    http_client::HttpClient,
+};
+use http_body_util::Full;
+use hyper::{header, Method, Request};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{error, info};
+
+/// Failures are forgotten once a client has not failed for this long.
+const FAILURE_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Expired entries are only pruned once this many clients are tracked.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+impl CaptchaProvider {
+    fn verify_url(&self) -> &'static str {
+        match self {
+            CaptchaProvider::Hcaptcha => "https://api.hcaptcha.com/siteverify",
+            CaptchaProvider::Turnstile => {
+                "https://challenges.cloudflare.com/turnstile/v0/siteverify"
+            }
+        }
+    }
+
+    fn script_url(&self) -> &'static str {
+        match self {
+            CaptchaProvider::Hcaptcha => "https://js.hcaptcha.com/1/api.js?render=explicit",
+            CaptchaProvider::Turnstile => {
+                "https://challenges.cloudflare.com/turnstile/v0/api.js?render=explicit"
+            }
+        }
+    }
+}
+
+/// What the client needs to render the CAPTCHA widget. Both supported providers expose the same
+/// `render(container, { sitekey, callback })` API from their script.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CaptchaChallenge {
+    provider: CaptchaProvider,
+    site_key: String,
+    script_url: &'static str,
+}
+
+#[derive(Serialize)]
+struct SiteverifyRequest<'a> {
+    secret: &'a str,
+    response: &'a str,
+    remoteip: String,
+}
+
+#[derive(Deserialize)]
+struct SiteverifyResponse {
+    success: bool,
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+}
+
+#[derive(Debug)]
+struct ClientFailures {
+    count: u32,
+    last: Instant,
+}
+
+/// Recent authentication failures per client IP address.
+#[derive(Debug, Default)]
+struct Failures(Mutex<HashMap<IpAddr, ClientFailures>>);
+
+impl Failures {
+    fn record_failure(&self, ip: IpAddr) {
+        let mut failures = self.0.lock().expect("failures lock poisoned");
+        if failures.len() >= MAX_TRACKED_CLIENTS {
+            failures.retain(|_, f| f.last.elapsed() < FAILURE_TTL);
+        }
+        let entry = failures.entry(ip).or_insert(ClientFailures {
+            count: 0,
+            last: Instant::now(),
+        });
+        if entry.last.elapsed() >= FAILURE_TTL {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = Instant::now();
+    }
+
+    fn clear(&self, ip: IpAddr) {
+        self.0.lock().expect("failures lock poisoned").remove(&ip);
+    }
+
+    fn count(&self, ip: IpAddr) -> u32 {
+        self.0
+            .lock()
+            .expect("failures lock poisoned")
+            .get(&ip)
+            .filter(|f| f.last.elapsed() < FAILURE_TTL)
+            .map_or(0, |f| f.count)
+    }
+}
+
+/// Requires clients that repeatedly failed to authenticate to solve a CAPTCHA, verified with the
+/// provider's siteverify API.
+pub struct Captcha {
+    config: CaptchaConfig,
+    secret_key: SecretString,
+    client: HttpClient,
+    failures: Failures,
+}
+
+impl Captcha {
+    pub fn new(config: CaptchaConfig, client: HttpClient) -> anyhow::Result<Self> {
+        Ok(Self {
+            secret_key: config.load_secret_key()?,
+            config,
+            client,
+            failures: Failures::default(),
+        })
+    }
+
+    pub fn record_failure(&self, ip: IpAddr) {
+        self.failures.record_failure(ip);
+    }
+
+    pub fn record_success(&self, ip: IpAddr) {
+        self.failures.clear(ip);
+    }
+
+    /// Returns a challenge if the client failed too often recently.
+    pub fn challenge(&self, ip: IpAddr) -> Option<CaptchaChallenge> {
+        let required = self.failures.count(ip) >= self.config.failure_threshold;
+        required.then(|| CaptchaChallenge {
+            provider: self.config.provider,
+            site_key: self.config.site_key.clone(),
+            script_url: self.config.provider.script_url(),
+        })
+    }
+
+    /// Asks the provider whether `token` is a solved CAPTCHA. Any failure to reach the provider
+    /// counts as unsolved.
+    pub async fn verify(&self, token: &str, ip: IpAddr) -> bool {
+        match self.siteverify(token, ip).await {
+            Ok(response) => {
+                if !response.success {
+                    info!("captcha rejected: {:?}", response.error_codes);
+                }
+                response.success
+            }
+            Err(e) => {
+                error!("could not verify captcha: {e}");
+                false
+            }
+        }
+    }
+
+    async fn siteverify(&self, token: &str, ip: IpAddr) -> anyhow::Result<SiteverifyResponse> {
+        let body = serde_urlencoded::to_string(SiteverifyRequest {
+            secret: self.secret_key.expose_secret(),
+            response: token,
+            remoteip: ip.to_string(),
+        })?;
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.config.provider.verify_url())
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Full::from(body))?;
+        let (_, body) = self.client.send(request).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failures() {
+        let failures = Failures::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert_eq!(failures.count(ip), 0);
+        failures.record_failure(ip);
+        failures.record_failure(ip);
+        assert_eq!(failures.count(ip), 2);
+        assert_eq!(failures.count(other), 0);
+
+        failures.clear(ip);
+        assert_eq!(failures.count(ip), 0);
+    }
+}
@@ -0,0 +1,352 @@
+use crate::metadata::registration_auth_data_bytes;
+use axum::http::{header, HeaderMap};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use webauthn_rs::prelude::{Url, WebauthnError};
+use webauthn_rs_proto::{PublicKeyCredential, RegisterPublicKeyCredential};
+
+/// How many failed ceremonies `--debug-ceremonies` keeps, dropping the oldest first.
+pub const CAPACITY: usize = 100;
+
+/// Longest User-Agent header that is kept, so that a client cannot fill memory with its own.
+const MAX_USER_AGENT_LEN: usize = 512;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Ceremony {
+    Registration,
+    Authentication,
+}
+
+impl Ceremony {
+    fn client_data_type(self) -> &'static str {
+        match self {
+            Self::Registration => "webauthn.create",
+            Self::Authentication => "webauthn.get",
+        }
+    }
+}
+
+/// The parts of the client data JSON that tell why a ceremony failed. The challenge is left out.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientData {
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub origin: Option<String>,
+    #[serde(rename = "crossOrigin")]
+    pub cross_origin: Option<bool>,
+    #[serde(rename = "topOrigin")]
+    pub top_origin: Option<String>,
+}
+
+/// The flags of the authenticator data.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flags {
+    pub user_present: bool,
+    pub user_verified: bool,
+    pub backup_eligible: bool,
+    pub backup_state: bool,
+    pub attested_credential_data: bool,
+    pub extension_data: bool,
+}
+
+impl Flags {
+    fn of(flags: u8) -> Self {
+        Self {
+            user_present: flags & 0x01 != 0,
+            user_verified: flags & 0x04 != 0,
+            backup_eligible: flags & 0x08 != 0,
+            backup_state: flags & 0x10 != 0,
+            attested_credential_data: flags & 0x40 != 0,
+            extension_data: flags & 0x80 != 0,
+        }
+    }
+}
+
+/// One of the checks a ceremony has to pass, redone on the failed ceremony so that it tells which
+/// of them the client did not pass. webauthn-rs verifies more than these (e.g. signatures), its
+/// error tells about those.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub step: &'static str,
+    pub passed: bool,
+}
+
+/// A ceremony webauthn-rs refused to finish, as kept by `--debug-ceremonies`. Nothing that could be
+/// replayed or identifies the credential (challenges, signatures, keys, IDs) is kept.
+#[derive(Serialize, Debug, Clone)]
+pub struct FailedCeremony {
+    pub at: u64,
+    pub ceremony: Ceremony,
+    /// The endpoint the ceremony was finished at, e.g. `/api/kiosk/register`.
+    pub path: &'static str,
+    pub username: Option<String>,
+    pub user_agent: Option<String>,
+    pub error: String,
+    /// `None` if the client data JSON could not be parsed.
+    pub client_data: Option<ClientData>,
+    /// `None` if the authenticator data could not be parsed.
+    pub flags: Option<Flags>,
+    /// Checks that depend on data that could not be parsed are left out.
+    pub checks: Vec<Check>,
+}
+
+/// The failed ceremonies kept by `--debug-ceremonies`, for debugging why a client cannot register
+/// or authenticate without having to reproduce it with verbose logging.
+#[derive(Debug)]
+pub struct CeremonyLog {
+    capacity: usize,
+    rp_id_hash: [u8; 32],
+    /// The origins webauthn-rs allows, along with their subdomains.
+    allowed_origins: Vec<Url>,
+    failures: Mutex<VecDeque<FailedCeremony>>,
+}
+
+/// Where a failed ceremony came from.
+pub struct FailureContext<'a> {
+    pub path: &'static str,
+    pub username: Option<String>,
+    pub headers: &'a HeaderMap,
+    pub error: &'a WebauthnError,
+}
+
+impl CeremonyLog {
+    pub fn new(capacity: usize, rp_id: &str, allowed_origins: Vec<Url>) -> Self {
+        Self {
+            capacity,
+            rp_id_hash: Sha256::digest(rp_id.as_bytes()).into(),
+            allowed_origins,
+            failures: Mutex::default(),
+        }
+    }
+
+    pub fn registration_failed(
+        &self,
+        context: FailureContext,
+        credential: &RegisterPublicKeyCredential,
+    ) {
+        self.record(
+            Ceremony::Registration,
+            context,
+            credential.response.client_data_json.as_ref(),
+            registration_auth_data_bytes(credential),
+        );
+    }
+
+    pub fn authentication_failed(&self, context: FailureContext, credential: &PublicKeyCredential) {
+        self.record(
+            Ceremony::Authentication,
+            context,
+            credential.response.client_data_json.as_ref(),
+            Some(credential.response.authenticator_data.as_ref()),
+        );
+    }
+
+    /// The failed ceremonies, newest first.
+    pub fn failures(&self) -> Vec<FailedCeremony> {
+        self.failures
+            .lock()
+            .expect("ceremony log poisoned")
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    fn record(
+        &self,
+        ceremony: Ceremony,
+        context: FailureContext,
+        client_data_json: &[u8],
+        auth_data: Option<&[u8]>,
+    ) {
+        let client_data = serde_json::from_slice::<ClientData>(client_data_json).ok();
+        // The RP ID hash and the flags byte come first, neither needs the rest to parse.
+        let auth_data = auth_data.filter(|auth_data| auth_data.len() >= 33);
+        let failure = FailedCeremony {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            ceremony,
+            path: context.path,
+            username: context.username,
+            user_agent: context
+                .headers
+                .get(header::USER_AGENT)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()))
+                .map(|value| value.chars().take(MAX_USER_AGENT_LEN).collect()),
+            error: context.error.to_string(),
+            checks: self.checks(ceremony, client_data.as_ref(), auth_data),
+            client_data,
+            flags: auth_data.map(|auth_data| Flags::of(auth_data[32])),
+        };
+
+        let mut failures = self.failures.lock().expect("ceremony log poisoned");
+        if failures.len() >= self.capacity {
+            failures.pop_front();
+        }
+        failures.push_back(failure);
+    }
+
+    fn checks(
+        &self,
+        ceremony: Ceremony,
+        client_data: Option<&ClientData>,
+        auth_data: Option<&[u8]>,
+    ) -> Vec<Check> {
+        let mut checks = Vec::new();
+        let mut check = |step, passed| checks.push(Check { step, passed });
+
+        check("client_data_parsed", client_data.is_some());
+        if let Some(client_data) = client_data {
+            check(
+                "client_data_type",
+                client_data.type_.as_deref() == Some(ceremony.client_data_type()),
+            );
+            check(
+                "origin_allowed",
+                client_data
+                    .origin
+                    .as_deref()
+                    .and_then(|origin| Url::parse(origin).ok())
+                    .is_some_and(|origin| origin_is_allowed(&self.allowed_origins, &origin)),
+            );
+        }
+        check("authenticator_data_parsed", auth_data.is_some());
+        if let Some(auth_data) = auth_data {
+            let flags = Flags::of(auth_data[32]);
+            check("rp_id_hash", auth_data[..32] == self.rp_id_hash);
+            check("user_present", flags.user_present);
+            // Passkeys always require user verification.
+            check("user_verified", flags.user_verified);
+        }
+        checks
+    }
+}
+
+/// Whether webauthn-rs accepts ceremonies from `origin`, following
+/// `WebauthnBuilder::allow_subdomains`, which `main` enables.
+pub fn origin_is_allowed(allowed_origins: &[Url], origin: &Url) -> bool {
+    allowed_origins.iter().any(|allowed| {
+        let (Some(host), Some(allowed_host)) = (origin.host_str(), allowed.host_str()) else {
+            return false;
+        };
+        origin.scheme() == allowed.scheme()
+            && origin.port_or_known_default() == allowed.port_or_known_default()
+            && (host == allowed_host
+                || host
+                    .strip_suffix(allowed_host)
+                    .is_some_and(|prefix| prefix.ends_with('.')))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+    use webauthn_rs_proto::AuthenticatorAssertionResponseRaw;
+
+    fn assertion(client_data: &str, auth_data: Vec<u8>) -> PublicKeyCredential {
+        PublicKeyCredential {
+            id: "AA".to_string(),
+            raw_id: vec![0].into(),
+            response: AuthenticatorAssertionResponseRaw {
+                authenticator_data: auth_data.into(),
+                client_data_json: client_data.as_bytes().to_vec().into(),
+                signature: vec![0].into(),
+                user_handle: None,
+            },
+            extensions: Default::default(),
+            type_: "public-key".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_ceremony_log() {
+        let log = CeremonyLog::new(
+            2,
+            "example.com",
+            vec![Url::parse("https://example.com").unwrap()],
+        );
+        let headers =
+            HeaderMap::from_iter([(header::USER_AGENT, "Mozilla/5.0 (iPhone)".parse().unwrap())]);
+        let context = || FailureContext {
+            path: "/api/authenticate",
+            username: Some("user".to_string()),
+            headers: &headers,
+            error: &WebauthnError::InvalidRPOrigin,
+        };
+        let challenge = URL_SAFE_NO_PAD.encode(b"secret challenge");
+        let client_data = |origin: &str| {
+            format!(
+                r#"{{"type":"webauthn.get","challenge":"{challenge}","origin":"{origin}","crossOrigin":false}}"#
+            )
+        };
+        let mut auth_data = Sha256::digest(b"example.com").to_vec();
+        auth_data.extend([0x01, 0, 0, 0, 1]);
+
+        log.authentication_failed(
+            context(),
+            &assertion(&client_data("https://evil.example"), auth_data.clone()),
+        );
+        let failure = &log.failures()[0];
+        assert_eq!(failure.ceremony, Ceremony::Authentication);
+        assert_eq!(failure.user_agent.as_deref(), Some("Mozilla/5.0 (iPhone)"));
+        assert_eq!(
+            failure.client_data.as_ref().unwrap().origin.as_deref(),
+            Some("https://evil.example")
+        );
+        let flags = failure.flags.unwrap();
+        assert!(flags.user_present);
+        assert!(!flags.user_verified);
+        let failed: Vec<_> = failure
+            .checks
+            .iter()
+            .filter(|check| !check.passed)
+            .map(|check| check.step)
+            .collect();
+        assert_eq!(failed, ["origin_allowed", "user_verified"]);
+        let json = serde_json::to_string(failure).unwrap();
+        assert!(!json.contains(&challenge));
+
+        // subdomains are allowed, lookalikes are not
+        let auth_data = {
+            let mut auth_data = auth_data;
+            auth_data[32] = 0x05;
+            auth_data
+        };
+        log.authentication_failed(
+            context(),
+            &assertion(&client_data("https://auth.example.com"), auth_data.clone()),
+        );
+        assert!(log.failures()[0].checks.iter().all(|check| check.passed));
+        log.authentication_failed(
+            context(),
+            &assertion(&client_data("https://badexample.com"), auth_data),
+        );
+        let failures = log.failures();
+        assert_eq!(failures.len(), 2);
+        assert!(!failures[0].checks[2].passed);
+
+        log.authentication_failed(context(), &assertion("not json", vec![0; 4]));
+        assert_eq!(
+            log.failures()[0].checks,
+            [
+                Check {
+                    step: "client_data_parsed",
+                    passed: false
+                },
+                Check {
+                    step: "authenticator_data_parsed",
+                    passed: false
+                },
+            ]
+        );
+    }
+}
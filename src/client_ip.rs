@@ -0,0 +1,142 @@
+use axum::http::HeaderMap;
+use clap::ValueEnum;
+use std::net::{IpAddr, SocketAddr};
+
+/// The header the reverse proxy puts the client address in, given with `--real-ip-header`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RealIpHeader {
+    XForwardedFor,
+    XRealIp,
+    // RFC 7239
+    Forwarded,
+    // Use the address of the connection, for servers exposed without a proxy.
+    None,
+}
+
+/// Determines the client address of a request for everything keyed by it, e.g. CAPTCHA
+/// thresholds and the audit log (see `ClientIp`). Localhost checks only trust the connection.
+#[derive(Debug, Clone)]
+pub struct ClientIpResolver {
+    header: RealIpHeader,
+    /// The number of proxies appending to the header, the client address being the one the
+    /// outermost of them appended. Entries before it were sent by the client, which may have
+    /// made them up.
+    trusted_hops: usize,
+}
+
+impl Default for ClientIpResolver {
+    fn default() -> Self {
+        Self::new(RealIpHeader::None, 1)
+    }
+}
+
+impl ClientIpResolver {
+    pub fn new(header: RealIpHeader, trusted_hops: usize) -> Self {
+        Self {
+            header,
+            trusted_hops,
+        }
+    }
+
+    /// The client address given by the header, or `peer` (the address of the connection, if
+    /// there is one) if the header is missing. `None` if the header cannot be parsed.
+    pub fn resolve(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+        let name = match self.header {
+            RealIpHeader::XForwardedFor => "x-forwarded-for",
+            RealIpHeader::XRealIp => "x-real-ip",
+            RealIpHeader::Forwarded => "forwarded",
+            RealIpHeader::None => return peer,
+        };
+        // Proxies may add another header rather than append to the one already there.
+        let mut values = headers.get_all(name).iter().peekable();
+        if values.peek().is_none() {
+            return peer;
+        }
+        let mut hops = Vec::new();
+        for value in values {
+            hops.extend(value.to_str().ok()?.split(',').map(str::trim));
+        }
+
+        // X-Real-IP holds a single address, which the proxy replaces.
+        if self.header == RealIpHeader::XRealIp {
+            return match hops[..] {
+                [hop] => hop.parse().ok(),
+                _ => None,
+            };
+        }
+        let hop = hops[hops.len().saturating_sub(self.trusted_hops.max(1))];
+        match self.header {
+            RealIpHeader::Forwarded => forwarded_for(hop),
+            _ => hop.parse().ok(),
+        }
+    }
+}
+
+/// The address in the `for` parameter of an element of a `Forwarded` header, e.g.
+/// `for="[2001:db8::1]:4711";proto=https`. Obfuscated identifiers and `unknown` have none.
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+    let node = element.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        key.eq_ignore_ascii_case("for").then_some(value)
+    })?;
+    let node = node.trim_matches('"');
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(name: &'static str, values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_resolve() {
+        let peer = Some("10.0.0.1".parse().unwrap());
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+        let xff = headers("x-forwarded-for", &["198.51.100.1, 192.0.2.1", "10.0.0.2"]);
+        // the connection's address unless a header is configured
+        assert_eq!(ClientIpResolver::default().resolve(&xff, peer), peer);
+        let resolver = |hops| ClientIpResolver::new(RealIpHeader::XForwardedFor, hops);
+        assert_eq!(resolver(1).resolve(&xff, peer), ip("10.0.0.2"));
+        assert_eq!(resolver(2).resolve(&xff, peer), ip("192.0.2.1"));
+        // with fewer entries than proxies, the first entry is used
+        assert_eq!(resolver(5).resolve(&xff, peer), ip("198.51.100.1"));
+        assert_eq!(resolver(1).resolve(&HeaderMap::new(), peer), peer);
+        assert_eq!(
+            resolver(1).resolve(&headers("x-forwarded-for", &["garbage"]), peer),
+            None
+        );
+
+        assert_eq!(
+            ClientIpResolver::new(RealIpHeader::XRealIp, 1)
+                .resolve(&headers("x-real-ip", &["2001:db8::1"]), peer),
+            ip("2001:db8::1")
+        );
+        assert_eq!(
+            ClientIpResolver::new(RealIpHeader::XRealIp, 1).resolve(&xff, peer),
+            peer
+        );
+
+        let forwarded = headers(
+            "forwarded",
+            &[
+                r#"for=_hidden, For="[2001:db8::1]:4711";proto=https, for=192.0.2.43:47011;by=10.0.0.2"#,
+            ],
+        );
+        let resolver = |hops| ClientIpResolver::new(RealIpHeader::Forwarded, hops);
+        assert_eq!(resolver(3).resolve(&forwarded, peer), None);
+        assert_eq!(resolver(2).resolve(&forwarded, peer), ip("2001:db8::1"));
+        assert_eq!(resolver(1).resolve(&forwarded, peer), ip("192.0.2.43"));
+    }
+}
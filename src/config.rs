@@ -0,0 +1,290 @@
+use anyhow::Context;
+use secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use webauthn_rs::prelude::Url;
+use zeroize::Zeroizing;
+
+/// Settings that are too structured to comfortably pass as command line flags. The config file
+/// is JSON so that it can be generated directly from nix expressions.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Config {
+    /// Origins (in addition to the RP origin) that may use credentials registered under the RP
+    /// ID, served at `/.well-known/webauthn` per the WebAuthn Related Origin Requests spec.
+    pub related_origins: Vec<Url>,
+    /// Branding applied to the built-in pages.
+    pub theme: Theme,
+    /// Require clients with repeated authentication failures to solve a CAPTCHA.
+    pub captcha: Option<CaptchaConfig>,
+    /// Tell admins about registrations awaiting approval (see `--require-registration-approval`).
+    pub approval_notifications: Option<NotificationConfig>,
+    /// Requirements of the apps behind the reverse proxy, checked by `/api/validate?app=<name>`.
+    pub apps: BTreeMap<String, ProtectedApp>,
+    /// Liquid template replacing the built-in error page, see `templates/error.liquid`.
+    pub error_template: Option<PathBuf>,
+}
+
+/// What a session has to satisfy to be let through to an app, on top of being logged in.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct ProtectedApp {
+    /// Groups the user has to be a member of, all of them.
+    pub required_groups: Vec<String>,
+    /// Minimum authenticator assurance level of the login, 2 to rule out logins with just a
+    /// password (see `X-Auth-Aal`).
+    pub required_aal: Option<u16>,
+    /// Seconds since the login after which the user has to log in again.
+    pub max_session_age: Option<u64>,
+    /// Path prefixes served by the app, for looking it up by path in `/api/validate/batch`.
+    pub paths: Vec<String>,
+    /// HTTP methods the requirements apply to on those paths, all of them if empty.
+    pub methods: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct NotificationConfig {
+    /// Receives a JSON POST request for each notification.
+    pub webhook_url: Option<Url>,
+    pub email: Option<EmailConfig>,
+}
+
+/// Emails are handed to a sendmail-compatible program rather than sent over SMTP directly, so
+/// that delivery (and credentials for it) stays in the hands of the system's MTA.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct EmailConfig {
+    pub to: Vec<String>,
+    pub from: String,
+    #[serde(default = "default_sendmail")]
+    pub sendmail: PathBuf,
+}
+
+fn default_sendmail() -> PathBuf {
+    PathBuf::from("sendmail")
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptchaProvider {
+    Hcaptcha,
+    Turnstile,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct CaptchaConfig {
+    pub provider: CaptchaProvider,
+    pub site_key: String,
+    /// Exactly one of `secretKey` and `secretKeyFile` must be set. Prefer the latter when the
+    /// config file is world-readable, e.g. in the nix store.
+    pub secret_key: Option<SecretString>,
+    pub secret_key_file: Option<PathBuf>,
+    /// Failed authentications from a single IP address after which a CAPTCHA is required.
+    #[serde(default = "default_captcha_failure_threshold")]
+    pub failure_threshold: u32,
+}
+
+fn default_captcha_failure_threshold() -> u32 {
+    5
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Theme {
+    pub product_name: String,
+    pub logo_url: Option<Url>,
+    pub colors: ThemeColors,
+    /// Used instead of `colors` when the browser prefers a dark color scheme.
+    pub dark_colors: ThemeColors,
+}
+
+/// CSS color values, e.g. `#0969da` or `rebeccapurple`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ThemeColors {
+    pub background: String,
+    pub foreground: String,
+    pub accent: String,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            product_name: "WebAuthnTiny".to_string(),
+            logo_url: None,
+            colors: ThemeColors {
+                background: "#ffffff".to_string(),
+                foreground: "#1f2328".to_string(),
+                accent: "#0969da".to_string(),
+            },
+            dark_colors: ThemeColors {
+                background: "#0d1117".to_string(),
+                foreground: "#e6edf3".to_string(),
+                accent: "#4493f8".to_string(),
+            },
+        }
+    }
+}
+
+impl CaptchaConfig {
+    pub fn load_secret_key(&self) -> anyhow::Result<SecretString> {
+        match (&self.secret_key, &self.secret_key_file) {
+            (Some(secret_key), None) => Ok(secret_key.clone()),
+            (None, Some(path)) => {
+                let secret_key = Zeroizing::new(
+                    std::fs::read_to_string(path)
+                        .with_context(|| format!("could not read {}", path.display()))?,
+                );
+                Ok(secret_key.trim().into())
+            }
+            _ => anyhow::bail!(
+                "exactly one of captcha.secretKey and captcha.secretKeyFile must be set"
+            ),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// The app that a request to `path` with `method` goes to: of the apps with a prefix of the
+    /// path (matching whole segments), and the method if they list methods, the one with the
+    /// longest prefix.
+    pub fn app_for(&self, path: &str, method: &str) -> Option<(&str, &ProtectedApp)> {
+        self.apps
+            .iter()
+            .filter(|(_, app)| {
+                app.methods.is_empty()
+                    || app
+                        .methods
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(method))
+            })
+            .filter_map(|(name, app)| {
+                let prefix = app
+                    .paths
+                    .iter()
+                    .filter(|prefix| {
+                        path.strip_prefix(prefix.as_str()).is_some_and(|rest| {
+                            rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/')
+                        })
+                    })
+                    .map(String::len)
+                    .max()?;
+                Some((prefix, name.as_str(), app))
+            })
+            .max_by_key(|(prefix, _, _)| *prefix)
+            .map(|(_, name, app)| (name, app))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrecy::ExposeSecret;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = serde_json::from_str("{}").unwrap();
+        assert!(config.related_origins.is_empty());
+
+        let config: Config =
+            serde_json::from_str(r#"{"relatedOrigins": ["https://foo.com", "https://bar.com"]}"#)
+                .unwrap();
+        assert_eq!(config.related_origins.len(), 2);
+        assert_eq!(config.theme.product_name, "WebAuthnTiny");
+
+        let config: Config = serde_json::from_str(
+            r#"{"theme": {"productName": "Foo Login", "colors": {"background": "white", "foreground": "black", "accent": "red"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.theme.product_name, "Foo Login");
+        assert_eq!(config.theme.colors.accent, "red");
+        assert_eq!(config.theme.dark_colors.accent, "#4493f8");
+
+        // a partial set of colors is rejected rather than silently mixed with the defaults
+        assert!(
+            serde_json::from_str::<Config>(r#"{"theme": {"colors": {"accent": "red"}}}"#).is_err()
+        );
+
+        let config: Config = serde_json::from_str(
+            r#"{"captcha": {"provider": "turnstile", "siteKey": "site", "secretKey": "secret"}}"#,
+        )
+        .unwrap();
+        let captcha = config.captcha.unwrap();
+        assert_eq!(captcha.provider, CaptchaProvider::Turnstile);
+        assert_eq!(captcha.failure_threshold, 5);
+        assert_eq!(captcha.load_secret_key().unwrap().expose_secret(), "secret");
+        // the secret key is redacted when the config is debug-printed
+        assert!(!format!("{captcha:?}").contains("\"secret\""));
+        let config: Config = serde_json::from_str(
+            r#"{"captcha": {"provider": "hcaptcha", "siteKey": "site", "secretKey": "secret", "secretKeyFile": "/secret"}}"#,
+        )
+        .unwrap();
+        assert!(config.captcha.unwrap().load_secret_key().is_err());
+        assert!(serde_json::from_str::<Config>(
+            r#"{"captcha": {"provider": "recaptcha", "siteKey": "site", "secretKey": "secret"}}"#,
+        )
+        .is_err());
+
+        let config: Config = serde_json::from_str(
+            r#"{"approvalNotifications": {"webhookUrl": "https://hooks.example.com/foo", "email": {"to": ["admin@example.com"], "from": "webauthn@example.com"}}}"#,
+        )
+        .unwrap();
+        let notifications = config.approval_notifications.unwrap();
+        assert!(notifications.webhook_url.is_some());
+        assert_eq!(
+            notifications.email.unwrap().sendmail,
+            PathBuf::from("sendmail")
+        );
+
+        let config: Config = serde_json::from_str(
+            r#"{"apps": {"grafana": {"requiredGroups": ["ops"], "requiredAal": 2}, "wiki": {}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.apps["grafana"],
+            ProtectedApp {
+                required_groups: vec!["ops".to_string()],
+                required_aal: Some(2),
+                ..Default::default()
+            }
+        );
+        assert_eq!(config.apps["wiki"], ProtectedApp::default());
+
+        assert!(serde_json::from_str::<Config>(r#"{"relatedOrigins": ["not a url"]}"#).is_err());
+        assert!(serde_json::from_str::<Config>(r#"{"unknownField": true}"#).is_err());
+    }
+
+    #[test]
+    fn test_app_for() {
+        let config: Config = serde_json::from_str(
+            r#"{"apps": {
+                "grafana": {"paths": ["/grafana"]},
+                "grafana-admin": {"paths": ["/grafana/admin/"], "methods": ["POST", "DELETE"]},
+                "wiki": {"paths": ["/wiki/", "/w"]}
+            }}"#,
+        )
+        .unwrap();
+        let name = |path, method| config.app_for(path, method).map(|(name, _)| name);
+
+        assert_eq!(name("/grafana", "GET"), Some("grafana"));
+        assert_eq!(name("/grafana/api/dashboards", "GET"), Some("grafana"));
+        // prefixes match whole path segments
+        assert_eq!(name("/grafanax", "GET"), None);
+        assert_eq!(name("/wx", "GET"), None);
+        assert_eq!(name("/w/index", "GET"), Some("wiki"));
+        // the longest prefix among apps handling the method wins
+        assert_eq!(name("/grafana/admin/users", "post"), Some("grafana-admin"));
+        assert_eq!(name("/grafana/admin/users", "GET"), Some("grafana"));
+        assert_eq!(name("/other", "GET"), None);
+    }
+}
@@ -0,0 +1,135 @@
+use metrics::gauge;
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+/// How often database sizes and free space are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Watches the size of the databases and the space left next to them, so that registrations can
+/// be refused before the disk fills up and writes start failing halfway through a ceremony.
+#[derive(Debug)]
+pub struct DiskSpace {
+    state_directory: PathBuf,
+    /// Databases by the name they are labelled with in metrics.
+    databases: Vec<(&'static str, PathBuf)>,
+    min_free_bytes: u64,
+    low: AtomicBool,
+}
+
+impl DiskSpace {
+    pub fn new(
+        state_directory: PathBuf,
+        databases: Vec<(&'static str, PathBuf)>,
+        min_free_bytes: u64,
+    ) -> Self {
+        Self {
+            state_directory,
+            databases,
+            min_free_bytes,
+            low: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether less than the minimum was free at the last check.
+    pub fn is_low(&self) -> bool {
+        self.low.load(Ordering::Relaxed)
+    }
+
+    /// Updates the `database_size_bytes` and `state_directory_free_bytes` gauges, and whether
+    /// space is low.
+    pub fn check(&self) {
+        for (name, path) in &self.databases {
+            gauge!("database_size_bytes", "database" => *name).set(database_size(path) as f64);
+        }
+
+        let free = match free_space(&self.state_directory) {
+            Ok(free) => free,
+            Err(e) => {
+                warn!(
+                    "could not get free space of {}: {e}",
+                    self.state_directory.display()
+                );
+                return;
+            }
+        };
+        gauge!("state_directory_free_bytes").set(free as f64);
+
+        let low = free < self.min_free_bytes;
+        if low != self.low.swap(low, Ordering::Relaxed) {
+            if low {
+                error!(
+                    "only {free} bytes are free in {}, refusing registrations until at least {} \
+                     bytes are",
+                    self.state_directory.display(),
+                    self.min_free_bytes
+                );
+            } else {
+                info!(
+                    "{free} bytes are free in {} again, accepting registrations",
+                    self.state_directory.display()
+                );
+            }
+        }
+    }
+
+    /// Checks disk space until the server exits.
+    pub async fn watch(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.check();
+        }
+    }
+}
+
+/// The size of an SQLite database along with its write-ahead log and shared memory files.
+fn database_size(path: &Path) -> u64 {
+    ["", "-wal", "-shm"]
+        .into_iter()
+        .filter_map(|suffix| {
+            let mut path = OsString::from(path);
+            path.push(suffix);
+            std::fs::metadata(path).ok()
+        })
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// The space available to unprivileged users on the filesystem containing `path`.
+fn free_space(path: &Path) -> std::io::Result<u64> {
+    let stat = rustix::fs::statvfs(path)?;
+    Ok(stat.f_bavail * stat.f_frsize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_space() {
+        let directory = std::env::temp_dir().join(format!("webauthn-tiny-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let database = directory.join("test.db");
+        std::fs::write(&database, [0; 100]).unwrap();
+        std::fs::write(directory.join("test.db-wal"), [0; 20]).unwrap();
+        assert_eq!(database_size(&database), 120);
+        assert_eq!(database_size(&directory.join("missing.db")), 0);
+
+        let disk_space = DiskSpace::new(directory.clone(), vec![("test", database.clone())], 0);
+        disk_space.check();
+        assert!(!disk_space.is_low());
+        let disk_space = DiskSpace::new(directory.clone(), vec![("test", database)], u64::MAX);
+        disk_space.check();
+        assert!(disk_space.is_low());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::watch;
+use tracing::warn;
+
+/// Draining before a blue/green deploy shuts the instance down, started with
+/// `POST /api/admin/drain`: readiness fails so that traffic moves to the new instance, no new
+/// ceremonies are started, and ceremonies already started as well as sessions keep working for
+/// the grace period, after which the servers shut down.
+#[derive(Debug)]
+pub struct Drain {
+    grace: Duration,
+    /// Unix timestamp (in seconds) of the end of the grace period, 0 until draining started.
+    ends_at: AtomicU64,
+    started: watch::Sender<bool>,
+}
+
+impl Drain {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            grace,
+            ends_at: AtomicU64::new(0),
+            started: watch::Sender::new(false),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.ends_at.load(Ordering::Relaxed) != 0
+    }
+
+    /// Starts draining unless it has already, returning when the grace period ends.
+    pub fn start(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let ends_at = (now + self.grace).as_secs().max(1);
+        match self
+            .ends_at
+            .compare_exchange(0, ends_at, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => {
+                warn!(
+                    "draining, shutting down in {} seconds",
+                    self.grace.as_secs()
+                );
+                self.started.send_replace(true);
+                ends_at
+            }
+            Err(ends_at) => ends_at,
+        }
+    }
+
+    /// Resolves once the grace period is over, for the servers to shut down gracefully.
+    pub async fn finished(self: Arc<Self>) {
+        let mut started = self.started.subscribe();
+        if started.wait_for(|started| *started).await.is_ok() {
+            tokio::time::sleep(self.grace).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let drain = Arc::new(Drain::new(Duration::from_millis(10)));
+        assert!(!drain.is_draining());
+        let finished = tokio::spawn(drain.clone().finished());
+        tokio::task::yield_now().await;
+        assert!(!finished.is_finished());
+
+        let ends_at = drain.start();
+        assert!(drain.is_draining());
+        assert_eq!(drain.start(), ends_at);
+        finished.await.unwrap();
+    }
+}
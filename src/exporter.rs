@@ -0,0 +1,53 @@
+use clap::ValueEnum;
+
+/// Where metrics are exported to, given with `--metrics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MetricsExporter {
+    // Do not record metrics at all, and do not serve `/metrics`.
+    Off,
+    // Serve metrics at `/metrics`, and push them to a Pushgateway if one is configured.
+    Prometheus,
+}
+
+/// Parses a `--metrics-label` of the form `name=value`, where the name has to be a valid
+/// Prometheus label name.
+pub fn parse_label(s: &str) -> Result<(String, String), String> {
+    let Some((name, value)) = s.split_once('=') else {
+        return Err(format!("expected name=value, got {s}"));
+    };
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__");
+    if !valid {
+        return Err(format!("invalid label name {name}"));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(
+            parse_label("instance=auth-1").unwrap(),
+            ("instance".to_string(), "auth-1".to_string())
+        );
+        assert_eq!(
+            parse_label("env=a=b").unwrap(),
+            ("env".to_string(), "a=b".to_string())
+        );
+        assert_eq!(
+            parse_label("env=").unwrap(),
+            ("env".to_string(), "".to_string())
+        );
+        assert!(parse_label("instance").is_err());
+        assert!(parse_label("1st=x").is_err());
+        assert!(parse_label("__name__=x").is_err());
+        assert!(parse_label("some-label=x").is_err());
+    }
+}
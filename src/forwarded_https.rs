@@ -0,0 +1,90 @@
+use axum::http::HeaderMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::warn;
+use webauthn_rs::prelude::Url;
+
+/// Detects ceremonies reaching the server over plain HTTP although the RP origin is https, i.e.
+/// without a reverse proxy terminating TLS in front of it. Browsers only offer WebAuthn in secure
+/// contexts, so such deployments fail with client errors that do not tell why. Whether the proxy
+/// received the request over HTTPS is told by the `X-Forwarded-Proto` header it sets.
+#[derive(Debug)]
+pub struct ForwardedHttps {
+    /// Nothing is checked for http RP origins, e.g. `http://localhost` during development.
+    enabled: bool,
+    /// Refuse plaintext ceremonies instead of warning about them, see
+    /// `--require-forwarded-https`.
+    required: bool,
+    /// Plaintext ceremonies are only warned about once, so that they do not flood the log.
+    warned: AtomicBool,
+}
+
+impl ForwardedHttps {
+    pub fn new(rp_origin: &Url, required: bool) -> Self {
+        Self {
+            enabled: rp_origin.scheme() == "https",
+            required,
+            warned: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether a ceremony with `headers` may go ahead, warning the first time one did not reach
+    /// the proxy over HTTPS.
+    pub fn check(&self, headers: &HeaderMap) -> bool {
+        if !self.enabled || is_forwarded_https(headers) {
+            return true;
+        }
+        if !self.warned.swap(true, Ordering::Relaxed) {
+            warn!(
+                "the RP origin is https, but a ceremony was received over plain HTTP without \
+                 X-Forwarded-Proto: https, browsers will refuse WebAuthn unless TLS is terminated \
+                 by a reverse proxy setting the header{}",
+                if self.required {
+                    ", refusing such ceremonies"
+                } else {
+                    ""
+                }
+            );
+        }
+        !self.required
+    }
+}
+
+/// Whether the proxy closest to the client received the request over HTTPS, as told by the
+/// first entry of `X-Forwarded-Proto`.
+fn is_forwarded_https(headers: &HeaderMap) -> bool {
+    headers
+        .get("x-forwarded-proto")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(proto: Option<&str>) -> HeaderMap {
+        HeaderMap::from_iter(
+            proto.map(|proto| ("x-forwarded-proto".parse().unwrap(), proto.parse().unwrap())),
+        )
+    }
+
+    #[test]
+    fn test_forwarded_https() {
+        let https = Url::parse("https://example.com").unwrap();
+        let http = Url::parse("http://localhost:8080").unwrap();
+
+        let warn_only = ForwardedHttps::new(&https, false);
+        assert!(warn_only.check(&headers(None)));
+        assert!(warn_only.warned.load(Ordering::Relaxed));
+
+        let required = ForwardedHttps::new(&https, true);
+        assert!(required.check(&headers(Some("https"))));
+        assert!(required.check(&headers(Some("HTTPS, http"))));
+        assert!(!required.check(&headers(Some("http"))));
+        assert!(!required.check(&headers(Some("http, https"))));
+        assert!(!required.check(&headers(None)));
+
+        assert!(ForwardedHttps::new(&http, true).check(&headers(None)));
+    }
+}
@@ -0,0 +1,3022 @@
+use super::{
+    authenticate_context, consume_assertion_challenge,
+    extractors::{ClientIp, LoggedIn, RequireFreshAuth},
+    get_redirect_url, insert_pending_ceremony, kiosk_operator, needs_basic_auth_response,
+    page_path, request_cookie, set_page_error, take_pending_ceremony, unix_now, verified_within,
+    AuthContext, AuthMethod, AuthenticateRejection, CeremonyId, CredentialIDWithName, Enrollment,
+    GetAuthenticateQueryParams, HandlerResult, Impersonation, PageError, SESSIONKEY_AUTHCONTEXT,
+    SESSIONKEY_CAPTCHA, SESSIONKEY_CLIENTFINGERPRINT, SESSIONKEY_ENROLLMENT,
+    SESSIONKEY_ENROLLMENTREGISTRATION, SESSIONKEY_IMPERSONATION, SESSIONKEY_KIOSKREGISTRATION,
+    SESSIONKEY_LOGGEDIN, SESSIONKEY_MUSTREENROLL, SESSIONKEY_PASSKEYAUTHENTICATION,
+    SESSIONKEY_PASSKEYREGISTRATION, SESSIONKEY_PASSKEYSTEPUP, SESSIONKEY_PROOFOFWORK,
+    SESSIONKEY_RECENTLYVERIFIEDAT, SESSIONKEY_RECOVERY, SESSIONKEY_RECOVERYREGISTRATION,
+    SESSIONKEY_USERNAME,
+};
+use crate::{
+    app::{
+        credential_handle, recovery_code_hash, App, AppError, AuditEvent, BlockedItem,
+        BlocklistEntry, CredentialSummary, CredentialWithName, PendingCredential, SharedAppState,
+        UserExport, UserFilter, UserSummary,
+    },
+    build_info::BuildInfo,
+    captcha::{Captcha, CaptchaChallenge},
+    ceremony_log::{origin_is_allowed, CeremonyLog, FailedCeremony, FailureContext},
+    config::{Config, ProtectedApp},
+    drain::Drain,
+    health::DatabaseHealth,
+    locale::Preferences,
+    metadata::{cred_protect, registration_info, AuthenticatorInfo, WithAttachment},
+    notify::{Notifier, PendingRegistration},
+    origins::AllowedOrigins,
+    policy::{algorithm_name, Policy},
+    pow::{ProofOfWork, ProofOfWorkChallenge},
+    public_key,
+    recovery::{self, RecoveryClaims, RecoveryTokens},
+    self_test::SelfTest,
+    session::{SqliteSessionStore, SESSION_COOKIE},
+    state::{AppState, Passwords},
+    throttle::LoginThrottle,
+    timing,
+    user_agent::{ClientFingerprint, ClientInfo, SessionBinding},
+};
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, SaltString,
+    },
+    Argon2,
+};
+use axum::{
+    debug_handler,
+    extract::{self, Path, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Redirect, Response,
+    },
+    Json,
+};
+use base64::{engine::general_purpose, Engine as _};
+use futures_util::{stream, Stream, StreamExt};
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+use tower_sessions::{
+    cookie::{time::OffsetDateTime, Key},
+    Expiry, Session,
+};
+use tracing::{error, info, trace, warn};
+use webauthn_rs::{prelude::*, Webauthn};
+use webauthn_rs_proto::{
+    AuthenticatorAttachment, COSEAlgorithm, CreationChallengeResponse, CredProtect,
+    CredentialProtectionPolicy, PublicKeyCredential, RegisterPublicKeyCredential,
+    RequestChallengeResponse, ResidentKeyRequirement, UserVerificationPolicy,
+};
+
+#[derive(Deserialize)]
+pub struct SetPageErrorRequestPayload {
+    error: PageError,
+}
+
+/// Lets the client record an error that the next page render should show, e.g. when the
+/// browser's WebAuthn prompt times out.
+#[debug_handler(state = AppState)]
+pub async fn set_page_error_handler(
+    session: Session,
+    payload: extract::Json<SetPageErrorRequestPayload>,
+) -> HandlerResult<StatusCode> {
+    trace!("set_page_error_handler");
+
+    set_page_error(&session, payload.error).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterStartResponsePayload {
+    #[serde(flatten)]
+    challenge: CreationChallengeResponse,
+    /// A default credential name derived from the client's User-Agent and client hints.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggested_name: Option<String>,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn register_start_handler(
+    session: Session,
+    headers: HeaderMap,
+    shared_state: State<SharedAppState>,
+    webauthn: State<Arc<Webauthn>>,
+    policy: State<Arc<Policy>>,
+) -> HandlerResult<(CeremonyId, Json<RegisterStartResponsePayload>)> {
+    trace!("register_start_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let app = shared_state.read().await;
+    let user = app.get_user_with_credentials(username).await?;
+
+    let existing_credentials: Vec<CredentialID> = user
+        .credentials
+        .iter()
+        .map(|c| c.credential.cred_id().to_owned())
+        .collect();
+
+    let Ok((mut req_chal, passkey_reg)) = timing::measure_sync("ceremony", || {
+        webauthn.start_passkey_registration(
+            user.id,
+            &user.username,
+            &user.username, // use username as display name
+            if existing_credentials.is_empty() {
+                None
+            } else {
+                Some(existing_credentials)
+            },
+        )
+    }) else {
+        return Err(AppError::WebauthnFailed);
+    };
+
+    apply_registration_policy(&mut req_chal, &policy)?;
+
+    let ceremony_id = insert_pending_ceremony(
+        &app,
+        &session,
+        SESSIONKEY_PASSKEYREGISTRATION,
+        passkey_reg,
+        &policy,
+    )
+    .await?;
+
+    Ok((
+        ceremony_id,
+        Json(RegisterStartResponsePayload {
+            challenge: req_chal,
+            suggested_name: ClientInfo::from_headers(&headers).suggested_credential_name(),
+        }),
+    ))
+}
+
+/// Only advertise the algorithms the policy allows, and ask authenticators to enforce
+/// credProtect if the policy requires it, so that the authenticator does not create a credential
+/// we would reject when finishing registration anyway. minPinLength is always requested to be
+/// able to show it along with the credential.
+fn apply_registration_policy(
+    req_chal: &mut CreationChallengeResponse,
+    policy: &Policy,
+) -> HandlerResult<()> {
+    req_chal.public_key.pub_key_cred_params.retain(|param| {
+        COSEAlgorithm::try_from(param.alg as i128)
+            .is_ok_and(|alg| policy.algorithm_is_allowed(&alg))
+    });
+    if req_chal.public_key.pub_key_cred_params.is_empty() {
+        return Err(AppError::AlgorithmNotAllowed);
+    }
+
+    let extensions = req_chal
+        .public_key
+        .extensions
+        .get_or_insert_with(Default::default);
+    extensions.min_pin_length = Some(true);
+    if policy.require_cred_protect {
+        extensions.cred_protect = Some(CredProtect {
+            credential_protection_policy: CredentialProtectionPolicy::UserVerificationRequired,
+            enforce_credential_protection_policy: Some(true),
+        });
+    }
+    Ok(())
+}
+
+/// Checks a newly registered credential against the policy.
+fn check_registration_policy(passkey: &Passkey, policy: &Policy) -> HandlerResult<()> {
+    if !policy.algorithm_is_allowed(passkey.cred_algorithm()) {
+        info!(
+            "rejected credential using {}",
+            algorithm_name(passkey.cred_algorithm())
+        );
+        return Err(AppError::AlgorithmNotAllowed);
+    }
+    if !policy.cred_protect_is_allowed(cred_protect(passkey)) {
+        info!("rejected credential without credProtect userVerificationRequired");
+        return Err(AppError::CredentialProtectionRequired);
+    }
+    Ok(())
+}
+
+/// Keeps a registration webauthn-rs refused for `--debug-ceremonies`.
+fn log_failed_registration(
+    ceremony_log: &Option<Arc<CeremonyLog>>,
+    path: &'static str,
+    username: Option<String>,
+    headers: &HeaderMap,
+    credential: &RegisterPublicKeyCredential,
+    error: &WebauthnError,
+) {
+    if let Some(ceremony_log) = ceremony_log {
+        ceremony_log.registration_failed(
+            FailureContext {
+                path,
+                username,
+                headers,
+                error,
+            },
+            credential,
+        );
+    }
+}
+
+/// Keeps an assertion webauthn-rs refused for `--debug-ceremonies`.
+fn log_failed_authentication(
+    ceremony_log: &Option<Arc<CeremonyLog>>,
+    path: &'static str,
+    username: Option<String>,
+    headers: &HeaderMap,
+    credential: &PublicKeyCredential,
+    error: &WebauthnError,
+) {
+    if let Some(ceremony_log) = ceremony_log {
+        ceremony_log.authentication_failed(
+            FailureContext {
+                path,
+                username,
+                headers,
+                error,
+            },
+            credential,
+        );
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegisterEndRequestPayload {
+    name: String,
+    credential: WithAttachment<RegisterPublicKeyCredential>,
+}
+
+/// Responds with 202 Accepted instead of 200 OK when the credential has to be approved by an
+/// admin before it can be used.
+#[debug_handler(state = AppState)]
+#[allow(clippy::too_many_arguments)]
+pub async fn register_end_handler(
+    session: Session,
+    headers: HeaderMap,
+    shared_state: State<SharedAppState>,
+    webauthn: State<Arc<Webauthn>>,
+    ceremony_log: State<Option<Arc<CeremonyLog>>>,
+    policy: State<Arc<Policy>>,
+    notifier: State<Option<Arc<Notifier>>>,
+    payload: extract::Json<RegisterEndRequestPayload>,
+) -> HandlerResult<StatusCode> {
+    trace!("register_end_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let app = shared_state.read().await;
+
+    let passkey_reg: PasskeyRegistration =
+        take_pending_ceremony(&app, &session, &headers, SESSIONKEY_PASSKEYREGISTRATION).await?;
+
+    let Ok(passkey) = timing::measure_sync("ceremony", || {
+        webauthn.finish_passkey_registration(&payload.credential, &passkey_reg)
+    })
+    .inspect_err(|e| {
+        log_failed_registration(
+            &ceremony_log,
+            "/api/register",
+            Some(username.clone()),
+            &headers,
+            &payload.credential,
+            e,
+        )
+    }) else {
+        counter!("failed_registrations").increment(1);
+        set_page_error(&session, PageError::RegistrationFailed).await?;
+
+        return Err(AppError::WebauthnFailed);
+    };
+
+    if let Err(e) = check_registration_policy(&passkey, &policy) {
+        counter!("failed_registrations").increment(1);
+        set_page_error(&session, PageError::RegistrationFailed).await?;
+        return Err(e);
+    }
+
+    let result = add_self_registered_credential(
+        &app,
+        &policy,
+        notifier.as_ref(),
+        username,
+        payload.name.clone(),
+        &passkey,
+        registration_info(&payload.credential),
+    )
+    .await;
+
+    let pending = match result {
+        Ok(pending) => pending,
+        Err(e) => {
+            match e {
+                AppError::DuplicateCredential => {
+                    set_page_error(&session, PageError::CredentialAlreadyRegistered).await?
+                }
+                AppError::CredentialBlocked => {
+                    set_page_error(&session, PageError::CredentialBlocked).await?
+                }
+                _ => {}
+            }
+            return Err(e);
+        }
+    };
+
+    counter!("successful_registrations").increment(1);
+
+    // A pending credential does not replace an expired one yet.
+    if pending {
+        return Ok(StatusCode::ACCEPTED);
+    }
+    _ = session.remove_value(SESSIONKEY_MUSTREENROLL).await?;
+
+    Ok(StatusCode::OK)
+}
+
+/// Adds a credential that a user registered themselves, holding it back for approval if the
+/// policy requires it. Returns whether the credential is awaiting approval.
+async fn add_self_registered_credential(
+    app: &App,
+    policy: &Policy,
+    notifier: Option<&Arc<Notifier>>,
+    username: String,
+    name: String,
+    passkey: &Passkey,
+    info: AuthenticatorInfo,
+) -> HandlerResult<bool> {
+    if !policy.require_approval {
+        app.add_credential(username, name, passkey, info).await?;
+        return Ok(false);
+    }
+
+    let id = app
+        .add_pending_credential(username.clone(), name.clone(), passkey, info)
+        .await?;
+    if let Some(notifier) = notifier {
+        notifier.registration_pending(PendingRegistration {
+            id,
+            username,
+            credential_name: name,
+        });
+    }
+    Ok(true)
+}
+
+#[derive(Deserialize)]
+pub struct KioskRegisterStartQueryParams {
+    username: String,
+}
+
+/// Starts registering a credential for another user at a kiosk. Unlike self-service
+/// registration, only one ceremony is pending at a time, for the user entered last.
+#[debug_handler(state = AppState)]
+pub async fn kiosk_register_start_handler(
+    params: Query<KioskRegisterStartQueryParams>,
+    session: Session,
+    shared_state: State<SharedAppState>,
+    webauthn: State<Arc<Webauthn>>,
+    policy: State<Arc<Policy>>,
+) -> HandlerResult<(CeremonyId, Json<CreationChallengeResponse>)> {
+    trace!("kiosk_register_start_handler");
+
+    kiosk_operator(&session, &shared_state, &policy).await?;
+
+    let app = shared_state.read().await;
+    let username = app.canonical_username(&params.username).await?;
+    let user = app.get_user_with_credentials(username).await?;
+    drop(app);
+    if !user.active {
+        return Err(AppError::UserDeactivated);
+    }
+
+    let existing_credentials: Vec<CredentialID> = user
+        .credentials
+        .iter()
+        .map(|c| c.credential.cred_id().to_owned())
+        .collect();
+
+    let Ok((mut req_chal, passkey_reg)) = timing::measure_sync("ceremony", || {
+        webauthn.start_passkey_registration(
+            user.id,
+            &user.username,
+            &user.username,
+            (!existing_credentials.is_empty()).then_some(existing_credentials),
+        )
+    }) else {
+        return Err(AppError::WebauthnFailed);
+    };
+
+    apply_registration_policy(&mut req_chal, &policy)?;
+
+    let ceremony_id = insert_pending_ceremony(
+        &*shared_state.read().await,
+        &session,
+        SESSIONKEY_KIOSKREGISTRATION,
+        (user.username, passkey_reg),
+        &policy,
+    )
+    .await?;
+
+    Ok((ceremony_id, Json(req_chal)))
+}
+
+#[derive(Serialize)]
+pub struct KioskRegisterEndResponsePayload {
+    username: String,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn kiosk_register_end_handler(
+    session: Session,
+    headers: HeaderMap,
+    shared_state: State<SharedAppState>,
+    webauthn: State<Arc<Webauthn>>,
+    ceremony_log: State<Option<Arc<CeremonyLog>>>,
+    policy: State<Arc<Policy>>,
+    payload: extract::Json<RegisterEndRequestPayload>,
+) -> HandlerResult<Json<KioskRegisterEndResponsePayload>> {
+    trace!("kiosk_register_end_handler");
+
+    let operator = kiosk_operator(&session, &shared_state, &policy).await?;
+
+    let (username, passkey_reg): (String, PasskeyRegistration) = take_pending_ceremony(
+        &*shared_state.read().await,
+        &session,
+        &headers,
+        SESSIONKEY_KIOSKREGISTRATION,
+    )
+    .await?;
+
+    let Ok(passkey) = timing::measure_sync("ceremony", || {
+        webauthn.finish_passkey_registration(&payload.credential, &passkey_reg)
+    })
+    .inspect_err(|e| {
+        log_failed_registration(
+            &ceremony_log,
+            "/api/kiosk/register",
+            Some(username.clone()),
+            &headers,
+            &payload.credential,
+            e,
+        )
+    }) else {
+        counter!("failed_registrations").increment(1);
+        return Err(AppError::WebauthnFailed);
+    };
+
+    if let Err(e) = check_registration_policy(&passkey, &policy) {
+        counter!("failed_registrations").increment(1);
+        return Err(e);
+    }
+
+    shared_state
+        .read()
+        .await
+        .add_kiosk_credential(
+            operator,
+            username.clone(),
+            payload.name.clone(),
+            &passkey,
+            registration_info(&payload.credential),
+        )
+        .await?;
+
+    counter!("successful_registrations").increment(1);
+
+    Ok(Json(KioskRegisterEndResponsePayload { username }))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticateStartResponsePayload {
+    #[serde(flatten)]
+    challenge: RequestChallengeResponse,
+    /// Present when the client must solve a proof-of-work puzzle and send the nonce in the
+    /// `X-Proof-Of-Work` header when finishing authentication.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proof_of_work: Option<ProofOfWorkChallenge>,
+    /// Present when the client failed to authenticate too often and must solve a CAPTCHA,
+    /// sending its response token in the `X-Captcha-Response` header when finishing
+    /// authentication.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    captcha: Option<CaptchaChallenge>,
+}
+
+/// The credentials a user can authenticate with, i.e. the ones that are not on the blocklist.
+fn usable_passkeys(credentials: &[CredentialWithName]) -> HandlerResult<Vec<Passkey>> {
+    let passkeys: Vec<_> = credentials
+        .iter()
+        .filter(|c| !c.blocked)
+        .map(|c| c.credential.to_owned())
+        .collect();
+    if passkeys.is_empty() {
+        info!("all credentials of the user are blocked");
+        return Err(AppError::CredentialBlocked);
+    }
+    Ok(passkeys)
+}
+
+#[debug_handler(state = AppState)]
+#[allow(clippy::too_many_arguments)]
+pub async fn authenticate_start_handler(
+    session: Session,
+    ClientIp(ip): ClientIp,
+    shared_state: State<SharedAppState>,
+    webauthn: State<Arc<Webauthn>>,
+    pow: State<Arc<ProofOfWork>>,
+    throttle: State<Arc<LoginThrottle>>,
+    captcha: State<Option<Arc<Captcha>>>,
+    policy: State<Arc<Policy>>,
+) -> HandlerResult<(CeremonyId, Json<AuthenticateStartResponsePayload>)> {
+    trace!("authenticate_start_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    if throttle.is_throttled(ip, Some(&username)) {
+        info!("too many failed logins");
+        return Err(AppError::TooManyFailures);
+    }
+
+    let app = shared_state.read().await;
+    let user = app.get_user_with_credentials(username.clone()).await?;
+
+    if !user.active {
+        info!("user is deactivated");
+        return Err(AppError::UserDeactivated);
+    }
+
+    if user.credentials.is_empty() {
+        // Otherwise the password alone would keep logging in users whose only credentials
+        // were not approved.
+        if !app
+            .list_pending_credentials(Some(username.clone()))
+            .await?
+            .is_empty()
+        {
+            info!("credentials of the user are awaiting approval");
+            set_page_error(&session, PageError::CredentialPending).await?;
+            return Err(AppError::CredentialPending);
+        }
+
+        // Users who logged in with a database password are in the enrollment flow too, which
+        // they have to skip explicitly.
+        if policy.require_enrollment
+            || session
+                .get::<Enrollment>(SESSIONKEY_ENROLLMENT)
+                .await?
+                .is_some()
+        {
+            info!("user has to enroll a credential");
+            return Err(AppError::EnrollmentRequired);
+        }
+
+        info!("user does not have any credentials");
+        session.cycle_id().await?;
+        if let Err(e) = session.insert(SESSIONKEY_LOGGEDIN, true).await {
+            error!("session.insert: {e}");
+            return Err(AppError::BadSession);
+        }
+        session
+            .insert(
+                SESSIONKEY_AUTHCONTEXT,
+                AuthContext::new(AuthMethod::Password, None, false),
+            )
+            .await?;
+
+        return Err(AppError::NoUserCredentials);
+    }
+
+    // Users whose credentials are all blocked are not treated like users without any, they
+    // have to recover their account instead.
+    let passkeys = usable_passkeys(&user.credentials)?;
+
+    let Ok((req_chal, passkey_auth)) = timing::measure_sync("ceremony", || {
+        webauthn.start_passkey_authentication(&passkeys)
+    }) else {
+        counter!("failed_authentications").increment(1);
+        pow.record_failure();
+        return Err(AppError::WebauthnFailed);
+    };
+
+    let ceremony_id = insert_pending_ceremony(
+        &app,
+        &session,
+        SESSIONKEY_PASSKEYAUTHENTICATION,
+        passkey_auth,
+        &policy,
+    )
+    .await?;
+
+    let proof_of_work = pow.challenge();
+    match proof_of_work.as_ref() {
+        Some(proof_of_work) => {
+            session
+                .insert(SESSIONKEY_PROOFOFWORK, proof_of_work)
+                .await?
+        }
+        None => _ = session.remove_value(SESSIONKEY_PROOFOFWORK).await?,
+    }
+
+    let captcha = captcha
+        .as_ref()
+        .zip(ip)
+        .and_then(|(captcha, ip)| captcha.challenge(ip));
+    match captcha {
+        Some(_) => session.insert(SESSIONKEY_CAPTCHA, true).await?,
+        None => _ = session.remove_value(SESSIONKEY_CAPTCHA).await?,
+    }
+
+    Ok((
+        ceremony_id,
+        Json(AuthenticateStartResponsePayload {
+            challenge: req_chal,
+            proof_of_work,
+            captcha,
+        }),
+    ))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticateEndResponsePayload {
+    /// Set when the credential used is older than the maximum credential age, in which case the
+    /// user should register a replacement.
+    must_reenroll: bool,
+}
+
+#[debug_handler(state = AppState)]
+#[allow(clippy::too_many_arguments)]
+pub async fn authenticate_end_handler(
+    session: Session,
+    headers: HeaderMap,
+    ClientIp(ip): ClientIp,
+    shared_state: State<SharedAppState>,
+    webauthn: State<Arc<Webauthn>>,
+    ceremony_log: State<Option<Arc<CeremonyLog>>>,
+    pow: State<Arc<ProofOfWork>>,
+    throttle: State<Arc<LoginThrottle>>,
+    captcha: State<Option<Arc<Captcha>>>,
+    policy: State<Arc<Policy>>,
+    payload: extract::Json<WithAttachment<PublicKeyCredential>>,
+) -> HandlerResult<Json<AuthenticateEndResponsePayload>> {
+    trace!("authenticate_end_handler");
+
+    // Refused before anything else, so that a throttled client cannot keep making the server
+    // verify proofs-of-work, CAPTCHAs or assertions.
+    let username = session.get::<String>(SESSIONKEY_USERNAME).await?;
+    if throttle.is_throttled(ip, username.as_deref()) {
+        info!("too many failed logins");
+        return Err(AppError::TooManyFailures);
+    }
+
+    // Check the (cheap) proof-of-work before doing any signature verification. The requirement
+    // stays until it is solved, so failing it does not let the client retry without a nonce.
+    if let Some(proof_of_work) = session
+        .get::<ProofOfWorkChallenge>(SESSIONKEY_PROOFOFWORK)
+        .await?
+    {
+        let solved = headers
+            .get("x-proof-of-work")
+            .and_then(|nonce| nonce.to_str().ok())
+            .is_some_and(|nonce| proof_of_work.verify(nonce));
+        if !solved {
+            counter!("failed_proofs_of_work").increment(1);
+            return Err(AppError::ProofOfWorkRequired);
+        }
+        _ = session.remove_value(SESSIONKEY_PROOFOFWORK).await?;
+    }
+
+    let captcha = captcha.0.zip(ip);
+    // Like the proof-of-work, the requirement stays until a CAPTCHA is solved.
+    if session.get::<bool>(SESSIONKEY_CAPTCHA).await?.is_some() {
+        if let Some((captcha, ip)) = captcha.as_ref() {
+            let token = headers
+                .get("x-captcha-response")
+                .and_then(|token| token.to_str().ok())
+                .unwrap_or_default();
+            if !captcha.verify(token, *ip).await {
+                counter!("failed_captchas").increment(1);
+                return Err(AppError::CaptchaFailed);
+            }
+        }
+        _ = session.remove_value(SESSIONKEY_CAPTCHA).await?;
+    }
+
+    let passkey_authentication: PasskeyAuthentication = take_pending_ceremony(
+        &*shared_state.read().await,
+        &session,
+        &headers,
+        SESSIONKEY_PASSKEYAUTHENTICATION,
+    )
+    .await?;
+
+    let Ok(auth_result) = timing::measure_sync("ceremony", || {
+        webauthn.finish_passkey_authentication(&payload.0, &passkey_authentication)
+    })
+    .inspect_err(|e| {
+        log_failed_authentication(
+            &ceremony_log,
+            "/api/authenticate",
+            username.clone(),
+            &headers,
+            &payload.0,
+            e,
+        )
+    }) else {
+        counter!("failed_authentications").increment(1);
+        pow.record_failure();
+        throttle.record_failure(ip, username.as_deref());
+        if let Some((captcha, ip)) = captcha {
+            captcha.record_failure(ip);
+        }
+        set_page_error(&session, PageError::AuthenticationFailed).await?;
+        shared_state
+            .read()
+            .await
+            .record_event("authentication_failed", username, None)
+            .await?;
+        return Err(AppError::WebauthnFailed);
+    };
+
+    let state = shared_state.read().await;
+    consume_assertion_challenge(&state, &payload.0, &policy).await?;
+
+    // The user may have been deactivated after the ceremony was started.
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+    if !state.user_is_active(username.clone()).await? {
+        return Err(AppError::UserDeactivated);
+    }
+
+    // Expired credentials still log the user in, but they are asked to replace them.
+    let must_reenroll = match policy.credential_max_age {
+        Some(_) => policy.credential_is_expired(
+            state.credential_created_at(auth_result.cred_id()).await?,
+            unix_now(),
+        ),
+        None => false,
+    };
+
+    let auth_context = AuthContext::new(
+        AuthMethod::Webauthn,
+        Some(auth_result.cred_id()),
+        auth_result.user_verified(),
+    );
+
+    state
+        .update_credential(auth_result, payload.attachment)
+        .await?;
+
+    // Issue a new session ID (deleting the old session) so that an ID observed before login
+    // cannot be used to ride on the authenticated session.
+    session.cycle_id().await?;
+
+    if let Err(e) = session.insert(SESSIONKEY_LOGGEDIN, true).await {
+        error!("session.insert: {e}");
+        return Err(AppError::BadSession);
+    }
+
+    session
+        .insert(SESSIONKEY_RECENTLYVERIFIEDAT, unix_now())
+        .await?;
+    session.insert(SESSIONKEY_AUTHCONTEXT, auth_context).await?;
+
+    if must_reenroll {
+        session.insert(SESSIONKEY_MUSTREENROLL, true).await?;
+    }
+
+    state
+        .record_event("authentication_succeeded", Some(username.clone()), None)
+        .await?;
+    throttle.record_success(&username);
+    if let Some((captcha, ip)) = captcha {
+        captcha.record_success(ip);
+    }
+    counter!("successful_authentications").increment(1);
+
+    Ok(Json(AuthenticateEndResponsePayload { must_reenroll }))
+}
+
+/// Starts a fresh WebAuthn assertion for an already logged in user, used to confirm presence
+/// before sensitive actions.
+#[debug_handler(state = AppState)]
+pub async fn step_up_start_handler(
+    session: Session,
+    shared_state: State<SharedAppState>,
+    webauthn: State<Arc<Webauthn>>,
+    policy: State<Arc<Policy>>,
+) -> HandlerResult<(CeremonyId, Json<RequestChallengeResponse>)> {
+    trace!("step_up_start_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let user = shared_state
+        .read()
+        .await
+        .get_user_with_credentials(username)
+        .await?;
+
+    if user.credentials.is_empty() {
+        info!("user does not have any credentials to step up with");
+        return Err(AppError::NoUserCredentials);
+    }
+
+    let passkeys = usable_passkeys(&user.credentials)?;
+
+    let Ok((req_chal, passkey_auth)) = timing::measure_sync("ceremony", || {
+        webauthn.start_passkey_authentication(&passkeys)
+    }) else {
+        return Err(AppError::WebauthnFailed);
+    };
+
+    let ceremony_id = insert_pending_ceremony(
+        &*shared_state.read().await,
+        &session,
+        SESSIONKEY_PASSKEYSTEPUP,
+        passkey_auth,
+        &policy,
+    )
+    .await?;
+
+    Ok((ceremony_id, Json(req_chal)))
+}
+
+#[debug_handler(state = AppState)]
+pub async fn step_up_end_handler(
+    session: Session,
+    headers: HeaderMap,
+    shared_state: State<SharedAppState>,
+    webauthn: State<Arc<Webauthn>>,
+    ceremony_log: State<Option<Arc<CeremonyLog>>>,
+    policy: State<Arc<Policy>>,
+    payload: extract::Json<WithAttachment<PublicKeyCredential>>,
+) -> HandlerResult<()> {
+    trace!("step_up_end_handler");
+
+    let passkey_authentication: PasskeyAuthentication = take_pending_ceremony(
+        &*shared_state.read().await,
+        &session,
+        &headers,
+        SESSIONKEY_PASSKEYSTEPUP,
+    )
+    .await?;
+
+    let username = session.get::<String>(SESSIONKEY_USERNAME).await?;
+    let Ok(auth_result) = timing::measure_sync("ceremony", || {
+        webauthn.finish_passkey_authentication(&payload.0, &passkey_authentication)
+    })
+    .inspect_err(|e| {
+        log_failed_authentication(
+            &ceremony_log,
+            "/api/step-up",
+            username.clone(),
+            &headers,
+            &payload.0,
+            e,
+        )
+    }) else {
+        counter!("failed_authentications").increment(1);
+        return Err(AppError::WebauthnFailed);
+    };
+
+    let state = shared_state.read().await;
+    consume_assertion_challenge(&state, &payload.0, &policy).await?;
+    state
+        .update_credential(auth_result, payload.attachment)
+        .await?;
+
+    session
+        .insert(SESSIONKEY_RECENTLYVERIFIEDAT, unix_now())
+        .await?;
+
+    counter!("successful_authentications").increment(1);
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct ValidateQueryParams {
+    /// Maximum number of seconds since the user last completed a WebAuthn assertion.
+    pub max_age: Option<u64>,
+    /// Describe how the session was established in `X-Auth-*` response headers.
+    #[serde(default)]
+    pub context: bool,
+    /// Name of the app in the config file whose requirements the session has to satisfy.
+    pub app: Option<String>,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn validate_handler(
+    params: Query<ValidateQueryParams>,
+    session: Session,
+    shared_state: State<SharedAppState>,
+    config: State<Arc<Config>>,
+) -> HandlerResult<Response> {
+    trace!("validate_handler");
+
+    if let Some(max_age) = params.max_age {
+        if !verified_within(&session, max_age).await? {
+            return Ok(StatusCode::UNAUTHORIZED.into_response());
+        }
+    }
+
+    if let Some(name) = params.app.as_ref() {
+        let Some(app) = config.apps.get(name) else {
+            warn!("validation requested for unknown app {name}");
+            return Ok(StatusCode::FORBIDDEN.into_response());
+        };
+        let auth_context = session.get::<AuthContext>(SESSIONKEY_AUTHCONTEXT).await?;
+        let groups = if app.required_groups.is_empty() {
+            vec![]
+        } else {
+            session_groups(&session, &shared_state).await?
+        };
+        let status = app_status(app, auth_context.as_ref(), &groups);
+        if status != StatusCode::OK {
+            return Ok(status.into_response());
+        }
+    }
+
+    // Sessions established before auth contexts were recorded have none to describe.
+    if params.context {
+        if let Some(auth_context) = session.get::<AuthContext>(SESSIONKEY_AUTHCONTEXT).await? {
+            let mut headers = auth_context.headers();
+            if let Some(operator) = session
+                .get::<Impersonation>(SESSIONKEY_IMPERSONATION)
+                .await?
+                .and_then(|impersonation| HeaderValue::from_str(&impersonation.operator).ok())
+            {
+                headers.insert("x-auth-impersonated-by", operator);
+            }
+            return Ok((StatusCode::OK, headers).into_response());
+        }
+    }
+
+    Ok(StatusCode::OK.into_response())
+}
+
+/// `/api/validate` without its parameters, for proxies asking on every request. The session is
+/// read from the store directly instead of through `Session`, so that nothing is written, its
+/// expiry is not refreshed and no cookie is set. Sessions are not bound to the client here, only
+/// checked against the client they were bound to.
+#[debug_handler(state = AppState)]
+pub async fn validate_fast_handler(
+    headers: HeaderMap,
+    State(key): State<Key>,
+    session_store: State<SqliteSessionStore>,
+    shared_state: State<SharedAppState>,
+    policy: State<Arc<Policy>>,
+) -> StatusCode {
+    let valid = match session_is_valid(
+        &headers,
+        &key,
+        &session_store,
+        &shared_state,
+        policy.session_binding,
+    )
+    .await
+    {
+        Ok(valid) => valid,
+        Err(e) => {
+            error!("could not validate session: {e:#}");
+            false
+        }
+    };
+
+    if valid {
+        counter!("authorized_requests").increment(1);
+        StatusCode::OK
+    } else {
+        counter!("unauthorized_requests").increment(1);
+        StatusCode::UNAUTHORIZED
+    }
+}
+
+/// Whether the session cookie in `headers` belongs to a logged in session of an active user, as
+/// `require_logged_in` would decide without writing to the session.
+async fn session_is_valid(
+    headers: &HeaderMap,
+    key: &Key,
+    session_store: &SqliteSessionStore,
+    shared_state: &SharedAppState,
+    binding: SessionBinding,
+) -> anyhow::Result<bool> {
+    let Some(cookie) = request_cookie(headers, SESSION_COOKIE) else {
+        return Ok(false);
+    };
+    let Some(record) = session_store.load_by_cookie(key, cookie).await? else {
+        return Ok(false);
+    };
+
+    let logged_in = record.data.get(SESSIONKEY_LOGGEDIN) == Some(&Value::Bool(true));
+    let impersonation_expired =
+        record
+            .data
+            .get(SESSIONKEY_IMPERSONATION)
+            .is_some_and(|impersonation| {
+                serde_json::from_value::<Impersonation>(impersonation.clone())
+                    .map_or(true, |impersonation| impersonation.expires_at <= unix_now())
+            });
+    let Some(username) = record.data.get(SESSIONKEY_USERNAME).and_then(Value::as_str) else {
+        return Ok(false);
+    };
+    if !logged_in || impersonation_expired {
+        return Ok(false);
+    }
+
+    if binding != SessionBinding::Off {
+        let bound = record
+            .data
+            .get(SESSIONKEY_CLIENTFINGERPRINT)
+            .and_then(|bound| serde_json::from_value::<ClientFingerprint>(bound.clone()).ok());
+        let fingerprint = ClientFingerprint::from_headers(headers);
+        if let Some(bound) = bound.filter(|bound| *bound != fingerprint) {
+            counter!("session_binding_mismatches").increment(1);
+            warn!("session of {username} was bound to {bound}, but is used from {fingerprint}");
+            if binding == SessionBinding::Enforce {
+                return Ok(false);
+            }
+        }
+    }
+
+    Ok(shared_state
+        .read()
+        .await
+        .user_is_active(username.to_string())
+        .await?)
+}
+
+/// Whether a logged in session may go on to `app`. A weak or old login can be fixed by logging
+/// in again (`401 Unauthorized`), missing groups cannot (`403 Forbidden`).
+fn app_status(
+    app: &ProtectedApp,
+    auth_context: Option<&AuthContext>,
+    groups: &[String],
+) -> StatusCode {
+    if !AuthContext::satisfies(auth_context, app, unix_now()) {
+        StatusCode::UNAUTHORIZED
+    } else if !app
+        .required_groups
+        .iter()
+        .all(|group| groups.contains(group))
+    {
+        StatusCode::FORBIDDEN
+    } else {
+        StatusCode::OK
+    }
+}
+
+async fn session_groups(
+    session: &Session,
+    shared_state: &SharedAppState,
+) -> HandlerResult<Vec<String>> {
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+    shared_state.read().await.user_groups(username).await
+}
+
+/// Upper bound of the number of requests in `POST /api/validate/batch`.
+const MAX_VALIDATE_BATCH: usize = 100;
+
+#[derive(Deserialize)]
+pub struct ValidateBatchItem {
+    path: String,
+    method: String,
+}
+
+#[derive(Deserialize)]
+pub struct ValidateBatchRequestPayload {
+    requests: Vec<ValidateBatchItem>,
+}
+
+#[derive(Serialize)]
+pub struct ValidateBatchDecision {
+    path: String,
+    method: String,
+    /// The app in the config file whose `paths` the request matched, if any.
+    app: Option<String>,
+    /// What `/api/validate?app=<app>` would answer, `200` for requests to no app.
+    status: u16,
+}
+
+#[derive(Serialize)]
+pub struct ValidateBatchResponsePayload {
+    data: Vec<ValidateBatchDecision>,
+}
+
+/// Decides for each of the given requests whether the session may make it, as `/api/validate`
+/// does for one, so that proxies and single-page apps can learn what a user may access in one
+/// round trip. Decisions are in the order of the requests.
+#[debug_handler(state = AppState)]
+pub async fn validate_batch_handler(
+    session: Session,
+    shared_state: State<SharedAppState>,
+    config: State<Arc<Config>>,
+    payload: extract::Json<ValidateBatchRequestPayload>,
+) -> HandlerResult<Json<ValidateBatchResponsePayload>> {
+    trace!("validate_batch_handler");
+
+    let requests = payload.0.requests;
+    if requests.len() > MAX_VALIDATE_BATCH {
+        return Err(AppError::BadInput);
+    }
+
+    let auth_context = session.get::<AuthContext>(SESSIONKEY_AUTHCONTEXT).await?;
+    let mut groups = None;
+    let mut data = Vec::with_capacity(requests.len());
+    for ValidateBatchItem { path, method } in requests {
+        let (app, status) = match config.app_for(&path, &method) {
+            Some((name, app)) => {
+                // Looked up once, and only if some app needs them.
+                let groups = match groups.as_ref() {
+                    Some(groups) => groups,
+                    None if app.required_groups.is_empty() => &vec![],
+                    None => groups.insert(session_groups(&session, &shared_state).await?),
+                };
+                (
+                    Some(name.to_string()),
+                    app_status(app, auth_context.as_ref(), groups),
+                )
+            }
+            None => (None, StatusCode::OK),
+        };
+        data.push(ValidateBatchDecision {
+            path,
+            method,
+            app,
+            status: status.as_u16(),
+        });
+    }
+
+    Ok(Json(ValidateBatchResponsePayload { data }))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WhoamiResponsePayload {
+    pub username: Option<String>,
+    pub logged_in: bool,
+    /// When the user logged in, in seconds since the epoch. `None` for sessions established
+    /// before this was recorded.
+    pub auth_time: Option<u64>,
+    pub groups: Vec<String>,
+    /// The admin who opened the session with an impersonation link, if any.
+    pub impersonated_by: Option<String>,
+}
+
+/// Describes the session of the request, so that frontends can render it without trying
+/// requests that need a login. Sessions that are not (or no longer) logged in, e.g. of
+/// deactivated users, are described as anonymous.
+#[debug_handler(state = AppState)]
+pub async fn whoami_handler(
+    LoggedIn(logged_in): LoggedIn,
+    session: Session,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<Json<WhoamiResponsePayload>> {
+    trace!("whoami_handler");
+
+    let anonymous = WhoamiResponsePayload {
+        username: None,
+        logged_in: false,
+        auth_time: None,
+        groups: vec![],
+        impersonated_by: None,
+    };
+    if !logged_in {
+        return Ok(Json(anonymous));
+    }
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Ok(Json(anonymous));
+    };
+
+    let app = shared_state.read().await;
+    if !app.user_is_active(username.clone()).await? {
+        return Ok(Json(anonymous));
+    }
+
+    Ok(Json(WhoamiResponsePayload {
+        groups: app.user_groups(username.clone()).await?,
+        username: Some(username),
+        logged_in: true,
+        auth_time: session
+            .get::<AuthContext>(SESSIONKEY_AUTHCONTEXT)
+            .await?
+            .map(|auth_context| auth_context.auth_time),
+        impersonated_by: session
+            .get::<Impersonation>(SESSIONKEY_IMPERSONATION)
+            .await?
+            .map(|impersonation| impersonation.operator),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct GetCredentialsQueryParams {
+    /// Only list credentials carrying this tag.
+    pub tag: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GetCredentialsResponsePayload {
+    pub data: Vec<CredentialIDWithName>,
+    /// Credentials that cannot be used until an admin approves them.
+    pub pending: Vec<PendingCredential>,
+    /// Tags the deployment expects on some credential (see `Policy::expected_credential_tags`)
+    /// that none of the user's credentials carries, regardless of `tag`.
+    pub missing_tags: Vec<String>,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn get_credentials_api_handler(
+    params: Query<GetCredentialsQueryParams>,
+    session: Session,
+    shared_state: State<SharedAppState>,
+    policy: State<Arc<Policy>>,
+) -> HandlerResult<Json<GetCredentialsResponsePayload>> {
+    trace!("get_credentials_api_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let app = shared_state.read().await;
+    let user = app.get_user_with_credentials(username.clone()).await?;
+    let preferences = Preferences::from_settings(&app.user_settings(username.clone()).await?);
+
+    Ok(Json(GetCredentialsResponsePayload {
+        data: user
+            .credentials
+            .iter()
+            .filter(|c| params.tag.as_ref().is_none_or(|tag| c.tags.contains(tag)))
+            .map(|c| CredentialIDWithName::new(c, &preferences.timezone))
+            .collect(),
+        pending: app.list_pending_credentials(Some(username)).await?,
+        missing_tags: policy.missing_credential_tags(&user.credentials),
+    }))
+}
+
+/// Upper bound of `limit` of `GET /api/history`.
+const MAX_HISTORY_PAGE: usize = 100;
+
+#[derive(Deserialize)]
+pub struct HistoryQueryParams {
+    /// Maximum number of entries to return (default: 20, at most `MAX_HISTORY_PAGE`).
+    limit: Option<usize>,
+    /// Only return entries older than this one, i.e. the `next` of the previous page.
+    before: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct GetHistoryResponsePayload {
+    data: Vec<AuditEvent>,
+    /// The `before` of the next page, if there may be one.
+    next: Option<i64>,
+}
+
+/// Lists the logged in user's entries of the audit log, e.g. logins, failed attempts and
+/// registrations, newest first.
+#[debug_handler(state = AppState)]
+pub async fn get_history_handler(
+    params: Query<HistoryQueryParams>,
+    session: Session,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<Json<GetHistoryResponsePayload>> {
+    trace!("get_history_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let limit = params.limit.unwrap_or(20).clamp(1, MAX_HISTORY_PAGE);
+    let data = shared_state
+        .read()
+        .await
+        .user_history(username, params.before, limit)
+        .await?;
+    let next = data
+        .last()
+        .filter(|_| data.len() == limit)
+        .map(|event| event.id);
+
+    Ok(Json(GetHistoryResponsePayload { data, next }))
+}
+
+/// Replaces the tags of one of the logged in user's credentials with the given JSON array,
+/// returning the tags as stored.
+#[debug_handler(state = AppState)]
+pub async fn put_credential_tags_handler(
+    Path(handle): Path<String>,
+    session: Session,
+    shared_state: State<SharedAppState>,
+    payload: extract::Json<Vec<String>>,
+) -> HandlerResult<Json<Vec<String>>> {
+    trace!("put_credential_tags_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    Ok(Json(
+        shared_state
+            .read()
+            .await
+            .set_credential_tags(username, &handle, payload.0)
+            .await?,
+    ))
+}
+
+/// Deletes one of the logged in user's credentials, which needs a fresh assertion.
+#[debug_handler(state = AppState)]
+pub async fn delete_credentials_api_handler(
+    _: RequireFreshAuth,
+    Path(handle): Path<String>,
+    session: Session,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<StatusCode> {
+    trace!("delete_credentials_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let app = shared_state.read().await;
+    app.delete_credential(username, &handle).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Preferences of the logged in user, e.g. their language or the app to go to after logging in,
+/// stored server-side so that they follow the user across browsers.
+#[debug_handler(state = AppState)]
+pub async fn get_settings_handler(
+    session: Session,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<Json<BTreeMap<String, serde_json::Value>>> {
+    trace!("get_settings_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    Ok(Json(
+        shared_state.read().await.user_settings(username).await?,
+    ))
+}
+
+/// Replaces all settings of the logged in user with the given JSON object.
+#[debug_handler(state = AppState)]
+pub async fn put_settings_handler(
+    session: Session,
+    shared_state: State<SharedAppState>,
+    payload: extract::Json<BTreeMap<String, serde_json::Value>>,
+) -> HandlerResult<StatusCode> {
+    trace!("put_settings_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    shared_state
+        .read()
+        .await
+        .replace_user_settings(username, payload.0)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Deletes the logged in user along with their credentials and sessions, after they confirmed it
+/// with a fresh WebAuthn assertion.
+#[debug_handler(state = AppState)]
+pub async fn delete_self_handler(
+    _: RequireFreshAuth,
+    session: Session,
+    shared_state: State<SharedAppState>,
+    session_store: State<SqliteSessionStore>,
+) -> HandlerResult<StatusCode> {
+    trace!("delete_self_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    shared_state
+        .read()
+        .await
+        .delete_user(username.clone())
+        .await?;
+    session_store.delete_user(username).await.map_err(|err| {
+        error!("could not delete sessions: {err}");
+        AppError::UnknownError
+    })?;
+    session.flush().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A session of the user, without its ID, which would let anyone reading the export log in.
+#[derive(Serialize)]
+pub struct SessionExport {
+    /// Unix timestamp (in seconds).
+    expires_at: i64,
+    /// Whether this is the session the export was requested with.
+    current: bool,
+    data: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Serialize)]
+pub struct PrivacyExportResponsePayload {
+    #[serde(flatten)]
+    user: UserExport,
+    sessions: Vec<SessionExport>,
+}
+
+/// Everything stored about the logged in user, as JSON.
+#[debug_handler(state = AppState)]
+pub async fn privacy_export_handler(
+    session: Session,
+    shared_state: State<SharedAppState>,
+    session_store: State<SqliteSessionStore>,
+) -> HandlerResult<Json<PrivacyExportResponsePayload>> {
+    trace!("privacy_export_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let app = shared_state.read().await;
+    let user = app.export_user(username.clone()).await?;
+    let sessions = session_store
+        .user_sessions(username.clone())
+        .await
+        .map_err(|err| {
+            error!("could not list sessions: {err}");
+            AppError::UnknownError
+        })?
+        .into_iter()
+        .map(|record| SessionExport {
+            expires_at: record.expiry_date.unix_timestamp(),
+            current: Some(record.id) == session.id(),
+            data: record.data,
+        })
+        .collect();
+    app.record_event("data_exported", Some(username), None)
+        .await?;
+
+    Ok(Json(PrivacyExportResponsePayload { user, sessions }))
+}
+
+/// Like `delete_self_handler`, but also anonymizes the user's audit log entries.
+#[debug_handler(state = AppState)]
+pub async fn privacy_erase_handler(
+    _: RequireFreshAuth,
+    session: Session,
+    shared_state: State<SharedAppState>,
+    session_store: State<SqliteSessionStore>,
+) -> HandlerResult<StatusCode> {
+    trace!("privacy_erase_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    shared_state
+        .read()
+        .await
+        .erase_user(username.clone())
+        .await?;
+    session_store.delete_user(username).await.map_err(|err| {
+        error!("could not delete sessions: {err}");
+        AppError::UnknownError
+    })?;
+    session.flush().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// How many recovery codes users get when regenerating them.
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Serialize)]
+pub struct RecoveryCodesResponsePayload {
+    codes: Vec<String>,
+}
+
+/// Replaces all of the logged in user's recovery codes with new ones, which are only ever shown
+/// in this response.
+#[debug_handler(state = AppState)]
+pub async fn regenerate_recovery_codes_handler(
+    _: RequireFreshAuth,
+    session: Session,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<Json<RecoveryCodesResponsePayload>> {
+    trace!("regenerate_recovery_codes_handler");
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let codes = recovery::generate_codes(RECOVERY_CODE_COUNT);
+    shared_state
+        .read()
+        .await
+        .replace_recovery_codes(
+            username,
+            codes.iter().map(|code| recovery_code_hash(code)).collect(),
+        )
+        .await?;
+
+    Ok(Json(RecoveryCodesResponsePayload { codes }))
+}
+
+#[derive(Serialize)]
+pub struct CountResponsePayload {
+    count: usize,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn delete_user_credentials_admin_handler(
+    Path(username): Path<String>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<Json<CountResponsePayload>> {
+    trace!("delete_user_credentials_admin_handler");
+
+    let count = shared_state
+        .read()
+        .await
+        .delete_user_credentials(username)
+        .await?;
+
+    Ok(Json(CountResponsePayload { count }))
+}
+
+#[derive(Deserialize)]
+pub struct MoveCredentialsRequestPayload {
+    to: String,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn move_user_credentials_admin_handler(
+    Path(username): Path<String>,
+    shared_state: State<SharedAppState>,
+    payload: extract::Json<MoveCredentialsRequestPayload>,
+) -> HandlerResult<Json<CountResponsePayload>> {
+    trace!("move_user_credentials_admin_handler");
+
+    let count = shared_state
+        .read()
+        .await
+        .move_credentials(username, payload.0.to)
+        .await?;
+
+    Ok(Json(CountResponsePayload { count }))
+}
+
+#[derive(Deserialize)]
+pub struct RenameUserRequestPayload {
+    to: String,
+}
+
+/// Renames a user after their username changed upstream, merging them into the user of the new
+/// name if there already is one. Responds with the number of credentials that were moved.
+#[debug_handler(state = AppState)]
+pub async fn rename_user_admin_handler(
+    Path(username): Path<String>,
+    shared_state: State<SharedAppState>,
+    session_store: State<SqliteSessionStore>,
+    payload: extract::Json<RenameUserRequestPayload>,
+) -> HandlerResult<Json<CountResponsePayload>> {
+    trace!("rename_user_admin_handler");
+
+    let to = payload.0.to;
+    let count = shared_state
+        .read()
+        .await
+        .rename_user(username.clone(), to.clone())
+        .await?;
+    session_store
+        .rename_user(username, to)
+        .await
+        .map_err(|err| {
+            error!("could not rename sessions: {err}");
+            AppError::UnknownError
+        })?;
+
+    Ok(Json(CountResponsePayload { count }))
+}
+
+#[derive(Serialize)]
+pub struct IssueRecoveryResponsePayload {
+    url: Url,
+    expires_at: u64,
+}
+
+/// Issues a single-use link that lets a locked out user replace all of their credentials with a
+/// new one.
+#[debug_handler(state = AppState)]
+pub async fn issue_recovery_admin_handler(
+    Path(username): Path<String>,
+    shared_state: State<SharedAppState>,
+    recovery: State<Arc<RecoveryTokens>>,
+) -> HandlerResult<Json<IssueRecoveryResponsePayload>> {
+    trace!("issue_recovery_admin_handler");
+
+    let claims = RecoveryClaims {
+        id: Uuid::new_v4().to_string(),
+        username,
+        expires_at: unix_now() + recovery.ttl.as_secs(),
+    };
+
+    shared_state
+        .read()
+        .await
+        .issue_recovery_token(
+            claims.username.clone(),
+            claims.id.clone(),
+            claims.expires_at,
+        )
+        .await?;
+
+    Ok(Json(IssueRecoveryResponsePayload {
+        url: recovery.link(&recovery.sign(&claims)),
+        expires_at: claims.expires_at,
+    }))
+}
+
+/// How long impersonation links can be opened after they were issued. The session they open
+/// lasts `Policy::impersonation_max_age`.
+const IMPERSONATION_LINK_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Deserialize)]
+pub struct IssueImpersonationRequestPayload {
+    /// Who the link is issued to, recorded in the audit log and shown to protected apps.
+    operator: String,
+    /// Why, e.g. a ticket number, recorded in the audit log.
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct IssueImpersonationResponsePayload {
+    url: Url,
+    expires_at: u64,
+}
+
+/// Issues a single-use link that logs whoever opens it in as the user for
+/// `Policy::impersonation_max_age`, so that helpdesk staff can reproduce what the user sees on
+/// protected apps without their authenticator.
+#[debug_handler(state = AppState)]
+pub async fn issue_impersonation_admin_handler(
+    Path(username): Path<String>,
+    shared_state: State<SharedAppState>,
+    base_url: State<Arc<Url>>,
+    payload: extract::Json<IssueImpersonationRequestPayload>,
+) -> HandlerResult<Json<IssueImpersonationResponsePayload>> {
+    trace!("issue_impersonation_admin_handler");
+
+    let IssueImpersonationRequestPayload { operator, reason } = payload.0;
+    if operator.trim().is_empty() {
+        return Err(AppError::BadInput);
+    }
+
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+    let token = general_purpose::URL_SAFE_NO_PAD.encode(token);
+    let expires_at = unix_now() + IMPERSONATION_LINK_TTL.as_secs();
+
+    shared_state
+        .read()
+        .await
+        .issue_impersonation(username, token.clone(), operator, reason, expires_at)
+        .await?;
+
+    let mut url = base_url.as_ref().clone();
+    url.set_path(&format!(
+        "{}/api/impersonate",
+        base_url.path().trim_end_matches('/')
+    ));
+    url.query_pairs_mut().append_pair("token", &token);
+
+    Ok(Json(IssueImpersonationResponsePayload { url, expires_at }))
+}
+
+#[derive(Deserialize)]
+pub struct AuditLogQueryParams {
+    /// Maximum number of entries to return (default: 100).
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct GetAuditLogResponsePayload {
+    data: Vec<AuditEvent>,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn get_audit_log_admin_handler(
+    params: Query<AuditLogQueryParams>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<Json<GetAuditLogResponsePayload>> {
+    trace!("get_audit_log_admin_handler");
+
+    let data = shared_state
+        .read()
+        .await
+        .audit_log(params.limit.unwrap_or(100))
+        .await?;
+
+    Ok(Json(GetAuditLogResponsePayload { data }))
+}
+
+/// How often the audit log is checked for new entries to stream.
+const EVENTS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+pub struct EventsQueryParams {
+    /// Comma separated event types to stream (e.g. `authentication_failed,recovery_issued`).
+    /// All events are streamed by default.
+    #[serde(rename = "type")]
+    types: Option<String>,
+}
+
+/// Streams audit log entries as server-sent events as they are recorded. Clients reconnecting
+/// with `Last-Event-ID` resume after that entry, otherwise only new entries are streamed.
+#[debug_handler(state = AppState)]
+pub async fn get_events_admin_handler(
+    headers: HeaderMap,
+    params: Query<EventsQueryParams>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<Sse<impl Stream<Item = Result<Event, axum::Error>>>> {
+    trace!("get_events_admin_handler");
+
+    let types: Option<Vec<String>> = params
+        .0
+        .types
+        .map(|types| types.split(',').map(|t| t.trim().to_string()).collect());
+
+    let last_event_id = match headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse::<i64>().ok())
+    {
+        Some(id) => id,
+        None => shared_state.read().await.last_audit_event_id().await?,
+    };
+
+    let mut interval = tokio::time::interval(EVENTS_POLL_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    let events = stream::unfold(
+        (shared_state.0, last_event_id, interval),
+        |(shared_state, mut last_event_id, mut interval)| async move {
+            let events = loop {
+                interval.tick().await;
+                match shared_state
+                    .read()
+                    .await
+                    .audit_log_after(last_event_id, 100)
+                    .await
+                {
+                    Ok(events) if !events.is_empty() => break events,
+                    Ok(_) => {}
+                    Err(e) => error!("could not read audit log: {e}"),
+                }
+            };
+            last_event_id = events.last().map_or(last_event_id, |event| event.id);
+            Some((events, (shared_state, last_event_id, interval)))
+        },
+    )
+    .flat_map(move |events| {
+        let matching: Vec<_> = events
+            .into_iter()
+            .filter(|event| {
+                types
+                    .as_ref()
+                    .is_none_or(|types| types.contains(&event.event))
+            })
+            .map(|event| {
+                Event::default()
+                    .id(event.id.to_string())
+                    .event(&event.event)
+                    .json_data(&event)
+            })
+            .collect();
+        stream::iter(matching)
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Upper bound of `limit` of `GET /api/admin/users`.
+const MAX_USERS_PAGE: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct UsersQueryParams {
+    /// Only users whose username or one of whose aliases starts with this.
+    search: Option<String>,
+    has_credentials: Option<bool>,
+    /// Only deactivated users, or only active ones.
+    locked: Option<bool>,
+    /// Maximum number of users to return (default: 100, at most `MAX_USERS_PAGE`).
+    limit: Option<usize>,
+    /// Only return users after this username, i.e. the `next` of the previous page.
+    after: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GetAdminUsersResponsePayload {
+    data: Vec<UserSummary>,
+    /// The `after` of the next page, if there may be one.
+    next: Option<String>,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn get_users_admin_handler(
+    params: Query<UsersQueryParams>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<Json<GetAdminUsersResponsePayload>> {
+    trace!("get_users_admin_handler");
+
+    let Query(params) = params;
+    let limit = params.limit.unwrap_or(100).clamp(1, MAX_USERS_PAGE);
+    let filter = UserFilter {
+        search: params.search,
+        has_credentials: params.has_credentials,
+        locked: params.locked,
+    };
+    let data = shared_state
+        .read()
+        .await
+        .search_users(filter, params.after, Some(limit))
+        .await?;
+    let next = data
+        .last()
+        .filter(|_| data.len() == limit)
+        .map(|user| user.username.clone());
+
+    Ok(Json(GetAdminUsersResponsePayload { data, next }))
+}
+
+#[derive(Deserialize)]
+pub struct AddAliasRequestPayload {
+    alias: String,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn add_alias_admin_handler(
+    Path(username): Path<String>,
+    shared_state: State<SharedAppState>,
+    payload: extract::Json<AddAliasRequestPayload>,
+) -> HandlerResult<StatusCode> {
+    trace!("add_alias_admin_handler");
+
+    shared_state
+        .read()
+        .await
+        .add_user_alias(username, payload.0.alias)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler(state = AppState)]
+pub async fn remove_alias_admin_handler(
+    Path((username, alias)): Path<(String, String)>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<StatusCode> {
+    trace!("remove_alias_admin_handler");
+
+    shared_state
+        .read()
+        .await
+        .remove_user_alias(username, alias)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Exactly one of the fields has to be given.
+#[derive(Deserialize)]
+pub struct SetPasswordRequestPayload {
+    pub password: Option<String>,
+    /// An existing argon2 hash in PHC string format, e.g. when migrating from another system.
+    pub hash: Option<String>,
+}
+
+/// Sets the password a user missing from the password file logs in with when
+/// `--database-passwords` is given, creating the user if needed.
+#[debug_handler(state = AppState)]
+pub async fn set_password_admin_handler(
+    Path(username): Path<String>,
+    shared_state: State<SharedAppState>,
+    payload: extract::Json<SetPasswordRequestPayload>,
+) -> HandlerResult<StatusCode> {
+    trace!("set_password_admin_handler");
+
+    let hash = match (&payload.password, &payload.hash) {
+        (Some(password), None) if !password.is_empty() => Argon2::default()
+            .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+            .map_err(|e| {
+                error!("could not hash password: {e}");
+                AppError::UnknownError
+            })?
+            .to_string(),
+        (None, Some(hash))
+            if PasswordHash::new(hash)
+                .is_ok_and(|hash| hash.algorithm.as_str().starts_with("argon2")) =>
+        {
+            hash.clone()
+        }
+        _ => return Err(AppError::BadInput),
+    };
+
+    let app = shared_state.read().await;
+    app.get_user_with_credentials(username.clone()).await?;
+    app.set_password_hash(username, Some(hash)).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler(state = AppState)]
+pub async fn remove_password_admin_handler(
+    Path(username): Path<String>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<StatusCode> {
+    trace!("remove_password_admin_handler");
+
+    shared_state
+        .read()
+        .await
+        .set_password_hash(username, None)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler(state = AppState)]
+pub async fn activate_user_admin_handler(
+    Path(username): Path<String>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<StatusCode> {
+    trace!("activate_user_admin_handler");
+
+    shared_state
+        .read()
+        .await
+        .set_user_active(username, true)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler(state = AppState)]
+pub async fn deactivate_user_admin_handler(
+    Path(username): Path<String>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<StatusCode> {
+    trace!("deactivate_user_admin_handler");
+
+    shared_state
+        .read()
+        .await
+        .set_user_active(username, false)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct AaguidQueryParams {
+    aaguid: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+pub struct GetAdminCredentialsResponsePayload {
+    data: Vec<CredentialSummary>,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn get_credentials_admin_handler(
+    params: Query<AaguidQueryParams>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<Json<GetAdminCredentialsResponsePayload>> {
+    trace!("get_credentials_admin_handler");
+
+    let data = shared_state
+        .read()
+        .await
+        .list_credentials(params.aaguid, None)
+        .await?;
+
+    Ok(Json(GetAdminCredentialsResponsePayload { data }))
+}
+
+/// The public key of a credential, for systems that want to verify signatures made with it
+/// outside of WebAuthn.
+#[derive(Serialize)]
+pub struct GetPublicKeyResponsePayload {
+    username: String,
+    name: String,
+    handle: String,
+    algorithm: COSEAlgorithm,
+    /// Base64url encoded CBOR COSE_Key.
+    cose: String,
+    /// PEM encoded SubjectPublicKeyInfo.
+    pem: String,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn get_public_key_admin_handler(
+    Path(handle): Path<String>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<Json<GetPublicKeyResponsePayload>> {
+    trace!("get_public_key_admin_handler");
+
+    let (username, name, passkey) = shared_state.read().await.find_credential(&handle).await?;
+    let key = passkey.get_public_key();
+    let pem = public_key::pem(key).map_err(|e| {
+        error!("could not encode public key: {e}");
+        AppError::UnknownError
+    })?;
+
+    Ok(Json(GetPublicKeyResponsePayload {
+        username,
+        name,
+        handle: credential_handle(passkey.cred_id()),
+        algorithm: *passkey.cred_algorithm(),
+        cose: general_purpose::URL_SAFE_NO_PAD.encode(public_key::cose(key)),
+        pem,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct ExpiringQueryParams {
+    /// Include credentials expiring within this many days (default: 30). Already expired
+    /// credentials are always included.
+    within_days: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ExpiringCredential {
+    #[serde(flatten)]
+    credential: CredentialSummary,
+    expires_at: u64,
+}
+
+#[derive(Serialize)]
+pub struct GetExpiringCredentialsResponsePayload {
+    data: Vec<ExpiringCredential>,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn get_expiring_credentials_admin_handler(
+    params: Query<ExpiringQueryParams>,
+    shared_state: State<SharedAppState>,
+    policy: State<Arc<Policy>>,
+) -> HandlerResult<Json<GetExpiringCredentialsResponsePayload>> {
+    trace!("get_expiring_credentials_admin_handler");
+
+    let Some(max_age) = policy.credential_max_age else {
+        return Ok(Json(GetExpiringCredentialsResponsePayload { data: vec![] }));
+    };
+
+    let within = params
+        .within_days
+        .unwrap_or(30)
+        .saturating_mul(24 * 60 * 60);
+    let created_before = unix_now()
+        .saturating_add(within)
+        .saturating_sub(max_age.as_secs());
+
+    let data = shared_state
+        .read()
+        .await
+        .list_credentials(None, Some(created_before))
+        .await?
+        .into_iter()
+        .filter_map(|credential| {
+            Some(ExpiringCredential {
+                expires_at: policy.credential_expires_at(credential.created_at)?,
+                credential,
+            })
+        })
+        .collect();
+
+    Ok(Json(GetExpiringCredentialsResponsePayload { data }))
+}
+
+#[debug_handler(state = AppState)]
+pub async fn delete_credentials_admin_handler(
+    params: Query<AaguidQueryParams>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<Json<CountResponsePayload>> {
+    trace!("delete_credentials_admin_handler");
+
+    // Refuse to delete every credential when the filter is forgotten.
+    let Some(aaguid) = params.aaguid else {
+        return Err(AppError::BadInput);
+    };
+
+    let count = shared_state
+        .read()
+        .await
+        .delete_credentials_by_aaguid(aaguid)
+        .await?;
+
+    Ok(Json(CountResponsePayload { count }))
+}
+
+#[derive(Serialize)]
+pub struct GetBlocklistResponsePayload {
+    data: Vec<BlocklistEntry>,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn get_blocklist_admin_handler(
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<Json<GetBlocklistResponsePayload>> {
+    trace!("get_blocklist_admin_handler");
+
+    let data = shared_state.read().await.list_blocklist().await?;
+
+    Ok(Json(GetBlocklistResponsePayload { data }))
+}
+
+/// Exactly one of `aaguid` and `credential_id` must be set.
+#[derive(Deserialize)]
+pub struct AddToBlocklistRequestPayload {
+    aaguid: Option<Uuid>,
+    credential_id: Option<CredentialID>,
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AddToBlocklistResponsePayload {
+    id: i64,
+    /// How many already registered credentials are now blocked.
+    count: usize,
+}
+
+/// Blocks an authenticator model (e.g. after a vulnerability disclosure) or a single
+/// credential. Matching credentials can no longer be registered or used to authenticate.
+#[debug_handler(state = AppState)]
+pub async fn add_to_blocklist_admin_handler(
+    shared_state: State<SharedAppState>,
+    payload: extract::Json<AddToBlocklistRequestPayload>,
+) -> HandlerResult<Json<AddToBlocklistResponsePayload>> {
+    trace!("add_to_blocklist_admin_handler");
+
+    let payload = payload.0;
+    let item = match (payload.aaguid, payload.credential_id) {
+        (Some(aaguid), None) => BlockedItem::Aaguid(aaguid),
+        (None, Some(cred_id)) => BlockedItem::Credential(cred_id),
+        _ => return Err(AppError::BadInput),
+    };
+
+    let (id, count) = shared_state
+        .read()
+        .await
+        .add_to_blocklist(item, payload.reason)
+        .await?;
+
+    Ok(Json(AddToBlocklistResponsePayload { id, count }))
+}
+
+#[debug_handler(state = AppState)]
+pub async fn delete_blocklist_admin_handler(
+    Path(id): Path<i64>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<StatusCode> {
+    trace!("delete_blocklist_admin_handler");
+
+    shared_state.read().await.remove_from_blocklist(id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+pub struct GetPendingCredentialsResponsePayload {
+    data: Vec<PendingCredential>,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn get_pending_credentials_admin_handler(
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<Json<GetPendingCredentialsResponsePayload>> {
+    trace!("get_pending_credentials_admin_handler");
+
+    let data = shared_state
+        .read()
+        .await
+        .list_pending_credentials(None)
+        .await?;
+
+    Ok(Json(GetPendingCredentialsResponsePayload { data }))
+}
+
+#[debug_handler(state = AppState)]
+pub async fn approve_pending_credential_admin_handler(
+    Path(id): Path<i64>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<StatusCode> {
+    trace!("approve_pending_credential_admin_handler");
+
+    shared_state
+        .read()
+        .await
+        .approve_pending_credential(id)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler(state = AppState)]
+pub async fn reject_pending_credential_admin_handler(
+    Path(id): Path<i64>,
+    shared_state: State<SharedAppState>,
+) -> HandlerResult<StatusCode> {
+    trace!("reject_pending_credential_admin_handler");
+
+    shared_state
+        .read()
+        .await
+        .reject_pending_credential(id)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+pub struct WellKnownWebauthnResponse {
+    origins: Vec<String>,
+}
+
+/// Serves the list of related origins, allowing passkeys registered under our RP ID to be used
+/// from other domains. See https://w3c.github.io/webauthn/#sctn-related-origins.
+pub async fn well_known_webauthn_handler(config: State<Arc<Config>>) -> Response {
+    trace!("well_known_webauthn_handler");
+
+    if config.related_origins.is_empty() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    Json(WellKnownWebauthnResponse {
+        origins: config
+            .related_origins
+            .iter()
+            .map(|url| url.origin().ascii_serialization())
+            .collect(),
+    })
+    .into_response()
+}
+
+/// Describes the build serving the request, so that the versions deployed across a fleet can be
+/// told apart. The same is exported as the `build_info` metric.
+pub async fn get_version_handler() -> Json<BuildInfo> {
+    trace!("get_version_handler");
+
+    Json(BuildInfo::new())
+}
+
+/// Sends password managers and browsers looking for where users manage their credentials to
+/// the credentials page, see https://w3c.github.io/webappsec-change-password-url/.
+pub async fn well_known_change_password_handler(base_url: State<Arc<Url>>) -> Redirect {
+    trace!("well_known_change_password_handler");
+
+    Redirect::to(&page_path(&base_url, "/credentials"))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    user_verification: UserVerificationPolicy,
+    /// `None` means any kind of authenticator may be used.
+    authenticator_attachment: Option<AuthenticatorAttachment>,
+    resident_key: ResidentKeyRequirement,
+    /// Whether users can log in by picking a credential without providing their username first.
+    discoverable_login: bool,
+    /// `None` means there is no limit on the number of credentials per user.
+    max_credentials: Option<usize>,
+    /// `None` means every algorithm supported by the server is allowed.
+    allowed_algorithms: Option<Vec<&'static str>>,
+    credential_max_age_seconds: Option<u64>,
+    ceremony_timeout_seconds: u64,
+    /// Whether only credentials protected with credProtect `userVerificationRequired` are
+    /// accepted, which rules out most platform authenticators.
+    require_cred_protect: bool,
+    /// Whether self-registered credentials have to be approved by an admin before they can be
+    /// used.
+    require_registration_approval: bool,
+}
+
+/// Describes how this deployment runs ceremonies, so that frontends can adapt to it instead of
+/// hardcoding assumptions (e.g. whether to ask for a username or to offer a security key).
+pub async fn get_capabilities_handler(policy: State<Arc<Policy>>) -> Json<Capabilities> {
+    trace!("get_capabilities_handler");
+
+    // These match what webauthn-rs requests for passkeys, and logins always start from the
+    // username given to the reverse proxy.
+    Json(Capabilities {
+        user_verification: UserVerificationPolicy::Required,
+        authenticator_attachment: None,
+        resident_key: ResidentKeyRequirement::Discouraged,
+        discoverable_login: false,
+        max_credentials: None,
+        allowed_algorithms: (!policy.allowed_algorithms.is_empty()).then(|| {
+            policy
+                .allowed_algorithms
+                .iter()
+                .map(algorithm_name)
+                .collect()
+        }),
+        credential_max_age_seconds: policy.credential_max_age.map(|max_age| max_age.as_secs()),
+        ceremony_timeout_seconds: policy.ceremony_timeout.as_secs(),
+        require_cred_protect: policy.require_cred_protect,
+        require_registration_approval: policy.require_approval,
+    })
+}
+
+/// Ready unless the startup self-test (see `SelfTest`) failed, in which case the failure is
+/// returned so that it shows up wherever readiness is checked, or the database is down (see
+/// `DatabaseHealth`), or the instance is draining (see `Drain`).
+pub async fn readyz_handler(
+    self_test: State<Arc<SelfTest>>,
+    database_health: State<Arc<DatabaseHealth>>,
+    drain: State<Arc<Drain>>,
+) -> Response {
+    trace!("readyz_handler");
+
+    if drain.is_draining() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "draining\n").into_response();
+    }
+    if database_health.is_down() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the database is unavailable\n",
+        )
+            .into_response();
+    }
+    match self_test.as_ref() {
+        SelfTest::Failed(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("self-test failed: {e}\n"),
+        )
+            .into_response(),
+        SelfTest::Skipped | SelfTest::Passed => "ok\n".into_response(),
+    }
+}
+
+#[derive(Serialize)]
+pub struct DrainResponsePayload {
+    /// Unix timestamp (in seconds) of when the grace period ends and the server shuts down.
+    ends_at: u64,
+}
+
+/// Starts draining the instance (see `Drain`), or tells when it shuts down if it is already.
+#[debug_handler(state = AppState)]
+pub async fn drain_admin_handler(
+    drain: State<Arc<Drain>>,
+) -> (StatusCode, Json<DrainResponsePayload>) {
+    trace!("drain_admin_handler");
+
+    (
+        StatusCode::ACCEPTED,
+        Json(DrainResponsePayload {
+            ends_at: drain.start(),
+        }),
+    )
+}
+
+#[derive(Serialize)]
+pub struct GetFailedCeremoniesResponsePayload {
+    data: Vec<FailedCeremony>,
+}
+
+/// The failed ceremonies kept by `--debug-ceremonies`, newest first. Only routed with it.
+#[debug_handler(state = AppState)]
+pub async fn get_failed_ceremonies_admin_handler(
+    ceremony_log: State<Option<Arc<CeremonyLog>>>,
+) -> Json<GetFailedCeremoniesResponsePayload> {
+    trace!("get_failed_ceremonies_admin_handler");
+
+    Json(GetFailedCeremoniesResponsePayload {
+        data: ceremony_log
+            .as_ref()
+            .map(|ceremony_log| ceremony_log.failures())
+            .unwrap_or_default(),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct GetOriginsQueryParams {
+    /// An origin or redirect URL to check against the allowed origins.
+    test: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct OriginTest {
+    url: String,
+    /// Whether the authenticate page would redirect to the URL after logging in, which requires
+    /// its origin to be one of the allowed origins exactly (or a path on this server).
+    redirect_allowed: bool,
+    /// Whether ceremonies from the URL's origin would be accepted, which subdomains of the
+    /// allowed origins are too. `None` for paths.
+    ceremony_allowed: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct GetOriginsResponsePayload {
+    /// `--rp-origin`, `--extra-allowed-origin` and the related origins of the config file.
+    origins: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    test: Option<OriginTest>,
+}
+
+/// The effective allowed origins, and with `?test=<url>` whether a URL passes the same checks as
+/// the authenticate page's `redirect_url` and the ceremonies, for debugging setups spanning
+/// several subdomains.
+#[debug_handler(state = AppState)]
+pub async fn get_origins_admin_handler(
+    allowed_origins: State<Arc<AllowedOrigins>>,
+    query: Query<GetOriginsQueryParams>,
+) -> Json<GetOriginsResponsePayload> {
+    trace!("get_origins_admin_handler");
+
+    let test = query.0.test.map(|url| OriginTest {
+        redirect_allowed: get_redirect_url(url.clone(), &allowed_origins).is_ok(),
+        ceremony_allowed: match Url::parse(&url) {
+            Ok(parsed) => Some(origin_is_allowed(allowed_origins.urls(), &parsed)),
+            Err(_) if url.starts_with('/') => None,
+            Err(_) => Some(false),
+        },
+        url,
+    });
+
+    Json(GetOriginsResponsePayload {
+        origins: allowed_origins
+            .urls()
+            .iter()
+            .map(|origin| origin.origin().ascii_serialization())
+            .collect(),
+        test,
+    })
+}
+
+/// JSON equivalent of the authenticate page, for frontends that render the flow themselves.
+#[debug_handler(state = AppState)]
+#[allow(clippy::too_many_arguments)]
+pub async fn get_authenticate_context_handler(
+    LoggedIn(logged_in): LoggedIn,
+    params: Query<GetAuthenticateQueryParams>,
+    headers: HeaderMap,
+    session: Session,
+    shared_state: State<SharedAppState>,
+    allowed_origins: State<Arc<AllowedOrigins>>,
+    passwords: State<Passwords>,
+    policy: State<Arc<Policy>>,
+    ClientIp(ip): ClientIp,
+    throttle: State<Arc<LoginThrottle>>,
+) -> HandlerResult<Response> {
+    trace!("get_authenticate_context_handler");
+
+    match authenticate_context(
+        logged_in,
+        &params,
+        &headers,
+        &session,
+        &shared_state,
+        &allowed_origins,
+        &passwords,
+        &policy,
+        ip,
+        &throttle,
+    )
+    .await
+    {
+        Ok(context) => Ok(Json(context).into_response()),
+        Err(AuthenticateRejection::NeedsBasicAuth) => Ok(needs_basic_auth_response()),
+        Err(AuthenticateRejection::App(e)) => Err(e),
+    }
+}
+
+/// Starts registering the first credential of a user in the enrollment flow (see
+/// `Enrollment`), who is not logged in yet.
+#[debug_handler(state = AppState)]
+pub async fn enroll_start_handler(
+    session: Session,
+    headers: HeaderMap,
+    shared_state: State<SharedAppState>,
+    webauthn: State<Arc<Webauthn>>,
+    policy: State<Arc<Policy>>,
+) -> HandlerResult<(CeremonyId, Json<RegisterStartResponsePayload>)> {
+    trace!("enroll_start_handler");
+
+    let Some(enrollment) = session.get::<Enrollment>(SESSIONKEY_ENROLLMENT).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let user = shared_state
+        .read()
+        .await
+        .get_user_with_credentials(enrollment.username)
+        .await?;
+    if !user.active {
+        return Err(AppError::UserDeactivated);
+    }
+
+    let Ok((mut req_chal, passkey_reg)) = timing::measure_sync("ceremony", || {
+        webauthn.start_passkey_registration(user.id, &user.username, &user.username, None)
+    }) else {
+        return Err(AppError::WebauthnFailed);
+    };
+
+    apply_registration_policy(&mut req_chal, &policy)?;
+
+    let ceremony_id = insert_pending_ceremony(
+        &*shared_state.read().await,
+        &session,
+        SESSIONKEY_ENROLLMENTREGISTRATION,
+        passkey_reg,
+        &policy,
+    )
+    .await?;
+
+    Ok((
+        ceremony_id,
+        Json(RegisterStartResponsePayload {
+            challenge: req_chal,
+            suggested_name: ClientInfo::from_headers(&headers).suggested_credential_name(),
+        }),
+    ))
+}
+
+/// Finishes enrollment, logging the user in with their new credential. Like registration, this
+/// responds with 202 Accepted when the credential has to be approved first, in which case the
+/// user is not logged in.
+#[debug_handler(state = AppState)]
+#[allow(clippy::too_many_arguments)]
+pub async fn enroll_end_handler(
+    session: Session,
+    headers: HeaderMap,
+    shared_state: State<SharedAppState>,
+    webauthn: State<Arc<Webauthn>>,
+    ceremony_log: State<Option<Arc<CeremonyLog>>>,
+    policy: State<Arc<Policy>>,
+    notifier: State<Option<Arc<Notifier>>>,
+    payload: extract::Json<RegisterEndRequestPayload>,
+) -> HandlerResult<StatusCode> {
+    trace!("enroll_end_handler");
+
+    let Some(enrollment) = session.get::<Enrollment>(SESSIONKEY_ENROLLMENT).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let passkey_reg: PasskeyRegistration = take_pending_ceremony(
+        &*shared_state.read().await,
+        &session,
+        &headers,
+        SESSIONKEY_ENROLLMENTREGISTRATION,
+    )
+    .await?;
+
+    let Ok(passkey) = timing::measure_sync("ceremony", || {
+        webauthn.finish_passkey_registration(&payload.credential, &passkey_reg)
+    })
+    .inspect_err(|e| {
+        log_failed_registration(
+            &ceremony_log,
+            "/api/enroll",
+            Some(enrollment.username.clone()),
+            &headers,
+            &payload.credential,
+            e,
+        )
+    }) else {
+        counter!("failed_registrations").increment(1);
+        return Err(AppError::WebauthnFailed);
+    };
+
+    if let Err(e) = check_registration_policy(&passkey, &policy) {
+        counter!("failed_registrations").increment(1);
+        return Err(e);
+    }
+
+    let pending = add_self_registered_credential(
+        &*shared_state.read().await,
+        &policy,
+        notifier.as_ref(),
+        enrollment.username.clone(),
+        payload.name.clone(),
+        &passkey,
+        registration_info(&payload.credential),
+    )
+    .await?;
+
+    counter!("successful_registrations").increment(1);
+    _ = session.remove_value(SESSIONKEY_ENROLLMENT).await?;
+    if pending {
+        return Ok(StatusCode::ACCEPTED);
+    }
+
+    session.cycle_id().await?;
+    session
+        .insert(SESSIONKEY_USERNAME, enrollment.username)
+        .await?;
+    session.insert(SESSIONKEY_LOGGEDIN, true).await?;
+    session
+        .insert(SESSIONKEY_RECENTLYVERIFIEDAT, unix_now())
+        .await?;
+    // Passkey registrations require user verification.
+    session
+        .insert(
+            SESSIONKEY_AUTHCONTEXT,
+            AuthContext::new(AuthMethod::Enrollment, Some(passkey.cred_id()), true),
+        )
+        .await?;
+
+    Ok(StatusCode::OK)
+}
+
+/// Logs a user in the enrollment flow in with just their password, as long as their grace
+/// period lasts.
+#[debug_handler(state = AppState)]
+pub async fn enroll_skip_handler(session: Session) -> HandlerResult<StatusCode> {
+    trace!("enroll_skip_handler");
+
+    let Some(enrollment) = session.get::<Enrollment>(SESSIONKEY_ENROLLMENT).await? else {
+        return Err(AppError::BadSession);
+    };
+    if !enrollment.is_skippable() {
+        return Err(AppError::EnrollmentRequired);
+    }
+
+    _ = session.remove_value(SESSIONKEY_ENROLLMENT).await?;
+    session.cycle_id().await?;
+    session
+        .insert(SESSIONKEY_USERNAME, enrollment.username)
+        .await?;
+    session.insert(SESSIONKEY_LOGGEDIN, true).await?;
+    session
+        .insert(
+            SESSIONKEY_AUTHCONTEXT,
+            AuthContext::new(AuthMethod::Password, None, false),
+        )
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct ImpersonateQueryParams {
+    token: String,
+}
+
+/// Landing route of impersonation links (see `issue_impersonation_admin_handler`), replacing
+/// whatever session the browser had with one logged in as the user until
+/// `Policy::impersonation_max_age` has passed, and sending it on to the credentials page.
+#[debug_handler(state = AppState)]
+pub async fn impersonate_handler(
+    params: Query<ImpersonateQueryParams>,
+    session: Session,
+    shared_state: State<SharedAppState>,
+    policy: State<Arc<Policy>>,
+    base_url: State<Arc<Url>>,
+) -> HandlerResult<Response> {
+    trace!("impersonate_handler");
+
+    let (username, operator) = shared_state
+        .read()
+        .await
+        .redeem_impersonation(params.0.token)
+        .await?;
+    warn!("{operator} is impersonating {username}");
+
+    let impersonation = Impersonation {
+        operator,
+        expires_at: unix_now() + policy.impersonation_max_age.as_secs(),
+    };
+    let expires_at = OffsetDateTime::from_unix_timestamp(impersonation.expires_at as i64)
+        .map_err(|_| AppError::UnknownError)?;
+
+    session.clear().await;
+    session.cycle_id().await?;
+    session.set_expiry(Some(Expiry::AtDateTime(expires_at)));
+    session.insert(SESSIONKEY_USERNAME, username).await?;
+    session.insert(SESSIONKEY_LOGGEDIN, true).await?;
+    session
+        .insert(
+            SESSIONKEY_AUTHCONTEXT,
+            AuthContext::new(AuthMethod::Impersonation, None, false),
+        )
+        .await?;
+    session
+        .insert(SESSIONKEY_IMPERSONATION, impersonation)
+        .await?;
+
+    counter!("impersonations").increment(1);
+
+    Ok(Redirect::to(&page_path(&base_url, "/credentials")).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct RecoverWithCodeRequestPayload {
+    username: String,
+    code: String,
+}
+
+/// Trades a recovery code from the seed file for recovery rights in this session, after which
+/// recovery continues as if a recovery link had been opened.
+#[debug_handler(state = AppState)]
+pub async fn recover_with_code_handler(
+    session: Session,
+    shared_state: State<SharedAppState>,
+    recovery: State<Arc<RecoveryTokens>>,
+    Json(payload): Json<RecoverWithCodeRequestPayload>,
+) -> HandlerResult<StatusCode> {
+    trace!("recover_with_code_handler");
+
+    let app = shared_state.read().await;
+    let claims = RecoveryClaims {
+        id: Uuid::new_v4().to_string(),
+        username: app.canonical_username(&payload.username).await?,
+        expires_at: unix_now() + recovery.ttl.as_secs(),
+    };
+
+    app.exchange_recovery_code(
+        claims.username.clone(),
+        payload.code,
+        claims.id.clone(),
+        claims.expires_at,
+    )
+    .await?;
+
+    session.insert(SESSIONKEY_RECOVERY, claims).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler(state = AppState)]
+pub async fn recover_start_handler(
+    session: Session,
+    shared_state: State<SharedAppState>,
+    webauthn: State<Arc<Webauthn>>,
+    policy: State<Arc<Policy>>,
+) -> HandlerResult<(CeremonyId, Json<CreationChallengeResponse>)> {
+    trace!("recover_start_handler");
+
+    let Some(claims) = session.get::<RecoveryClaims>(SESSIONKEY_RECOVERY).await? else {
+        return Err(AppError::InvalidRecoveryToken);
+    };
+
+    let user = shared_state
+        .read()
+        .await
+        .get_user_with_credentials(claims.username)
+        .await?;
+    if !user.active {
+        return Err(AppError::UserDeactivated);
+    }
+
+    // Existing credentials are not excluded since they are all revoked once recovery finishes.
+    let Ok((mut req_chal, passkey_reg)) = timing::measure_sync("ceremony", || {
+        webauthn.start_passkey_registration(user.id, &user.username, &user.username, None)
+    }) else {
+        return Err(AppError::WebauthnFailed);
+    };
+
+    apply_registration_policy(&mut req_chal, &policy)?;
+
+    let ceremony_id = insert_pending_ceremony(
+        &*shared_state.read().await,
+        &session,
+        SESSIONKEY_RECOVERYREGISTRATION,
+        passkey_reg,
+        &policy,
+    )
+    .await?;
+
+    Ok((ceremony_id, Json(req_chal)))
+}
+
+#[debug_handler(state = AppState)]
+pub async fn recover_end_handler(
+    session: Session,
+    headers: HeaderMap,
+    shared_state: State<SharedAppState>,
+    webauthn: State<Arc<Webauthn>>,
+    ceremony_log: State<Option<Arc<CeremonyLog>>>,
+    policy: State<Arc<Policy>>,
+    payload: extract::Json<RegisterEndRequestPayload>,
+) -> HandlerResult<()> {
+    trace!("recover_end_handler");
+
+    let Some(claims) = session.get::<RecoveryClaims>(SESSIONKEY_RECOVERY).await? else {
+        return Err(AppError::InvalidRecoveryToken);
+    };
+
+    let passkey_reg: PasskeyRegistration = take_pending_ceremony(
+        &*shared_state.read().await,
+        &session,
+        &headers,
+        SESSIONKEY_RECOVERYREGISTRATION,
+    )
+    .await?;
+
+    let Ok(passkey) = timing::measure_sync("ceremony", || {
+        webauthn.finish_passkey_registration(&payload.credential, &passkey_reg)
+    })
+    .inspect_err(|e| {
+        log_failed_registration(
+            &ceremony_log,
+            "/api/recover",
+            Some(claims.username.clone()),
+            &headers,
+            &payload.credential,
+            e,
+        )
+    }) else {
+        counter!("failed_registrations").increment(1);
+        return Err(AppError::WebauthnFailed);
+    };
+
+    if let Err(e) = check_registration_policy(&passkey, &policy) {
+        counter!("failed_registrations").increment(1);
+        return Err(e);
+    }
+
+    let n_revoked = shared_state
+        .read()
+        .await
+        .redeem_recovery_token(
+            claims.id,
+            claims.username.clone(),
+            payload.name.clone(),
+            &passkey,
+            registration_info(&payload.credential),
+        )
+        .await?;
+
+    info!(
+        "recovered account {}, revoked {n_revoked} credentials",
+        claims.username
+    );
+
+    _ = session.remove_value(SESSIONKEY_RECOVERY).await?;
+
+    // Same as a regular login, the session is only granted once the new credential is in place.
+    session.cycle_id().await?;
+    session.insert(SESSIONKEY_USERNAME, claims.username).await?;
+    session.insert(SESSIONKEY_LOGGEDIN, true).await?;
+    session
+        .insert(SESSIONKEY_RECENTLYVERIFIEDAT, unix_now())
+        .await?;
+    // Passkey registrations require user verification.
+    session
+        .insert(
+            SESSIONKEY_AUTHCONTEXT,
+            AuthContext::new(AuthMethod::Recovery, Some(passkey.cred_id()), true),
+        )
+        .await?;
+
+    counter!("successful_registrations").increment(1);
+    counter!("account_recoveries").increment(1);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::App;
+    use std::net::IpAddr;
+    use tokio::sync::RwLock;
+    use webauthn_rs::WebauthnBuilder;
+
+    async fn app_with_session() -> (SharedAppState, Arc<Webauthn>, Session) {
+        let db = tokio_rusqlite::Connection::open(":memory:").await.unwrap();
+        let store = SqliteSessionStore::new(db.clone());
+        store.init().await.unwrap();
+        let app = App::new(db);
+        app.init().await.unwrap();
+        let webauthn = Arc::new(
+            WebauthnBuilder::new("foo.com", &Url::parse("https://foo.com").unwrap())
+                .unwrap()
+                .build()
+                .unwrap(),
+        );
+        let session = Session::new(None, Arc::new(store), None);
+        (Arc::new(RwLock::new(app)), webauthn, session)
+    }
+
+    /// Finishes an authentication with an assertion that cannot be valid.
+    async fn authenticate_end(
+        (shared_state, webauthn, session): &(SharedAppState, Arc<Webauthn>, Session),
+        headers: HeaderMap,
+        ip: Option<IpAddr>,
+        throttle: Arc<LoginThrottle>,
+    ) -> HandlerResult<Json<AuthenticateEndResponsePayload>> {
+        let payload = serde_json::from_value(serde_json::json!({
+            "id": "AA",
+            "rawId": "AA",
+            "response": {
+                "authenticatorData": "AA",
+                "clientDataJSON": "AA",
+                "signature": "AA",
+            },
+            "extensions": {},
+            "type": "public-key",
+        }))
+        .unwrap();
+        authenticate_end_handler(
+            session.clone(),
+            headers,
+            ClientIp(ip),
+            State(shared_state.clone()),
+            State(webauthn.clone()),
+            State(None),
+            State(Arc::new(ProofOfWork::new(true, 1, 64))),
+            State(throttle),
+            State(None),
+            State(Arc::new(Policy::default())),
+            extract::Json(payload),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_end_requires_proof_of_work_until_solved() {
+        let state = app_with_session().await;
+        state
+            .2
+            .insert(
+                SESSIONKEY_PROOFOFWORK,
+                ProofOfWorkChallenge {
+                    challenge: "challenge".to_string(),
+                    // no nonce a test could guess
+                    difficulty: 64,
+                },
+            )
+            .await
+            .unwrap();
+        let throttle = Arc::new(LoginThrottle::default());
+
+        let mut wrong_nonce = HeaderMap::new();
+        wrong_nonce.insert("x-proof-of-work", HeaderValue::from_static("wrong"));
+        assert!(matches!(
+            authenticate_end(&state, wrong_nonce, None, throttle.clone()).await,
+            Err(AppError::ProofOfWorkRequired)
+        ));
+        // failing the proof-of-work does not lift the requirement for the retry
+        assert!(matches!(
+            authenticate_end(&state, HeaderMap::new(), None, throttle).await,
+            Err(AppError::ProofOfWorkRequired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_end_refuses_throttled_logins() {
+        let state = app_with_session().await;
+        state
+            .2
+            .insert(SESSIONKEY_USERNAME, "foo".to_string())
+            .await
+            .unwrap();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let throttle = Arc::new(LoginThrottle::new(Some(2)));
+
+        // without a pending ceremony, the assertion fails before it is verified
+        assert!(!matches!(
+            authenticate_end(&state, HeaderMap::new(), Some(ip), throttle.clone()).await,
+            Err(AppError::TooManyFailures)
+        ));
+        throttle.record_failure(Some(ip), Some("foo"));
+        throttle.record_failure(Some(ip), Some("foo"));
+        assert!(matches!(
+            authenticate_end(&state, HeaderMap::new(), Some(ip), throttle.clone()).await,
+            Err(AppError::TooManyFailures)
+        ));
+        // the account is throttled from other addresses too
+        assert!(matches!(
+            authenticate_end(&state, HeaderMap::new(), Some(other), throttle).await,
+            Err(AppError::TooManyFailures)
+        ));
+    }
+}
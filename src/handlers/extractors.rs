@@ -0,0 +1,122 @@
+use super::{
+    unix_now, verified_within, Impersonation, SESSIONKEY_IMPERSONATION, SESSIONKEY_LOGGEDIN,
+};
+use crate::{
+    app::AppError, client_ip::ClientIpResolver, listener::ProxiedAddr, policy::Policy, timing,
+};
+use axum::{
+    extract::{ConnectInfo, FromRef, FromRequestParts},
+    http::{header, request::Parts, HeaderMap},
+};
+use base64::{engine::general_purpose, Engine as _};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tower_sessions::Session;
+use tracing::trace;
+
+/// Whether the session is logged in. Sessions opened by impersonating a user are only logged in
+/// until the impersonation expires.
+pub struct LoggedIn(pub bool);
+
+impl<S> FromRequestParts<S> for LoggedIn
+where
+    S: Send + Sync,
+{
+    type Rejection = (axum::http::StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        trace!("LoggedIn extractor");
+        timing::measure("extractor", async {
+            let session = Session::from_request_parts(parts, state).await?;
+            let logged_in = session
+                .get::<bool>(SESSIONKEY_LOGGEDIN)
+                .await
+                .unwrap_or_default()
+                .unwrap_or_default();
+            let impersonation_expired =
+                match session.get::<Impersonation>(SESSIONKEY_IMPERSONATION).await {
+                    Ok(impersonation) => impersonation
+                        .is_some_and(|impersonation| impersonation.expires_at <= unix_now()),
+                    Err(_) => true,
+                };
+            Ok(LoggedIn(logged_in && !impersonation_expired))
+        })
+        .await
+    }
+}
+
+/// Rejects the request with `AppError::StepUpRequired` unless the session completed a WebAuthn
+/// assertion, at login or with a step-up (see `step_up_start_handler`), within
+/// `Policy::fresh_auth_max_age`. For actions that someone who finds an unlocked browser should
+/// not be able to take.
+pub struct RequireFreshAuth;
+
+impl<S> FromRequestParts<S> for RequireFreshAuth
+where
+    Arc<Policy>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        trace!("RequireFreshAuth extractor");
+        let max_age = Arc::<Policy>::from_ref(state).fresh_auth_max_age.as_secs();
+        let session = Session::from_request_parts(parts, state)
+            .await
+            .map_err(|_| AppError::BadSession)?;
+        if verified_within(&session, max_age).await? {
+            Ok(RequireFreshAuth)
+        } else {
+            Err(AppError::StepUpRequired)
+        }
+    }
+}
+
+/// The client's address, taken from the PROXY protocol header with `--proxy-protocol`, else as
+/// resolved by `ClientIpResolver` from `--real-ip-header` (i.e. the request is coming from a
+/// proxy) or the direct connection info. `None` if the header cannot be parsed.
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl<S> FromRequestParts<S> for ClientIp
+where
+    Arc<ClientIpResolver>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // Proxies speaking the PROXY protocol commonly pass requests through untouched, so
+        // headers may well have been sent by the client itself.
+        if let Some(ConnectInfo(ProxiedAddr(addr))) = parts.extensions.get() {
+            return Ok(ClientIp(Some(addr.ip().to_canonical())));
+        }
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|connect_info| connect_info.ip());
+        let ip = Arc::<ClientIpResolver>::from_ref(state).resolve(&parts.headers, peer);
+        Ok(ClientIp(ip.map(|ip| ip.to_canonical())))
+    }
+}
+
+pub(super) fn basic_auth(headers: &HeaderMap) -> Option<(String, String)> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()
+        .and_then(|authorization_header| {
+            general_purpose::STANDARD
+                .decode(authorization_header.trim_start_matches("Basic "))
+                .ok()
+                .and_then(|decoded_auth| {
+                    String::from_utf8(decoded_auth).ok().and_then(|str_auth| {
+                        str_auth
+                            .split_once(':')
+                            .map(|(u, p)| (String::from(u), String::from(p)))
+                    })
+                })
+        })
+}
@@ -0,0 +1,447 @@
+use super::{
+    authenticate_context,
+    extractors::{ClientIp, LoggedIn},
+    kiosk_operator, needs_basic_auth_response, take_page_error, unix_now, AuthenticateRejection,
+    CredentialIDWithName, Enrollment, GetAuthenticateQueryParams, HandlerResult, Impersonation,
+    PageErrorQueryParams, SESSIONKEY_ENROLLMENT, SESSIONKEY_IMPERSONATION, SESSIONKEY_MUSTREENROLL,
+    SESSIONKEY_RECOVERY, SESSIONKEY_USERNAME,
+};
+use crate::{
+    app::{AppError, SharedAppState},
+    assets::{asset_handler, assets},
+    config::Theme,
+    locale::Preferences,
+    origins::AllowedOrigins,
+    policy::Policy,
+    recovery::{RecoveryClaims, RecoveryTokens},
+    state::{AppState, Passwords},
+    throttle::LoginThrottle,
+};
+use axum::{
+    body::{Body, HttpBody},
+    debug_handler,
+    extract::{Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use liquid::Template;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tower_http::request_id::RequestId;
+use tower_sessions::Session;
+use tracing::{error, trace};
+
+pub async fn root_handler(
+    templates: State<Arc<Templates>>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    match uri.path() {
+        "/" => Redirect::permanent(&templates.url("/credentials")).into_response(),
+        "/favicon.ico" | "/main.js" => asset_handler(uri, headers).await,
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .expect("could not build response"),
+    }
+}
+
+pub struct Templates {
+    pub layout_template: Template,
+    pub credentials_template: Template,
+    pub authenticate_template: Template,
+    pub recover_template: Template,
+    pub kiosk_template: Template,
+    pub enroll_template: Template,
+    pub error_template: Template,
+    pub theme: Theme,
+    /// `--path-prefix`, prepended to the links and redirects to the pages.
+    pub path_prefix: String,
+}
+
+impl Templates {
+    /// The URL of one of the pages, e.g. `/auth/credentials` under the prefix `/auth`.
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.path_prefix)
+    }
+
+    /// Wraps a rendered page in the themed layout.
+    fn finish_html(&self, page_html: String) -> HandlerResult<String> {
+        self.layout_template
+            .render(&liquid::object!({
+                "theme": self.theme,
+                "assets": assets().urls(),
+                "path_prefix": self.path_prefix,
+                "content": page_html,
+            }))
+            .map_err(|e| {
+                error!("templates.layout_template.render: {e}");
+                AppError::UnknownError
+            })
+    }
+
+    /// Renders one of the page templates and wraps it in the layout.
+    fn render(&self, template: &Template, data: &liquid::Object) -> HandlerResult<Html<String>> {
+        let mut data = data.clone();
+        data.insert(
+            "path_prefix".into(),
+            liquid::model::Value::scalar(self.path_prefix.clone()),
+        );
+        let page_html = template.render(&data).map_err(|e| {
+            error!("template.render: {e}");
+            AppError::UnknownError
+        })?;
+        Ok(Html(self.finish_html(page_html)?))
+    }
+
+    /// Renders the error page for `status`, with `message` explaining it if there is more to say
+    /// than the status. With `retry_after`, the page reloads itself after that many seconds.
+    fn error_page(
+        &self,
+        status: StatusCode,
+        message: Option<String>,
+        request_id: Option<String>,
+        retry_after: Option<u64>,
+    ) -> HandlerResult<String> {
+        let page_html = self
+            .error_template
+            .render(&liquid::object!({
+                "status": status.as_u16(),
+                "reason": status.canonical_reason().unwrap_or_default(),
+                "message": message,
+                "request_id": request_id,
+                "retry_after": retry_after,
+                "theme": self.theme,
+                "path_prefix": self.path_prefix,
+            }))
+            .map_err(|e| {
+                error!("templates.error_template.render: {e}");
+                AppError::UnknownError
+            })?;
+        self.finish_html(page_html)
+    }
+
+    /// Replaces the body of an error response that has none or carries an `AppError` as JSON
+    /// with the error page. Other responses are returned as they are.
+    pub fn with_error_page(&self, response: Response, request_id: Option<String>) -> Response {
+        let status = response.status();
+        if !status.is_client_error() && !status.is_server_error() {
+            return response;
+        }
+        let message = match response.extensions().get::<AppError>() {
+            Some(error) => Some(error.to_string()),
+            None if response.body().size_hint().exact() == Some(0) => None,
+            None => return response,
+        };
+        let retry_after = response
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok()?.parse().ok());
+        let Ok(page) = self.error_page(status, message, request_id, retry_after) else {
+            return response;
+        };
+
+        let (mut parts, _) = response.into_parts();
+        parts.headers.remove(header::CONTENT_LENGTH);
+        parts.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        Response::from_parts(parts, Body::from(page))
+    }
+}
+
+/// The ID of a request (see `X-Request-Id`), for reporting errors.
+pub fn request_id(req: &Request) -> Option<String> {
+    req.extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(String::from)
+}
+
+/// Middleware for the built-in pages, replacing error responses that have no body (e.g. `404 Not
+/// Found` from `root_handler`) or carry an `AppError` as JSON with the error page, which also
+/// shows the request's ID (see `X-Request-Id`) for reporting the error. Unknown API endpoints
+/// also end up at `root_handler`, but are left alone.
+pub async fn render_error_pages(
+    templates: State<Arc<Templates>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if req.uri().path().starts_with("/api/") {
+        return next.run(req).await;
+    }
+    let request_id = request_id(&req);
+    let response = next.run(req).await;
+    templates.with_error_page(response, request_id)
+}
+
+/// A credential as shown on the credentials page, with its timestamps also formatted for people
+/// without JavaScript, which otherwise reformats them for the user's locale.
+#[derive(Serialize)]
+struct CredentialTemplateData {
+    #[serde(flatten)]
+    credential: CredentialIDWithName,
+    created_display: Option<String>,
+    last_used_display: Option<String>,
+}
+
+#[debug_handler(state = AppState)]
+pub async fn get_credentials_template_handler(
+    LoggedIn(logged_in): LoggedIn,
+    error_params: Query<PageErrorQueryParams>,
+    session: Session,
+    templates: State<Arc<Templates>>,
+    shared_state: State<SharedAppState>,
+    policy: State<Arc<Policy>>,
+) -> HandlerResult<Response> {
+    trace!("get_credentials_template_handler");
+
+    let app = shared_state.read().await;
+
+    if !logged_in {
+        return Ok(Redirect::temporary(&templates.url(&format!(
+            "/authenticate?redirect_url={}",
+            templates.url("/credentials")
+        )))
+        .into_response());
+    }
+
+    let Some(username) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+
+    let user = app.get_user_with_credentials(username.clone()).await?;
+    if !user.active {
+        return Err(AppError::UserDeactivated);
+    }
+    let preferences = Preferences::from_settings(&app.user_settings(username.clone()).await?);
+    let pending = app.list_pending_credentials(Some(username)).await?;
+
+    let credentials: Vec<CredentialTemplateData> = user
+        .credentials
+        .iter()
+        .map(|c| CredentialTemplateData {
+            credential: CredentialIDWithName::new(c, &preferences.timezone),
+            created_display: c.created_at.map(|at| preferences.timezone.display(at)),
+            last_used_display: c
+                .usage
+                .last_used_at
+                .map(|at| preferences.timezone.display(at)),
+        })
+        .collect();
+
+    let must_reenroll = session
+        .get::<bool>(SESSIONKEY_MUSTREENROLL)
+        .await?
+        .unwrap_or_default();
+
+    let impersonation = session
+        .get::<Impersonation>(SESSIONKEY_IMPERSONATION)
+        .await?
+        .map(|impersonation| {
+            liquid::object!({
+                "operator": impersonation.operator,
+                "expires_display": preferences.timezone.display(impersonation.expires_at),
+            })
+        });
+
+    let tmpl_data = liquid::object!({
+        "credentials": credentials,
+        "pending": pending,
+        "missing_tags": policy.missing_credential_tags(&user.credentials),
+        "must_reenroll": must_reenroll,
+        "impersonation": impersonation,
+        "timezone": preferences.timezone.name(),
+        "locale": preferences.locale,
+        "error": take_page_error(&session, &error_params).await?,
+        "theme": templates.theme,
+    });
+
+    Ok((
+        // Ask for the client hints used to suggest names for new credentials.
+        [("accept-ch", "Sec-CH-UA-Platform, Sec-CH-UA-Model")],
+        templates.render(&templates.credentials_template, &tmpl_data)?,
+    )
+        .into_response())
+}
+
+#[debug_handler(state = AppState)]
+#[allow(clippy::too_many_arguments)]
+pub async fn get_authenticate_template_handler(
+    LoggedIn(logged_in): LoggedIn,
+    params: Query<GetAuthenticateQueryParams>,
+    error_params: Query<PageErrorQueryParams>,
+    headers: HeaderMap,
+    session: Session,
+    templates: State<Arc<Templates>>,
+    shared_state: State<SharedAppState>,
+    allowed_origins: State<Arc<AllowedOrigins>>,
+    passwords: State<Passwords>,
+    policy: State<Arc<Policy>>,
+    ClientIp(ip): ClientIp,
+    throttle: State<Arc<LoginThrottle>>,
+) -> HandlerResult<Response> {
+    trace!("get_authenticate_template_handler");
+
+    let context = match authenticate_context(
+        logged_in,
+        &params,
+        &headers,
+        &session,
+        &shared_state,
+        &allowed_origins,
+        &passwords,
+        &policy,
+        ip,
+        &throttle,
+    )
+    .await
+    {
+        Ok(context) => context,
+        Err(AuthenticateRejection::NeedsBasicAuth) => return Ok(needs_basic_auth_response()),
+        Err(AuthenticateRejection::App(e)) => return Err(e),
+    };
+
+    if context.is_finished() {
+        if let Some(redirect_url) = context.redirect_url.as_ref() {
+            return Ok(Redirect::temporary(redirect_url).into_response());
+        }
+    }
+    if context.enrollment.is_some() {
+        return Ok(Redirect::temporary(&templates.url("/enroll")).into_response());
+    }
+
+    let tmpl_data = liquid::object!({
+        "username": context.username,
+        "logged_in": context.logged_in,
+        "step_up": context.step_up,
+        "error": take_page_error(&session, &error_params).await?,
+        "theme": templates.theme,
+    });
+    Ok(templates
+        .render(&templates.authenticate_template, &tmpl_data)?
+        .into_response())
+}
+
+/// Walks a user without credentials through registering their first one, see `Enrollment`.
+#[debug_handler(state = AppState)]
+pub async fn get_enroll_template_handler(
+    LoggedIn(logged_in): LoggedIn,
+    session: Session,
+    templates: State<Arc<Templates>>,
+) -> HandlerResult<Response> {
+    trace!("get_enroll_template_handler");
+
+    if logged_in {
+        return Ok(Redirect::temporary(&templates.url("/credentials")).into_response());
+    }
+    let Some(enrollment) = session.get::<Enrollment>(SESSIONKEY_ENROLLMENT).await? else {
+        return Ok(Redirect::temporary(&templates.url("/authenticate")).into_response());
+    };
+
+    let days_left = enrollment
+        .skippable_until
+        .filter(|_| enrollment.is_skippable())
+        .map(|until| until.saturating_sub(unix_now()).div_ceil(24 * 60 * 60));
+
+    let tmpl_data = liquid::object!({
+        "username": enrollment.username,
+        "days_left": days_left,
+        "database_password": enrollment.database_password,
+        "theme": templates.theme,
+    });
+    Ok(templates
+        .render(&templates.enroll_template, &tmpl_data)?
+        .into_response())
+}
+
+/// Enrolls credentials for a queue of users, for members of the kiosk group to unlock e.g. at an
+/// IT desk handing out security keys.
+#[debug_handler(state = AppState)]
+pub async fn get_kiosk_template_handler(
+    LoggedIn(logged_in): LoggedIn,
+    session: Session,
+    templates: State<Arc<Templates>>,
+    shared_state: State<SharedAppState>,
+    policy: State<Arc<Policy>>,
+) -> HandlerResult<Response> {
+    trace!("get_kiosk_template_handler");
+
+    if !logged_in {
+        return Ok(Redirect::temporary(&templates.url(&format!(
+            "/authenticate?redirect_url={}",
+            templates.url("/kiosk")
+        )))
+        .into_response());
+    }
+
+    let operator = kiosk_operator(&session, &shared_state, &policy).await?;
+
+    let tmpl_data = liquid::object!({
+        "operator": operator,
+        "theme": templates.theme,
+    });
+    Ok(templates
+        .render(&templates.kiosk_template, &tmpl_data)?
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct GetRecoverQueryParams {
+    pub token: Option<String>,
+}
+
+/// Landing page for recovery links. Opening the link does not use up the token (so that link
+/// previews cannot burn it); that only happens once a new credential has been registered.
+/// Without a token, the page asks for a recovery code instead, unless the session was already
+/// granted recovery rights.
+#[debug_handler(state = AppState)]
+pub async fn get_recover_template_handler(
+    params: Query<GetRecoverQueryParams>,
+    session: Session,
+    templates: State<Arc<Templates>>,
+    shared_state: State<SharedAppState>,
+    recovery: State<Arc<RecoveryTokens>>,
+) -> HandlerResult<Response> {
+    trace!("get_recover_template_handler");
+
+    let claims = match &params.token {
+        Some(token) => recovery.verify(token),
+        None => session.get::<RecoveryClaims>(SESSIONKEY_RECOVERY).await?,
+    };
+
+    let claims = match claims {
+        Some(claims)
+            if shared_state
+                .read()
+                .await
+                .recovery_token_is_valid(claims.id.clone(), claims.username.clone())
+                .await? =>
+        {
+            claims
+        }
+        None if params.token.is_none() => {
+            let tmpl_data = liquid::object!({
+                "code_form": true,
+                "theme": templates.theme,
+            });
+            return Ok(templates
+                .render(&templates.recover_template, &tmpl_data)?
+                .into_response());
+        }
+        _ => return Err(AppError::InvalidRecoveryToken),
+    };
+
+    let tmpl_data = liquid::object!({
+        "code_form": false,
+        "username": claims.username,
+        "theme": templates.theme,
+    });
+    session.insert(SESSIONKEY_RECOVERY, claims).await?;
+
+    Ok(templates
+        .render(&templates.recover_template, &tmpl_data)?
+        .into_response())
+}
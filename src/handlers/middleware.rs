@@ -0,0 +1,501 @@
+use super::{
+    api::ValidateQueryParams,
+    extractors::LoggedIn,
+    get_redirect_url,
+    html::{request_id, Templates},
+    request_cookie, unix_now, Impersonation, SESSIONKEY_CLIENTFINGERPRINT,
+    SESSIONKEY_IMPERSONATION, SESSIONKEY_TOUCHEDAT, SESSIONKEY_USERNAME,
+};
+use crate::{
+    app::{AppError, SharedAppState},
+    disk::DiskSpace,
+    drain::Drain,
+    forwarded_https::ForwardedHttps,
+    health::{DatabaseHealth, PROBE_INTERVAL},
+    listener::ProxiedAddr,
+    origins::AllowedOrigins,
+    policy::Policy,
+    session::{SqliteSessionStore, LEGACY_SESSION_COOKIE},
+    user_agent::{ClientFingerprint, SessionBinding},
+    validation::Payload,
+};
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use metrics::counter;
+use std::{net::SocketAddr, sync::Arc};
+use tower::load_shed::error::Overloaded;
+use tower_sessions::{
+    cookie::{time::OffsetDateTime, Cookie, Key},
+    Expiry, Session,
+};
+use tracing::{debug, error, warn};
+use webauthn_rs::prelude::Url;
+
+/// Seconds clients are asked to wait before retrying a request that was shed.
+const SHED_RETRY_AFTER: u64 = 1;
+
+/// axum's default body limit, beyond which the `Json` extractor would refuse the payload anyway.
+const MAX_PAYLOAD_LEN: usize = 2 * 1024 * 1024;
+
+/// Middleware that only allows requests from logged in sessions whose user has not been
+/// deactivated since logging in, and, with `--session-binding enforce`, from the kind of client
+/// the session was first used from.
+pub async fn require_logged_in(
+    LoggedIn(logged_in): LoggedIn,
+    session: Session,
+    shared_state: State<SharedAppState>,
+    State(policy): State<Arc<Policy>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let active = logged_in
+        && match session.get::<String>(SESSIONKEY_USERNAME).await {
+            Ok(Some(username)) => {
+                shared_state
+                    .read()
+                    .await
+                    .user_is_active(username.clone())
+                    .await
+                    .unwrap_or_default()
+                    && check_session_binding(
+                        &session,
+                        &username,
+                        ClientFingerprint::from_headers(req.headers()),
+                        policy.session_binding,
+                    )
+                    .await
+            }
+            _ => false,
+        };
+
+    if active {
+        counter!("authorized_requests").increment(1);
+        next.run(req).await
+    } else {
+        counter!("unauthorized_requests").increment(1);
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+/// Whether a request from a client with `fingerprint` may use the session. Sessions are bound
+/// to the fingerprint of the first request that passes `require_logged_in`, which is the one
+/// right after logging in.
+async fn check_session_binding(
+    session: &Session,
+    username: &str,
+    fingerprint: ClientFingerprint,
+    binding: SessionBinding,
+) -> bool {
+    if binding == SessionBinding::Off {
+        return true;
+    }
+
+    match session
+        .get::<ClientFingerprint>(SESSIONKEY_CLIENTFINGERPRINT)
+        .await
+    {
+        Ok(Some(bound)) if bound == fingerprint => true,
+        Ok(Some(bound)) => {
+            counter!("session_binding_mismatches").increment(1);
+            warn!("session of {username} was bound to {bound}, but is used from {fingerprint}");
+            binding == SessionBinding::Log
+        }
+        Ok(None) => session
+            .insert(SESSIONKEY_CLIENTFINGERPRINT, fingerprint)
+            .await
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Middleware for `/api/validate`, telling the proxy where to send users it turns away with `401
+/// Unauthorized` in the `X-Auth-Redirect` header: the authenticate page, redirecting back to the
+/// URL the proxy was asked for once they logged in (and stepped up for `max_age`). The proxy gives
+/// the URL in `X-Original-URL`, or a path on this host in `X-Original-URI`; URLs that the
+/// authenticate page would not redirect to are ignored.
+pub async fn add_login_redirect(
+    State(allowed_origins): State<Arc<AllowedOrigins>>,
+    State(base_url): State<Arc<Url>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let original_url = original_url(req.headers(), &allowed_origins);
+    let max_age = Query::<ValidateQueryParams>::try_from_uri(req.uri())
+        .ok()
+        .and_then(|params| params.max_age);
+
+    let mut response = next.run(req).await;
+    if response.status() != StatusCode::UNAUTHORIZED {
+        return response;
+    }
+    let Some(original_url) = original_url else {
+        return response;
+    };
+
+    let mut login_url = (*base_url).clone();
+    login_url.set_path(&format!(
+        "{}/authenticate",
+        base_url.path().trim_end_matches('/')
+    ));
+    login_url
+        .query_pairs_mut()
+        .append_pair("redirect_url", &original_url);
+    if let Some(max_age) = max_age {
+        login_url
+            .query_pairs_mut()
+            .append_pair("max_age", &max_age.to_string());
+    }
+    if let Ok(value) = HeaderValue::from_str(login_url.as_str()) {
+        response.headers_mut().insert("x-auth-redirect", value);
+    }
+    response
+}
+
+/// The URL a proxy asked `/api/validate` about, if the authenticate page would redirect to it.
+pub(super) fn original_url(
+    headers: &HeaderMap,
+    allowed_origins: &AllowedOrigins,
+) -> Option<String> {
+    let url = ["x-original-url", "x-original-uri"]
+        .into_iter()
+        .find_map(|name| headers.get(name))?
+        .to_str()
+        .ok()?;
+    get_redirect_url(url.to_string(), allowed_origins)
+        .inspect_err(|e| debug!("not redirecting to {url} after login: {e:?}"))
+        .ok()
+}
+
+/// Middleware for `--read-only` instances, refusing everything that would write to the database
+/// (which includes storing ceremony state in the session).
+pub async fn reject_when_read_only(_req: Request<Body>, _next: Next) -> AppError {
+    AppError::ReadOnly
+}
+
+/// Middleware refusing registrations while less than `--min-free-space-mb` is free, so that the
+/// remaining space is left to logins and validation.
+pub async fn reject_when_low_on_disk_space(
+    State(disk_space): State<Arc<DiskSpace>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    if disk_space.is_low() {
+        AppError::LowDiskSpace.into_response()
+    } else {
+        next.run(req).await
+    }
+}
+
+/// Middleware refusing to start ceremonies while the instance is draining (see `Drain`), for the
+/// routes answering `GET` with a challenge. Ceremonies started before can still be finished.
+pub async fn reject_new_ceremonies_when_draining(
+    State(drain): State<Arc<Drain>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    if req.method() == Method::GET && drain.is_draining() {
+        AppError::Draining.into_response()
+    } else {
+        next.run(req).await
+    }
+}
+
+/// Middleware refusing requests from sessions that impersonate a user (see `Impersonation`), for
+/// routes that would hand the admin a credential for the account or the user's data.
+pub async fn reject_when_impersonating(
+    session: Session,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    match session.get::<Impersonation>(SESSIONKEY_IMPERSONATION).await {
+        Ok(None) => next.run(req).await,
+        Ok(Some(_)) => AppError::Impersonating.into_response(),
+        Err(err) => AppError::from(err).into_response(),
+    }
+}
+
+/// Middleware pushing back the expiry of sessions that expire when idle (see
+/// `Policy::session_touch_interval`). `SessionManagerLayer` only saves sessions that changed, so
+/// they are changed by recording when they were last touched, at most once per interval however
+/// often they are used, instead of being written on every request as `with_always_save` would.
+pub async fn touch_session(
+    State(policy): State<Arc<Policy>>,
+    session: Session,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    // Requests without a session cookie have nothing to touch, and are not worth loading one.
+    if let (Some(interval), Some(_)) = (policy.session_touch_interval, session.id()) {
+        let touched_at = session
+            .get::<u64>(SESSIONKEY_TOUCHEDAT)
+            .await
+            .unwrap_or_default();
+        let now = unix_now();
+        // The ID is gone when the session could not be loaded, e.g. after logging out elsewhere.
+        if session.id().is_some()
+            && touched_at
+                .is_none_or(|touched_at| now.saturating_sub(touched_at) >= interval.as_secs())
+        {
+            if let Err(e) = session.insert(SESSIONKEY_TOUCHEDAT, now).await {
+                debug!("could not touch session: {e}");
+            }
+        }
+    }
+    next.run(req).await
+}
+
+/// Middleware carrying sessions of the axum-sessions format over into a new session until
+/// `--legacy-sessions-until`, so that upgrading from a version using axum-sessions does not log
+/// everyone out. Requests that already have a session cookie of the current format are left
+/// alone, and the old cookie is removed either way.
+pub async fn upgrade_legacy_session(
+    State(policy): State<Arc<Policy>>,
+    State(key): State<Key>,
+    State(session_store): State<SqliteSessionStore>,
+    shared_state: State<SharedAppState>,
+    session: Session,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let legacy_cookie = policy
+        .legacy_sessions_until
+        .filter(|until| OffsetDateTime::now_utc() < *until)
+        .and_then(|_| request_cookie(req.headers(), LEGACY_SESSION_COOKIE))
+        .map(str::to_string);
+    let Some(legacy_cookie) = legacy_cookie else {
+        return next.run(req).await;
+    };
+
+    if session.id().is_none() {
+        if let Err(e) = carry_over_legacy_session(
+            &legacy_cookie,
+            &key,
+            &session_store,
+            &shared_state,
+            &session,
+        )
+        .await
+        {
+            error!("could not upgrade legacy session: {e:#}");
+        }
+    }
+
+    let mut response = next.run(req).await;
+    // axum-sessions set the cookie for the whole host.
+    let mut removal = Cookie::build(LEGACY_SESSION_COOKIE).path("/").build();
+    removal.make_removal();
+    if let Ok(value) = HeaderValue::from_str(&removal.to_string()) {
+        response.headers_mut().append(header::SET_COOKIE, value);
+    }
+    response
+}
+
+/// Copies the contents of the legacy session with the cookie `legacy_cookie` into `session`,
+/// unless its user was deleted or deactivated since.
+async fn carry_over_legacy_session(
+    legacy_cookie: &str,
+    key: &Key,
+    session_store: &SqliteSessionStore,
+    shared_state: &SharedAppState,
+    session: &Session,
+) -> anyhow::Result<()> {
+    let Some(legacy) = session_store.take_legacy(key, legacy_cookie).await? else {
+        return Ok(());
+    };
+    if let Some(serde_json::Value::String(username)) = legacy.data.get(SESSIONKEY_USERNAME) {
+        if !shared_state
+            .read()
+            .await
+            .user_is_active(username.clone())
+            .await?
+        {
+            return Ok(());
+        }
+    }
+    for (key, value) in legacy.data {
+        session.insert_value(&key, value).await?;
+    }
+    // The new session ends no later than the old one would have.
+    if let Some(expiry) = legacy.expiry {
+        session.set_expiry(Some(Expiry::AtDateTime(expiry)));
+    }
+    counter!("upgraded_legacy_sessions").increment(1);
+    Ok(())
+}
+
+/// Middleware for the ceremony routes, warning about or, with `--require-forwarded-https`,
+/// refusing ceremonies that did not reach the server over HTTPS although the RP origin is https
+/// (see `ForwardedHttps`).
+pub async fn check_forwarded_https(
+    State(forwarded_https): State<Arc<ForwardedHttps>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    if forwarded_https.check(req.headers()) {
+        next.run(req).await
+    } else {
+        counter!("plaintext_ceremonies").increment(1);
+        AppError::PlaintextCeremony.into_response()
+    }
+}
+
+/// Middleware answering requests with `503 Service Unavailable` while the database is down (see
+/// `DatabaseHealth`), the pages with an error page that reloads itself until it is back, and
+/// telling `DatabaseHealth` which of the other requests failed to access it. Paths that do not
+/// need the database, like `/readyz` (which reports the outage) and the assets, are let through.
+pub async fn degrade_when_database_down(
+    State(health): State<Arc<DatabaseHealth>>,
+    State(templates): State<Arc<Templates>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let path = req.uri().path();
+    let needs_database = !matches!(path, "/readyz" | "/metrics" | "/favicon.ico" | "/main.js")
+        && !path.starts_with("/assets/");
+    if !needs_database || !health.is_down() {
+        let response = next.run(req).await;
+        health.record(!matches!(
+            response.extensions().get::<AppError>(),
+            Some(AppError::Io | AppError::Corrupt)
+        ));
+        return response;
+    }
+
+    counter!("database_unavailable_requests").increment(1);
+    let response = (
+        [(header::RETRY_AFTER, PROBE_INTERVAL.as_secs())],
+        AppError::DatabaseUnavailable,
+    )
+        .into_response();
+    if path.starts_with("/api/") {
+        response
+    } else {
+        templates.with_error_page(response, request_id(&req))
+    }
+}
+
+/// Middleware for `--strict-validation` (and debug builds), logging where a ceremony payload
+/// does not match the webauthn-rs types before the handler rejects it without saying why.
+pub async fn validate_webauthn_payloads(req: Request<Body>, next: Next) -> Response {
+    let Some(payload) = Payload::of(req.method(), req.uri().path()) else {
+        return next.run(req).await;
+    };
+
+    let (parts, body) = req.into_parts();
+    let Ok(body) = to_bytes(body, MAX_PAYLOAD_LEN).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    if let Err(e) = payload.check(&body) {
+        counter!("invalid_webauthn_payloads").increment(1);
+        warn!(
+            "invalid payload for {} {}: {e}",
+            parts.method,
+            parts.uri.path()
+        );
+    }
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+/// Middleware that only allows connections from a loopback address (see `is_local`).
+pub async fn allow_only_localhost(req: Request<Body>, next: Next) -> Response {
+    if is_local(&req) {
+        next.run(req).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+/// Whether the peer of the connection (or the client in its PROXY protocol header) has a
+/// loopback address. Unlike `ClientIp`, this never takes an address from a header, which the
+/// client may have written itself. Requests over unix sockets have no peer address, and requests
+/// carrying a forwarding header came through a proxy, whose own connection says nothing about
+/// where they came from, so neither are local.
+fn is_local<B>(req: &Request<B>) -> bool {
+    let forwarded = ["x-forwarded-for", "x-real-ip", "forwarded"]
+        .iter()
+        .any(|name| req.headers().contains_key(*name));
+    let peer = match req.extensions().get::<ConnectInfo<ProxiedAddr>>() {
+        Some(ConnectInfo(ProxiedAddr(addr))) => Some(addr),
+        None => req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr),
+    };
+    !forwarded && peer.is_some_and(|addr| addr.ip().to_canonical().is_loopback())
+}
+
+/// Answers requests rejected by the `LoadShedLayer` in front of the routes, which sheds requests
+/// once `--max-concurrent-requests` are being handled.
+pub async fn handle_shed_request(err: BoxError) -> Response {
+    if err.is::<Overloaded>() {
+        counter!("shed_requests").increment(1);
+        (
+            [(header::RETRY_AFTER, SHED_RETRY_AFTER)],
+            AppError::Overloaded,
+        )
+            .into_response()
+    } else {
+        error!("unhandled error in middleware: {err}");
+        AppError::UnknownError.into_response()
+    }
+}
+
+/// Answers requests whose method the path has no handler for. It replaces axum's default, which
+/// goes through the middleware of the route and would e.g. be answered with `401 Unauthorized`
+/// by `require_logged_in` instead of `405 Method Not Allowed`. `OPTIONS` is answered for every
+/// known path with `204 No Content`. axum adds the `Allow` header to both.
+pub async fn method_not_allowed(method: Method) -> StatusCode {
+    if method == Method::OPTIONS {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::METHOD_NOT_ALLOWED
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(peer: Option<&str>, forwarded_for: Option<&'static str>) -> Request<()> {
+        let mut req = Request::new(());
+        if let Some(peer) = peer {
+            let peer: SocketAddr = peer.parse().unwrap();
+            req.extensions_mut().insert(ConnectInfo(peer));
+        }
+        if let Some(forwarded_for) = forwarded_for {
+            req.headers_mut()
+                .insert("x-forwarded-for", HeaderValue::from_static(forwarded_for));
+        }
+        req
+    }
+
+    #[test]
+    fn test_is_local() {
+        assert!(is_local(&request(Some("127.0.0.1:1234"), None)));
+        assert!(is_local(&request(Some("[::1]:1234"), None)));
+        assert!(is_local(&request(Some("[::ffff:127.0.0.1]:1234"), None)));
+        assert!(!is_local(&request(Some("192.0.2.1:1234"), None)));
+
+        // the header cannot make a remote peer local, and a proxied request is never local
+        assert!(!is_local(&request(
+            Some("192.0.2.1:1234"),
+            Some("127.0.0.1")
+        )));
+        assert!(!is_local(&request(Some("[::1]:1234"), Some("127.0.0.1"))));
+        // unix sockets have no peer address
+        assert!(!is_local(&request(None, None)));
+        assert!(!is_local(&request(None, Some("127.0.0.1"))));
+
+        let mut proxied = request(Some("127.0.0.1:1234"), None);
+        proxied
+            .extensions_mut()
+            .insert(ConnectInfo(ProxiedAddr("192.0.2.1:1234".parse().unwrap())));
+        assert!(!is_local(&proxied));
+    }
+}
@@ -0,0 +1,977 @@
+pub mod api;
+mod extractors;
+pub mod html;
+pub mod middleware;
+
+use self::extractors::basic_auth;
+use crate::{
+    app::{credential_handle, App, AppError, CredentialUsage, CredentialWithName, SharedAppState},
+    assets::{assets, ProviderInfo},
+    config::ProtectedApp,
+    locale::TimeZone,
+    metadata::cred_protect,
+    origins::AllowedOrigins,
+    policy::{algorithm_name, algorithm_strength, AlgorithmStrength, Policy},
+    throttle::LoginThrottle,
+};
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponseParts, Response, ResponseParts},
+};
+use metrics::counter;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tower_sessions::Session;
+use tracing::{error, info, warn};
+use webauthn_rs::prelude::*;
+use webauthn_rs_proto::CredentialProtectionPolicy;
+
+/// Result of handlers and the helpers they share. Errors are rendered as JSON error responses.
+pub type HandlerResult<T> = Result<T, AppError>;
+
+const SESSIONKEY_AUTHCONTEXT: &str = "auth_context";
+const SESSIONKEY_CAPTCHA: &str = "captcha";
+const SESSIONKEY_CLIENTFINGERPRINT: &str = "client_fingerprint";
+const SESSIONKEY_ENROLLMENT: &str = "enrollment";
+const SESSIONKEY_ENROLLMENTREGISTRATION: &str = "enrollment_registration";
+pub(crate) const SESSIONKEY_IMPERSONATION: &str = "impersonation";
+pub(crate) const SESSIONKEY_LOGGEDIN: &str = "logged_in";
+const SESSIONKEY_MUSTREENROLL: &str = "must_reenroll";
+const SESSIONKEY_KIOSKREGISTRATION: &str = "kiosk_registration";
+const SESSIONKEY_PAGEERROR: &str = "page_error";
+const SESSIONKEY_PASSKEYREGISTRATION: &str = "passkey_registration";
+const SESSIONKEY_PASSKEYAUTHENTICATION: &str = "passkey_authentication";
+const SESSIONKEY_PASSKEYSTEPUP: &str = "passkey_step_up";
+const SESSIONKEY_PROOFOFWORK: &str = "proof_of_work";
+const SESSIONKEY_RECENTLYVERIFIEDAT: &str = "recently_verified_at";
+const SESSIONKEY_RECOVERY: &str = "recovery";
+const SESSIONKEY_RECOVERYREGISTRATION: &str = "recovery_registration";
+const SESSIONKEY_REDIRECTURL: &str = "redirect_url";
+const SESSIONKEY_TOUCHEDAT: &str = "touched_at";
+pub(crate) const SESSIONKEY_USERNAME: &str = "username";
+
+/// Problems that the server-rendered pages can explain to the user, set either by the server when
+/// a ceremony fails or by the client (see `set_page_error_handler`) for failures that only the
+/// browser sees.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PageError {
+    CeremonyTimedOut,
+    CredentialAlreadyRegistered,
+    RegistrationFailed,
+    AuthenticationFailed,
+    CredentialBlocked,
+    CredentialPending,
+}
+
+impl PageError {
+    fn message(&self) -> &'static str {
+        match self {
+            PageError::CeremonyTimedOut => {
+                "The security key prompt timed out or was cancelled. Please try again."
+            }
+            PageError::CredentialAlreadyRegistered => {
+                "This credential is already registered. Try a different security key or device."
+            }
+            PageError::RegistrationFailed => {
+                "The credential could not be registered. Please try again."
+            }
+            PageError::AuthenticationFailed => {
+                "The credential could not be verified. Make sure to use a credential registered for this account."
+            }
+            PageError::CredentialBlocked => {
+                "This security key or device has been blocked by the administrator. Try a different one."
+            }
+            PageError::CredentialPending => {
+                "Your credential can be used once an administrator approves it."
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct PageErrorState {
+    code: PageError,
+    message: &'static str,
+}
+
+impl From<PageError> for PageErrorState {
+    fn from(error: PageError) -> Self {
+        Self {
+            code: error,
+            message: error.message(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PageErrorQueryParams {
+    error: Option<String>,
+}
+
+async fn set_page_error(session: &Session, error: PageError) -> HandlerResult<()> {
+    Ok(session.insert(SESSIONKEY_PAGEERROR, error).await?)
+}
+
+/// Takes the error to show on the page being rendered, preferring one stored in the session over
+/// one passed in the query string. Unknown error codes from the query are ignored.
+async fn take_page_error(
+    session: &Session,
+    params: &PageErrorQueryParams,
+) -> HandlerResult<Option<PageErrorState>> {
+    let error = session
+        .remove::<PageError>(SESSIONKEY_PAGEERROR)
+        .await?
+        .or_else(|| {
+            params.error.as_ref().and_then(|error| {
+                serde_json::from_value(serde_json::Value::String(error.clone())).ok()
+            })
+        });
+    Ok(error.map(PageErrorState::from))
+}
+
+#[derive(Deserialize)]
+pub struct GetAuthenticateQueryParams {
+    pub redirect_url: Option<String>,
+    /// When set, logged in users whose last verification is older than this many seconds are
+    /// asked to step up instead of being considered authenticated.
+    pub max_age: Option<u64>,
+}
+
+/// Everything a frontend needs to drive the authentication flow.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticateContext {
+    pub username: Option<String>,
+    pub logged_in: bool,
+    pub step_up: bool,
+    /// Where to send the user once they are authenticated. When set while logged in (and no
+    /// step-up is needed), the flow is finished and the client should navigate there now.
+    pub redirect_url: Option<String>,
+    /// Set when the user has no credentials and has to enroll one to log in, see `/enroll`.
+    pub enrollment: Option<Enrollment>,
+}
+
+impl AuthenticateContext {
+    fn is_finished(&self) -> bool {
+        self.logged_in && !self.step_up && self.redirect_url.is_some()
+    }
+}
+
+/// A user without credentials being walked through enrolling their first one. It is stored in
+/// the session once their password was verified, and removed once they either enrolled a
+/// credential or skipped enrolling, both of which log them in.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Enrollment {
+    pub username: String,
+    /// Unix timestamp until which the user may skip enrolling and log in with just their
+    /// password. `None` once the grace period is over.
+    pub skippable_until: Option<u64>,
+    /// Whether the user logged in with a password from the database (see
+    /// `Policy::database_passwords`), who is asked to enroll each time but may always skip it.
+    #[serde(default)]
+    pub database_password: bool,
+}
+
+impl Enrollment {
+    fn is_skippable(&self) -> bool {
+        self.database_password || self.skippable_until.is_some_and(|until| unix_now() < until)
+    }
+}
+
+/// Puts the session into the enrollment flow for `username`, starting their grace period if this
+/// is their first login.
+async fn begin_enrollment(
+    session: &Session,
+    shared_state: &SharedAppState,
+    policy: &Policy,
+    username: String,
+    database_password: bool,
+) -> HandlerResult<Enrollment> {
+    let started_at = shared_state
+        .read()
+        .await
+        .start_enrollment(username.clone())
+        .await?;
+    let enrollment = Enrollment {
+        username,
+        skippable_until: policy.enrollment_skippable_until(started_at, unix_now()),
+        database_password,
+    };
+    session.insert(SESSIONKEY_ENROLLMENT, &enrollment).await?;
+    Ok(enrollment)
+}
+
+enum AuthenticateRejection {
+    /// The client has not sent basic auth credentials yet.
+    NeedsBasicAuth,
+    App(AppError),
+}
+
+impl From<AppError> for AuthenticateRejection {
+    fn from(error: AppError) -> Self {
+        AuthenticateRejection::App(error)
+    }
+}
+
+impl From<tower_sessions::session::Error> for AuthenticateRejection {
+    fn from(error: tower_sessions::session::Error) -> Self {
+        AuthenticateRejection::App(error.into())
+    }
+}
+
+fn needs_basic_auth_response() -> Response {
+    Response::builder()
+        .header(header::WWW_AUTHENTICATE, "Basic")
+        .status(StatusCode::UNAUTHORIZED)
+        .body(Body::empty())
+        .expect("could not build response")
+}
+
+/// Validates the redirect URL and the user's basic auth credentials and prepares the session
+/// for a WebAuthn ceremony, or for enrollment when the user has no credentials and the policy
+/// requires them to enroll one. Shared by the authenticate page and its JSON equivalent.
+#[allow(clippy::too_many_arguments)]
+async fn authenticate_context(
+    logged_in: bool,
+    params: &GetAuthenticateQueryParams,
+    headers: &HeaderMap,
+    session: &Session,
+    shared_state: &SharedAppState,
+    allowed_origins: &AllowedOrigins,
+    passwords: &HashMap<String, String>,
+    policy: &Policy,
+    ip: Option<IpAddr>,
+    throttle: &LoginThrottle,
+) -> Result<AuthenticateContext, AuthenticateRejection> {
+    let needs_step_up = match params.max_age {
+        Some(max_age) if logged_in => !verified_within(session, max_age).await?,
+        _ => false,
+    };
+
+    if logged_in && !needs_step_up {
+        if let Some(redirect_url) = session.remove::<String>(SESSIONKEY_REDIRECTURL).await? {
+            return Ok(AuthenticateContext {
+                username: session.get::<String>(SESSIONKEY_USERNAME).await?,
+                logged_in,
+                step_up: false,
+                redirect_url: Some(redirect_url),
+                enrollment: None,
+            });
+        }
+    }
+
+    let Some((username, password)) = basic_auth(headers) else {
+        return Err(AuthenticateRejection::NeedsBasicAuth);
+    };
+
+    // The password is the one of the name the client logged in with, while the session belongs
+    // to the account it normalizes to or is an alias of, which is also what is throttled.
+    let app = shared_state.read().await;
+    let canonical_username = app.canonical_username(&username).await?;
+    if throttle.is_throttled(ip, Some(&canonical_username)) {
+        info!("too many failed logins");
+        return Err(AppError::TooManyFailures.into());
+    }
+
+    // Entries in the password file may be spelled as given by the client or normalized. Users
+    // missing from it fall back to a password stored in the database, when allowed.
+    let (hashed_password, database_password) = match passwords
+        .get(&username)
+        .or_else(|| passwords.get(&app.normalize_username(&username)))
+    {
+        Some(hashed_password) => (Some(hashed_password.clone()), false),
+        None if policy.database_passwords => {
+            (app.password_hash(canonical_username.clone()).await?, true)
+        }
+        None => (None, false),
+    };
+    drop(app);
+    if hashed_password
+        .and_then(|hashed_password| {
+            PasswordHash::new(&hashed_password)
+                .ok()
+                .and_then(|parsed_hash| {
+                    Argon2::default()
+                        .verify_password(password.as_bytes(), &parsed_hash)
+                        .ok()
+                })
+        })
+        .is_none()
+    {
+        throttle.record_failure(ip, Some(&canonical_username));
+        return Err(AppError::InvalidPassword.into());
+    }
+
+    let username = canonical_username;
+    let app = shared_state.read().await;
+    let user = app.get_user_with_credentials(username.clone()).await?;
+    drop(app);
+    if !user.active {
+        return Err(AppError::UserDeactivated.into());
+    }
+
+    session
+        .insert(SESSIONKEY_USERNAME, username.clone())
+        .await?;
+
+    let mut redirect_url = None;
+    if !logged_in || needs_step_up {
+        if let Some(requested_url) = params.redirect_url.as_ref() {
+            if let Ok(accepted_redirect_url) =
+                get_redirect_url(requested_url.to_string(), allowed_origins)
+            {
+                session
+                    .insert(SESSIONKEY_REDIRECTURL, accepted_redirect_url.clone())
+                    .await?;
+                redirect_url = Some(accepted_redirect_url);
+            }
+        }
+    }
+
+    // Users whose credentials are only awaiting approval are not enrolled again.
+    let enrollment = if !logged_in
+        && (policy.require_enrollment || database_password)
+        && user.credentials.is_empty()
+        && shared_state
+            .read()
+            .await
+            .list_pending_credentials(Some(username.clone()))
+            .await?
+            .is_empty()
+    {
+        Some(
+            begin_enrollment(
+                session,
+                shared_state,
+                policy,
+                username.clone(),
+                database_password,
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+
+    Ok(AuthenticateContext {
+        username: Some(username),
+        logged_in,
+        step_up: needs_step_up,
+        redirect_url,
+        enrollment,
+    })
+}
+
+#[derive(Serialize, Debug)]
+pub struct CredentialIDWithName {
+    handle: String,
+    /// Deprecated in favor of `handle`.
+    id: CredentialID,
+    name: String,
+    algorithm: &'static str,
+    strength: AlgorithmStrength,
+    blocked: bool,
+    cred_protect: Option<CredentialProtectionPolicy>,
+    min_pin_length: Option<u32>,
+    tags: Vec<String>,
+    attachment: Option<AuthenticatorAttachment>,
+    /// The passkey provider (e.g. a password manager) keeping the credential, if a known one.
+    provider: Option<ProviderInfo>,
+    usage: CredentialUsage,
+    /// When the credential was registered and last used, as RFC 3339 in the owner's timezone.
+    created: Option<String>,
+    last_used: Option<String>,
+}
+
+impl CredentialIDWithName {
+    pub fn new(c: &CredentialWithName, timezone: &TimeZone) -> Self {
+        Self {
+            handle: c.handle.clone(),
+            id: c.credential.cred_id().to_owned(),
+            name: c.name.clone(),
+            algorithm: algorithm_name(&c.algorithm),
+            strength: algorithm_strength(&c.algorithm),
+            blocked: c.blocked,
+            cred_protect: cred_protect(&c.credential),
+            min_pin_length: c.min_pin_length,
+            tags: c.tags.clone(),
+            attachment: c.attachment,
+            provider: c.aaguid.and_then(|aaguid| assets().provider(&aaguid)),
+            usage: c.usage.clone(),
+            created: c.created_at.map(|at| timezone.rfc3339(at)),
+            last_used: c.usage.last_used_at.map(|at| timezone.rfc3339(at)),
+        }
+    }
+}
+
+/// The logged in user operating the kiosk, who has to be a member of the kiosk group.
+async fn kiosk_operator(
+    session: &Session,
+    shared_state: &SharedAppState,
+    policy: &Policy,
+) -> HandlerResult<String> {
+    let Some(group) = policy.kiosk_group.clone() else {
+        return Err(AppError::NotKioskOperator);
+    };
+    let Some(operator) = session.get::<String>(SESSIONKEY_USERNAME).await? else {
+        return Err(AppError::BadSession);
+    };
+    let app = shared_state.read().await;
+    if !app.user_is_active(operator.clone()).await?
+        || !app.user_in_group(operator.clone(), group).await?
+    {
+        return Err(AppError::NotKioskOperator);
+    }
+    Ok(operator)
+}
+
+/// Header that start handlers return the ID of the pending ceremony in, and that clients may
+/// send back when finishing it.
+const CEREMONY_ID_HEADER: &str = "x-ceremony-id";
+
+/// Opaque ID of a pending ceremony, returned in the `X-Ceremony-Id` header along with the
+/// challenge.
+pub struct CeremonyId(String);
+
+impl IntoResponseParts for CeremonyId {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        if let Ok(value) = HeaderValue::from_str(&self.0) {
+            res.headers_mut().insert(CEREMONY_ID_HEADER, value);
+        }
+        Ok(res)
+    }
+}
+
+/// Stores the state of a ceremony between its start and end in the database rather than the
+/// session, which only holds the ID. It expires along with the timeout the browser was given, so
+/// that a challenge cannot be answered long after it was issued. Starting a ceremony again
+/// replaces the one the session had pending.
+async fn insert_pending_ceremony<T: Serialize>(
+    app: &App,
+    session: &Session,
+    key: &str,
+    state: T,
+    policy: &Policy,
+) -> HandlerResult<CeremonyId> {
+    // Sessions from before ceremonies were stored separately hold the whole state instead.
+    if let Ok(Some(previous)) = session.get::<String>(key).await {
+        app.take_ceremony(previous, key.to_string()).await?;
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let state = serde_json::to_string(&state).map_err(|e| {
+        error!("serializing ceremony state: {e}");
+        AppError::UnknownError
+    })?;
+    app.insert_ceremony(
+        id.clone(),
+        key.to_string(),
+        state,
+        unix_now().saturating_add(policy.ceremony_timeout.as_secs()),
+    )
+    .await?;
+    if let Err(e) = session.insert(key, &id).await {
+        error!("session.insert: {e}");
+        return Err(AppError::BadSession);
+    }
+    Ok(CeremonyId(id))
+}
+
+/// Removes a pending ceremony, so that each challenge can only be answered once. The ceremony is
+/// the one named by the `X-Ceremony-Id` header, falling back to the last one started in the
+/// session.
+async fn take_pending_ceremony<T: DeserializeOwned>(
+    app: &App,
+    session: &Session,
+    headers: &HeaderMap,
+    key: &str,
+) -> HandlerResult<T> {
+    let session_id = session.remove::<String>(key).await.ok().flatten();
+    let Some(id) = headers
+        .get(CEREMONY_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .map(String::from)
+        .or(session_id)
+    else {
+        return Err(AppError::BadSession);
+    };
+    let Some((state, expires_at)) = app.take_ceremony(id, key.to_string()).await? else {
+        return Err(AppError::BadSession);
+    };
+    if expires_at <= unix_now() {
+        set_page_error(session, PageError::CeremonyTimedOut).await?;
+        return Err(AppError::CeremonyTimedOut);
+    }
+    serde_json::from_str(&state).map_err(|e| {
+        error!("deserializing ceremony state: {e}");
+        AppError::BadSession
+    })
+}
+
+/// Rejects an assertion webauthn-rs accepted if its challenge was answered before, in this
+/// session or any other one, which taking the pending ceremony alone does not rule out.
+async fn consume_assertion_challenge(
+    app: &App,
+    credential: &PublicKeyCredential,
+    policy: &Policy,
+) -> HandlerResult<()> {
+    #[derive(Deserialize)]
+    struct ClientData {
+        challenge: String,
+    }
+
+    let Ok(client_data) =
+        serde_json::from_slice::<ClientData>(credential.response.client_data_json.as_ref())
+    else {
+        return Err(AppError::WebauthnFailed);
+    };
+    let expires_at = unix_now().saturating_add(policy.ceremony_timeout.as_secs());
+    if !app
+        .consume_challenge(client_data.challenge, expires_at)
+        .await?
+    {
+        warn!("assertion was replayed");
+        counter!("replayed_assertions").increment(1);
+        return Err(AppError::WebauthnFailed);
+    }
+    Ok(())
+}
+
+/// The path of one of the pages under `base_url` (see `AppState::base_url`), e.g.
+/// `/auth/credentials` under the prefix `/auth`.
+fn page_path(base_url: &Url, page: &str) -> String {
+    format!("{}{page}", base_url.path().trim_end_matches('/'))
+}
+
+/// The value of the cookie `name` in the `Cookie` headers, for cookies not managed by the
+/// session layer.
+fn request_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| pair.trim().strip_prefix(name)?.strip_prefix('='))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Whether the session completed a WebAuthn assertion within the last `max_age` seconds.
+async fn verified_within(session: &Session, max_age: u64) -> HandlerResult<bool> {
+    Ok(session
+        .get::<u64>(SESSIONKEY_RECENTLYVERIFIEDAT)
+        .await?
+        .is_some_and(|verified_at| unix_now().saturating_sub(verified_at) <= max_age))
+}
+
+/// How a user logged in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum AuthMethod {
+    /// Only the password, as users without credentials do to register their first one.
+    Password,
+    /// The password followed by a WebAuthn assertion.
+    Webauthn,
+    /// A recovery link, registering a new credential.
+    Recovery,
+    /// The password of a user without credentials, followed by registering their first one.
+    Enrollment,
+    /// An impersonation link issued to an admin, see `Impersonation`.
+    Impersonation,
+}
+
+impl AuthMethod {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuthMethod::Password => "password",
+            AuthMethod::Webauthn => "webauthn",
+            AuthMethod::Recovery => "recovery",
+            AuthMethod::Enrollment => "enrollment",
+            AuthMethod::Impersonation => "impersonation",
+        }
+    }
+}
+
+/// Marks a session that an admin opened with an impersonation link to reproduce what the user
+/// sees. Such sessions end at `expires_at`, are shown a banner, and cannot register credentials,
+/// export the user's data, or take any action that needs a fresh assertion.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Impersonation {
+    /// Who issued the link, as given to the admin API.
+    pub operator: String,
+    /// Seconds since the epoch.
+    pub expires_at: u64,
+}
+
+/// How the session was established, stored at login and returned by `/api/validate?context=true`
+/// as headers so that protected apps can make risk-based decisions. Step-ups do not change it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct AuthContext {
+    method: AuthMethod,
+    /// Seconds since the epoch.
+    auth_time: u64,
+    /// Handle of the credential used to log in (or registered during recovery).
+    credential: Option<String>,
+    user_verified: bool,
+}
+
+impl AuthContext {
+    fn new(method: AuthMethod, credential: Option<&CredentialID>, user_verified: bool) -> Self {
+        Self {
+            method,
+            auth_time: unix_now(),
+            credential: credential.map(credential_handle),
+            user_verified,
+        }
+    }
+
+    /// The NIST SP 800-63B authenticator assurance level. The password together with a WebAuthn
+    /// credential is AAL2, there is no telling whether a credential is hardware-bound as AAL3
+    /// requires.
+    fn assurance_level(&self) -> u16 {
+        match self.method {
+            AuthMethod::Webauthn => 2,
+            AuthMethod::Password
+            | AuthMethod::Recovery
+            | AuthMethod::Enrollment
+            | AuthMethod::Impersonation => 1,
+        }
+    }
+
+    /// Whether a login described by `auth_context` is strong and recent enough for `app`. Sessions
+    /// without an auth context only pass apps that care about neither.
+    fn satisfies(auth_context: Option<&Self>, app: &ProtectedApp, now: u64) -> bool {
+        match auth_context {
+            Some(auth_context) => {
+                app.required_aal
+                    .is_none_or(|aal| auth_context.assurance_level() >= aal)
+                    && app
+                        .max_session_age
+                        .is_none_or(|max_age| now.saturating_sub(auth_context.auth_time) <= max_age)
+            }
+            None => app.required_aal.is_none() && app.max_session_age.is_none(),
+        }
+    }
+
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-auth-method",
+            HeaderValue::from_static(self.method.as_str()),
+        );
+        headers.insert("x-auth-time", HeaderValue::from(self.auth_time));
+        headers.insert("x-auth-aal", HeaderValue::from(self.assurance_level()));
+        headers.insert(
+            "x-auth-user-verified",
+            HeaderValue::from_static(if self.user_verified { "true" } else { "false" }),
+        );
+        if let Some(credential) = self
+            .credential
+            .as_ref()
+            .and_then(|credential| HeaderValue::from_str(credential).ok())
+        {
+            headers.insert("x-auth-credential", credential);
+        }
+        headers
+    }
+}
+
+fn get_redirect_url(
+    requested_url: String,
+    allowed_origins: &AllowedOrigins,
+) -> HandlerResult<String> {
+    if let Ok(url) = Url::parse(&requested_url) {
+        if allowed_origins.contains(&url) {
+            Ok(requested_url)
+        } else {
+            Err(AppError::OriginNotAllowed)
+        }
+    } else if requested_url.starts_with('/') {
+        Ok(requested_url)
+    } else {
+        Err(AppError::BadUrl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SqliteSessionStore;
+    use std::sync::Arc;
+    use webauthn_rs::WebauthnBuilder;
+
+    #[test]
+    fn test_get_redirect_url() {
+        let webauthn =
+            WebauthnBuilder::new("foo.com", &Url::parse("https://auth.foo.com").unwrap())
+                .unwrap()
+                .allow_subdomains(true)
+                .append_allowed_origin(&Url::parse("https://foo.com").unwrap())
+                .append_allowed_origin(&Url::parse("https://bar.foo.com").unwrap())
+                .build()
+                .unwrap();
+        let allowed_origins = AllowedOrigins::new(&webauthn);
+
+        // passes
+        [
+            "/somepath",
+            "https://bar.foo.com",
+            "https://auth.foo.com",
+            "https://foo.com",
+        ]
+        .iter()
+        .for_each(|&url| {
+            assert_eq!(
+                url,
+                get_redirect_url(url.to_string(), &allowed_origins).unwrap(),
+                "url not accepted by get_redirect_url: {}",
+                url
+            );
+        });
+
+        // fails
+        ["https://fo.com", "https://foo.bar.com"]
+            .iter()
+            .for_each(|&url| {
+                assert!(
+                    get_redirect_url(url.to_string(), &allowed_origins).is_err(),
+                    "url accepted by get_redirect_url: {}",
+                    url
+                );
+            });
+    }
+
+    #[test]
+    fn test_original_url() {
+        let allowed_origins =
+            AllowedOrigins::from_urls(vec![Url::parse("https://app.foo.com").unwrap()]);
+        let headers = |pairs: &[(&'static str, &str)]| {
+            HeaderMap::from_iter(pairs.iter().map(|(name, value)| {
+                (
+                    header::HeaderName::from_static(name),
+                    value.parse().unwrap(),
+                )
+            }))
+        };
+
+        assert_eq!(
+            middleware::original_url(
+                &headers(&[
+                    ("x-original-url", "https://app.foo.com/x?y=1"),
+                    ("x-original-uri", "/z"),
+                ]),
+                &allowed_origins
+            )
+            .as_deref(),
+            Some("https://app.foo.com/x?y=1")
+        );
+        assert_eq!(
+            middleware::original_url(&headers(&[("x-original-uri", "/z")]), &allowed_origins)
+                .as_deref(),
+            Some("/z")
+        );
+        assert_eq!(
+            middleware::original_url(
+                &headers(&[("x-original-url", "https://evil.com/")]),
+                &allowed_origins
+            ),
+            None
+        );
+        assert_eq!(
+            middleware::original_url(&headers(&[]), &allowed_origins),
+            None
+        );
+    }
+
+    #[test]
+    fn test_auth_context_headers() {
+        let context = AuthContext {
+            method: AuthMethod::Webauthn,
+            auth_time: 1700000000,
+            credential: Some("AQID".to_string()),
+            user_verified: true,
+        };
+        let headers = context.headers();
+        assert_eq!(headers["x-auth-method"], "webauthn");
+        assert_eq!(headers["x-auth-time"], "1700000000");
+        assert_eq!(headers["x-auth-aal"], "2");
+        assert_eq!(headers["x-auth-user-verified"], "true");
+        assert_eq!(headers["x-auth-credential"], "AQID");
+
+        let context = AuthContext {
+            method: AuthMethod::Password,
+            credential: None,
+            user_verified: false,
+            ..context
+        };
+        let headers = context.headers();
+        assert_eq!(headers["x-auth-aal"], "1");
+        assert_eq!(headers["x-auth-user-verified"], "false");
+        assert!(!headers.contains_key("x-auth-credential"));
+    }
+
+    #[test]
+    fn test_auth_context_satisfies() {
+        let password = AuthContext {
+            method: AuthMethod::Password,
+            auth_time: 1700000000,
+            credential: None,
+            user_verified: false,
+        };
+        let webauthn = AuthContext {
+            method: AuthMethod::Webauthn,
+            ..password.clone()
+        };
+        let strong = ProtectedApp {
+            required_aal: Some(2),
+            ..Default::default()
+        };
+        let recent = ProtectedApp {
+            max_session_age: Some(60),
+            ..Default::default()
+        };
+
+        assert!(AuthContext::satisfies(
+            None,
+            &ProtectedApp::default(),
+            1700000000
+        ));
+        assert!(!AuthContext::satisfies(None, &strong, 1700000000));
+        assert!(!AuthContext::satisfies(
+            Some(&password),
+            &strong,
+            1700000000
+        ));
+        assert!(AuthContext::satisfies(Some(&webauthn), &strong, 1700000000));
+        assert!(AuthContext::satisfies(Some(&password), &recent, 1700000060));
+        assert!(!AuthContext::satisfies(
+            Some(&password),
+            &recent,
+            1700000061
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pending_ceremony() {
+        let db = tokio_rusqlite::Connection::open(":memory:").await.unwrap();
+        let store = SqliteSessionStore::new(db.clone());
+        store.init().await.unwrap();
+        let app = App::new(db);
+        app.init().await.unwrap();
+        let session = Session::new(None, Arc::new(store), None);
+        let no_headers = HeaderMap::new();
+
+        insert_pending_ceremony(&app, &session, "ceremony", 42, &Policy::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            take_pending_ceremony::<u32>(&app, &session, &no_headers, "ceremony")
+                .await
+                .unwrap(),
+            42
+        );
+        // challenges can only be answered once
+        assert!(matches!(
+            take_pending_ceremony::<u32>(&app, &session, &no_headers, "ceremony").await,
+            Err(AppError::BadSession)
+        ));
+
+        // the ID returned with the challenge works without the session, but only for its kind
+        let CeremonyId(id) =
+            insert_pending_ceremony(&app, &session, "ceremony", 43, &Policy::default())
+                .await
+                .unwrap();
+        session.remove_value("ceremony").await.unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(CEREMONY_ID_HEADER, id.parse().unwrap());
+        assert!(matches!(
+            take_pending_ceremony::<u32>(&app, &session, &headers, "other").await,
+            Err(AppError::BadSession)
+        ));
+        assert_eq!(
+            take_pending_ceremony::<u32>(&app, &session, &headers, "ceremony")
+                .await
+                .unwrap(),
+            43
+        );
+
+        // starting again replaces the pending ceremony
+        let CeremonyId(first) =
+            insert_pending_ceremony(&app, &session, "ceremony", 44, &Policy::default())
+                .await
+                .unwrap();
+        insert_pending_ceremony(&app, &session, "ceremony", 45, &Policy::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            app.take_ceremony(first, "ceremony".to_string())
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            take_pending_ceremony::<u32>(&app, &session, &no_headers, "ceremony")
+                .await
+                .unwrap(),
+            45
+        );
+
+        let policy = Policy {
+            ceremony_timeout: std::time::Duration::ZERO,
+            ..Default::default()
+        };
+        insert_pending_ceremony(&app, &session, "ceremony", 42, &policy)
+            .await
+            .unwrap();
+        assert!(matches!(
+            take_pending_ceremony::<u32>(&app, &session, &no_headers, "ceremony").await,
+            Err(AppError::CeremonyTimedOut)
+        ));
+        assert!(matches!(
+            take_page_error(&session, &PageErrorQueryParams { error: None })
+                .await
+                .unwrap(),
+            Some(PageErrorState {
+                code: PageError::CeremonyTimedOut,
+                ..
+            })
+        ));
+
+        insert_pending_ceremony(&app, &session, "ceremony", 42, &policy)
+            .await
+            .unwrap();
+        assert_eq!(app.delete_expired_ceremonies(unix_now()).await.unwrap(), 1);
+
+        // challenges can only be consumed once until they expire
+        let challenge = || "challenge".to_string();
+        assert!(app
+            .consume_challenge(challenge(), unix_now())
+            .await
+            .unwrap());
+        assert!(!app
+            .consume_challenge(challenge(), unix_now())
+            .await
+            .unwrap());
+        app.delete_expired_ceremonies(unix_now()).await.unwrap();
+        assert!(app
+            .consume_challenge(challenge(), unix_now())
+            .await
+            .unwrap());
+    }
+}
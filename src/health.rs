@@ -0,0 +1,101 @@
+use crate::app::SharedAppState;
+use metrics::gauge;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{error, info};
+
+/// How many accesses in a row have to fail for the database to be considered down.
+const FAILURE_THRESHOLD: u32 = 3;
+
+/// How often the database is probed, which is also how long clients are asked to wait before
+/// retrying while it is down.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A circuit breaker for the database: once requests (or probes) fail to access it a few times in
+/// a row, requests that need it are answered with `503 Service Unavailable` right away instead of
+/// each failing on their own, until a probe finds the database accessible again.
+#[derive(Debug, Default)]
+pub struct DatabaseHealth {
+    failures: AtomicU32,
+    down: AtomicBool,
+}
+
+impl DatabaseHealth {
+    pub fn is_down(&self) -> bool {
+        self.down.load(Ordering::Relaxed)
+    }
+
+    /// Records whether a request got through to the database, tripping the breaker after
+    /// `FAILURE_THRESHOLD` failures in a row.
+    pub fn record(&self, ok: bool) {
+        if ok {
+            self.failures.store(0, Ordering::Relaxed);
+            return;
+        }
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= FAILURE_THRESHOLD && !self.down.swap(true, Ordering::Relaxed) {
+            gauge!("database_available").set(0);
+            error!(
+                "could not access the database {failures} times in a row, answering requests \
+                 with 503 Service Unavailable until it is accessible again"
+            );
+        }
+    }
+
+    /// Records the result of a probe, which is the only way for the database to come back up.
+    pub fn probed(&self, ok: bool) {
+        if ok && self.down.swap(false, Ordering::Relaxed) {
+            gauge!("database_available").set(1);
+            info!("the database is accessible again, accepting requests");
+        }
+        self.record(ok);
+    }
+
+    /// Probes the database until the server exits.
+    pub async fn watch(self: Arc<Self>, app: SharedAppState) {
+        gauge!("database_available").set(1);
+        let mut interval = tokio::time::interval(PROBE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let ok = app.read().await.ping().await.is_ok();
+            self.probed(ok);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_database_health() {
+        let health = DatabaseHealth::default();
+        health.record(false);
+        health.record(false);
+        health.record(true);
+        health.record(false);
+        health.record(false);
+        assert!(!health.is_down());
+        health.record(false);
+        assert!(health.is_down());
+
+        // requests that make it through do not bring it back up, only probes do
+        health.record(true);
+        assert!(health.is_down());
+        health.probed(false);
+        assert!(health.is_down());
+        health.probed(true);
+        assert!(!health.is_down());
+
+        // probes alone trip it too
+        for _ in 0..FAILURE_THRESHOLD {
+            health.probed(false);
+        }
+        assert!(health.is_down());
+    }
+}
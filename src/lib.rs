@@ -0,0 +1,47 @@
+//! The server behind the `webauthn-tiny` binary. `main` assembles an `AppState` from the command
+//! line and serves the routers from `routes`, which embedders can mount on their own instead.
+
+pub mod app;
+pub mod assets;
+pub mod audit;
+pub mod build_info;
+pub mod captcha;
+pub mod ceremony_log;
+pub mod client_ip;
+pub mod config;
+pub mod disk;
+pub mod drain;
+pub mod exporter;
+pub mod forwarded_https;
+pub mod handlers;
+pub mod health;
+pub mod http_client;
+pub mod listener;
+pub mod locale;
+pub mod metadata;
+pub mod notify;
+pub mod origins;
+pub mod pam;
+pub mod policy;
+pub mod pow;
+pub mod providers;
+pub mod public_key;
+pub mod reconcile;
+pub mod recovery;
+pub mod routes;
+#[cfg(target_os = "linux")]
+pub mod sandbox;
+pub mod secret;
+pub mod seed;
+pub mod self_test;
+pub mod session;
+pub mod spa;
+pub mod startup;
+pub mod state;
+pub mod test_mode;
+pub mod throttle;
+pub mod timing;
+pub mod user_agent;
+pub mod username;
+pub mod validation;
+pub mod vault;
@@ -0,0 +1,349 @@
+use anyhow::Context;
+use axum::{
+    extract::connect_info::Connected,
+    serve::{IncomingStream, Listener},
+};
+use socket2::{Domain, Socket, Type};
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::{TcpListener, TcpStream, UnixListener},
+    sync::mpsc,
+};
+use tracing::debug;
+
+/// How long a connection may take to send its PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest PROXY protocol v1 header, including the trailing CRLF.
+const MAX_PROXY_V1_HEADER_LEN: usize = 107;
+
+const PROXY_V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Something to listen on given with `--address`, either a TCP socket address or a unix socket
+/// path prefixed with `unix:`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("unix socket path is empty".to_string()),
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(Self::Tcp)
+                .map_err(|_| format!("{s:?} is neither a socket address nor unix:<path>")),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{address}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Binds a TCP listener. IPv6 sockets accept IPv4 connections as well (where the system allows
+/// it) unless `v6_only` is set, which is needed to also bind the same port on an IPv4 address.
+pub fn bind_tcp(address: SocketAddr, v6_only: bool) -> anyhow::Result<TcpListener> {
+    let bind = || -> std::io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        if address.is_ipv6() {
+            socket.set_only_v6(v6_only)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&address.into())?;
+        socket.listen(1024)?;
+        TcpListener::from_std(socket.into())
+    };
+    bind().with_context(|| format!("could not bind {address}"))
+}
+
+/// Binds a unix socket with the given permissions, replacing a stale one left behind by a
+/// previous run. The socket is bound in a directory only the server can access and moved into
+/// place once it has its permissions, so it is never reachable with the ones the umask gives it.
+pub fn bind_unix(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{} is not a file path", path.display()))?;
+    let private = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .with_context(|| format!("could not create {}", private.display()))?;
+    let bind = || -> std::io::Result<UnixListener> {
+        let socket = private.join("socket");
+        let listener = UnixListener::bind(&socket)?;
+        std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(mode))?;
+        // Replaces the stale socket, if any.
+        std::fs::rename(&socket, path)?;
+        Ok(listener)
+    };
+    let listener = bind();
+    _ = std::fs::remove_dir_all(&private);
+    listener.with_context(|| format!("could not bind {}", path.display()))
+}
+
+/// Whether an IPv6 address has to be bound v6-only because an IPv4 address on the same port is
+/// bound as well.
+pub fn needs_v6_only(address: &SocketAddr, addresses: &[ListenAddress]) -> bool {
+    address.is_ipv6()
+        && addresses.iter().any(|other| {
+            matches!(other, ListenAddress::Tcp(other) if other.is_ipv4() && other.port() == address.port())
+        })
+}
+
+/// The client address of a connection, as reported by the proxy in front of the server in its
+/// PROXY protocol header. Unlike the peer address of a direct connection, it is trusted over
+/// `--real-ip-header` (see `ClientIp`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxiedAddr(pub SocketAddr);
+
+impl Connected<IncomingStream<'_, ProxyProtocolListener>> for ProxiedAddr {
+    fn connect_info(stream: IncomingStream<'_, ProxyProtocolListener>) -> Self {
+        *stream.remote_addr()
+    }
+}
+
+/// A TCP listener for `--proxy-protocol`, which reads the PROXY protocol (v1 or v2) header of
+/// each connection before handing it on. Connections without a valid header are closed.
+/// Headers are read in the background, so a slow connection does not hold up the others.
+pub struct ProxyProtocolListener {
+    local_addr: SocketAddr,
+    connections: mpsc::Receiver<(TcpStream, ProxiedAddr)>,
+}
+
+impl ProxyProtocolListener {
+    pub fn new(mut listener: TcpListener) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, connections) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, peer) = Listener::accept(&mut listener).await;
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut stream))
+                        .await
+                    {
+                        Ok(Ok(addr)) => {
+                            // The header of a health check (`LOCAL`) carries no client address.
+                            let addr = addr.unwrap_or(peer);
+                            _ = sender.send((stream, ProxiedAddr(addr))).await;
+                        }
+                        Ok(Err(e)) => debug!("invalid proxy protocol header from {peer}: {e}"),
+                        Err(_) => debug!("no proxy protocol header from {peer}"),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            local_addr,
+            connections,
+        })
+    }
+}
+
+impl Listener for ProxyProtocolListener {
+    type Io = TcpStream;
+    type Addr = ProxiedAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        // The accepting task holds a sender for as long as the listener exists.
+        self.connections
+            .recv()
+            .await
+            .expect("proxy protocol listener stopped accepting")
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(ProxiedAddr(self.local_addr))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads a PROXY protocol header, and nothing after it, returning the client address it carries
+/// or `None` if the proxy sent none (v1 `UNKNOWN`, v2 `LOCAL` or a non-IP address family).
+async fn read_proxy_header(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0; 12];
+    reader.read_exact(&mut start[..6]).await?;
+    if &start[..6] == b"PROXY " {
+        // v1 is a single line, read byte by byte so that nothing of the request is consumed.
+        let mut line = start[..6].to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == MAX_PROXY_V1_HEADER_LEN {
+                return Err(invalid("v1 header too long"));
+            }
+            line.push(reader.read_u8().await?);
+        }
+        let line = std::str::from_utf8(&line).map_err(|_| invalid("v1 header is not ASCII"))?;
+        return parse_proxy_v1(line.trim_end()).ok_or_else(|| invalid("malformed v1 header"));
+    }
+
+    reader.read_exact(&mut start[6..]).await?;
+    if &start != PROXY_V2_SIGNATURE {
+        return Err(invalid("no proxy protocol signature"));
+    }
+    let version_command = reader.read_u8().await?;
+    let family = reader.read_u8().await?;
+    let len = reader.read_u16().await?;
+    let mut addresses = vec![0; len.into()];
+    reader.read_exact(&mut addresses).await?;
+
+    match version_command {
+        0x20 => return Ok(None),
+        0x21 => {}
+        _ => return Err(invalid("unsupported v2 version or command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    Ok(match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().expect("checked length");
+            Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8)))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().expect("checked length");
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32)))
+        }
+        0x1 | 0x2 => return Err(invalid("v2 addresses too short")),
+        // AF_UNIX or AF_UNSPEC
+        _ => None,
+    })
+}
+
+/// Parses e.g. `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443`.
+fn parse_proxy_v1(line: &str) -> Option<Option<SocketAddr>> {
+    let mut fields = line.split(' ').skip(1);
+    match fields.next()? {
+        "UNKNOWN" => return Some(None),
+        "TCP4" | "TCP6" => {}
+        _ => return None,
+    }
+    let ip: IpAddr = fields.next()?.parse().ok()?;
+    let _destination: IpAddr = fields.next()?.parse().ok()?;
+    let port: u16 = fields.next()?.parse().ok()?;
+    let _destination_port: u16 = fields.next()?.parse().ok()?;
+    fields
+        .next()
+        .is_none()
+        .then_some(Some(SocketAddr::new(ip, port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_address() {
+        let addresses: Vec<ListenAddress> = ["[::]:8080", "0.0.0.0:8080", "unix:/run/foo.sock"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert_eq!(
+            addresses,
+            vec![
+                ListenAddress::Tcp("[::]:8080".parse().unwrap()),
+                ListenAddress::Tcp("0.0.0.0:8080".parse().unwrap()),
+                ListenAddress::Unix(PathBuf::from("/run/foo.sock")),
+            ]
+        );
+        assert_eq!(addresses[2].to_string(), "unix:/run/foo.sock");
+        assert!("unix:".parse::<ListenAddress>().is_err());
+        assert!("localhost:8080".parse::<ListenAddress>().is_err());
+
+        assert!(needs_v6_only(&"[::]:8080".parse().unwrap(), &addresses));
+        assert!(!needs_v6_only(&"[::]:8081".parse().unwrap(), &addresses));
+        assert!(!needs_v6_only(&"0.0.0.0:8080".parse().unwrap(), &addresses));
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let directory =
+            std::env::temp_dir().join(format!("webauthn-tiny-listener-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("test.sock");
+        std::fs::write(&path, "stale").unwrap();
+
+        let listener = bind_unix(&path, 0o660).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+        // the private directory is gone, and the socket can still be connected to where it was
+        // moved
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+        let (connected, accepted) =
+            tokio::join!(tokio::net::UnixStream::connect(&path), listener.accept());
+        connected.unwrap();
+        accepted.unwrap();
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_proxy_header() {
+        let mut v1: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            read_proxy_header(&mut v1).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(v1, b"GET / HTTP/1.1\r\n");
+
+        let mut v1: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(
+            read_proxy_header(&mut v1).await.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+        let mut v1: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut v1).await.unwrap(), None);
+        let mut v1: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n";
+        assert!(read_proxy_header(&mut v1).await.is_err());
+
+        let mut v2 = PROXY_V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[
+            0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 1, 0xbb,
+        ]);
+        v2.extend_from_slice(b"GET");
+        let mut reader = v2.as_slice();
+        assert_eq!(
+            read_proxy_header(&mut reader).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(reader, b"GET");
+
+        let mut v2 = PROXY_V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_proxy_header(&mut v2.as_slice()).await.unwrap(), None);
+
+        let mut request: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        assert!(read_proxy_header(&mut request).await.is_err());
+    }
+}
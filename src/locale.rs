@@ -0,0 +1,605 @@
+use std::{collections::BTreeMap, path::PathBuf};
+use tower_sessions::cookie::time::{util::is_leap_year, Date, Month, OffsetDateTime};
+
+/// The settings (see `App::replace_user_settings`) holding a user's IANA timezone, e.g.
+/// `Europe/Berlin`, and BCP 47 locale, e.g. `de-DE`.
+pub const TIMEZONE_SETTING: &str = "timezone";
+pub const LOCALE_SETTING: &str = "locale";
+
+/// Longest name of a timezone or locale that is accepted.
+const MAX_NAME_LEN: usize = 64;
+
+/// Where timestamps shown to a user are rendered for, from their `timezone` and `locale`
+/// settings. Users without a (still valid) timezone see UTC.
+#[derive(Debug, Clone, Default)]
+pub struct Preferences {
+    pub timezone: TimeZone,
+    pub locale: Option<String>,
+}
+
+impl Preferences {
+    pub fn from_settings(settings: &BTreeMap<String, serde_json::Value>) -> Self {
+        let setting = |key| settings.get(key).and_then(serde_json::Value::as_str);
+        Self {
+            timezone: setting(TIMEZONE_SETTING)
+                .and_then(TimeZone::named)
+                .unwrap_or_default(),
+            locale: setting(LOCALE_SETTING)
+                .filter(|locale| is_locale(locale))
+                .map(str::to_string),
+        }
+    }
+}
+
+/// Whether the timezone and locale settings, if present, are ones timestamps can be rendered
+/// for, so that typos are refused rather than silently falling back to UTC.
+pub fn valid_settings(settings: &BTreeMap<String, serde_json::Value>) -> bool {
+    let timezone = settings
+        .get(TIMEZONE_SETTING)
+        .map(|value| value.as_str().and_then(TimeZone::named).is_some());
+    let locale = settings
+        .get(LOCALE_SETTING)
+        .map(|value| value.as_str().is_some_and(is_locale));
+    timezone.unwrap_or(true) && locale.unwrap_or(true)
+}
+
+/// Whether `locale` looks like a BCP 47 language tag, e.g. `en`, `pt-BR` or `zh-Hant-TW`. This
+/// is only checked syntactically, as browsers fall back on their own for tags they do not know.
+pub fn is_locale(locale: &str) -> bool {
+    let mut subtags = locale.split('-');
+    let language = subtags.next().unwrap_or_default();
+    locale.len() <= MAX_NAME_LEN
+        && (2..=8).contains(&language.len())
+        && language.bytes().all(|b| b.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+        })
+}
+
+/// The offset from UTC in effect at some instant, along with its abbreviation, e.g. `CEST`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LocalTimeType {
+    /// Seconds east of UTC.
+    offset: i32,
+    abbreviation: String,
+}
+
+impl LocalTimeType {
+    fn utc() -> Self {
+        Self {
+            offset: 0,
+            abbreviation: "UTC".to_string(),
+        }
+    }
+}
+
+/// A timezone of the system's tz database (`$TZDIR`, or `/usr/share/zoneinfo`), read from its
+/// TZif file (RFC 8536).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZone {
+    name: String,
+    /// Instants (Unix timestamps) at which the local time type changes, ascending, along with
+    /// the index into `types` in effect from then on.
+    transitions: Vec<(i64, usize)>,
+    types: Vec<LocalTimeType>,
+    /// Applies after the last transition, e.g. `CET-1CEST,M3.5.0,M10.5.0/3`.
+    rule: Option<PosixRule>,
+}
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        Self {
+            name: "UTC".to_string(),
+            transitions: Vec::new(),
+            types: vec![LocalTimeType::utc()],
+            rule: None,
+        }
+    }
+}
+
+impl TimeZone {
+    /// Loads the timezone called `name`, e.g. `America/New_York`, or `None` if there is none by
+    /// that name.
+    pub fn named(name: &str) -> Option<Self> {
+        // Names are paths into the database, which must not lead out of it.
+        if name.is_empty()
+            || name.len() > MAX_NAME_LEN
+            || name.starts_with('/')
+            || name
+                .split('/')
+                .any(|part| part.is_empty() || part.starts_with('.'))
+            || !name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"/_+-".contains(&b))
+        {
+            return None;
+        }
+        let dir = std::env::var_os("TZDIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("/usr/share/zoneinfo"));
+        match std::fs::read(dir.join(name)) {
+            Ok(data) => Self::parse(name, &data),
+            // Without a database, UTC is still known.
+            Err(_) if name == "UTC" => Some(Self::default()),
+            Err(_) => None,
+        }
+    }
+
+    fn parse(name: &str, data: &[u8]) -> Option<Self> {
+        let mut reader = Reader(data);
+        let header = Header::read(&mut reader)?;
+        let (header, time_size) = if header.version >= b'2' {
+            // Skip the data with 32-bit transition times, which version 2 repeats with 64-bit
+            // ones followed by the rule.
+            reader.take(header.data_len(4))?;
+            (Header::read(&mut reader)?, 8)
+        } else {
+            (header, 4)
+        };
+
+        let times = (0..header.timecnt)
+            .map(|_| reader.int(time_size))
+            .collect::<Option<Vec<_>>>()?;
+        let indices = reader.take(header.timecnt)?;
+        let raw_types = (0..header.typecnt)
+            .map(|_| Some((reader.int(4)? as i32, reader.take(2)?)))
+            .collect::<Option<Vec<_>>>()?;
+        let abbreviations = reader.take(header.charcnt)?;
+        reader.take(header.leapcnt * (time_size + 4) + header.isstdcnt + header.isutcnt)?;
+
+        let types = raw_types
+            .into_iter()
+            .map(|(offset, flags)| {
+                let abbreviation = abbreviations.get(usize::from(flags[1])..)?;
+                let end = abbreviation.iter().position(|&b| b == 0)?;
+                Some(LocalTimeType {
+                    offset,
+                    abbreviation: String::from_utf8(abbreviation[..end].to_vec()).ok()?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if types.is_empty() {
+            return None;
+        }
+        let transitions = times
+            .into_iter()
+            .zip(indices.iter().map(|&i| usize::from(i)))
+            .map(|(at, i)| (i < types.len()).then_some((at, i)))
+            .collect::<Option<Vec<_>>>()?;
+
+        let rule = match time_size {
+            8 => {
+                let footer = std::str::from_utf8(reader.0).ok()?;
+                let footer = footer.strip_prefix('\n')?.split('\n').next()?;
+                if footer.is_empty() {
+                    None
+                } else {
+                    Some(PosixRule::parse(footer)?)
+                }
+            }
+            _ => None,
+        };
+
+        Some(Self {
+            name: name.to_string(),
+            transitions,
+            types,
+            rule,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn local_time_type(&self, at: i64) -> LocalTimeType {
+        let n = self.transitions.partition_point(|&(t, _)| t <= at);
+        if let (true, Some(rule)) = (n == self.transitions.len(), self.rule.as_ref()) {
+            return rule.local_time_type(at);
+        }
+        match n {
+            // Before the first transition, the first type applies.
+            0 => self.types[0].clone(),
+            n => self.types[self.transitions[n - 1].1].clone(),
+        }
+    }
+
+    /// `at` (a Unix timestamp) as RFC 3339 with the local offset, e.g.
+    /// `2024-07-01T14:00:00+02:00`.
+    pub fn rfc3339(&self, at: u64) -> String {
+        let (local, offset) = self.local(at);
+        let hours = offset.unsigned_abs() / 3600;
+        let minutes = offset.unsigned_abs() / 60 % 60;
+        let offset = match offset {
+            0 => "Z".to_string(),
+            _ if offset < 0 => format!("-{hours:02}:{minutes:02}"),
+            _ => format!("+{hours:02}:{minutes:02}"),
+        };
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{offset}",
+            local.year(),
+            u8::from(local.month()),
+            local.day(),
+            local.hour(),
+            local.minute(),
+            local.second()
+        )
+    }
+
+    /// `at` (a Unix timestamp) as shown in templates, e.g. `2024-07-01 14:00 CEST`.
+    pub fn display(&self, at: u64) -> String {
+        let (local, _) = self.local(at);
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02} {}",
+            local.year(),
+            u8::from(local.month()),
+            local.day(),
+            local.hour(),
+            local.minute(),
+            self.local_time_type(at as i64).abbreviation
+        )
+    }
+
+    /// The local date and time at `at`, with its offset in whole minutes (RFC 3339 has no
+    /// seconds in offsets, which only historical local mean times have).
+    fn local(&self, at: u64) -> (OffsetDateTime, i32) {
+        let at = at as i64;
+        let offset = self.local_time_type(at).offset / 60 * 60;
+        let local = OffsetDateTime::from_unix_timestamp(at + i64::from(offset))
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+        (local, offset)
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    /// A big-endian signed integer of 4 or 8 bytes.
+    fn int(&mut self, len: usize) -> Option<i64> {
+        let bytes = self.take(len)?;
+        Some(match len {
+            4 => i64::from(i32::from_be_bytes(bytes.try_into().ok()?)),
+            _ => i64::from_be_bytes(bytes.try_into().ok()?),
+        })
+    }
+}
+
+struct Header {
+    version: u8,
+    isutcnt: usize,
+    isstdcnt: usize,
+    leapcnt: usize,
+    timecnt: usize,
+    typecnt: usize,
+    charcnt: usize,
+}
+
+impl Header {
+    fn read(reader: &mut Reader) -> Option<Self> {
+        if reader.take(4)? != b"TZif" {
+            return None;
+        }
+        let version = reader.take(16)?[0];
+        let mut count = || usize::try_from(reader.int(4)?).ok();
+        Some(Self {
+            version,
+            isutcnt: count()?,
+            isstdcnt: count()?,
+            leapcnt: count()?,
+            timecnt: count()?,
+            typecnt: count()?,
+            charcnt: count()?,
+        })
+    }
+
+    fn data_len(&self, time_size: usize) -> usize {
+        self.timecnt * (time_size + 1)
+            + self.typecnt * 6
+            + self.charcnt
+            + self.leapcnt * (time_size + 4)
+            + self.isstdcnt
+            + self.isutcnt
+    }
+}
+
+/// A `TZ` string as in POSIX, which TZif files end with to describe local time after their last
+/// transition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PosixRule {
+    std: LocalTimeType,
+    dst: Option<(LocalTimeType, RuleDate, i32, RuleDate, i32)>,
+}
+
+/// The day of a year that daylight saving time starts or ends on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RuleDate {
+    /// `Jn`: the n-th day (1 to 365), not counting February 29.
+    Julian(u16),
+    /// `n`: the n-th day (0 to 365), counting February 29.
+    Zero(u16),
+    /// `Mm.w.d`: day `d` (0 being Sunday) of week `w` (5 being the last) of month `m`.
+    Month(u8, u8, u8),
+}
+
+impl RuleDate {
+    fn date(self, year: i32) -> Option<Date> {
+        let leap = is_leap_year(year);
+        match self {
+            Self::Julian(n) => {
+                let day = if leap && n >= 60 { n + 1 } else { n };
+                Date::from_ordinal_date(year, day).ok()
+            }
+            Self::Zero(n) => Date::from_ordinal_date(year, n + 1).ok(),
+            Self::Month(m, w, d) => {
+                let month = Month::try_from(m).ok()?;
+                let first = Date::from_calendar_date(year, month, 1).ok()?;
+                let first_weekday = first.weekday().number_days_from_sunday();
+                let mut day = 1 + (d + 7 - first_weekday) % 7 + (w - 1) * 7;
+                while day > month.length(year) {
+                    day -= 7;
+                }
+                Date::from_calendar_date(year, month, day).ok()
+            }
+        }
+    }
+}
+
+impl PosixRule {
+    fn parse(s: &str) -> Option<Self> {
+        let mut parser = RuleParser(s);
+        let std = LocalTimeType {
+            abbreviation: parser.abbreviation()?,
+            offset: -parser.time()?,
+        };
+        if parser.0.is_empty() {
+            return Some(Self { std, dst: None });
+        }
+        let abbreviation = parser.abbreviation()?;
+        let offset = if parser.0.starts_with(',') {
+            std.offset + 3600
+        } else {
+            -parser.time()?
+        };
+        let dst = LocalTimeType {
+            offset,
+            abbreviation,
+        };
+        let mut transition = || {
+            parser.expect(',')?;
+            let date = parser.date()?;
+            let time = if parser.0.starts_with('/') {
+                parser.expect('/')?;
+                parser.time()?
+            } else {
+                2 * 3600
+            };
+            Some((date, time))
+        };
+        let (start, start_time) = transition()?;
+        let (end, end_time) = transition()?;
+        if !parser.0.is_empty() {
+            return None;
+        }
+        Some(Self {
+            std,
+            dst: Some((dst, start, start_time, end, end_time)),
+        })
+    }
+
+    fn local_time_type(&self, at: i64) -> LocalTimeType {
+        let Some((dst, start, start_time, end, end_time)) = self.dst.as_ref() else {
+            return self.std.clone();
+        };
+        let year = OffsetDateTime::from_unix_timestamp(at + i64::from(self.std.offset))
+            .map(|local| local.year())
+            .unwrap_or(1970);
+        // Transition times are given in the local time in effect before them.
+        let instant = |date: &RuleDate, time: &i32, offset: i32| {
+            let midnight = date.date(year)?.midnight().assume_utc().unix_timestamp();
+            Some(midnight + i64::from(*time) - i64::from(offset))
+        };
+        let (Some(start), Some(end)) = (
+            instant(start, start_time, self.std.offset),
+            instant(end, end_time, dst.offset),
+        ) else {
+            return self.std.clone();
+        };
+        let in_dst = if start <= end {
+            start <= at && at < end
+        } else {
+            // Southern hemisphere, where daylight saving time spans the new year.
+            at < end || start <= at
+        };
+        if in_dst {
+            dst.clone()
+        } else {
+            self.std.clone()
+        }
+    }
+}
+
+struct RuleParser<'a>(&'a str);
+
+impl RuleParser<'_> {
+    fn expect(&mut self, c: char) -> Option<()> {
+        self.0 = self.0.strip_prefix(c)?;
+        Some(())
+    }
+
+    /// Either letters, e.g. `CET`, or anything in angle brackets, e.g. `<+03>`.
+    fn abbreviation(&mut self) -> Option<String> {
+        let (abbreviation, rest) = match self.0.strip_prefix('<') {
+            Some(quoted) => {
+                let (abbreviation, rest) = quoted.split_once('>')?;
+                (abbreviation, rest)
+            }
+            None => {
+                let end = self
+                    .0
+                    .find(|c: char| !c.is_ascii_alphabetic())
+                    .unwrap_or(self.0.len());
+                self.0.split_at(end)
+            }
+        };
+        if abbreviation.len() < 3 {
+            return None;
+        }
+        self.0 = rest;
+        Some(abbreviation.to_string())
+    }
+
+    fn number(&mut self) -> Option<i32> {
+        let end = self
+            .0
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.0.len());
+        let (number, rest) = self.0.split_at(end);
+        self.0 = rest;
+        number.parse().ok()
+    }
+
+    /// `[+-]hh[:mm[:ss]]`, in seconds.
+    fn time(&mut self) -> Option<i32> {
+        let sign = match self.0.chars().next() {
+            Some('-') => -1,
+            _ => 1,
+        };
+        self.0 = self.0.trim_start_matches(['+', '-']);
+        let mut seconds = self.number()? * 3600;
+        for factor in [60, 1] {
+            if self.expect(':').is_none() {
+                break;
+            }
+            seconds += self.number()? * factor;
+        }
+        Some(sign * seconds)
+    }
+
+    fn date(&mut self) -> Option<RuleDate> {
+        if self.expect('J').is_some() {
+            let n = u16::try_from(self.number()?).ok()?;
+            return (1..=365).contains(&n).then_some(RuleDate::Julian(n));
+        }
+        if self.expect('M').is_some() {
+            let m = u8::try_from(self.number()?).ok()?;
+            self.expect('.')?;
+            let w = u8::try_from(self.number()?).ok()?;
+            self.expect('.')?;
+            let d = u8::try_from(self.number()?).ok()?;
+            return ((1..=12).contains(&m) && (1..=5).contains(&w) && d <= 6)
+                .then_some(RuleDate::Month(m, w, d));
+        }
+        let n = u16::try_from(self.number()?).ok()?;
+        (n <= 365).then_some(RuleDate::Zero(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_posix_rule() {
+        let berlin = TimeZone {
+            rule: Some(PosixRule::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap()),
+            ..Default::default()
+        };
+        // 2024-01-15T12:00:00Z
+        assert_eq!(berlin.rfc3339(1705320000), "2024-01-15T13:00:00+01:00");
+        // 2024-07-01T12:00:00Z
+        assert_eq!(berlin.rfc3339(1719835200), "2024-07-01T14:00:00+02:00");
+        assert_eq!(berlin.display(1719835200), "2024-07-01 14:00 CEST");
+        // daylight saving time starts at 2024-03-31T01:00:00Z
+        assert_eq!(berlin.rfc3339(1711846799), "2024-03-31T01:59:59+01:00");
+        assert_eq!(berlin.rfc3339(1711846800), "2024-03-31T03:00:00+02:00");
+
+        let sydney = TimeZone {
+            rule: Some(PosixRule::parse("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(sydney.rfc3339(1705320000), "2024-01-15T23:00:00+11:00");
+        assert_eq!(sydney.rfc3339(1719835200), "2024-07-01T22:00:00+10:00");
+
+        let kolkata = PosixRule::parse("IST-5:30").unwrap();
+        assert_eq!(kolkata.local_time_type(0).offset, 19800);
+        let quoted = PosixRule::parse("<-03>3").unwrap();
+        assert_eq!(quoted.local_time_type(0).abbreviation, "-03");
+        assert_eq!(quoted.local_time_type(0).offset, -10800);
+        assert!(PosixRule::parse("CET-1CEST,M3.5.0").is_none());
+    }
+
+    #[test]
+    fn test_parse() {
+        // version 2 with one transition from LMT to +01, but no rule
+        let mut data = Vec::new();
+        let header = |data: &mut Vec<u8>| {
+            data.extend(b"TZif2");
+            data.extend([0; 15]);
+            for count in [0u32, 0, 0, 1, 2, 8] {
+                data.extend(count.to_be_bytes());
+            }
+        };
+        header(&mut data);
+        data.extend(0i32.to_be_bytes());
+        data.extend([1]);
+        data.extend(1800i32.to_be_bytes());
+        data.extend([0, 0]);
+        data.extend(3600i32.to_be_bytes());
+        data.extend([0, 4]);
+        data.extend(b"LMT\0+01\0");
+        header(&mut data);
+        data.extend(0i64.to_be_bytes());
+        data.extend([1]);
+        data.extend(1800i32.to_be_bytes());
+        data.extend([0, 0]);
+        data.extend(3600i32.to_be_bytes());
+        data.extend([0, 4]);
+        data.extend(b"LMT\0+01\0");
+        data.extend(b"\n\n");
+
+        let zone = TimeZone::parse("Test/Zone", &data).unwrap();
+        assert_eq!(zone.name(), "Test/Zone");
+        assert_eq!(zone.rfc3339(0), "1970-01-01T01:00:00+01:00");
+        assert_eq!(zone.display(0), "1970-01-01 01:00 +01");
+        assert_eq!(zone.local_time_type(-1).abbreviation, "LMT");
+        assert!(TimeZone::parse("Test/Zone", &data[..data.len() - 20]).is_none());
+
+        assert_eq!(TimeZone::default().rfc3339(0), "1970-01-01T00:00:00Z");
+        assert!(TimeZone::named("UTC").is_some());
+        assert!(TimeZone::named("../etc/passwd").is_none());
+        assert!(TimeZone::named("/etc/passwd").is_none());
+        assert!(TimeZone::named("Europe/.hidden").is_none());
+    }
+
+    #[test]
+    fn test_settings() {
+        assert!(is_locale("en"));
+        assert!(is_locale("zh-Hant-TW"));
+        assert!(!is_locale("e"));
+        assert!(!is_locale("en_US"));
+        assert!(!is_locale("en-"));
+
+        let settings = |key: &str, value: serde_json::Value| BTreeMap::from([(key.into(), value)]);
+        assert!(valid_settings(&BTreeMap::new()));
+        assert!(valid_settings(&settings(LOCALE_SETTING, "de-DE".into())));
+        assert!(!valid_settings(&settings(LOCALE_SETTING, 1.into())));
+        assert!(!valid_settings(&settings(
+            TIMEZONE_SETTING,
+            "Not/AZone".into()
+        )));
+
+        let preferences =
+            Preferences::from_settings(&settings(TIMEZONE_SETTING, "Not/AZone".into()));
+        assert_eq!(preferences.timezone.name(), "UTC");
+        assert_eq!(preferences.locale, None);
+    }
+}
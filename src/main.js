@@ -1,64 +1,213 @@
 import {
-  create,
-  get,
-  parseCreationOptionsFromJSON,
-  parseRequestOptionsFromJSON,
-} from "https://cdn.jsdelivr.net/npm/@github/webauthn-json@2.1.1/browser-ponyfill/+esm";
+  authenticate,
+  deleteCredential,
+  enroll,
+  enrollFirstCredential,
+  getHistory,
+  PATH_PREFIX,
+  recover,
+  redeemRecoveryCode,
+  register,
+  renderCaptcha,
+  skipEnrollment,
+  stepUp,
+} from "/assets/webauthn.js";
+// Failures of the browser's WebAuthn prompt (timeouts, the user cancelling)
+// are only visible to the client, so report them to the server for the next
+// page render to explain. Other failures are recorded by the server itself.
+async function showPageError(error) {
+  if (error.code === "timed_out" || error.code === "aborted") {
+    await fetch(`${PATH_PREFIX}/api/page-error`, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ error: "ceremony_timed_out" }),
+    });
+  }
+  location.reload();
+}
+// Formats dates for the timezone and locale from the user's settings, which
+// pages put on <main>, falling back to the browser's own.
+function dateFormat() {
+  const main = document.querySelector("main");
+  const options = { dateStyle: "medium", timeStyle: "short" };
+  try {
+    return new Intl.DateTimeFormat(main?.dataset.locale, {
+      ...options,
+      timeZone: main?.dataset.timezone,
+    });
+  } catch (_) {
+    return new Intl.DateTimeFormat(undefined, options);
+  }
+}
 document.addEventListener("DOMContentLoaded", () => {
+  const format = dateFormat();
+  for (const time of document.querySelectorAll("time[datetime]")) {
+    time.textContent = format.format(new Date(time.dateTime));
+  }
   for (const button of document.getElementsByClassName("delete-credential")) {
     button.addEventListener("click", async function (_) {
-      const cred_id = button.getAttribute("value");
-      if (cred_id && window.confirm("Do you want to delete this credential?")) {
-        const response = await fetch(`/api/credentials/${cred_id}`, {
-          method: "DELETE",
-        });
-        if (!response.ok) return window.alert("Failed to delete credential");
-        else if (response.status === 204) return location.reload();
+      const handle = button.getAttribute("value");
+      if (handle && window.confirm("Do you want to delete this credential?")) {
+        try {
+          await deleteCredential(handle);
+        } catch (_) {
+          return window.alert("Failed to delete credential");
+        }
+        return location.reload();
       }
     });
   }
   const addButton = document.getElementById("add-credential");
   if (addButton != null) {
     addButton.addEventListener("click", async function (_) {
+      try {
+        const registered = await register((suggestedName) => {
+          const name = window.prompt(
+            "Enter name for the new credential",
+            suggestedName,
+          );
+          if (name === "") {
+            window.alert("Name for new credential is empty");
+            return null;
+          }
+          return name;
+        });
+        if (registered) location.reload();
+      } catch (error) {
+        await showPageError(error);
+      }
+    });
+  }
+  const history = document.getElementById("history");
+  if (history != null) {
+    const list = document.getElementById("history-events");
+    const moreButton = document.getElementById("history-more");
+    let before = null;
+    const loadHistory = async () => {
+      let page;
+      try {
+        page = await getHistory({ before });
+      } catch (_) {
+        return window.alert("Failed to load history");
+      }
+      for (const { at, event, detail } of page.data) {
+        const item = document.createElement("li");
+        const date = format.format(new Date(at * 1000));
+        item.textContent = `${date}: ${event.replaceAll("_", " ")}`;
+        if (detail) item.textContent += ` (${detail})`;
+        list.append(item);
+      }
+      before = page.next;
+      moreButton.hidden = before === null;
+    };
+    // Only fetched once the history is opened.
+    history.addEventListener("toggle", () => {
+      if (history.open && list.childElementCount === 0) loadHistory();
+    });
+    moreButton.addEventListener("click", loadHistory);
+  }
+  const kioskForm = document.getElementById("kiosk-form");
+  if (kioskForm != null) {
+    kioskForm.addEventListener("submit", async function (event) {
+      event.preventDefault();
+      const usernameInput = document.getElementById("kiosk-username");
+      const username = usernameInput.value;
+      try {
+        await enroll(username, document.getElementById("kiosk-name").value);
+      } catch (error) {
+        return window.alert(`Failed to enroll ${username}: ${error.message}`);
+      }
+      const item = document.createElement("li");
+      item.textContent = username;
+      document.getElementById("kiosk-enrolled").prepend(item);
+      usernameInput.value = "";
+      usernameInput.focus();
+    });
+  }
+  const recoveryCodeForm = document.getElementById("recovery-code-form");
+  if (recoveryCodeForm != null) {
+    recoveryCodeForm.addEventListener("submit", async function (event) {
+      event.preventDefault();
+      const data = new FormData(recoveryCodeForm);
+      try {
+        await redeemRecoveryCode(data.get("username"), data.get("code"));
+      } catch (_) {
+        return window.alert("Invalid recovery code");
+      }
+      return location.reload();
+    });
+  }
+  const enrollButton = document.getElementById("enroll-credential");
+  if (enrollButton != null) {
+    enrollButton.addEventListener("click", async function (_) {
+      const name = window.prompt("Enter name for the new credential");
+      if (name === null) return;
+      else if (name === "") {
+        return window.alert("Name for new credential is empty");
+      }
+      try {
+        await enrollFirstCredential(name);
+      } catch (error) {
+        return window.alert(`Failed to register credential: ${error.message}`);
+      }
+      // If the credential awaits approval, the authenticate page explains that.
+      return location.replace(`${PATH_PREFIX}/authenticate`);
+    });
+  }
+  const skipButton = document.getElementById("skip-enrollment");
+  if (skipButton != null) {
+    skipButton.addEventListener("click", async function (_) {
+      try {
+        await skipEnrollment();
+      } catch (error) {
+        return window.alert(`Failed to skip: ${error.message}`);
+      }
+      return location.replace(`${PATH_PREFIX}/authenticate`); // client is now logged in
+    });
+  }
+  const recoverButton = document.getElementById("recover");
+  if (recoverButton != null) {
+    recoverButton.addEventListener("click", async function (_) {
       const newCredential = window.prompt("Enter name for the new credential");
       if (newCredential === null) return;
       else if (newCredential === "") {
         return window.alert("Name for new credential is empty");
       }
-      const startResponse = await fetch("/api/register", { method: "GET" });
-      if (!startResponse.ok) {
-        return window.alert("Failed to start credential registration");
+      try {
+        await recover(newCredential);
+      } catch (_) {
+        return window.alert("Failed to recover account");
       }
-      const endResponse = await fetch("/api/register", {
-        method: "POST",
-        headers: { "Content-Type": "application/json" },
-        body: JSON.stringify({
-          name: newCredential,
-          credential: await create(
-            parseCreationOptionsFromJSON(await startResponse.json()),
-          ),
-        }),
-      });
-      if (!endResponse.ok) {
-        window.alert("Failed to end credential registration");
-      } else location.reload();
+      return location.replace(`${PATH_PREFIX}/credentials`);
     });
   }
+  const login = async () => {
+    try {
+      const { noCredentials, mustReenroll } = await authenticate({
+        solveCaptcha: (captcha) =>
+          renderCaptcha(captcha, document.getElementById("captcha")),
+      });
+      if (noCredentials) return location.reload();
+      if (mustReenroll) return location.replace(`${PATH_PREFIX}/credentials`);
+      return location.replace(`${PATH_PREFIX}/authenticate`); // client is now logged in
+    } catch (error) {
+      await showPageError(error);
+    }
+  };
   if (document.getElementById("authenticating-msg") !== null) {
+    const retryButton = document.getElementById("retry");
+    // After a failure, wait for the user instead of prompting again right away.
+    if (retryButton !== null) retryButton.addEventListener("click", login);
+    else login();
+  }
+  if (document.getElementById("step-up-msg") !== null) {
     (async () => {
-      const startResponse = await fetch("/api/authenticate", { method: "GET" });
-      if (!startResponse.ok) {
-        return window.alert("Failed to start authentication");
-      } else if (startResponse.status === 204) return location.reload(); // no user credentials
-      const endResponse = await fetch("/api/authenticate", {
-        method: "POST",
-        headers: { "Content-Type": "application/json" },
-        body: JSON.stringify(
-          await get(parseRequestOptionsFromJSON(await startResponse.json())),
-        ),
-      });
-      if (!endResponse.ok) return window.alert("Not authenticated");
-      return location.replace("/authenticate"); // client is now logged in
+      try {
+        await stepUp();
+      } catch (_) {
+        return window.alert("Not verified");
+      }
+      return location.replace(`${PATH_PREFIX}/authenticate`); // client is now verified
     })().catch(console.error);
   }
 });
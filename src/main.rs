@@ -1,52 +1,270 @@
-mod app;
-mod handlers;
-mod session;
-
-use app::App;
-use axum::{
-    middleware,
-    routing::{delete, get},
-    Extension, Router,
-};
-use clap::Parser;
-use handlers::{
-    allow_only_localhost, authenticate_end_handler, authenticate_start_handler,
-    delete_credentials_api_handler, get_authenticate_template_handler,
-    get_credentials_template_handler, register_end_handler, register_start_handler,
-    require_logged_in, root_handler, Templates,
+use anyhow::{bail, Context};
+use axum::{error_handling::HandleErrorLayer, middleware, routing::post, Router};
+use clap::{ArgGroup, Parser, Subcommand};
+use futures_util::{
+    future::{try_join_all, BoxFuture},
+    FutureExt,
 };
 use metrics::counter;
 use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
-use std::{collections::HashMap, env, net::SocketAddr, path::PathBuf, sync::Arc};
-use tokio::sync::RwLock;
-use tokio_rusqlite::Connection;
-use tower_http::trace::TraceLayer;
-use tower_sessions::{cookie::Key, SessionManagerLayer};
-use tracing::debug;
+use secrecy::ExposeSecret;
+use std::{
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    path::PathBuf,
+    process::ExitCode,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::AsyncReadExt,
+    sync::{RwLock, Semaphore},
+};
+use tokio_rusqlite::{Connection, OpenFlags};
+use tower::{limit::GlobalConcurrencyLimitLayer, load_shed::LoadShedLayer, ServiceBuilder};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tower_sessions::{
+    cookie::{time::OffsetDateTime, Key},
+    Expiry, SessionManagerLayer,
+};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
-use webauthn_rs::{prelude::Url, WebauthnBuilder};
+use webauthn_rs::{prelude::Url, WebauthnBuilder, DEFAULT_AUTHENTICATOR_TIMEOUT};
+use webauthn_rs_proto::COSEAlgorithm;
+#[cfg(target_os = "linux")]
+use webauthn_tiny::sandbox::{self, SandboxPaths};
+use webauthn_tiny::{
+    app::{self, App, AuditLogVerification},
+    assets,
+    audit::AuditLogKey,
+    build_info::BuildInfo,
+    captcha::Captcha,
+    ceremony_log::{self, CeremonyLog},
+    client_ip::{ClientIpResolver, RealIpHeader},
+    config::Config,
+    disk::DiskSpace,
+    drain::Drain,
+    exporter::{self, MetricsExporter},
+    forwarded_https::ForwardedHttps,
+    handlers::{
+        html::Templates,
+        middleware::{
+            allow_only_localhost, degrade_when_database_down, handle_shed_request,
+            method_not_allowed,
+        },
+    },
+    health::DatabaseHealth,
+    http_client::{HttpClient, HttpClientConfig},
+    listener::{self, ListenAddress, ProxiedAddr, ProxyProtocolListener},
+    notify::Notifier,
+    origins::AllowedOrigins,
+    pam::{self, PamVerifier},
+    policy::{self, MaxUsers, Policy, SeededUsers},
+    pow::ProofOfWork,
+    reconcile::{Reconciler, Reconciliation, UserSource},
+    recovery::RecoveryTokens,
+    routes::{
+        admin_router, api_router, html_router, metrics_router, parse_path_prefix, RouterOptions,
+    },
+    secret::{self, SecretSource},
+    seed::Seed,
+    self_test::SelfTest,
+    session::{self, SqliteSessionStore},
+    startup::{self, StartupFailure},
+    state::AppState,
+    test_mode::{test_reset_handler, test_user_handler, TestMode},
+    throttle::LoginThrottle,
+    timing::{log_request_timings, RequestTimingConfig},
+    user_agent::SessionBinding,
+    username::{NormalizationStep, UsernameNormalization},
+    vault::{VaultAuth, VaultSecret},
+};
+use zeroize::Zeroizing;
 
 #[derive(Parser)]
-#[clap(author, version, about, long_about = None)] // Read from `Cargo.toml`
+#[clap(author, version, about, long_about = None, subcommand_negates_reqs = true)] // Read from `Cargo.toml`
+#[clap(group(
+    ArgGroup::new("session_secret_source")
+        .args(["session_secret_file", "session_secret_credential", "secret_command", "vault_secret_path"])
+))]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
     #[clap(
         env,
         long,
         value_parser,
-        help = "Address to bind on",
+        value_delimiter = ',',
+        help = "Address to bind on, either a socket address or unix:<path>, can be given multiple times",
         default_value = "[::]:8080"
     )]
-    address: SocketAddr,
-    #[clap(env, long, value_parser, help = "Relying Party ID")]
-    rp_id: String,
-    #[clap(env, long, value_parser, help = "Relying Party origin")]
-    rp_origin: String,
+    address: Vec<ListenAddress>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        value_delimiter = ',',
+        help = "Address to serve /api/admin (not served without one) and /metrics on instead of the other addresses, can be given multiple times"
+    )]
+    admin_address: Vec<ListenAddress>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Expect a PROXY protocol (v1 or v2) header on TCP connections to --address, e.g. from HAProxy, and take client addresses from it"
+    )]
+    proxy_protocol: bool,
+    #[clap(
+        env,
+        long,
+        value_enum,
+        help = "Header the reverse proxy puts the client address in, none to use the address of the connection",
+        default_value_t = RealIpHeader::None
+    )]
+    real_ip_header: RealIpHeader,
+    #[clap(
+        env,
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Number of proxies appending to --real-ip-header, the client address being the entry the outermost of them appended",
+        default_value_t = 1
+    )]
+    trusted_proxy_hops: u64,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Refuse ceremonies without X-Forwarded-Proto: https when --rp-origin is https, instead of only warning about them"
+    )]
+    require_forwarded_https: bool,
+    // The options required for running the server are optional in the struct so that
+    // subcommands can be run without them, clap still requires them otherwise.
+    #[clap(env, long, value_parser, required = true, help = "Relying Party ID")]
+    rp_id: Option<String>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        required = true,
+        help = "Relying Party origin"
+    )]
+    rp_origin: Option<String>,
     #[clap(env, long, value_parser, help = "Extra allowed origin")]
     extra_allowed_origin: Vec<String>,
-    #[clap(env, long, value_parser, help = "Session secret file")]
-    session_secret_file: PathBuf,
-    #[clap(env, long, value_parser, help = "Password file")]
-    password_file: PathBuf,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Start even though --rp-id differs from the one existing credentials were registered for, which then stop working"
+    )]
+    force_rp_id_change: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        required_unless_present_any = [
+            "auto_generate_session_secret",
+            "session_secret_credential",
+            "secret_command",
+            "vault_secret_path",
+        ],
+        help = "Session secret file (default with --auto-generate-session-secret: <STATE_DIRECTORY>/session-secret)"
+    )]
+    session_secret_file: Option<PathBuf>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        conflicts_with_all = ["session_secret_credential", "secret_command", "vault_secret_path"],
+        help = "Generate and persist a session secret if the session secret file does not exist"
+    )]
+    auto_generate_session_secret: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Name of the systemd credential (LoadCredential= or LoadCredentialEncrypted=) holding the session secret"
+    )]
+    session_secret_credential: Option<String>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Executable printing a secret to stdout, called with the name of the secret (session-secret)"
+    )]
+    secret_command: Option<PathBuf>,
+    #[clap(env, long, value_parser, help = "Address of the Vault server")]
+    vault_addr: Option<Url>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        requires = "vault_addr",
+        help = "Path of the KV secret holding the session secret, e.g. secret/data/webauthn-tiny"
+    )]
+    vault_secret_path: Option<String>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Field of the Vault secret holding the session secret",
+        default_value = "session_secret"
+    )]
+    vault_secret_field: String,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        conflicts_with = "vault_role_id",
+        help = "File containing a Vault token"
+    )]
+    vault_token_file: Option<PathBuf>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        requires = "vault_secret_id_file",
+        help = "Vault AppRole role ID"
+    )]
+    vault_role_id: Option<String>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        requires = "vault_role_id",
+        help = "File containing a Vault AppRole secret ID"
+    )]
+    vault_secret_id_file: Option<PathBuf>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "HTTP proxy for outbound requests (CAPTCHA verification, webhooks, Vault), e.g. http://proxy:3128"
+    )]
+    outbound_proxy: Option<Url>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "PEM file of CA certificates trusted for outbound requests in addition to the system's"
+    )]
+    outbound_ca_file: Option<PathBuf>,
+    #[clap(
+        env,
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Seconds for outbound requests to complete",
+        default_value_t = 10
+    )]
+    outbound_timeout_seconds: u64,
+    #[clap(env, long, value_parser, required = true, help = "Password file")]
+    password_file: Option<PathBuf>,
+    #[clap(env, long, value_parser, help = "Path to a JSON config file")]
+    config_file: Option<PathBuf>,
     #[clap(
         env,
         long,
@@ -55,6 +273,653 @@ struct Cli {
         default_value = "/var/lib/webauthn-tiny"
     )]
     state_directory: PathBuf,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "SQLite database for users and credentials (default: <STATE_DIRECTORY>/webauthn-tiny.db)"
+    )]
+    credential_db: Option<PathBuf>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "SQLite database for sessions (default: the credential database)"
+    )]
+    session_db: Option<PathBuf>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Open the databases read-only and only serve validation, e.g. from a replica"
+    )]
+    read_only: bool,
+    #[clap(
+        env,
+        long,
+        value_parser = policy::parse_algorithm,
+        help = "COSE algorithm allowed for new credentials, by name or ID (default: all)"
+    )]
+    allowed_algorithm: Vec<COSEAlgorithm>,
+    #[clap(
+        env,
+        long,
+        value_enum,
+        help = "Where to export metrics to",
+        default_value_t = MetricsExporter::Prometheus
+    )]
+    metrics: MetricsExporter,
+    #[clap(
+        env,
+        long,
+        value_parser = exporter::parse_label,
+        value_delimiter = ',',
+        help = "Label added to every metric as name=value, e.g. instance=auth-1, can be given multiple times"
+    )]
+    metrics_label: Vec<(String, String)>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        value_delimiter = ',',
+        help = "Upper bounds in seconds of the buckets of histograms, which are exported as summaries without them"
+    )]
+    metrics_buckets: Vec<f64>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Prometheus Pushgateway URL to periodically push metrics to"
+    )]
+    metrics_push_gateway: Option<String>,
+    #[clap(
+        env,
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Interval in seconds between metric pushes",
+        default_value_t = 15
+    )]
+    metrics_push_interval: u64,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Basic auth username for the Pushgateway"
+    )]
+    metrics_push_username: Option<String>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "File containing the basic auth password for the Pushgateway"
+    )]
+    metrics_push_password_file: Option<PathBuf>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Log requests taking at least this many milliseconds at WARN (0 disables)",
+        default_value_t = 1000
+    )]
+    slow_request_threshold_ms: u64,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Fraction of requests (0.0 to 1.0) to log with a timing breakdown",
+        default_value_t = 0.0
+    )]
+    trace_sample_rate: f64,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Require proof-of-work from clients when authentication failures spike"
+    )]
+    proof_of_work: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Authentication failures per minute at which proof-of-work is required",
+        default_value_t = 30
+    )]
+    proof_of_work_failure_threshold: u64,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Maximum proof-of-work difficulty in leading zero bits",
+        default_value_t = 20
+    )]
+    proof_of_work_max_difficulty: u32,
+    #[clap(
+        env,
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Refuse logins from a client address, or for a username, for 15 minutes after this many failures"
+    )]
+    max_login_failures: Option<u32>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Days after which users must register a replacement for a credential"
+    )]
+    credential_max_age_days: Option<u64>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Hours for which account recovery links issued by admins stay valid",
+        default_value_t = 24
+    )]
+    recovery_link_ttl_hours: u64,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Seconds users have to complete a WebAuthn prompt before its challenge expires",
+        default_value_t = DEFAULT_AUTHENTICATOR_TIMEOUT.as_secs()
+    )]
+    ceremony_timeout_seconds: u64,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Seconds between POST /api/admin/drain and shutting down, by default the ceremony timeout so that ceremonies started before can be finished"
+    )]
+    drain_grace_seconds: Option<u64>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Directory of a single-page app to serve instead of the built-in pages"
+    )]
+    spa_dist: Option<PathBuf>,
+    #[clap(
+        env,
+        long,
+        value_parser = parse_path_prefix,
+        help = "Path to serve everything under instead of the root, e.g. /auth"
+    )]
+    path_prefix: Option<String>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Path of the session cookie, by default the path prefix; set to / if apps validated with /api/validate are served outside of it"
+    )]
+    session_cookie_path: Option<String>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Log where WebAuthn payloads do not match what the server expects (always on in debug builds)"
+    )]
+    strict_validation: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Keep the last failed WebAuthn ceremonies (redacted) for GET /api/admin/debug/ceremonies"
+    )]
+    debug_ceremonies: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Maximum number of users, beyond which unknown usernames are refused"
+    )]
+    max_users: Option<usize>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        conflicts_with = "read_only",
+        help = "JSON file declaring users, reconciled into the database at startup"
+    )]
+    seed_file: Option<PathBuf>,
+    #[clap(
+        env,
+        long,
+        value_enum,
+        conflicts_with = "read_only",
+        help = "Hourly report, deactivate or delete users missing from the seed file, or the password file without one"
+    )]
+    reconcile_users: Option<Reconciliation>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Group whose members can use /kiosk to enroll credentials for other users"
+    )]
+    kiosk_group: Option<String>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Only accept credentials that authenticators protect with credProtect userVerificationRequired"
+    )]
+    require_cred_protect: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Hold back self-registered credentials until an admin approves them"
+    )]
+    require_registration_approval: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Walk users without credentials through registering one instead of logging them in with just their password"
+    )]
+    require_enrollment: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        requires = "require_enrollment",
+        help = "Days after their first login during which users may skip enrolling a credential",
+        default_value_t = 0
+    )]
+    enrollment_grace_period_days: u64,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Megabytes that must be free in the state directory for registrations to be accepted",
+        default_value_t = 64
+    )]
+    min_free_space_mb: u64,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Days after which audit log entries are deleted"
+    )]
+    audit_retention_days: Option<u64>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Days after which credential uses no longer count towards usage stats"
+    )]
+    usage_retention_days: Option<u64>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Let users missing from the password file log in with a password set through the admin API, asking them to enroll a credential each time"
+    )]
+    database_passwords: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Warn users none of whose credentials is tagged with this, e.g. backup, can be given multiple times"
+    )]
+    expected_credential_tag: Vec<String>,
+    #[clap(
+        env,
+        long,
+        value_enum,
+        value_delimiter = ',',
+        help = "Normalize usernames before looking them up, e.g. lowercase,strip-realm"
+    )]
+    normalize_username: Vec<NormalizationStep>,
+    #[clap(
+        env,
+        long,
+        value_enum,
+        help = "Log or reject requests from another browser or platform than the session was first used from",
+        default_value_t = SessionBinding::Off
+    )]
+    session_binding: SessionBinding,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Seconds after logging in or stepping up after which users have to step up again to e.g. delete a credential",
+        default_value_t = 5 * 60
+    )]
+    fresh_auth_max_age_seconds: u64,
+    #[clap(
+        env,
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Minutes of inactivity after which sessions expire, instead of two weeks after they last changed"
+    )]
+    session_idle_minutes: Option<u64>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        requires = "session_idle_minutes",
+        help = "Seconds between writes pushing back the expiry of a session that is in use",
+        default_value_t = 60
+    )]
+    session_touch_interval_seconds: u64,
+    #[clap(
+        env,
+        long,
+        value_parser = session::parse_legacy_sessions_until,
+        help = "Date (e.g. 2025-01-31) until which sessions of the axum-sessions cookie format are carried over into new sessions instead of their users being logged out"
+    )]
+    legacy_sessions_until: Option<OffsetDateTime>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Minutes that sessions opened with an impersonation link issued through the admin API last",
+        default_value_t = 15
+    )]
+    impersonation_minutes: u64,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Unix socket on which `pam-verify` can check sessions"
+    )]
+    pam_socket: Option<PathBuf>,
+    #[clap(
+        env,
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Number of requests handled at once across all addresses, further requests are answered with 503 Service Unavailable"
+    )]
+    max_concurrent_requests: Option<u32>,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Register and authenticate with a software authenticator from each allowed origin at startup, reporting the result at /readyz"
+    )]
+    self_test: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        conflicts_with = "read_only",
+        help = "Serve /api/test endpoints that reset the database and create users with credentials for virtual authenticators, for end-to-end tests; refused by release builds without --force-test-mode"
+    )]
+    test_mode: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        requires = "test_mode",
+        help = "Allow --test-mode in a release build"
+    )]
+    force_test_mode: bool,
+    #[clap(
+        env,
+        long,
+        value_parser,
+        help = "Restrict filesystem access to the state directory and the configured files with Landlock, and refuse unneeded syscalls with seccomp once listening (Linux only)"
+    )]
+    sandbox: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage users in the state directory
+    #[clap(subcommand)]
+    User(UserCommand),
+    /// Print a new random session secret, or write it to a file readable only by its owner
+    GenerateSecret {
+        #[clap(
+            long,
+            value_parser,
+            help = "File to write the secret to instead of stdout"
+        )]
+        output: Option<PathBuf>,
+    },
+    /// Check a session cookie read from stdin against a server running with --pam-socket, e.g.
+    /// from pam_exec
+    PamVerify {
+        #[clap(
+            long,
+            value_parser,
+            help = "Socket of the server",
+            default_value = "/run/webauthn-tiny/pam.sock"
+        )]
+        socket: PathBuf,
+        #[clap(env = "PAM_USER", long, value_parser, help = "User to verify")]
+        user: String,
+    },
+    /// Inspect the audit log in the state directory
+    #[clap(subcommand)]
+    Audit(AuditCommand),
+    /// Fill in what is recorded about credentials registered before it was, from their stored
+    /// passkeys
+    MigrateCredentials,
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// Rename a user after their username changed upstream, merging them into an existing user
+    /// of the new name
+    Rename { from: String, to: String },
+}
+
+#[derive(Subcommand)]
+enum AuditCommand {
+    /// Check that no entry was modified or removed since the server signed it with the session
+    /// secret
+    Verify,
+}
+
+fn credential_db_path(cli: &Cli) -> PathBuf {
+    cli.credential_db
+        .clone()
+        .unwrap_or_else(|| cli.state_directory.join("webauthn-tiny.db"))
+}
+
+/// Opens the credential and session databases and brings their schemas up to date. Both live in
+/// the same file unless a separate session database is configured, which keeps the high-churn
+/// session data away from credentials (e.g. on a tmpfs, or excluded from backups).
+async fn open_databases(cli: &Cli) -> anyhow::Result<(App, SqliteSessionStore)> {
+    let credential_db_path = credential_db_path(cli);
+    let flags = if cli.read_only {
+        OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX
+    } else {
+        OpenFlags::default()
+    };
+    let credential_db = Connection::open_with_flags(&credential_db_path, flags).await?;
+    let session_db = match cli.session_db.as_ref() {
+        Some(session_db_path) if *session_db_path != credential_db_path => {
+            Connection::open_with_flags(session_db_path, flags).await?
+        }
+        _ => credential_db.clone(),
+    };
+
+    let store = SqliteSessionStore::new(session_db);
+    let app = App::new(credential_db);
+
+    if cli.read_only {
+        if !app.schema_is_current().await? {
+            bail!(
+                "the database schema does not match this version, and cannot be migrated in \
+                 read-only mode; run a writable instance of the same version against it first"
+            );
+        }
+    } else {
+        store.init().await?;
+        app.init().await?;
+    }
+
+    Ok((app, store))
+}
+
+/// Sessions of the axum-sessions format are upgraded on their next request until
+/// `--legacy-sessions-until`, and deleted on the first start after it (or without it), as they
+/// could not be used anymore anyway.
+async fn migrate_legacy_sessions(cli: &Cli, store: &SqliteSessionStore) -> anyhow::Result<()> {
+    match cli.legacy_sessions_until {
+        Some(until) if OffsetDateTime::now_utc() < until => {
+            let count = store.count_legacy().await?;
+            if count > 0 {
+                info!(
+                    "{count} sessions of the axum-sessions format are upgraded on their next \
+                     request until {until}"
+                );
+            }
+        }
+        _ => {
+            let deleted = store.delete_legacy().await?;
+            if deleted > 0 {
+                info!("deleted {deleted} sessions of the axum-sessions format");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Refuses to start with another RP ID than the database was used with, as the credentials in it
+/// are scoped to that RP ID and would silently stop working.
+async fn check_rp_id(cli: &Cli, app: &App, rp_id: &str) -> anyhow::Result<()> {
+    if let Some(stored) = app.stored_rp_id().await?.filter(|stored| stored != rp_id) {
+        let credentials = app.credential_count().await?;
+        if credentials > 0 && !cli.force_rp_id_change {
+            bail!(
+                "--rp-id is {rp_id}, but the {credentials} credentials in the database were \
+                 registered for {stored} and cannot be used with another RP ID; set --rp-id \
+                 back to {stored}, or pass --force-rp-id-change to have users register new \
+                 credentials"
+            );
+        }
+        warn!("RP ID changed from {stored} to {rp_id}, {credentials} credentials no longer work");
+    }
+    if !cli.read_only {
+        app.record_rp_id(rp_id.to_string()).await?;
+    }
+    Ok(())
+}
+
+/// Runs a maintenance subcommand instead of starting the server.
+async fn run_command(cli: &Cli, command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::User(_) if cli.read_only => bail!("users cannot be managed in read-only mode"),
+        Command::User(UserCommand::Rename { from, to }) => {
+            let (app, store) = open_databases(cli).await?;
+            let app = app.with_audit_log_key(audit_log_key(cli).await?);
+            let count = app.rename_user(from.clone(), to.clone()).await?;
+            store.rename_user(from.clone(), to.clone()).await?;
+            println!("renamed {from} to {to}, moving {count} credentials");
+        }
+        Command::GenerateSecret { output: None } => print!("{}", secret::generate().as_str()),
+        Command::GenerateSecret {
+            output: Some(output),
+        } => secret::write_new(&output)?,
+        Command::PamVerify { socket, user } => {
+            let mut token = String::new();
+            tokio::io::stdin().read_to_string(&mut token).await?;
+            // pam_exec terminates the token it passes with expose_authtok with a NUL byte.
+            let token = token.trim_end_matches('\0').to_string();
+            if !pam::verify_with_server(&socket, user, token).await? {
+                bail!("not a valid session");
+            }
+        }
+        Command::MigrateCredentials if cli.read_only => {
+            bail!("credentials cannot be migrated in read-only mode")
+        }
+        Command::MigrateCredentials => {
+            let (app, _) = open_databases(cli).await?;
+            let app = app.with_audit_log_key(audit_log_key(cli).await?);
+            let migrations = app.migrate_credentials().await?;
+            let mut failed = 0;
+            for migration in &migrations {
+                let credential = format!("{}/{}", migration.username, migration.name);
+                match &migration.result {
+                    Ok(columns) if columns.is_empty() => println!("unchanged {credential}"),
+                    Ok(columns) => println!("migrated {credential}: filled {}", columns.join(", ")),
+                    Err(e) => {
+                        failed += 1;
+                        println!("failed {credential}: {e}");
+                    }
+                }
+            }
+            if failed > 0 {
+                bail!(
+                    "{failed} of {} credentials could not be migrated",
+                    migrations.len()
+                );
+            }
+            println!("migrated {} credentials", migrations.len());
+        }
+        Command::Audit(AuditCommand::Verify) => {
+            let (app, _) = open_databases(cli).await?;
+            match app.verify_audit_log(audit_log_key(cli).await?).await? {
+                verification @ AuditLogVerification::Intact { .. } => println!("{verification}"),
+                verification => bail!("{verification}"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Audit log entries are signed with a key derived from the session secret, so that
+/// subcommands rewriting the log have to be given the secret too.
+async fn audit_log_key(cli: &Cli) -> anyhow::Result<AuditLogKey> {
+    let session_secret = secret::load(&*session_secret_source(cli, &http_client(cli)?)?).await?;
+    Ok(AuditLogKey::new(session_secret.expose_secret().as_bytes()))
+}
+
+fn http_client(cli: &Cli) -> anyhow::Result<HttpClient> {
+    HttpClient::new(&HttpClientConfig {
+        proxy: cli.outbound_proxy.clone(),
+        ca_file: cli.outbound_ca_file.clone(),
+        timeout: Duration::from_secs(cli.outbound_timeout_seconds),
+    })
+}
+
+/// Picks where to read the session secret from, clap makes sure at most one source is given.
+fn session_secret_source(
+    cli: &Cli,
+    http_client: &HttpClient,
+) -> anyhow::Result<Box<dyn SecretSource>> {
+    if let Some(name) = &cli.session_secret_credential {
+        return Ok(Box::new(secret::SystemdCredential { name: name.clone() }));
+    }
+    if let Some(program) = &cli.secret_command {
+        return Ok(Box::new(secret::Command {
+            program: program.clone(),
+            name: "session-secret",
+        }));
+    }
+    if let Some(path) = &cli.vault_secret_path {
+        let auth = match (
+            &cli.vault_token_file,
+            &cli.vault_role_id,
+            &cli.vault_secret_id_file,
+        ) {
+            (Some(token_file), _, _) => VaultAuth::TokenFile(token_file.clone()),
+            (None, Some(role_id), Some(secret_id_file)) => VaultAuth::AppRole {
+                role_id: role_id.clone(),
+                secret_id_file: secret_id_file.clone(),
+            },
+            _ => bail!("--vault-secret-path requires --vault-token-file or --vault-role-id"),
+        };
+        return Ok(Box::new(VaultSecret {
+            address: cli.vault_addr.clone().expect("required by clap"),
+            auth,
+            path: path.clone(),
+            field: cli.vault_secret_field.clone(),
+            client: http_client.clone(),
+        }));
+    }
+    Ok(Box::new(secret::File {
+        path: cli
+            .session_secret_file
+            .clone()
+            .unwrap_or_else(|| cli.state_directory.join("session-secret")),
+        auto_generate: cli.auto_generate_session_secret,
+    }))
 }
 
 fn read_password_file(filepath: PathBuf) -> anyhow::Result<HashMap<String, String>> {
@@ -68,49 +933,227 @@ fn read_password_file(filepath: PathBuf) -> anyhow::Result<HashMap<String, Strin
         }))
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+/// How long connections still open once draining is finished are waited for.
+const DRAIN_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often ceremonies that were started but never finished are deleted.
+const CEREMONY_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Deletes expired ceremonies until the server exits.
+async fn delete_expired_ceremonies(app: app::SharedAppState) {
+    let mut interval = tokio::time::interval(CEREMONY_CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        match app.read().await.delete_expired_ceremonies(now).await {
+            Ok(0) => {}
+            Ok(count) => debug!("deleted {count} expired ceremonies"),
+            Err(e) => error!("could not delete expired ceremonies: {e}"),
+        }
+    }
+}
+
+/// How often data older than the retention windows is deleted.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Deletes audit log entries and credential uses older than their retention windows, given in
+/// seconds, until the server exits.
+async fn purge_expired_data(
+    app: app::SharedAppState,
+    audit_retention: Option<u64>,
+    usage_retention: Option<u64>,
+) {
+    let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let app = app.read().await;
+        if let Some(retention) = audit_retention {
+            match app.purge_audit_log(now.saturating_sub(retention)).await {
+                Ok(0) => {}
+                Ok(count) => {
+                    counter!("purged_audit_log_entries").increment(count as u64);
+                    debug!("purged {count} audit log entries");
+                }
+                Err(e) => error!("could not purge audit log: {e}"),
+            }
+        }
+        if let Some(retention) = usage_retention {
+            match app
+                .purge_credential_uses(now.saturating_sub(retention))
+                .await
+            {
+                Ok(0) => {}
+                Ok(count) => {
+                    counter!("purged_credential_uses").increment(count as u64);
+                    debug!("purged {count} credential uses");
+                }
+                Err(e) => error!("could not purge credential uses: {e}"),
+            }
+        }
+    }
+}
+
+/// Installs the global metrics recorder, unless metrics are off. Metrics are then rendered at
+/// `/metrics`, and are additionally pushed to a Pushgateway if one is configured.
+fn install_metrics_recorder(cli: &Cli) -> anyhow::Result<Option<PrometheusHandle>> {
+    if cli.metrics == MetricsExporter::Off {
+        if cli.metrics_push_gateway.is_some() {
+            bail!("--metrics-push-gateway cannot be used with --metrics off");
+        }
+        return Ok(None);
+    }
+
+    let mut builder = PrometheusBuilder::new();
+    for (name, value) in &cli.metrics_label {
+        builder = builder.add_global_label(name, value);
+    }
+    if !cli.metrics_buckets.is_empty() {
+        builder = builder.set_buckets(&cli.metrics_buckets)?;
+    }
+
+    let Some(endpoint) = cli.metrics_push_gateway.as_ref() else {
+        return Ok(Some(builder.install_recorder()?));
+    };
+
+    // The exporter keeps its own copy, only the one read from the file is overwritten.
+    let password = match cli.metrics_push_password_file.as_ref() {
+        Some(password_file) => Some(
+            Zeroizing::new(std::fs::read_to_string(password_file)?)
+                .trim()
+                .to_string(),
+        ),
+        None => None,
+    };
+
+    let (recorder, exporter) = builder
+        .with_push_gateway(
+            endpoint,
+            Duration::from_secs(cli.metrics_push_interval),
+            cli.metrics_push_username.clone(),
+            password,
+        )?
+        .build()?;
+    let handle = recorder.handle();
+    metrics::set_global_recorder(recorder)?;
+    tokio::spawn(exporter);
+
+    debug!("pushing metrics to {endpoint}");
+
+    Ok(Some(handle))
+}
+
+fn main() -> ExitCode {
     tracing_subscriber::registry()
         .with(fmt::layer())
         .with(EnvFilter::from_env("WEBAUTHN_TINY_LOG"))
         .init();
 
-    let prometheus_handle = PrometheusBuilder::new().install_recorder()?;
+    match start(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => startup::report(&e),
+    }
+}
 
-    counter!("successful_registrations").absolute(0);
-    counter!("failed_registrations").absolute(0);
-    counter!("successful_authentications").absolute(0);
-    counter!("failed_authentications").absolute(0);
-    counter!("authorized_requests").absolute(0);
-    counter!("unauthorized_requests").absolute(0);
+fn start(mut cli: Cli) -> anyhow::Result<()> {
+    let runtime = || {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+    };
+
+    if let Some(command) = cli.command.take() {
+        return runtime()?.block_on(run_command(&cli, command));
+    }
 
-    let cli = Cli::parse();
-    let origin_url = Url::parse(&cli.rp_origin)?;
-    let mut builder = WebauthnBuilder::new(&cli.rp_id, &origin_url)?.allow_subdomains(true);
-    for url in cli.extra_allowed_origin {
-        builder = builder.append_allowed_origin(&Url::parse(&url)?);
+    let config = match cli.config_file.as_ref() {
+        Some(config_file) => Config::load(config_file).context(StartupFailure::Config)?,
+        None => Config::default(),
+    };
+
+    // Landlock only restricts the threads started after it, so this has to come before the
+    // runtime starts its workers.
+    if cli.sandbox {
+        #[cfg(target_os = "linux")]
+        sandbox::restrict_filesystem(&sandbox_paths(&cli, &config))?;
+        #[cfg(not(target_os = "linux"))]
+        bail!("--sandbox is only supported on Linux");
     }
-    let webauthn = builder.build()?;
 
-    let mut db_path = cli.state_directory;
-    db_path.push("webauthn-tiny.db");
-    let db = Connection::open(db_path).await?;
+    // Dropping the runtime drops the tasks still holding the state, and with it the keys
+    // derived from the session secret, before the process exits.
+    let runtime = runtime()?;
+    let result = runtime.block_on(run(cli, config));
+    drop(runtime);
+    result
+}
 
-    let store = session::SqliteSessionStore::new(db.clone());
-    store.init().await?;
+/// What `--sandbox` leaves accessible of the files and directories given on the command line and
+/// in the config file.
+#[cfg(target_os = "linux")]
+fn sandbox_paths(cli: &Cli, config: &Config) -> SandboxPaths {
+    let parent = |path: &PathBuf| path.parent().map(PathBuf::from);
+    let unix_sockets = cli
+        .address
+        .iter()
+        .chain(cli.admin_address.iter())
+        .filter_map(|address| match address {
+            ListenAddress::Unix(path) => parent(path),
+            ListenAddress::Tcp(_) => None,
+        });
 
-    let session_layer = SessionManagerLayer::new(store)
-        .with_private(Key::try_from(
-            std::fs::read_to_string(cli.session_secret_file)?.as_bytes(),
-        )?)
-        .with_always_save(false)
-        .with_domain(cli.rp_id);
+    let mut write: Vec<_> = [cli.state_directory.clone()]
+        .into_iter()
+        .chain(cli.credential_db.as_ref().and_then(parent))
+        .chain(cli.session_db.as_ref().and_then(parent))
+        .chain(cli.pam_socket.as_ref().and_then(parent))
+        .chain(unix_sockets)
+        .collect();
+    // The session secret is generated next to where it is expected.
+    if cli.auto_generate_session_secret {
+        write.extend(cli.session_secret_file.as_ref().and_then(parent));
+    }
+
+    let read = [
+        &cli.password_file,
+        &cli.config_file,
+        &cli.seed_file,
+        &cli.spa_dist,
+        &cli.session_secret_file,
+        &cli.secret_command,
+        &cli.vault_token_file,
+        &cli.vault_secret_id_file,
+        &cli.outbound_ca_file,
+        &cli.metrics_push_password_file,
+        &config.error_template,
+        &config
+            .captcha
+            .as_ref()
+            .and_then(|captcha| captcha.secret_key_file.clone()),
+    ]
+    .into_iter()
+    .flatten()
+    .cloned()
+    .chain(std::env::var_os("CREDENTIALS_DIRECTORY").map(PathBuf::from))
+    .collect();
 
-    let app = App::new(db);
-    app.init().await?;
+    SandboxPaths { read, write }
+}
 
+/// Parses the built-in templates, and the error template of the config file if it has one.
+fn load_templates(config: &Config, path_prefix: &str) -> anyhow::Result<Templates> {
     let parser = liquid::ParserBuilder::with_stdlib().build()?;
-    let templates = Templates {
+    Ok(Templates {
+        layout_template: parser.parse(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/templates/layout.liquid"
+        )))?,
         credentials_template: parser.parse(include_str!(concat!(
             env!("CARGO_MANIFEST_DIR"),
             "/templates/credentials.liquid"
@@ -119,51 +1162,515 @@ async fn main() -> anyhow::Result<()> {
             env!("CARGO_MANIFEST_DIR"),
             "/templates/authenticate.liquid"
         )))?,
+        recover_template: parser.parse(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/templates/recover.liquid"
+        )))?,
+        kiosk_template: parser.parse(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/templates/kiosk.liquid"
+        )))?,
+        enroll_template: parser.parse(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/templates/enroll.liquid"
+        )))?,
+        error_template: match config.error_template.as_ref() {
+            Some(path) => parser.parse(
+                &std::fs::read_to_string(path)
+                    .with_context(|| format!("could not read {}", path.display()))?,
+            )?,
+            None => parser.parse(include_str!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/templates/error.liquid"
+            )))?,
+        },
+        theme: config.theme.clone(),
+        path_prefix: path_prefix.to_string(),
+    })
+}
+
+async fn run(cli: Cli, config: Config) -> anyhow::Result<()> {
+    let required = "required by clap when no subcommand is given";
+    let rp_id = cli.rp_id.clone().expect(required);
+    let rp_origin = cli.rp_origin.clone().expect(required);
+    let password_file = cli.password_file.clone().expect(required);
+    let path_prefix = cli.path_prefix.clone().unwrap_or_default();
+    assets::init(&path_prefix);
+
+    if cli.test_mode {
+        if !cfg!(debug_assertions) && !cli.force_test_mode {
+            bail!(
+                "--test-mode lets anyone reset the database, and is refused by release builds \
+                 unless --force-test-mode is given"
+            );
+        }
+        warn!("test mode is enabled, anyone can reset the database at /api/test/reset");
+    }
+
+    let prometheus_handle = install_metrics_recorder(&cli)?;
+
+    counter!("successful_registrations").absolute(0);
+    counter!("failed_registrations").absolute(0);
+    counter!("successful_authentications").absolute(0);
+    counter!("failed_authentications").absolute(0);
+    counter!("authorized_requests").absolute(0);
+    counter!("unauthorized_requests").absolute(0);
+    counter!("failed_proofs_of_work").absolute(0);
+    counter!("throttled_logins").absolute(0);
+    counter!("account_recoveries").absolute(0);
+    counter!("failed_captchas").absolute(0);
+    counter!("shed_requests").absolute(0);
+    counter!("database_unavailable_requests").absolute(0);
+    counter!("session_binding_mismatches").absolute(0);
+    counter!("purged_audit_log_entries").absolute(0);
+    counter!("purged_credential_uses").absolute(0);
+    counter!("reconciled_users").absolute(0);
+    counter!("plaintext_ceremonies").absolute(0);
+    counter!("impersonations").absolute(0);
+    counter!("upgraded_legacy_sessions").absolute(0);
+    counter!("legacy_credential_ids").absolute(0);
+    BuildInfo::new().record_metric();
+
+    let origin_url = Url::parse(&rp_origin)
+        .with_context(|| format!("--rp-origin {rp_origin} is not a URL"))
+        .context(StartupFailure::RpOrigin)?;
+    let ceremony_timeout = Duration::from_secs(cli.ceremony_timeout_seconds);
+    let mut builder = WebauthnBuilder::new(&rp_id, &origin_url)
+        .context(StartupFailure::RpOrigin)?
+        .allow_subdomains(true)
+        .timeout(ceremony_timeout);
+    for url in cli.extra_allowed_origin.iter() {
+        builder = builder.append_allowed_origin(
+            &Url::parse(url)
+                .with_context(|| format!("--extra-allowed-origin {url} is not a URL"))
+                .context(StartupFailure::RpOrigin)?,
+        );
+    }
+    for url in config.related_origins.iter() {
+        builder = builder.append_allowed_origin(url);
+    }
+    let webauthn = builder.build().context(StartupFailure::RpOrigin)?;
+
+    // Related origins are only usable through /.well-known/webauthn, which the software
+    // authenticator does not consult.
+    let self_test = if cli.self_test {
+        let origins = std::iter::once(Ok(origin_url.clone()))
+            .chain(cli.extra_allowed_origin.iter().map(|url| Url::parse(url)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let self_test = SelfTest::run(&webauthn, &origins);
+        match &self_test {
+            SelfTest::Failed(e) => error!("self-test failed: {e}"),
+            _ => info!("self-test passed"),
+        }
+        self_test
+    } else {
+        SelfTest::Skipped
     };
 
-    let router = Router::new()
-        .route(
-            "/metrics",
-            get(
-                |prom_handle: Extension<Arc<PrometheusHandle>>| async move { prom_handle.render() },
-            )
-            .layer(middleware::from_fn(allow_only_localhost)),
-        )
-        .route(
-            "/api/validate",
-            get(|| async {}).layer(middleware::from_fn(require_logged_in)),
-        )
-        .route(
-            "/api/register",
-            get(register_start_handler)
-                .post(register_end_handler)
-                .layer(middleware::from_fn(require_logged_in)),
-        )
-        .route(
-            "/api/authenticate",
-            get(authenticate_start_handler).post(authenticate_end_handler),
-        )
-        .route(
-            "/api/credentials/{cred_id}",
-            delete(delete_credentials_api_handler).layer(middleware::from_fn(require_logged_in)),
+    let http_client = http_client(&cli)?;
+    let session_secret = secret::load(&*session_secret_source(&cli, &http_client)?)
+        .await
+        .context(StartupFailure::Config)?;
+
+    let username_normalization = UsernameNormalization::new(&cli.normalize_username);
+    if cli.credential_db.is_none() && !cli.state_directory.is_dir() {
+        return Err(anyhow::anyhow!(
+            "{} does not exist or is not a directory",
+            cli.state_directory.display()
         )
-        .route("/authenticate", get(get_authenticate_template_handler))
-        .route("/credentials", get(get_credentials_template_handler))
-        .fallback(root_handler)
-        .layer(TraceLayer::new_for_http())
-        .layer(session_layer)
-        .layer(Extension(Arc::new(RwLock::new(app))))
-        .layer(Extension(Arc::new(webauthn)))
-        .layer(Extension(Arc::new(templates)))
-        .layer(Extension(Arc::new(prometheus_handle)))
-        .layer(Extension(read_password_file(cli.password_file)?))
-        .into_make_service_with_connect_info::<SocketAddr>();
+        .context(StartupFailure::StateDirectory));
+    }
+    let (app, store) = open_databases(&cli).await.map_err(|e| {
+        let failure = StartupFailure::database(&e);
+        e.context(failure)
+    })?;
+    if !cli.read_only {
+        migrate_legacy_sessions(&cli, &store).await.map_err(|e| {
+            let failure = StartupFailure::database(&e);
+            e.context(failure)
+        })?;
+    }
+
+    let mut databases = vec![("credentials", credential_db_path(&cli))];
+    if let Some(session_db_path) = cli.session_db.clone() {
+        if session_db_path != databases[0].1 {
+            databases.push(("sessions", session_db_path));
+        }
+    }
+    let disk_space = Arc::new(DiskSpace::new(
+        cli.state_directory.clone(),
+        databases,
+        cli.min_free_space_mb * 1024 * 1024,
+    ));
+    disk_space.check();
+    tokio::spawn(disk_space.clone().watch());
+
+    let app = app
+        .with_username_normalization(username_normalization.clone())
+        .with_audit_log_key(AuditLogKey::new(session_secret.expose_secret().as_bytes()));
+    check_rp_id(&cli, &app, &rp_id)
+        .await
+        .context(StartupFailure::Config)?;
+    for (username, usernames) in app.unnormalized_usernames().await? {
+        warn!(
+            "users {} are now looked up as {username}, rename them to it with `user rename`",
+            usernames.join(", ")
+        );
+    }
+    let seed = cli
+        .seed_file
+        .as_deref()
+        .map(Seed::load)
+        .transpose()
+        .context(StartupFailure::Config)?
+        .map(|seed| seed.normalize_usernames(&username_normalization))
+        .transpose()
+        .context(StartupFailure::Config)?;
+    let app = match &seed {
+        Some(seed) if seed.prune => app.with_user_creation_policy(Arc::new(SeededUsers {
+            usernames: seed.users.keys().cloned().collect(),
+            max_users: MaxUsers(cli.max_users),
+        })),
+        _ => app.with_user_creation_policy(Arc::new(MaxUsers(cli.max_users))),
+    };
+    if let Some(seed) = seed {
+        let (created, pruned) = app.apply_seed(seed).await?;
+        info!("applied seed file, created {created} users and pruned {pruned} users");
+    }
 
-    debug!("listening on {}", cli.address);
+    let session_key = Key::try_from(session_secret.expose_secret().as_bytes())?;
+    let mut session_layer = SessionManagerLayer::new(store.clone())
+        .with_private(session_key.clone())
+        .with_always_save(false)
+        .with_domain(rp_id.clone())
+        .with_path(match cli.session_cookie_path.clone() {
+            Some(path) => path,
+            None if path_prefix.is_empty() => "/".to_string(),
+            None => path_prefix.clone(),
+        });
+    if let Some(minutes) = cli.session_idle_minutes {
+        session_layer = session_layer.with_expiry(Expiry::OnInactivity(
+            tower_sessions::cookie::time::Duration::minutes(minutes as i64),
+        ));
+    }
+
+    let timing_config = RequestTimingConfig {
+        slow_threshold: (cli.slow_request_threshold_ms > 0)
+            .then(|| Duration::from_millis(cli.slow_request_threshold_ms)),
+        sample_rate: cli.trace_sample_rate,
+    };
 
-    let listener = tokio::net::TcpListener::bind(&cli.address).await?;
+    let captcha = config
+        .captcha
+        .clone()
+        .map(|config| Captcha::new(config, http_client.clone()))
+        .transpose()?
+        .map(Arc::new);
 
-    axum::serve(listener, router).await?;
+    let notifier = config
+        .approval_notifications
+        .clone()
+        .map(|config| Arc::new(Notifier::new(config, http_client.clone())));
+
+    let pow = ProofOfWork::new(
+        cli.proof_of_work,
+        cli.proof_of_work_failure_threshold,
+        cli.proof_of_work_max_difficulty,
+    );
+
+    let recovery = RecoveryTokens::new(
+        session_secret.expose_secret().as_bytes(),
+        origin_url.join(&path_prefix)?,
+        Duration::from_secs(cli.recovery_link_ttl_hours * 60 * 60),
+    );
+    // Everything signing with the session secret holds its own copy by now.
+    drop(session_secret);
+
+    let policy = Policy {
+        allowed_algorithms: cli.allowed_algorithm,
+        credential_max_age: cli
+            .credential_max_age_days
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+        ceremony_timeout,
+        kiosk_group: cli.kiosk_group.clone(),
+        require_cred_protect: cli.require_cred_protect,
+        require_approval: cli.require_registration_approval,
+        require_enrollment: cli.require_enrollment,
+        enrollment_grace_period: Duration::from_secs(
+            cli.enrollment_grace_period_days * 24 * 60 * 60,
+        ),
+        database_passwords: cli.database_passwords,
+        expected_credential_tags: cli.expected_credential_tag.clone(),
+        session_binding: cli.session_binding,
+        fresh_auth_max_age: Duration::from_secs(cli.fresh_auth_max_age_seconds),
+        impersonation_max_age: Duration::from_secs(cli.impersonation_minutes * 60),
+        // Read-only instances cannot write sessions, so they only expire.
+        session_touch_interval: (cli.session_idle_minutes.is_some() && !cli.read_only)
+            .then(|| Duration::from_secs(cli.session_touch_interval_seconds)),
+        legacy_sessions_until: cli.legacy_sessions_until.filter(|_| !cli.read_only),
+    };
+
+    let templates = load_templates(&config, &path_prefix).context(StartupFailure::Template)?;
+
+    let app = Arc::new(RwLock::new(app));
+
+    if !cli.read_only {
+        tokio::spawn(delete_expired_ceremonies(app.clone()));
+        if cli.audit_retention_days.is_some() || cli.usage_retention_days.is_some() {
+            tokio::spawn(purge_expired_data(
+                app.clone(),
+                cli.audit_retention_days.map(|days| days * 24 * 60 * 60),
+                cli.usage_retention_days.map(|days| days * 24 * 60 * 60),
+            ));
+        }
+        if let Some(reconciliation) = cli.reconcile_users {
+            let source = match cli.seed_file.clone() {
+                Some(seed_file) => UserSource::SeedFile(seed_file),
+                None => UserSource::PasswordFile(password_file.clone()),
+            };
+            let reconciler = Reconciler {
+                reconciliation,
+                source,
+                database_passwords: cli.database_passwords,
+                session_store: store.clone(),
+            };
+            tokio::spawn(reconciler.watch(app.clone()));
+        }
+    }
+
+    if let Some(pam_socket) = cli.pam_socket.as_ref() {
+        let verifier = PamVerifier::new(session_key.clone(), store.clone(), app.clone());
+        tokio::spawn(Arc::new(verifier).serve(pam::bind(pam_socket)?));
+        debug!("verifying sessions for pam on {}", pam_socket.display());
+    }
+
+    let drain = Arc::new(Drain::new(Duration::from_secs(
+        cli.drain_grace_seconds
+            .unwrap_or(cli.ceremony_timeout_seconds),
+    )));
+    let database_health = Arc::new(DatabaseHealth::default());
+    tokio::spawn(database_health.clone().watch(app.clone()));
+
+    let allowed_origins = Arc::new(AllowedOrigins::new(&webauthn));
+    let ceremony_log = cli.debug_ceremonies.then(|| {
+        warn!("keeping failed ceremonies for debugging");
+        Arc::new(CeremonyLog::new(
+            ceremony_log::CAPACITY,
+            &rp_id,
+            allowed_origins.urls().to_vec(),
+        ))
+    });
+
+    let state = AppState {
+        app,
+        allowed_origins,
+        webauthn: Arc::new(webauthn),
+        session_store: store,
+        session_key,
+        templates: Arc::new(templates),
+        policy: Arc::new(policy),
+        config: Arc::new(config),
+        timing: timing_config,
+        pow: Arc::new(pow),
+        throttle: Arc::new(LoginThrottle::new(cli.max_login_failures)),
+        captcha,
+        recovery: Arc::new(recovery),
+        prometheus: prometheus_handle.map(Arc::new),
+        passwords: Arc::new(read_password_file(password_file)?),
+        notifier,
+        self_test: Arc::new(self_test),
+        disk_space: disk_space.clone(),
+        database_health,
+        drain: drain.clone(),
+        client_ip: Arc::new(ClientIpResolver::new(
+            cli.real_ip_header,
+            cli.trusted_proxy_hops as usize,
+        )),
+        forwarded_https: Arc::new(ForwardedHttps::new(
+            &origin_url,
+            cli.require_forwarded_https,
+        )),
+        base_url: Arc::new(origin_url.join(&path_prefix)?),
+        ceremony_log,
+    };
+
+    let options = RouterOptions {
+        read_only: cli.read_only,
+        strict_validation: cli.strict_validation,
+        spa_dist: cli.spa_dist.clone(),
+        debug_ceremonies: cli.debug_ceremonies,
+    };
+    let mut admin_router = Router::new().nest("/api/admin", admin_router(&options));
+    if let Some(prometheus) = state.prometheus.clone() {
+        admin_router = admin_router.merge(metrics_router(prometheus));
+    }
+
+    let mut router = api_router(&state, &options).merge(html_router(&state, &options));
+    if cli.test_mode {
+        let test_mode = TestMode::new(
+            state.app.clone(),
+            state.session_store.clone(),
+            state.webauthn.clone(),
+            rp_id.clone(),
+            origin_url.clone(),
+        );
+        router = router.merge(
+            Router::new()
+                .route("/api/test/reset", post(test_reset_handler))
+                .route("/api/test/users", post(test_user_handler))
+                .with_state(Arc::new(test_mode)),
+        );
+    }
+
+    // The admin API is only served on a dedicated admin listener, whose address alone restricts
+    // who can reach it. Without one, it is not served at all: behind a reverse proxy on the same
+    // host, the peer of every request on the other listeners is a loopback address. /metrics is
+    // still served on them to loopback peers.
+    if cli.admin_address.is_empty() {
+        if let Some(prometheus) = state.prometheus.clone() {
+            router = router.merge(
+                metrics_router(prometheus).route_layer(middleware::from_fn(allow_only_localhost)),
+            );
+        }
+        admin_router = Router::new();
+    }
+
+    // The limit is shared by all routers, and requests are shed before their session is loaded
+    // so that a burst of requests cannot pile up on the database.
+    let concurrency_limit = cli
+        .max_concurrent_requests
+        .map(|max| Arc::new(Semaphore::new(max as usize)));
+    let finish = |router: Router<AppState>| {
+        let router = router
+            .method_not_allowed_fallback(method_not_allowed)
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                log_request_timings,
+            ))
+            .layer(TraceLayer::new_for_http())
+            .layer(PropagateRequestIdLayer::x_request_id())
+            .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+            .layer(session_layer.clone());
+        match &concurrency_limit {
+            Some(semaphore) => router.layer(
+                ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(handle_shed_request))
+                    .layer(LoadShedLayer::new())
+                    .layer(GlobalConcurrencyLimitLayer::with_semaphore(
+                        semaphore.clone(),
+                    )),
+            ),
+            None => router,
+        }
+        .with_state(state.clone())
+    };
+    // Applied inside the path prefix, which it tells API requests from the pages by.
+    let degrade = middleware::from_fn_with_state(state.clone(), degrade_when_database_down);
+    router = router.layer(degrade.clone());
+    admin_router = admin_router.layer(degrade);
+    if !path_prefix.is_empty() {
+        router = Router::new().nest(&path_prefix, router);
+    }
+    let router = finish(router);
+    let admin_router = finish(admin_router);
+
+    let all_addresses = [cli.address.clone(), cli.admin_address.clone()].concat();
+    let mut servers = Vec::new();
+    for address in cli.address.iter() {
+        debug!("listening on {address}");
+        servers.push(serve(
+            address,
+            &all_addresses,
+            cli.proxy_protocol,
+            router.clone(),
+            &drain,
+        )?);
+    }
+    for address in cli.admin_address.iter() {
+        debug!("listening for admin requests on {address}");
+        servers.push(serve(
+            address,
+            &all_addresses,
+            false,
+            admin_router.clone(),
+            &drain,
+        )?);
+    }
+
+    #[cfg(target_os = "linux")]
+    if cli.sandbox {
+        let sends_email = state
+            .config
+            .approval_notifications
+            .as_ref()
+            .is_some_and(|notifications| notifications.email.is_some());
+        sandbox::restrict_syscalls(sends_email)?;
+    }
+
+    // Streams like /api/admin/events never end by themselves, so connections still open after
+    // draining are only waited for a little while.
+    let drained = drain
+        .clone()
+        .finished()
+        .then(|_| tokio::time::sleep(DRAIN_CLOSE_TIMEOUT));
+    tokio::select! {
+        result = try_join_all(servers) => {
+            result?;
+        }
+        _ = drained => warn!("connections were still open after draining, shutting down anyway"),
+    }
 
     Ok(())
 }
+
+/// Binds `address` and returns a future serving `router` on it until `drain` is finished,
+/// `all_addresses` are needed to tell whether IPv6 sockets have to be v6-only. With
+/// `proxy_protocol`, TCP connections have to start with a PROXY protocol header.
+fn serve(
+    address: &ListenAddress,
+    all_addresses: &[ListenAddress],
+    proxy_protocol: bool,
+    router: Router,
+    drain: &Arc<Drain>,
+) -> anyhow::Result<BoxFuture<'static, std::io::Result<()>>> {
+    let shutdown = drain.clone().finished();
+    // Requests over unix sockets have no connect info, so they are only attributed to a client
+    // address through --real-ip-header.
+    Ok(match address {
+        ListenAddress::Tcp(socket_addr) => {
+            let listener = listener::bind_tcp(
+                *socket_addr,
+                listener::needs_v6_only(socket_addr, all_addresses),
+            )?;
+            if proxy_protocol {
+                let listener = ProxyProtocolListener::new(listener)?;
+                let service = router.into_make_service_with_connect_info::<ProxiedAddr>();
+                async move {
+                    axum::serve(listener, service)
+                        .with_graceful_shutdown(shutdown)
+                        .await
+                }
+                .boxed()
+            } else {
+                let service = router.into_make_service_with_connect_info::<SocketAddr>();
+                async move {
+                    axum::serve(listener, service)
+                        .with_graceful_shutdown(shutdown)
+                        .await
+                }
+                .boxed()
+            }
+        }
+        ListenAddress::Unix(path) => {
+            // Like the PAM socket, access is managed with the server's group.
+            let listener = listener::bind_unix(path, 0o660)?;
+            let service = router.into_make_service();
+            async move {
+                axum::serve(listener, service)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+            .boxed()
+        }
+    })
+}
@@ -0,0 +1,208 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_cbor_2::Value;
+use std::ops::Deref;
+use webauthn_rs::prelude::{Credential, Passkey, Uuid};
+use webauthn_rs_core::{
+    internals::AuthenticatorData,
+    proto::{AttestationMetadata, Registration},
+};
+use webauthn_rs_proto::{
+    AuthenticatorAttachment, AuthenticatorTransport, COSEAlgorithm, CredentialProtectionPolicy,
+    ExtnState, RegisterPublicKeyCredential,
+};
+
+#[derive(Deserialize)]
+struct AttestationObject<'a> {
+    #[serde(rename = "authData", borrow)]
+    auth_data: &'a [u8],
+}
+
+/// What an authenticator reported about itself and a new credential that webauthn-rs does not
+/// keep in the passkey.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AuthenticatorInfo {
+    pub aaguid: Option<Uuid>,
+    /// The minimum PIN length the authenticator enforces, from the minPinLength extension.
+    /// Authenticators only report it to relying parties they have been configured to tell.
+    pub min_pin_length: Option<u32>,
+    /// Whether the credential lives on the client device or on a roaming authenticator.
+    pub attachment: Option<AuthenticatorAttachment>,
+}
+
+/// A credential as sent by the browser, along with its `authenticatorAttachment`, which the
+/// webauthn-rs types drop.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WithAttachment<T> {
+    #[serde(flatten)]
+    pub credential: T,
+    /// Absent for browsers that do not report it; values added by later versions of the spec
+    /// are treated as absent rather than failing the ceremony.
+    #[serde(
+        rename = "authenticatorAttachment",
+        default,
+        deserialize_with = "lenient_attachment"
+    )]
+    pub attachment: Option<AuthenticatorAttachment>,
+}
+
+impl<T> Deref for WithAttachment<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.credential
+    }
+}
+
+fn lenient_attachment<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<AuthenticatorAttachment>, D::Error> {
+    Ok(Option::<serde_json::Value>::deserialize(deserializer)?
+        .and_then(|attachment| serde_json::from_value(attachment).ok()))
+}
+
+/// Whether an assertion was made over the hybrid transport, i.e. with a phone scanning a QR code
+/// rather than with the device itself or a security key plugged into it. Browsers do not report
+/// the transport of an assertion, so this is inferred from a roaming authenticator having
+/// registered a hybrid capable credential, which in practice only phones do.
+pub fn is_hybrid(passkey: &Passkey, attachment: Option<AuthenticatorAttachment>) -> bool {
+    attachment == Some(AuthenticatorAttachment::CrossPlatform)
+        && Credential::from(passkey.clone())
+            .transports
+            .is_some_and(|transports| transports.contains(&AuthenticatorTransport::Hybrid))
+}
+
+/// Extracts the authenticator's AAGUID and extension outputs from a registration response.
+/// webauthn-rs only keeps the AAGUID for attested credentials, but passkeys are usually
+/// registered without attestation, so it is read from the authenticator data directly. An
+/// all-zero AAGUID (commonly sent by authenticators that do not want to be identified) is treated
+/// as absent.
+pub fn registration_info(
+    credential: &WithAttachment<RegisterPublicKeyCredential>,
+) -> AuthenticatorInfo {
+    let Some(auth_data) = registration_auth_data(credential) else {
+        return AuthenticatorInfo {
+            attachment: credential.attachment,
+            ..Default::default()
+        };
+    };
+    let min_pin_length = match auth_data.extensions.unknown_keys.get("minPinLength") {
+        Some(Value::Integer(length)) => u32::try_from(*length).ok(),
+        _ => None,
+    };
+    AuthenticatorInfo {
+        aaguid: auth_data
+            .acd
+            .map(|acd| Uuid::from_bytes(acd.aaguid))
+            .filter(|aaguid| !aaguid.is_nil()),
+        min_pin_length,
+        attachment: credential.attachment,
+    }
+}
+
+fn registration_auth_data(
+    credential: &RegisterPublicKeyCredential,
+) -> Option<AuthenticatorData<Registration>> {
+    AuthenticatorData::<Registration>::try_from(registration_auth_data_bytes(credential)?).ok()
+}
+
+/// The authenticator data of a registration response, still encoded.
+pub fn registration_auth_data_bytes(credential: &RegisterPublicKeyCredential) -> Option<&[u8]> {
+    let attestation_object: AttestationObject =
+        serde_cbor_2::from_slice(credential.response.attestation_object.as_ref()).ok()?;
+    Some(attestation_object.auth_data)
+}
+
+/// What a passkey records about its credential, which is also kept in columns of its own so that
+/// it can be queried without parsing every passkey (see `App::migrate_credentials`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasskeyInfo {
+    pub algorithm: COSEAlgorithm,
+    /// Only known for attested credentials, `registration_info` is the better source.
+    pub aaguid: Option<Uuid>,
+    pub transports: Option<Vec<AuthenticatorTransport>>,
+    /// The BE flag: whether the credential may be synced to other devices.
+    pub backup_eligible: bool,
+    /// The BS flag: whether the credential is currently synced, as of its last use.
+    pub backup_state: bool,
+}
+
+impl PasskeyInfo {
+    pub fn of(passkey: &Passkey) -> Self {
+        let credential = Credential::from(passkey.clone());
+        let aaguid = match credential.attestation.metadata {
+            AttestationMetadata::Packed { aaguid } | AttestationMetadata::Tpm { aaguid, .. } => {
+                Some(aaguid).filter(|aaguid| !aaguid.is_nil())
+            }
+            _ => None,
+        };
+        Self {
+            algorithm: *passkey.cred_algorithm(),
+            aaguid,
+            transports: credential.transports,
+            backup_eligible: credential.backup_eligible,
+            backup_state: credential.backup_state,
+        }
+    }
+}
+
+/// The credProtect policy the authenticator applied to a credential, as recorded by webauthn-rs
+/// when it was registered. Unsigned outputs from the client do not count.
+pub fn cred_protect(passkey: &Passkey) -> Option<CredentialProtectionPolicy> {
+    match Credential::from(passkey.clone()).extensions.cred_protect {
+        ExtnState::Set(policy) | ExtnState::Unsolicited(policy) => Some(policy),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use webauthn_authenticator_rs::{
+        prelude::Url,
+        softtoken::{SoftToken, AAGUID},
+        WebauthnAuthenticator,
+    };
+    use webauthn_rs_core::WebauthnCore;
+
+    #[test]
+    fn test_registration_info() {
+        let wan = WebauthnCore::new_unsafe_experts_only(
+            "https://localhost:8080/auth",
+            "localhost",
+            vec![Url::parse("https://localhost:8080").unwrap()],
+            Duration::from_secs(1),
+            None,
+            None,
+        );
+        let (soft_token, _) = SoftToken::new(true).unwrap();
+        let mut wa = WebauthnAuthenticator::new(soft_token);
+
+        let (chal, _) = wan
+            .generate_challenge_register(
+                wan.new_challenge_register_builder(&[0; 16], "foo", "foo")
+                    .unwrap(),
+            )
+            .unwrap();
+        let r = wa
+            .do_registration(Url::parse("https://localhost:8080").unwrap(), chal)
+            .unwrap();
+
+        let mut json = serde_json::to_value(&r).unwrap();
+        json["authenticatorAttachment"] = "cross-platform".into();
+        let r: WithAttachment<RegisterPublicKeyCredential> = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            registration_info(&r),
+            AuthenticatorInfo {
+                aaguid: Some(AAGUID),
+                min_pin_length: None,
+                attachment: Some(AuthenticatorAttachment::CrossPlatform),
+            }
+        );
+
+        let mut json = serde_json::to_value(&r).unwrap();
+        json["authenticatorAttachment"] = "wearable".into();
+        let r: WithAttachment<RegisterPublicKeyCredential> = serde_json::from_value(json).unwrap();
+        assert_eq!(r.attachment, None);
+    }
+}
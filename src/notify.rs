@@ -0,0 +1,182 @@
+use crate::{
+    config::{EmailConfig, NotificationConfig},
+    http_client::HttpClient,
+};
+use anyhow::{bail, Context};
+use http_body_util::Full;
+use hyper::{body::Bytes, header, Method, Request};
+use serde::Serialize;
+use std::{process::Stdio, sync::Arc};
+use tokio::{io::AsyncWriteExt, process::Command};
+use tracing::{debug, error};
+use webauthn_rs::prelude::Url;
+
+/// A registration that has to be approved by an admin before the credential can be used.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingRegistration {
+    pub id: i64,
+    pub username: String,
+    pub credential_name: String,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    event: &'static str,
+    #[serde(flatten)]
+    registration: &'a PendingRegistration,
+}
+
+/// Sends notifications to admins through the configured channels. Delivery happens in the
+/// background, failures are only logged so that they never fail the request that caused them.
+pub struct Notifier {
+    config: NotificationConfig,
+    client: HttpClient,
+}
+
+impl Notifier {
+    pub fn new(config: NotificationConfig, client: HttpClient) -> Self {
+        Self { config, client }
+    }
+
+    pub fn registration_pending(self: &Arc<Self>, registration: PendingRegistration) {
+        let notifier = self.clone();
+        tokio::spawn(async move {
+            if let Some(url) = notifier.config.webhook_url.as_ref() {
+                if let Err(e) = send_webhook(&notifier.client, url, &registration).await {
+                    error!("could not send webhook notification: {e:#}");
+                }
+            }
+            if let Some(email) = notifier.config.email.as_ref() {
+                if let Err(e) = send_email(email, &registration).await {
+                    error!("could not send email notification: {e:#}");
+                }
+            }
+        });
+    }
+}
+
+async fn send_webhook(
+    client: &HttpClient,
+    url: &Url,
+    registration: &PendingRegistration,
+) -> anyhow::Result<()> {
+    let body = serde_json::to_vec(&WebhookPayload {
+        event: "registration_pending",
+        registration,
+    })?;
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url.as_str())
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::<Bytes>::from(body))?;
+
+    let (status, _) = client.send(request).await?;
+    if !status.is_success() {
+        bail!("webhook responded with {status}");
+    }
+    debug!(
+        "sent webhook notification for pending credential {}",
+        registration.id
+    );
+    Ok(())
+}
+
+/// Header values come from usernames and credential names, which must not be able to inject
+/// headers of their own.
+fn header_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+fn email_message(email: &EmailConfig, registration: &PendingRegistration) -> String {
+    format!(
+        "From: {from}\r\n\
+         To: {to}\r\n\
+         Subject: {subject}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n\
+         {username} registered the credential \"{name}\", which cannot be used until it is \
+         approved.\r\n\
+         \r\n\
+         Approve it with POST /api/admin/pending/{id}/approve or reject it with\r\n\
+         DELETE /api/admin/pending/{id}.\r\n",
+        from = header_value(&email.from),
+        to = email
+            .to
+            .iter()
+            .map(|to| header_value(to))
+            .collect::<Vec<_>>()
+            .join(", "),
+        subject = header_value(&format!(
+            "Credential of {} awaiting approval",
+            registration.username
+        )),
+        username = registration.username,
+        name = registration.credential_name,
+        id = registration.id,
+    )
+}
+
+async fn send_email(email: &EmailConfig, registration: &PendingRegistration) -> anyhow::Result<()> {
+    if email.to.is_empty() {
+        return Ok(());
+    }
+
+    // Recipients are taken from the message (-t), and a line with a single dot must not end it
+    // early (-i).
+    let mut child = Command::new(&email.sendmail)
+        .args(["-i", "-t"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("could not run {}", email.sendmail.display()))?;
+    let mut stdin = child.stdin.take().context("sendmail has no stdin")?;
+    stdin
+        .write_all(email_message(email, registration).as_bytes())
+        .await?;
+    drop(stdin);
+
+    let status = child.wait().await?;
+    if !status.success() {
+        bail!("{} exited with {status}", email.sendmail.display());
+    }
+    debug!(
+        "sent email notification for pending credential {}",
+        registration.id
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_email_message() {
+        let email = EmailConfig {
+            to: vec!["a@example.com".to_string(), "b@example.com".to_string()],
+            from: "webauthn@example.com".to_string(),
+            sendmail: PathBuf::from("sendmail"),
+        };
+        let message = email_message(
+            &email,
+            &PendingRegistration {
+                id: 3,
+                username: "foo\r\nBcc: evil@example.com".to_string(),
+                credential_name: "key".to_string(),
+            },
+        );
+
+        let (headers, body) = message.split_once("\r\n\r\n").unwrap();
+        assert_eq!(
+            headers.lines().collect::<Vec<_>>(),
+            vec![
+                "From: webauthn@example.com",
+                "To: a@example.com, b@example.com",
+                "Subject: Credential of foo  Bcc: evil@example.com awaiting approval",
+                "Content-Type: text/plain; charset=utf-8",
+            ]
+        );
+        assert!(body.contains("POST /api/admin/pending/3/approve"));
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashSet;
+use url::Origin;
+use webauthn_rs::{prelude::Url, Webauthn};
+
+/// The origins webauthn-rs allows, i.e. `--rp-origin`, `--extra-allowed-origin` and the related
+/// origins of the config file, collected once at startup so that checking the URLs users are
+/// redirected to after logging in is a lookup instead of a walk over them.
+#[derive(Debug, Clone)]
+pub struct AllowedOrigins {
+    origins: HashSet<Origin>,
+    /// In the order they were given, for listing them.
+    urls: Vec<Url>,
+}
+
+impl AllowedOrigins {
+    pub fn new(webauthn: &Webauthn) -> Self {
+        Self::from_urls(webauthn.get_allowed_origins().to_vec())
+    }
+
+    pub fn from_urls(urls: Vec<Url>) -> Self {
+        Self {
+            origins: urls.iter().map(Url::origin).collect(),
+            urls,
+        }
+    }
+
+    /// Whether `url` has exactly one of the allowed origins. Subdomains are not, as opposed to
+    /// ceremonies, see `ceremony_log::origin_is_allowed`.
+    pub fn contains(&self, url: &Url) -> bool {
+        self.origins.contains(&url.origin())
+    }
+
+    pub fn urls(&self) -> &[Url] {
+        &self.urls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_origins() {
+        let origins = AllowedOrigins::from_urls(vec![
+            Url::parse("https://auth.foo.com/some/path").unwrap(),
+            Url::parse("https://foo.com:8443").unwrap(),
+        ]);
+        let contains = |url| origins.contains(&Url::parse(url).unwrap());
+
+        assert!(contains("https://auth.foo.com/other?query"));
+        assert!(contains("https://auth.foo.com:443"));
+        assert!(contains("https://foo.com:8443/"));
+        assert!(!contains("https://foo.com"));
+        assert!(!contains("http://auth.foo.com"));
+        assert!(!contains("https://sub.auth.foo.com"));
+        assert_eq!(origins.urls().len(), 2);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::{
+    app::SharedAppState,
+    handlers::{SESSIONKEY_IMPERSONATION, SESSIONKEY_LOGGEDIN, SESSIONKEY_USERNAME},
+    listener,
+    session::{SqliteSessionStore, SESSION_COOKIE},
+};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{path::Path, sync::Arc};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+use tower_sessions::cookie::Key;
+use tracing::{debug, error};
+
+/// Requests are a single line of JSON, anything longer than this is not a session cookie.
+const MAX_REQUEST_LEN: u64 = 8 * 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct VerifyRequest {
+    username: String,
+    /// The value of the session cookie, optionally prefixed with `id=` as copied from a
+    /// `Cookie` header.
+    token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct VerifyResponse {
+    valid: bool,
+}
+
+/// Checks session cookies handed to local services (e.g. through PAM) the same way
+/// `require_logged_in` checks them for requests.
+pub struct PamVerifier {
+    key: Key,
+    store: SqliteSessionStore,
+    app: SharedAppState,
+}
+
+impl PamVerifier {
+    pub fn new(key: Key, store: SqliteSessionStore, app: SharedAppState) -> Self {
+        Self { key, store, app }
+    }
+
+    /// Whether `token` is the cookie of an unexpired, logged in session of `username`, who is
+    /// still active.
+    async fn verify(&self, username: &str, token: &str) -> anyhow::Result<bool> {
+        let token = token.trim();
+        let token = token
+            .strip_prefix(SESSION_COOKIE)
+            .and_then(|token| token.strip_prefix('='))
+            .unwrap_or(token);
+        let Some(record) = self.store.load_by_cookie(&self.key, token).await? else {
+            return Ok(false);
+        };
+
+        let app = self.app.read().await;
+        let username = app.canonical_username(username).await?;
+        if record.data.get(SESSIONKEY_LOGGEDIN) != Some(&Value::Bool(true))
+            || record.data.get(SESSIONKEY_USERNAME).and_then(Value::as_str) != Some(&username)
+            // Impersonation is for reproducing what users see in the browser, not for logging
+            // in to their machines.
+            || record.data.contains_key(SESSIONKEY_IMPERSONATION)
+        {
+            return Ok(false);
+        }
+
+        Ok(app.user_is_active(username).await?)
+    }
+
+    async fn handle(&self, stream: UnixStream) -> anyhow::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut line = String::new();
+        BufReader::new(reader.take(MAX_REQUEST_LEN))
+            .read_line(&mut line)
+            .await?;
+
+        let request: VerifyRequest = serde_json::from_str(&line).context("invalid request")?;
+        let valid = self.verify(&request.username, &request.token).await?;
+        debug!("pam verification for {}: {valid}", request.username);
+
+        let mut response = serde_json::to_vec(&VerifyResponse { valid })?;
+        response.push(b'\n');
+        writer.write_all(&response).await?;
+        Ok(())
+    }
+
+    /// Answers verification requests on `listener` until the server exits.
+    pub async fn serve(self: Arc<Self>, listener: UnixListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("could not accept pam connection: {e}");
+                    continue;
+                }
+            };
+            let verifier = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = verifier.handle(stream).await {
+                    error!("could not handle pam request: {e:#}");
+                }
+            });
+        }
+    }
+}
+
+/// Binds the verification socket. Only the owner and group can connect, so access is managed
+/// with the server's group.
+pub fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+    listener::bind_unix(path, 0o660)
+}
+
+/// Asks the server behind `socket` whether `token` is a valid session of `username`, for
+/// `webauthn-tiny pam-verify`.
+pub async fn verify_with_server(
+    socket: &Path,
+    username: String,
+    token: String,
+) -> anyhow::Result<bool> {
+    if username.is_empty() {
+        bail!("no username given");
+    }
+
+    let stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("could not connect to {}", socket.display()))?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut request = serde_json::to_vec(&VerifyRequest { username, token })?;
+    request.push(b'\n');
+    writer.write_all(&request).await?;
+
+    let mut line = String::new();
+    BufReader::new(reader.take(MAX_REQUEST_LEN))
+        .read_line(&mut line)
+        .await?;
+    let response: VerifyResponse = serde_json::from_str(&line).context("invalid response")?;
+    Ok(response.valid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app::App, seed::Seed};
+    use std::collections::HashMap;
+    use tokio::sync::RwLock;
+    use tokio_rusqlite::Connection;
+    use tower_sessions::{
+        cookie::{
+            time::{Duration, OffsetDateTime},
+            Cookie, CookieJar,
+        },
+        session::{Id, Record},
+        session_store::SessionStore,
+    };
+
+    #[tokio::test]
+    async fn test_verify() {
+        let db = Connection::open(":memory:").await.unwrap();
+        let store = SqliteSessionStore::new(db.clone());
+        store.init().await.unwrap();
+        let app = App::new(db);
+        app.init().await.unwrap();
+        app.apply_seed(serde_json::from_str::<Seed>(r#"{"users": {"alice": {}}}"#).unwrap())
+            .await
+            .unwrap();
+
+        let key = Key::generate();
+        let verifier = PamVerifier::new(key.clone(), store.clone(), Arc::new(RwLock::new(app)));
+
+        let mut record = Record {
+            id: Id::default(),
+            data: HashMap::from([
+                (SESSIONKEY_LOGGEDIN.to_string(), Value::Bool(true)),
+                (SESSIONKEY_USERNAME.to_string(), Value::from("alice")),
+            ]),
+            expiry_date: OffsetDateTime::now_utc() + Duration::hours(1),
+        };
+        store.create(&mut record).await.unwrap();
+
+        let mut jar = CookieJar::new();
+        jar.private_mut(&key)
+            .add(Cookie::new(SESSION_COOKIE, record.id.to_string()));
+        let token = jar.get(SESSION_COOKIE).unwrap().value().to_string();
+
+        assert!(verifier.verify("alice", &token).await.unwrap());
+        assert!(verifier
+            .verify("alice", &format!("id={token}\n"))
+            .await
+            .unwrap());
+        assert!(!verifier.verify("bob", &token).await.unwrap());
+        assert!(!verifier
+            .verify("alice", &record.id.to_string())
+            .await
+            .unwrap());
+
+        record.expiry_date = OffsetDateTime::now_utc() - Duration::hours(1);
+        store.save(&record).await.unwrap();
+        assert!(!verifier.verify("alice", &token).await.unwrap());
+    }
+}
@@ -0,0 +1,278 @@
+use crate::{app::CredentialWithName, user_agent::SessionBinding};
+use serde::Serialize;
+use std::{collections::HashSet, time::Duration};
+use tower_sessions::cookie::time::OffsetDateTime;
+use webauthn_rs::DEFAULT_AUTHENTICATOR_TIMEOUT;
+use webauthn_rs_proto::{COSEAlgorithm, CredentialProtectionPolicy};
+
+/// Deployment-wide rules that are applied on top of what webauthn-rs already enforces.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// COSE algorithms that newly registered credentials may use. An empty list allows every
+    /// algorithm that webauthn-rs offers.
+    pub allowed_algorithms: Vec<COSEAlgorithm>,
+    /// How long a credential may be used before its owner is asked to register a replacement.
+    /// `None` means credentials never expire.
+    pub credential_max_age: Option<Duration>,
+    /// How long the browser waits for the user to complete a ceremony, after which the server
+    /// no longer accepts a response to its challenge either.
+    pub ceremony_timeout: Duration,
+    /// Members of this group can unlock kiosk mode to enroll credentials for other users.
+    /// `None` disables kiosk mode.
+    pub kiosk_group: Option<String>,
+    /// Only accept credentials that the authenticator protects with credProtect
+    /// `userVerificationRequired`, so that they cannot be used without user verification even
+    /// outside of this service.
+    pub require_cred_protect: bool,
+    /// Credentials that users register themselves are held back until an admin approves them.
+    /// Credentials enrolled at a kiosk or during recovery are trusted right away.
+    pub require_approval: bool,
+    /// Users without credentials are walked through registering one before they are logged in,
+    /// instead of being logged in with just their password.
+    pub require_enrollment: bool,
+    /// How long after their first login users may skip enrolling a credential.
+    pub enrollment_grace_period: Duration,
+    /// Users missing from the password file may log in with a password set through the admin
+    /// API, as a transition to passkeys. Until they have a credential, they are asked to enroll
+    /// one on every login, which they may always skip.
+    pub database_passwords: bool,
+    /// Users are warned when none of their credentials carries one of these tags, e.g. `backup`
+    /// to make sure everyone registered a second authenticator.
+    pub expected_credential_tags: Vec<String>,
+    /// Whether requests from a different kind of client than the one a session was first used
+    /// from are logged or rejected, to make stolen session cookies harder to use.
+    pub session_binding: SessionBinding,
+    /// How recently a session has to have completed a WebAuthn assertion, at login or with a
+    /// step-up, for privileged actions such as deleting a credential (see `RequireFreshAuth`).
+    pub fresh_auth_max_age: Duration,
+    /// How long sessions that admins opened with an impersonation link last, after which they
+    /// are logged out no matter how active they are.
+    pub impersonation_max_age: Duration,
+    /// How often the expiry of sessions that expire when idle (`--session-idle-minutes`) is
+    /// pushed back while they are used, see `touch_session`. `None` for sessions that expire a
+    /// fixed time after they last changed.
+    pub session_touch_interval: Option<Duration>,
+    /// Until when sessions of the axum-sessions format are carried over into new sessions, see
+    /// `upgrade_legacy_session`. `None` to not look at them at all.
+    pub legacy_sessions_until: Option<OffsetDateTime>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            allowed_algorithms: Vec::new(),
+            credential_max_age: None,
+            ceremony_timeout: DEFAULT_AUTHENTICATOR_TIMEOUT,
+            kiosk_group: None,
+            require_cred_protect: false,
+            require_approval: false,
+            require_enrollment: false,
+            enrollment_grace_period: Duration::ZERO,
+            database_passwords: false,
+            expected_credential_tags: Vec::new(),
+            session_binding: SessionBinding::Off,
+            fresh_auth_max_age: Duration::from_secs(5 * 60),
+            impersonation_max_age: Duration::from_secs(15 * 60),
+            session_touch_interval: None,
+            legacy_sessions_until: None,
+        }
+    }
+}
+
+impl Policy {
+    pub fn algorithm_is_allowed(&self, algorithm: &COSEAlgorithm) -> bool {
+        self.allowed_algorithms.is_empty() || self.allowed_algorithms.contains(algorithm)
+    }
+
+    pub fn cred_protect_is_allowed(
+        &self,
+        cred_protect: Option<CredentialProtectionPolicy>,
+    ) -> bool {
+        !self.require_cred_protect
+            || cred_protect == Some(CredentialProtectionPolicy::UserVerificationRequired)
+    }
+
+    /// Unix timestamp at which a credential registered at `created_at` expires.
+    pub fn credential_expires_at(&self, created_at: u64) -> Option<u64> {
+        self.credential_max_age
+            .map(|max_age| created_at.saturating_add(max_age.as_secs()))
+    }
+
+    pub fn credential_is_expired(&self, created_at: u64, now: u64) -> bool {
+        self.credential_expires_at(created_at)
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Unix timestamp until which a user whose enrollment grace period started at `started_at`
+    /// may skip enrolling, `None` once the grace period is over.
+    /// The expected tags (see `expected_credential_tags`) that none of `credentials` carries.
+    pub fn missing_credential_tags(&self, credentials: &[CredentialWithName]) -> Vec<String> {
+        self.expected_credential_tags
+            .iter()
+            .filter(|tag| !credentials.iter().any(|c| c.tags.contains(tag)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn enrollment_skippable_until(&self, started_at: u64, now: u64) -> Option<u64> {
+        let until = started_at.saturating_add(self.enrollment_grace_period.as_secs());
+        (now < until).then_some(until)
+    }
+}
+
+/// Decides whether a user may be created on the fly, which happens the first time an unknown
+/// username logs in. This runs on the database thread while holding the write lock, so it has
+/// to be quick.
+pub trait UserCreationPolicy: Send + Sync {
+    /// `existing_users` is the number of users before `username` would be created.
+    fn allow_user_creation(&self, username: &str, existing_users: usize) -> bool;
+}
+
+/// Allows creating users until there are this many of them. `None` allows any number of users.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxUsers(pub Option<usize>);
+
+impl UserCreationPolicy for MaxUsers {
+    fn allow_user_creation(&self, _username: &str, existing_users: usize) -> bool {
+        self.0.is_none_or(|max_users| existing_users < max_users)
+    }
+}
+
+/// Only allows creating users declared in a pruning seed file, which would delete any other user
+/// on the next start anyway.
+#[derive(Debug, Clone)]
+pub struct SeededUsers {
+    pub usernames: HashSet<String>,
+    pub max_users: MaxUsers,
+}
+
+impl UserCreationPolicy for SeededUsers {
+    fn allow_user_creation(&self, username: &str, existing_users: usize) -> bool {
+        self.usernames.contains(username)
+            && self.max_users.allow_user_creation(username, existing_users)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlgorithmStrength {
+    Strong,
+    Acceptable,
+    Weak,
+}
+
+pub fn algorithm_strength(algorithm: &COSEAlgorithm) -> AlgorithmStrength {
+    match algorithm {
+        COSEAlgorithm::ES256
+        | COSEAlgorithm::ES384
+        | COSEAlgorithm::ES512
+        | COSEAlgorithm::EDDSA => AlgorithmStrength::Strong,
+        COSEAlgorithm::RS256
+        | COSEAlgorithm::RS384
+        | COSEAlgorithm::RS512
+        | COSEAlgorithm::PS256
+        | COSEAlgorithm::PS384
+        | COSEAlgorithm::PS512 => AlgorithmStrength::Acceptable,
+        COSEAlgorithm::INSECURE_RS1 | COSEAlgorithm::PinUvProtocol => AlgorithmStrength::Weak,
+    }
+}
+
+pub fn algorithm_name(algorithm: &COSEAlgorithm) -> &'static str {
+    match algorithm {
+        COSEAlgorithm::ES256 => "ES256",
+        COSEAlgorithm::ES384 => "ES384",
+        COSEAlgorithm::ES512 => "ES512",
+        COSEAlgorithm::RS256 => "RS256",
+        COSEAlgorithm::RS384 => "RS384",
+        COSEAlgorithm::RS512 => "RS512",
+        COSEAlgorithm::PS256 => "PS256",
+        COSEAlgorithm::PS384 => "PS384",
+        COSEAlgorithm::PS512 => "PS512",
+        COSEAlgorithm::EDDSA => "EdDSA",
+        COSEAlgorithm::INSECURE_RS1 => "RS1",
+        COSEAlgorithm::PinUvProtocol => "PinUvProtocol",
+    }
+}
+
+/// Parses an algorithm given on the command line, either by name (case-insensitive) or by its
+/// COSE identifier.
+pub fn parse_algorithm(s: &str) -> Result<COSEAlgorithm, String> {
+    if let Ok(id) = s.parse::<i128>() {
+        return COSEAlgorithm::try_from(id).map_err(|_| format!("unknown COSE algorithm {id}"));
+    }
+
+    COSEAlgorithm::all_possible_algs()
+        .into_iter()
+        .find(|alg| algorithm_name(alg).eq_ignore_ascii_case(s))
+        .ok_or_else(|| format!("unknown COSE algorithm {s}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_algorithm() {
+        assert_eq!(parse_algorithm("es256").unwrap(), COSEAlgorithm::ES256);
+        assert_eq!(parse_algorithm("EdDSA").unwrap(), COSEAlgorithm::EDDSA);
+        assert_eq!(parse_algorithm("-257").unwrap(), COSEAlgorithm::RS256);
+        assert!(parse_algorithm("foo").is_err());
+        assert!(parse_algorithm("-1").is_err());
+    }
+
+    #[test]
+    fn test_algorithm_is_allowed() {
+        assert!(Policy::default().algorithm_is_allowed(&COSEAlgorithm::RS256));
+
+        let policy = Policy {
+            allowed_algorithms: vec![COSEAlgorithm::ES256, COSEAlgorithm::EDDSA],
+            ..Default::default()
+        };
+        assert!(policy.algorithm_is_allowed(&COSEAlgorithm::ES256));
+        assert!(!policy.algorithm_is_allowed(&COSEAlgorithm::RS256));
+    }
+
+    #[test]
+    fn test_cred_protect_is_allowed() {
+        assert!(Policy::default().cred_protect_is_allowed(None));
+
+        let policy = Policy {
+            require_cred_protect: true,
+            ..Default::default()
+        };
+        assert!(policy
+            .cred_protect_is_allowed(Some(CredentialProtectionPolicy::UserVerificationRequired)));
+        assert!(!policy.cred_protect_is_allowed(Some(
+            CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIDList
+        )));
+        assert!(!policy.cred_protect_is_allowed(None));
+    }
+
+    #[test]
+    fn test_credential_is_expired() {
+        assert!(!Policy::default().credential_is_expired(0, u64::MAX));
+
+        let policy = Policy {
+            credential_max_age: Some(Duration::from_secs(100)),
+            ..Default::default()
+        };
+        assert_eq!(policy.credential_expires_at(1000), Some(1100));
+        assert!(!policy.credential_is_expired(1000, 1099));
+        assert!(policy.credential_is_expired(1000, 1100));
+    }
+
+    #[test]
+    fn test_enrollment_skippable_until() {
+        assert_eq!(
+            Policy::default().enrollment_skippable_until(1000, 1000),
+            None
+        );
+
+        let policy = Policy {
+            enrollment_grace_period: Duration::from_secs(100),
+            ..Default::default()
+        };
+        assert_eq!(policy.enrollment_skippable_until(1000, 1099), Some(1100));
+        assert_eq!(policy.enrollment_skippable_until(1000, 1100), None);
+    }
+}
@@ -0,0 +1,188 @@
+use base64::{engine::general_purpose, Engine as _};
+use metrics::gauge;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// A proof-of-work puzzle handed to the client along with an authentication challenge. The
+/// client must find a nonce such that `sha256(challenge || nonce)` starts with `difficulty` zero
+/// bits.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProofOfWorkChallenge {
+    pub challenge: String,
+    pub difficulty: u32,
+}
+
+impl ProofOfWorkChallenge {
+    fn new(difficulty: u32) -> Self {
+        let mut challenge = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut challenge);
+        Self {
+            challenge: general_purpose::URL_SAFE_NO_PAD.encode(challenge),
+            difficulty,
+        }
+    }
+
+    pub fn verify(&self, nonce: &str) -> bool {
+        let digest = Sha256::new()
+            .chain_update(self.challenge.as_bytes())
+            .chain_update(nonce.as_bytes())
+            .finalize();
+        leading_zero_bits(&digest) >= self.difficulty
+    }
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[derive(Debug)]
+struct FailureWindow {
+    started: Instant,
+    current: u64,
+    previous: u64,
+}
+
+/// Tracks authentication failures and decides how much work clients must do before the server
+/// spends time verifying their assertions.
+#[derive(Debug)]
+pub struct ProofOfWork {
+    enabled: bool,
+    /// Failures per minute at which clients start being asked for proof-of-work.
+    failure_threshold: u64,
+    max_difficulty: u32,
+    failures: Mutex<FailureWindow>,
+}
+
+impl ProofOfWork {
+    pub fn new(enabled: bool, failure_threshold: u64, max_difficulty: u32) -> Self {
+        Self {
+            enabled,
+            failure_threshold: failure_threshold.max(1),
+            max_difficulty,
+            failures: Mutex::new(FailureWindow {
+                started: Instant::now(),
+                current: 0,
+                previous: 0,
+            }),
+        }
+    }
+
+    fn rotate(window: &mut FailureWindow) {
+        let elapsed = window.started.elapsed();
+        if elapsed >= WINDOW * 2 {
+            window.previous = 0;
+            window.current = 0;
+            window.started = Instant::now();
+        } else if elapsed >= WINDOW {
+            window.previous = window.current;
+            window.current = 0;
+            window.started += WINDOW;
+        }
+    }
+
+    pub fn record_failure(&self) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut window = self.failures.lock().expect("failures lock poisoned");
+        Self::rotate(&mut window);
+        window.current += 1;
+    }
+
+    /// The number of leading zero bits currently required, where zero means no proof-of-work is
+    /// needed. Difficulty grows by one bit each time the failure rate doubles past the threshold.
+    pub fn difficulty(&self) -> u32 {
+        if !self.enabled {
+            return 0;
+        }
+
+        let failures = {
+            let mut window = self.failures.lock().expect("failures lock poisoned");
+            Self::rotate(&mut window);
+            window.current.max(window.previous)
+        };
+
+        let difficulty = if failures < self.failure_threshold {
+            0
+        } else {
+            (8 + (failures / self.failure_threshold).ilog2()).min(self.max_difficulty)
+        };
+
+        gauge!("proof_of_work_difficulty").set(difficulty);
+
+        difficulty
+    }
+
+    /// Returns a new puzzle if the current failure rate requires one.
+    pub fn challenge(&self) -> Option<ProofOfWorkChallenge> {
+        match self.difficulty() {
+            0 => None,
+            difficulty => Some(ProofOfWorkChallenge::new(difficulty)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x80]), 8);
+        assert_eq!(leading_zero_bits(&[0x00, 0x0f]), 12);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn test_verify() {
+        let challenge = ProofOfWorkChallenge::new(8);
+        let nonce = (0u64..)
+            .map(|n| n.to_string())
+            .find(|nonce| challenge.verify(nonce))
+            .unwrap();
+        assert!(challenge.verify(&nonce));
+
+        let impossible = ProofOfWorkChallenge {
+            difficulty: 257,
+            ..challenge
+        };
+        assert!(!impossible.verify(&nonce));
+    }
+
+    #[test]
+    fn test_difficulty_scales_with_failures() {
+        let disabled = ProofOfWork::new(false, 1, 20);
+        disabled.record_failure();
+        assert_eq!(disabled.difficulty(), 0);
+
+        let pow = ProofOfWork::new(true, 10, 12);
+        assert_eq!(pow.difficulty(), 0);
+        assert!(pow.challenge().is_none());
+
+        (0..10).for_each(|_| pow.record_failure());
+        assert_eq!(pow.difficulty(), 8);
+
+        (0..30).for_each(|_| pow.record_failure());
+        assert_eq!(pow.difficulty(), 10);
+
+        (0..1000).for_each(|_| pow.record_failure());
+        assert_eq!(pow.difficulty(), 12);
+        assert!(pow.challenge().is_some());
+    }
+}
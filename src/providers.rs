@@ -0,0 +1,86 @@
+use uuid::{uuid, Uuid};
+
+/// A passkey provider recognized by the AAGUID it reports, so that the credentials page can show
+/// "iCloud Keychain" instead of an authenticator model users have never heard of.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PasskeyProvider {
+    pub name: &'static str,
+    /// File name of the icon in `src/providers`, served by `assets`.
+    pub icon: &'static str,
+}
+
+const ICLOUD_KEYCHAIN: PasskeyProvider = PasskeyProvider {
+    name: "iCloud Keychain",
+    icon: "icloud-keychain.svg",
+};
+const GOOGLE_PASSWORD_MANAGER: PasskeyProvider = PasskeyProvider {
+    name: "Google Password Manager",
+    icon: "google-password-manager.svg",
+};
+const ONEPASSWORD: PasskeyProvider = PasskeyProvider {
+    name: "1Password",
+    icon: "1password.svg",
+};
+const BITWARDEN: PasskeyProvider = PasskeyProvider {
+    name: "Bitwarden",
+    icon: "bitwarden.svg",
+};
+
+/// The providers and the AAGUIDs they report, taken from
+/// https://github.com/passkeydeveloper/passkey-authenticator-aaguids. Only the common ones are
+/// listed; credentials of others are shown with their authenticator attachment as before.
+const PROVIDERS: &[(Uuid, &PasskeyProvider)] = &[
+    (
+        uuid!("fbfc3007-154e-4ecc-8c0b-6e020557d7bd"),
+        &ICLOUD_KEYCHAIN,
+    ),
+    // Devices managed through MDM.
+    (
+        uuid!("dd4ec289-e01d-41c9-bb89-70fa845d4bf2"),
+        &ICLOUD_KEYCHAIN,
+    ),
+    (
+        uuid!("ea9b8d66-4d01-1d21-3ce4-b6b48cb575d4"),
+        &GOOGLE_PASSWORD_MANAGER,
+    ),
+    (uuid!("bada5566-a7aa-401f-bd96-45619a55120d"), &ONEPASSWORD),
+    (uuid!("d548826e-79b4-db40-a3d8-11116f7e8349"), &BITWARDEN),
+];
+
+/// Icons of all providers, for `assets` to serve.
+pub const ICONS: [(&str, &[u8]); 4] = [
+    (
+        ICLOUD_KEYCHAIN.icon,
+        include_bytes!("providers/icloud-keychain.svg"),
+    ),
+    (
+        GOOGLE_PASSWORD_MANAGER.icon,
+        include_bytes!("providers/google-password-manager.svg"),
+    ),
+    (ONEPASSWORD.icon, include_bytes!("providers/1password.svg")),
+    (BITWARDEN.icon, include_bytes!("providers/bitwarden.svg")),
+];
+
+pub fn passkey_provider(aaguid: &Uuid) -> Option<&'static PasskeyProvider> {
+    PROVIDERS
+        .iter()
+        .find(|(provider_aaguid, _)| provider_aaguid == aaguid)
+        .map(|(_, provider)| *provider)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passkey_provider() {
+        assert_eq!(
+            passkey_provider(&uuid!("dd4ec289-e01d-41c9-bb89-70fa845d4bf2")),
+            Some(&ICLOUD_KEYCHAIN)
+        );
+        assert_eq!(passkey_provider(&Uuid::nil()), None);
+        for (_, provider) in PROVIDERS {
+            assert!(ICONS.iter().any(|(icon, _)| *icon == provider.icon));
+        }
+    }
+}
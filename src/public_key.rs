@@ -0,0 +1,91 @@
+use serde_cbor_2::Value;
+use std::collections::BTreeMap;
+use webauthn_rs::prelude::{COSEKey, COSEKeyType, WebauthnError};
+
+// Labels of COSE_Key parameters (RFC 9053), where the meaning of the negative ones depends on
+// the key type.
+const KTY: i128 = 1;
+const ALG: i128 = 3;
+const OKP: i128 = 1;
+const EC2: i128 = 2;
+const RSA: i128 = 3;
+
+/// Encodes the public key of a credential as a CBOR COSE_Key, the form authenticators report it
+/// in.
+pub fn cose(key: &COSEKey) -> Vec<u8> {
+    let mut map = BTreeMap::from([(Value::Integer(ALG), Value::Integer(key.type_ as i128))]);
+    match &key.key {
+        COSEKeyType::EC_OKP(okp) => {
+            map.insert(Value::Integer(KTY), Value::Integer(OKP));
+            map.insert(
+                Value::Integer(-1),
+                Value::Integer(okp.curve.clone() as i128),
+            );
+            map.insert(Value::Integer(-2), Value::Bytes(okp.x.to_vec()));
+        }
+        COSEKeyType::EC_EC2(ec2) => {
+            map.insert(Value::Integer(KTY), Value::Integer(EC2));
+            map.insert(
+                Value::Integer(-1),
+                Value::Integer(ec2.curve.clone() as i128),
+            );
+            map.insert(Value::Integer(-2), Value::Bytes(ec2.x.to_vec()));
+            map.insert(Value::Integer(-3), Value::Bytes(ec2.y.to_vec()));
+        }
+        COSEKeyType::RSA(rsa) => {
+            map.insert(Value::Integer(KTY), Value::Integer(RSA));
+            map.insert(Value::Integer(-1), Value::Bytes(rsa.n.to_vec()));
+            map.insert(Value::Integer(-2), Value::Bytes(rsa.e.to_vec()));
+        }
+    }
+    // Serializing a map of integers and byte strings cannot fail.
+    serde_cbor_2::to_vec(&Value::Map(map)).unwrap_or_default()
+}
+
+/// Encodes the public key of a credential as a PEM SubjectPublicKeyInfo, which most tools
+/// outside of WebAuthn understand.
+pub fn pem(key: &COSEKey) -> Result<String, WebauthnError> {
+    let pem = key
+        .get_openssl_pkey()?
+        .public_key_to_pem()
+        .map_err(WebauthnError::OpenSSLError)?;
+    Ok(String::from_utf8_lossy(&pem).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+    use webauthn_rs::prelude::{COSEAlgorithm, COSEEC2Key, ECDSACurve};
+
+    #[test]
+    fn test_public_key() {
+        // The base point of P-256, which is a valid public key.
+        let key = COSEKey {
+            type_: COSEAlgorithm::ES256,
+            key: COSEKeyType::EC_EC2(COSEEC2Key {
+                curve: ECDSACurve::SECP256R1,
+                x: URL_SAFE_NO_PAD
+                    .decode("axfR8uEsQkf4vOblY6RA8ncDfYEt6zOg9KE5RdiYwpY")
+                    .unwrap()
+                    .into(),
+                y: URL_SAFE_NO_PAD
+                    .decode("T-NC4v4af5uO5-tKfA-eFivOM1drMV7Oy7ZAaDe_UfU")
+                    .unwrap()
+                    .into(),
+            }),
+        };
+
+        let cose = cose(&key);
+        let decoded: Value = serde_cbor_2::from_slice(&cose).unwrap();
+        assert_eq!(COSEKey::try_from(&decoded).unwrap(), key);
+
+        assert_eq!(
+            pem(&key).unwrap(),
+            "-----BEGIN PUBLIC KEY-----\n\
+             MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEaxfR8uEsQkf4vOblY6RA8ncDfYEt\n\
+             6zOg9KE5RdiYwpZP40Li/hp/m47n60p8D54WK84zV2sxXs7LtkBoN79R9Q==\n\
+             -----END PUBLIC KEY-----\n"
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{app::SharedAppState, seed::Seed, session::SqliteSessionStore};
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use metrics::counter;
+use std::{path::PathBuf, time::Duration};
+use tracing::{error, info, warn};
+
+/// How often the users are compared with the authoritative source of users.
+pub const RECONCILIATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// What happens to users that no longer exist in the authoritative source of users, see
+/// `--reconcile-users`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Reconciliation {
+    /// Only log them, to see what the other modes would do.
+    Report,
+    /// Deactivate them, keeping their credentials in case they come back.
+    Deactivate,
+    /// Delete them along with their credentials and sessions.
+    Delete,
+}
+
+/// Where the users that should exist are read from. The seed file is authoritative when there is
+/// one, the password file otherwise. Both are read again on every run, so that users removed
+/// from them are noticed without a restart.
+#[derive(Debug, Clone)]
+pub enum UserSource {
+    SeedFile(PathBuf),
+    PasswordFile(PathBuf),
+}
+
+/// Compares the users in the database with a `UserSource` and acts on those missing from it.
+pub struct Reconciler {
+    pub reconciliation: Reconciliation,
+    pub source: UserSource,
+    /// Users with a password in the database exist without being in the password file.
+    pub database_passwords: bool,
+    pub session_store: SqliteSessionStore,
+}
+
+impl Reconciler {
+    async fn upstream_usernames(&self, app: &SharedAppState) -> anyhow::Result<Vec<String>> {
+        let app = app.read().await;
+        let usernames: Vec<String> = match &self.source {
+            UserSource::SeedFile(path) => Seed::load(path)?
+                .users
+                .keys()
+                .map(|username| app.normalize_username(username))
+                .collect(),
+            UserSource::PasswordFile(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("could not read password file {}", path.display()))?
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .map(|(username, _)| app.normalize_username(username))
+                .collect(),
+        };
+        // An empty file is more likely a botched deployment than every user leaving at once.
+        if usernames.is_empty() {
+            bail!("the source of users lists no users, refusing to reconcile");
+        }
+        Ok(usernames)
+    }
+
+    /// Reconciles the users once, returning the users that are missing upstream.
+    pub async fn run(&self, app: &SharedAppState) -> anyhow::Result<Vec<String>> {
+        let upstream = self.upstream_usernames(app).await?;
+        let missing = app
+            .read()
+            .await
+            .reconcile_users(upstream, self.database_passwords, self.reconciliation)
+            .await?;
+        if missing.is_empty() {
+            return Ok(missing);
+        }
+
+        match self.reconciliation {
+            Reconciliation::Report => warn!(
+                "users {} are missing upstream, pass --reconcile-users=deactivate or \
+                 --reconcile-users=delete to act on them",
+                missing.join(", ")
+            ),
+            Reconciliation::Deactivate => {
+                info!("deactivated users missing upstream: {}", missing.join(", "))
+            }
+            Reconciliation::Delete => {
+                for username in &missing {
+                    if let Err(e) = self.session_store.delete_user(username.clone()).await {
+                        error!("could not delete sessions of {username}: {e}");
+                    }
+                }
+                info!("deleted users missing upstream: {}", missing.join(", "));
+            }
+        }
+        if self.reconciliation != Reconciliation::Report {
+            counter!("reconciled_users").increment(missing.len() as u64);
+        }
+        Ok(missing)
+    }
+
+    /// Reconciles the users every `RECONCILIATION_INTERVAL` until the server exits.
+    pub async fn watch(self, app: SharedAppState) {
+        let mut interval = tokio::time::interval(RECONCILIATION_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.run(&app).await {
+                error!("could not reconcile users: {e:#}");
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::Duration;
+use webauthn_rs::prelude::Url;
+use zeroize::Zeroizing;
+
+/// Prefix mixed into every signature so that recovery tokens cannot be confused with anything
+/// else signed using the session secret.
+const DOMAIN: &[u8] = b"webauthn-tiny recovery token\0";
+
+/// What a recovery token grants: the right to replace all credentials of `username`, once,
+/// before `expires_at`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryClaims {
+    /// Identifies the token in the database, where it is marked as used once redeemed.
+    pub id: String,
+    pub username: String,
+    /// Unix timestamp (in seconds).
+    pub expires_at: u64,
+}
+
+/// Generates `count` recovery codes in the same format as `openssl rand -hex 16`.
+pub fn generate_codes(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|_| {
+            let mut bytes = [0u8; 16];
+            OsRng.fill_bytes(&mut bytes);
+            bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+        })
+        .collect()
+}
+
+/// Issues and verifies the signed tokens embedded in account recovery links.
+pub struct RecoveryTokens {
+    key: Zeroizing<Vec<u8>>,
+    base_url: Url,
+    pub ttl: Duration,
+}
+
+impl RecoveryTokens {
+    pub fn new(key: &[u8], base_url: Url, ttl: Duration) -> Self {
+        Self {
+            key: Zeroizing::new(key.to_vec()),
+            base_url,
+            ttl,
+        }
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("hmac accepts keys of any length");
+        mac.update(DOMAIN);
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// Encodes the claims as `<payload>.<signature>`, both base64url encoded.
+    pub fn sign(&self, claims: &RecoveryClaims) -> String {
+        let payload = general_purpose::URL_SAFE_NO_PAD
+            .encode(serde_json::to_vec(claims).expect("claims are serializable"));
+        let signature =
+            general_purpose::URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+        format!("{payload}.{signature}")
+    }
+
+    /// Returns the claims of a token with a valid signature. Whether the token has expired or
+    /// was already used is checked against the database when it is redeemed.
+    pub fn verify(&self, token: &str) -> Option<RecoveryClaims> {
+        let (payload, signature) = token.split_once('.')?;
+        let signature = general_purpose::URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.mac(payload).verify_slice(&signature).ok()?;
+        serde_json::from_slice(&general_purpose::URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+    }
+
+    /// The link to the recover page under `base_url`, which may include a path prefix.
+    pub fn link(&self, token: &str) -> Url {
+        let mut url = self.base_url.clone();
+        url.set_path(&format!(
+            "{}/recover",
+            self.base_url.path().trim_end_matches('/')
+        ));
+        url.query_pairs_mut().append_pair("token", token);
+        url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let tokens = RecoveryTokens::new(
+            b"secret",
+            Url::parse("https://auth.foo.com").unwrap(),
+            Duration::from_secs(60),
+        );
+        let claims = RecoveryClaims {
+            id: "some-id".to_string(),
+            username: "foo".to_string(),
+            expires_at: 1000,
+        };
+
+        let token = tokens.sign(&claims);
+        assert_eq!(tokens.verify(&token), Some(claims.clone()));
+        assert!(tokens
+            .link(&token)
+            .as_str()
+            .starts_with("https://auth.foo.com/recover?token="));
+
+        // tampered payload
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = RecoveryClaims {
+            username: "admin".to_string(),
+            ..claims.clone()
+        };
+        let forged_payload =
+            general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        assert!(tokens
+            .verify(&format!("{forged_payload}.{signature}"))
+            .is_none());
+
+        // signed with a different key
+        let other = RecoveryTokens::new(
+            b"other secret",
+            Url::parse("https://auth.foo.com").unwrap(),
+            Duration::from_secs(60),
+        );
+        assert!(other.verify(&token).is_none());
+        assert!(tokens.verify("garbage").is_none());
+
+        let prefixed = RecoveryTokens::new(
+            b"secret",
+            Url::parse("https://foo.com/auth").unwrap(),
+            Duration::from_secs(60),
+        );
+        assert!(prefixed
+            .link(&token)
+            .as_str()
+            .starts_with("https://foo.com/auth/recover?token="));
+    }
+}
@@ -0,0 +1,431 @@
+//! Routers serving the parts of the server, which `main` combines on its listeners. Embedders
+//! can mount them separately, e.g. only `api_router` under a prefix of their own, with their own
+//! middleware around them. All of them expect the session layer (`tower_sessions`) and
+//! `with_state` to be applied on top.
+
+use crate::{
+    assets::{asset_handler, assets},
+    handlers::{
+        api::{
+            activate_user_admin_handler, add_alias_admin_handler, add_to_blocklist_admin_handler,
+            approve_pending_credential_admin_handler, authenticate_end_handler,
+            authenticate_start_handler, deactivate_user_admin_handler,
+            delete_blocklist_admin_handler, delete_credentials_admin_handler,
+            delete_credentials_api_handler, delete_self_handler,
+            delete_user_credentials_admin_handler, drain_admin_handler, enroll_end_handler,
+            enroll_skip_handler, enroll_start_handler, get_audit_log_admin_handler,
+            get_authenticate_context_handler, get_blocklist_admin_handler,
+            get_capabilities_handler, get_credentials_admin_handler, get_credentials_api_handler,
+            get_events_admin_handler, get_expiring_credentials_admin_handler,
+            get_failed_ceremonies_admin_handler, get_history_handler, get_origins_admin_handler,
+            get_pending_credentials_admin_handler, get_public_key_admin_handler,
+            get_settings_handler, get_users_admin_handler, get_version_handler,
+            impersonate_handler, issue_impersonation_admin_handler, issue_recovery_admin_handler,
+            kiosk_register_end_handler, kiosk_register_start_handler,
+            move_user_credentials_admin_handler, privacy_erase_handler, privacy_export_handler,
+            put_credential_tags_handler, put_settings_handler, readyz_handler, recover_end_handler,
+            recover_start_handler, recover_with_code_handler, regenerate_recovery_codes_handler,
+            register_end_handler, register_start_handler, reject_pending_credential_admin_handler,
+            remove_alias_admin_handler, remove_password_admin_handler, rename_user_admin_handler,
+            set_page_error_handler, set_password_admin_handler, step_up_end_handler,
+            step_up_start_handler, validate_batch_handler, validate_fast_handler, validate_handler,
+            well_known_change_password_handler, well_known_webauthn_handler, whoami_handler,
+        },
+        html::{
+            get_authenticate_template_handler, get_credentials_template_handler,
+            get_enroll_template_handler, get_kiosk_template_handler, get_recover_template_handler,
+            render_error_pages, root_handler,
+        },
+        middleware::{
+            add_login_redirect, check_forwarded_https, reject_new_ceremonies_when_draining,
+            reject_when_impersonating, reject_when_low_on_disk_space, reject_when_read_only,
+            require_logged_in, touch_session, upgrade_legacy_session, validate_webauthn_payloads,
+        },
+    },
+    spa::{spa_handler, Spa},
+    state::AppState,
+};
+use axum::{
+    handler::Handler,
+    middleware,
+    routing::{delete, get, post, put},
+    Router,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::{path::PathBuf, sync::Arc};
+
+/// How the routers behave, mirroring the command line flags of the same name.
+#[derive(Debug, Clone, Default)]
+pub struct RouterOptions {
+    /// Answer everything that writes to the database with `503 Service Unavailable`.
+    pub read_only: bool,
+    /// Check WebAuthn payloads before they are handled, see `validation`. Always on in debug
+    /// builds.
+    pub strict_validation: bool,
+    /// Serve a single-page app from this directory instead of the built-in pages.
+    pub spa_dist: Option<PathBuf>,
+    /// Serve the failed ceremonies kept by `--debug-ceremonies`.
+    pub debug_ceremonies: bool,
+}
+
+/// Parses `--path-prefix`, which has to be an absolute path. Trailing slashes are dropped, so that
+/// `/` serves at the root like no prefix at all.
+pub fn parse_path_prefix(prefix: &str) -> Result<String, String> {
+    if !prefix.starts_with('/') {
+        return Err("must start with /".to_string());
+    }
+    if prefix.contains(['?', '#', '{', '}']) {
+        return Err("must be a plain path".to_string());
+    }
+    Ok(prefix.trim_end_matches('/').to_string())
+}
+
+/// The API used by the pages, e.g. `/api/authenticate`, along with `/api/validate` for reverse
+/// proxies, `/readyz`, `/.well-known/webauthn`, `/.well-known/change-password` and
+/// `/assets/webauthn.js`.
+pub fn api_router(state: &AppState, options: &RouterOptions) -> Router<AppState> {
+    let draining =
+        || middleware::from_fn_with_state(state.clone(), reject_new_ceremonies_when_draining);
+    let forwarded_https = || middleware::from_fn_with_state(state.clone(), check_forwarded_https);
+    let mut writable_router = Router::new()
+        .route(
+            "/api/register",
+            get(register_start_handler)
+                .post(register_end_handler)
+                .layer(draining())
+                .layer(forwarded_https())
+                .layer(middleware::from_fn(reject_when_impersonating))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_logged_in,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    reject_when_low_on_disk_space,
+                )),
+        )
+        .route(
+            "/api/authenticate",
+            get(authenticate_start_handler)
+                .post(authenticate_end_handler)
+                .layer(draining())
+                .layer(forwarded_https()),
+        )
+        .route(
+            "/api/authenticate/context",
+            get(get_authenticate_context_handler),
+        )
+        .route("/api/page-error", post(set_page_error_handler))
+        .route(
+            "/api/recover",
+            get(recover_start_handler)
+                .post(recover_end_handler)
+                .layer(draining())
+                .layer(forwarded_https())
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    reject_when_low_on_disk_space,
+                )),
+        )
+        .route("/api/recover/code", post(recover_with_code_handler))
+        .route("/api/impersonate", get(impersonate_handler))
+        .route(
+            "/api/enroll",
+            get(enroll_start_handler)
+                .post(enroll_end_handler)
+                .layer(draining())
+                .layer(forwarded_https())
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    reject_when_low_on_disk_space,
+                )),
+        )
+        .route("/api/enroll/skip", post(enroll_skip_handler))
+        .route(
+            "/api/step-up",
+            get(step_up_start_handler)
+                .post(step_up_end_handler)
+                .layer(draining())
+                .layer(forwarded_https())
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_logged_in,
+                )),
+        )
+        .route(
+            "/api/credentials",
+            get(get_credentials_api_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_logged_in,
+            )),
+        )
+        .route(
+            "/api/history",
+            get(get_history_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_logged_in,
+            )),
+        )
+        .route(
+            "/api/users/self",
+            delete(delete_self_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_logged_in,
+            )),
+        )
+        .route(
+            "/api/privacy/export",
+            get(privacy_export_handler)
+                .layer(middleware::from_fn(reject_when_impersonating))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_logged_in,
+                )),
+        )
+        .route(
+            "/api/privacy/erase",
+            post(privacy_erase_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_logged_in,
+            )),
+        )
+        .route(
+            "/api/recovery-codes",
+            post(regenerate_recovery_codes_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_logged_in,
+            )),
+        )
+        .route(
+            "/api/settings",
+            get(get_settings_handler).put(put_settings_handler).layer(
+                middleware::from_fn_with_state(state.clone(), require_logged_in),
+            ),
+        )
+        .route(
+            "/api/credentials/{cred_id}",
+            delete(delete_credentials_api_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_logged_in,
+            )),
+        )
+        .route(
+            "/api/credentials/{cred_id}/tags",
+            put(put_credential_tags_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_logged_in,
+            )),
+        )
+        .route(
+            "/api/kiosk/register",
+            get(kiosk_register_start_handler)
+                .post(kiosk_register_end_handler)
+                .layer(draining())
+                .layer(forwarded_https())
+                .layer(middleware::from_fn(reject_when_impersonating))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_logged_in,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    reject_when_low_on_disk_space,
+                )),
+        );
+
+    if options.strict_validation || cfg!(debug_assertions) {
+        writable_router = writable_router.layer(middleware::from_fn(validate_webauthn_payloads));
+    }
+    if options.read_only {
+        writable_router = writable_router.layer(middleware::from_fn(reject_when_read_only));
+    }
+
+    Router::new()
+        .route(
+            "/api/validate",
+            get(validate_handler)
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_logged_in,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    add_login_redirect,
+                )),
+        )
+        .route(
+            "/api/validate/batch",
+            post(validate_batch_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_logged_in,
+            )),
+        )
+        .route("/api/capabilities", get(get_capabilities_handler))
+        .route("/api/version", get(get_version_handler))
+        .route("/api/whoami", get(whoami_handler))
+        .route("/readyz", get(readyz_handler))
+        .route("/.well-known/webauthn", get(well_known_webauthn_handler))
+        .route(
+            "/.well-known/change-password",
+            get(well_known_change_password_handler),
+        )
+        .route("/assets/webauthn.js", get(asset_handler))
+        .merge(writable_router)
+        .layer(middleware::from_fn_with_state(state.clone(), touch_session))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            upgrade_legacy_session,
+        ))
+        // Added after `touch_session` and `upgrade_legacy_session`, which would load and write
+        // the session.
+        .route(
+            "/api/validate/fast",
+            get(validate_fast_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                add_login_redirect,
+            )),
+        )
+}
+
+/// The pages, i.e. the built-in templates or the single-page app, and their assets. Everything
+/// not routed elsewhere falls back to it.
+pub fn html_router(state: &AppState, options: &RouterOptions) -> Router<AppState> {
+    let mut router = match options.spa_dist.clone() {
+        Some(dist) => {
+            Router::new().fallback_service(spa_handler.with_state(Arc::new(Spa::new(dist))))
+        }
+        None => Router::new()
+            .route("/authenticate", get(get_authenticate_template_handler))
+            .route("/credentials", get(get_credentials_template_handler))
+            .route("/recover", get(get_recover_template_handler))
+            .route("/kiosk", get(get_kiosk_template_handler))
+            .route("/enroll", get(get_enroll_template_handler))
+            .fallback(root_handler)
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                render_error_pages,
+            )),
+    };
+    if options.read_only {
+        router = router.layer(middleware::from_fn(reject_when_read_only));
+    }
+    router = router
+        .layer(middleware::from_fn_with_state(state.clone(), touch_session))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            upgrade_legacy_session,
+        ));
+
+    for path in assets().hashed_paths() {
+        router = router.route(path, get(asset_handler));
+    }
+    router
+}
+
+/// The admin API, which `main` serves under `/api/admin` to localhost or on `--admin-address`.
+/// It does no authorization of its own, so the routes must only be reachable by admins.
+pub fn admin_router(options: &RouterOptions) -> Router<AppState> {
+    let router = Router::new()
+        .route(
+            "/credentials",
+            get(get_credentials_admin_handler).delete(delete_credentials_admin_handler),
+        )
+        .route(
+            "/credentials/expiring",
+            get(get_expiring_credentials_admin_handler),
+        )
+        .route(
+            "/credentials/{handle}/public-key",
+            get(get_public_key_admin_handler),
+        )
+        .route(
+            "/users/{username}/credentials",
+            delete(delete_user_credentials_admin_handler),
+        )
+        .route(
+            "/users/{username}/credentials/move",
+            post(move_user_credentials_admin_handler),
+        )
+        .route("/users/{username}/rename", post(rename_user_admin_handler))
+        .route("/users/{username}/aliases", post(add_alias_admin_handler))
+        .route(
+            "/users/{username}/password",
+            put(set_password_admin_handler).delete(remove_password_admin_handler),
+        )
+        .route(
+            "/users/{username}/aliases/{alias}",
+            delete(remove_alias_admin_handler),
+        )
+        .route("/audit-log", get(get_audit_log_admin_handler))
+        .route("/events", get(get_events_admin_handler))
+        .route(
+            "/blocklist",
+            get(get_blocklist_admin_handler).post(add_to_blocklist_admin_handler),
+        )
+        .route("/blocklist/{id}", delete(delete_blocklist_admin_handler))
+        .route("/users", get(get_users_admin_handler))
+        .route(
+            "/users/{username}/recovery",
+            post(issue_recovery_admin_handler),
+        )
+        .route(
+            "/users/{username}/impersonate",
+            post(issue_impersonation_admin_handler),
+        )
+        .route(
+            "/users/{username}/activate",
+            post(activate_user_admin_handler),
+        )
+        .route(
+            "/users/{username}/deactivate",
+            post(deactivate_user_admin_handler),
+        )
+        .route("/pending", get(get_pending_credentials_admin_handler))
+        .route(
+            "/pending/{id}",
+            delete(reject_pending_credential_admin_handler),
+        )
+        .route(
+            "/pending/{id}/approve",
+            post(approve_pending_credential_admin_handler),
+        );
+
+    let router = if options.read_only {
+        router.layer(middleware::from_fn(reject_when_read_only))
+    } else {
+        router
+    };
+    // Read-only instances are drained before deploys too.
+    let router = router
+        .route("/drain", post(drain_admin_handler))
+        .route("/debug/origins", get(get_origins_admin_handler));
+    if options.debug_ceremonies {
+        router.route(
+            "/debug/ceremonies",
+            get(get_failed_ceremonies_admin_handler),
+        )
+    } else {
+        router
+    }
+}
+
+/// `/metrics` in the Prometheus text format.
+pub fn metrics_router<S: Clone + Send + Sync + 'static>(
+    prometheus: Arc<PrometheusHandle>,
+) -> Router<S> {
+    Router::new().route("/metrics", get(|| async move { prometheus.render() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path_prefix() {
+        assert_eq!(parse_path_prefix("/auth"), Ok("/auth".to_string()));
+        assert_eq!(parse_path_prefix("/auth/"), Ok("/auth".to_string()));
+        assert_eq!(parse_path_prefix("/"), Ok("".to_string()));
+        assert!(parse_path_prefix("auth").is_err());
+        assert!(parse_path_prefix("/{user}").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::Context;
+use landlock::{
+    path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+    ABI,
+};
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+use std::{collections::BTreeMap, env, path::PathBuf};
+use tracing::{info, warn};
+
+/// Directories the server reads from no matter how it is configured: the dynamic loader and NSS
+/// modules of the resolver, `/etc` for name resolution and CA certificates, and the programs run
+/// through `$PATH` (e.g. sendmail), which are added by `restrict_filesystem`.
+const SYSTEM_READ_PATHS: [&str; 9] = [
+    "/etc",
+    "/usr",
+    "/bin",
+    "/sbin",
+    "/lib",
+    "/lib64",
+    "/nix/store",
+    "/run/current-system",
+    "/dev/urandom",
+];
+
+/// The newest Landlock ABI whose access rights are handled. Older kernels enforce what they
+/// support of it.
+const LANDLOCK_ABI: ABI = ABI::V3;
+
+/// Syscalls refused with `EPERM` once the server is running: binding and listening (all sockets
+/// are bound by then), and ones a web server has no use for that widen what an attacker who got
+/// code execution can do to the kernel or other processes.
+const DENIED_SYSCALLS: &[i64] = &[
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+];
+
+/// Refused unless the server runs programs after startup, i.e. sendmail for email notifications.
+const EXEC_SYSCALLS: &[i64] = &[libc::SYS_execve, libc::SYS_execveat];
+
+/// What `--sandbox` leaves accessible besides `SYSTEM_READ_PATHS`. Paths that do not exist are
+/// skipped.
+#[derive(Debug, Default)]
+pub struct SandboxPaths {
+    pub read: Vec<PathBuf>,
+    /// Directories the server creates and deletes files in, e.g. the state directory.
+    pub write: Vec<PathBuf>,
+}
+
+/// Restricts the filesystem to `paths` with Landlock. This only applies to the calling thread
+/// and the threads it starts afterwards, so it has to be called before the runtime starts its
+/// workers. Kernels without Landlock are only warned about.
+pub fn restrict_filesystem(paths: &SandboxPaths) -> anyhow::Result<()> {
+    let search_path = env::var_os("PATH")
+        .map(|path| env::split_paths(&path).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let read = SYSTEM_READ_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .chain(search_path)
+        .chain(paths.read.iter().cloned());
+    // Commands get /dev/null as their stdin, and SQLite puts temporary files in the temp
+    // directory.
+    let write = ["/dev/null".into(), env::temp_dir()]
+        .into_iter()
+        .chain(paths.write.iter().cloned());
+
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(LANDLOCK_ABI))?
+        .create()?
+        .add_rules(path_beneath_rules(read, AccessFs::from_read(LANDLOCK_ABI)))?
+        .add_rules(path_beneath_rules(write, AccessFs::from_all(LANDLOCK_ABI)))?
+        .restrict_self()
+        .context("could not restrict filesystem access")?;
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => info!("restricted filesystem access"),
+        RulesetStatus::PartiallyEnforced => {
+            warn!("restricted filesystem access only partially, the kernel's Landlock is too old")
+        }
+        RulesetStatus::NotEnforced => {
+            warn!("could not restrict filesystem access, the kernel does not support Landlock")
+        }
+    }
+    Ok(())
+}
+
+/// Refuses `DENIED_SYSCALLS`, and `EXEC_SYSCALLS` unless `allow_exec`, in every thread of the
+/// process. Called once all sockets are bound.
+pub fn restrict_syscalls(allow_exec: bool) -> anyhow::Result<()> {
+    let denied = DENIED_SYSCALLS
+        .iter()
+        .chain(if allow_exec { &[][..] } else { EXEC_SYSCALLS });
+    let filter = SeccompFilter::new(
+        denied
+            .map(|syscall| (*syscall, Vec::new()))
+            .collect::<BTreeMap<_, _>>(),
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        env::consts::ARCH.try_into()?,
+    )?;
+    let program: BpfProgram = filter.try_into()?;
+    seccompiler::apply_filter_all_threads(&program).context("could not apply seccomp filter")?;
+    info!("restricted syscalls");
+    Ok(())
+}
@@ -0,0 +1,216 @@
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use rand::{rngs::OsRng, RngCore};
+use secrecy::{ExposeSecret, SecretString};
+use std::{
+    collections::HashMap,
+    env,
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+use tracing::info;
+use zeroize::Zeroizing;
+
+/// The session layer derives its cookie keys from at least this many bytes of secret.
+pub const MIN_LEN: usize = 64;
+
+/// Secrets are rejected when they carry less estimated entropy than this, e.g. a long but
+/// repetitive passphrase.
+const MIN_ENTROPY_BITS: f64 = 256.0;
+
+/// Generates a secret in the same format as `openssl rand -hex 64`.
+pub fn generate() -> Zeroizing<String> {
+    let mut bytes = Zeroizing::new([0u8; MIN_LEN]);
+    OsRng.fill_bytes(bytes.as_mut());
+    let mut secret = Zeroizing::new(String::with_capacity(MIN_LEN * 2 + 1));
+    for byte in bytes.iter() {
+        secret.push(char::from_digit(u32::from(byte >> 4), 16).expect("nibble is a digit"));
+        secret.push(char::from_digit(u32::from(byte & 0xf), 16).expect("nibble is a digit"));
+    }
+    secret.push('\n');
+    secret
+}
+
+/// Writes a new secret to `path`, readable only by its owner. An existing (possibly in use)
+/// secret is never overwritten.
+pub fn write_new(path: &Path) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(generate().as_bytes())?;
+    Ok(())
+}
+
+/// Estimates the entropy of `secret` from how often each byte occurs in it. This overestimates
+/// human-chosen secrets, but catches those made up of only a few distinct characters.
+fn entropy_bits(secret: &[u8]) -> f64 {
+    let counts = secret.iter().fold(HashMap::new(), |mut counts, byte| {
+        *counts.entry(byte).or_insert(0usize) += 1;
+        counts
+    });
+    let len = secret.len() as f64;
+    counts
+        .values()
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2() * len
+        })
+        .sum()
+}
+
+/// Checks that a session secret is long and random enough, with an explanation of how to fix it
+/// otherwise. Surrounding whitespace (such as a trailing newline) does not count.
+pub fn validate(secret: &str) -> anyhow::Result<()> {
+    let trimmed = secret.trim().as_bytes();
+    if trimmed.len() < MIN_LEN {
+        bail!(
+            "session secret is too short ({} bytes, at least {MIN_LEN} are required), generate \
+             one with `webauthn-tiny generate-secret --output <file>`",
+            trimmed.len()
+        );
+    }
+    if entropy_bits(trimmed) < MIN_ENTROPY_BITS {
+        bail!(
+            "session secret is too predictable, generate a random one with `webauthn-tiny \
+             generate-secret --output <file>`"
+        );
+    }
+    Ok(())
+}
+
+/// Somewhere a secret is fetched from once at startup. Secrets are passed around as
+/// `SecretString`, which is redacted when debug-printed and overwritten when dropped.
+#[async_trait]
+pub trait SecretSource: Send + Sync {
+    /// Names the source in error messages, without revealing the secret.
+    fn describe(&self) -> String;
+
+    async fn fetch(&self) -> anyhow::Result<SecretString>;
+}
+
+/// A secret stored in a file, optionally generated on first use.
+pub struct File {
+    pub path: PathBuf,
+    pub auto_generate: bool,
+}
+
+#[async_trait]
+impl SecretSource for File {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    async fn fetch(&self) -> anyhow::Result<SecretString> {
+        if self.auto_generate && !self.path.exists() {
+            write_new(&self.path).context("could not generate secret")?;
+            info!("generated new session secret {}", self.path.display());
+        }
+        let secret = Zeroizing::new(tokio::fs::read_to_string(&self.path).await?);
+        Ok(secret.as_str().into())
+    }
+}
+
+/// A credential passed in by systemd (`LoadCredential=` or, for secrets encrypted with
+/// `systemd-creds encrypt`, `LoadCredentialEncrypted=`), which systemd makes available in
+/// `$CREDENTIALS_DIRECTORY` only to this service.
+pub struct SystemdCredential {
+    pub name: String,
+}
+
+#[async_trait]
+impl SecretSource for SystemdCredential {
+    fn describe(&self) -> String {
+        format!("systemd credential {}", self.name)
+    }
+
+    async fn fetch(&self) -> anyhow::Result<SecretString> {
+        let Some(directory) = env::var_os("CREDENTIALS_DIRECTORY") else {
+            bail!(
+                "$CREDENTIALS_DIRECTORY is not set, the service must be started by systemd with \
+                 LoadCredential="
+            );
+        };
+        let path = Path::new(&directory).join(&self.name);
+        let secret = Zeroizing::new(tokio::fs::read_to_string(path).await?);
+        Ok(secret.as_str().into())
+    }
+}
+
+/// An executable that prints the secret to stdout, for secret managers without built-in
+/// support. It is passed the name of the secret (e.g. `session-secret`) as its only argument.
+pub struct Command {
+    pub program: PathBuf,
+    pub name: &'static str,
+}
+
+#[async_trait]
+impl SecretSource for Command {
+    fn describe(&self) -> String {
+        format!("{} {}", self.program.display(), self.name)
+    }
+
+    async fn fetch(&self) -> anyhow::Result<SecretString> {
+        let output = tokio::process::Command::new(&self.program)
+            .arg(self.name)
+            .stdin(std::process::Stdio::null())
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .await?;
+        if !output.status.success() {
+            bail!("exited with {}", output.status);
+        }
+        let stdout = Zeroizing::new(output.stdout);
+        let secret = std::str::from_utf8(&stdout).context("secret is not valid UTF-8")?;
+        Ok(secret.into())
+    }
+}
+
+/// Fetches and validates the session secret.
+pub async fn load(source: &dyn SecretSource) -> anyhow::Result<SecretString> {
+    let secret = source
+        .fetch()
+        .await
+        .with_context(|| format!("could not read session secret from {}", source.describe()))?;
+    validate(secret.expose_secret())
+        .with_context(|| format!("invalid session secret from {}", source.describe()))?;
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        validate(&generate()).unwrap();
+        validate("correct horse battery staple, but a lot longer: 8f3kq9zXw2LmP4vR7tYb").unwrap();
+
+        assert!(validate("hunter2").is_err());
+        assert!(validate(&"a".repeat(200)).is_err());
+        assert!(validate(&"ab".repeat(100)).is_err());
+        // padding does not count towards the length
+        assert!(validate(&format!("{}\n\n\n", "x".repeat(60))).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_command() {
+        let echo = Command {
+            program: PathBuf::from("echo"),
+            name: "session-secret",
+        };
+        assert_eq!(
+            echo.fetch().await.unwrap().expose_secret(),
+            "session-secret\n"
+        );
+
+        let failing = Command {
+            program: PathBuf::from("false"),
+            name: "session-secret",
+        };
+        assert!(failing.fetch().await.is_err());
+    }
+}
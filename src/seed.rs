@@ -0,0 +1,131 @@
+use crate::username::UsernameNormalization;
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use std::{collections::BTreeMap, path::Path};
+
+/// Users declared up front, reconciled into the database at startup so that deployments can be
+/// described completely in e.g. a nix expression. Like the config file it is JSON.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Seed {
+    /// Delete users (along with their credentials) that are not listed, and refuse creating
+    /// them on login.
+    pub prune: bool,
+    pub users: BTreeMap<String, SeedUser>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct SeedUser {
+    pub active: bool,
+    /// Replaces the user's groups. Left alone when not given.
+    pub groups: Option<Vec<String>>,
+    /// Hex encoded SHA-256 hashes of single-use recovery codes, replacing the user's codes when
+    /// given. Codes that stay listed keep whether they were used.
+    pub recovery_codes: Option<Vec<String>>,
+}
+
+impl Default for SeedUser {
+    fn default() -> Self {
+        Self {
+            active: true,
+            groups: None,
+            recovery_codes: None,
+        }
+    }
+}
+
+impl Seed {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let seed: Self = serde_json::from_str(
+            &std::fs::read_to_string(path)
+                .with_context(|| format!("could not read seed file {}", path.display()))?,
+        )
+        .with_context(|| format!("invalid seed file {}", path.display()))?;
+        seed.validate()
+            .with_context(|| format!("invalid seed file {}", path.display()))?;
+        Ok(seed)
+    }
+
+    /// Normalizes the usernames of the seed the same way logins are, so that they refer to the
+    /// same users.
+    pub fn normalize_usernames(
+        self,
+        normalization: &UsernameNormalization,
+    ) -> anyhow::Result<Self> {
+        let mut users = BTreeMap::new();
+        for (username, user) in self.users {
+            let normalized = normalization.normalize(&username);
+            if users.insert(normalized.clone(), user).is_some() {
+                bail!("seed file lists {normalized} more than once after normalization");
+            }
+        }
+        Ok(Self { users, ..self })
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        for (username, user) in &self.users {
+            if username.is_empty() || username.contains(':') {
+                bail!("invalid username {username:?}");
+            }
+            for hash in user.recovery_codes.iter().flatten() {
+                if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    bail!("recovery codes of {username} must be hex encoded SHA-256 hashes");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::username::NormalizationStep;
+
+    #[test]
+    fn test_seed() {
+        let seed: Seed = serde_json::from_str(
+            r#"{
+                "prune": true,
+                "users": {
+                    "alice": {
+                        "groups": ["admins"],
+                        "recoveryCodes": [
+                            "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+                        ]
+                    },
+                    "bob": { "active": false }
+                }
+            }"#,
+        )
+        .unwrap();
+        seed.validate().unwrap();
+        assert!(seed.prune);
+        assert!(seed.users["alice"].active);
+        assert_eq!(seed.users["alice"].groups, Some(vec!["admins".to_string()]));
+        assert!(!seed.users["bob"].active);
+        assert_eq!(seed.users["bob"].recovery_codes, None);
+
+        let lowercase = UsernameNormalization::new(&[NormalizationStep::Lowercase]);
+        let mixed_case: Seed =
+            serde_json::from_str(r#"{"users": {"Alice": {}, "bob": {}}}"#).unwrap();
+        assert_eq!(
+            mixed_case
+                .normalize_usernames(&lowercase)
+                .unwrap()
+                .users
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec!["alice".to_string(), "bob".to_string()]
+        );
+        let colliding: Seed =
+            serde_json::from_str(r#"{"users": {"Alice": {}, "alice": {}}}"#).unwrap();
+        assert!(colliding.normalize_usernames(&lowercase).is_err());
+
+        let plaintext_code: Seed =
+            serde_json::from_str(r#"{"users": {"alice": {"recoveryCodes": ["foo"]}}}"#).unwrap();
+        assert!(plaintext_code.validate().is_err());
+        assert!(serde_json::from_str::<Seed>(r#"{"users": {"alice": {"admin": true}}}"#).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{anyhow, Context};
+use webauthn_authenticator_rs::{softtoken::SoftToken, WebauthnAuthenticator};
+use webauthn_rs::{
+    prelude::{Url, Uuid},
+    Webauthn,
+};
+
+/// Outcome of the startup self-test, served at `/readyz`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelfTest {
+    /// `--self-test` was not given.
+    Skipped,
+    Passed,
+    Failed(String),
+}
+
+impl SelfTest {
+    /// Runs a registration followed by an authentication with a software authenticator from
+    /// each of `origins`, as a browser on them would, so that e.g. an origin the RP ID is not a
+    /// suffix of is noticed before users hit it.
+    pub fn run(webauthn: &Webauthn, origins: &[Url]) -> Self {
+        match origins
+            .iter()
+            .try_for_each(|origin| ceremony(webauthn, origin).with_context(|| origin.to_string()))
+        {
+            Ok(()) => Self::Passed,
+            Err(e) => Self::Failed(format!("{e:#}")),
+        }
+    }
+}
+
+fn ceremony(webauthn: &Webauthn, origin: &Url) -> anyhow::Result<()> {
+    let (soft_token, _) =
+        SoftToken::new(true).map_err(|e| anyhow!("could not create authenticator: {e:?}"))?;
+    let mut authenticator = WebauthnAuthenticator::new(soft_token);
+
+    let (challenge, registration) = webauthn
+        .start_passkey_registration(Uuid::new_v4(), "self-test", "self-test", None)
+        .context("could not start registration")?;
+    let credential = authenticator
+        .do_registration(origin.clone(), challenge)
+        .map_err(|e| anyhow!("authenticator refused to register: {e:?}"))?;
+    let passkey = webauthn
+        .finish_passkey_registration(&credential, &registration)
+        .context("could not finish registration")?;
+
+    let (challenge, authentication) = webauthn
+        .start_passkey_authentication(&[passkey])
+        .context("could not start authentication")?;
+    let credential = authenticator
+        .do_authentication(origin.clone(), challenge)
+        .map_err(|e| anyhow!("authenticator refused to authenticate: {e:?}"))?;
+    webauthn
+        .finish_passkey_authentication(&credential, &authentication)
+        .context("could not finish authentication")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webauthn_rs::WebauthnBuilder;
+
+    #[test]
+    fn test_self_test() {
+        let origin = Url::parse("https://auth.example.com").unwrap();
+        let other = Url::parse("https://example.org").unwrap();
+        let webauthn = WebauthnBuilder::new("example.com", &origin)
+            .unwrap()
+            .append_allowed_origin(&other)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            SelfTest::run(&webauthn, std::slice::from_ref(&origin)),
+            SelfTest::Passed
+        );
+        // the RP ID is no suffix of the other origin, so browsers there cannot use it
+        assert!(matches!(
+            SelfTest::run(&webauthn, &[origin, other]),
+            SelfTest::Failed(e) if e.starts_with("https://example.org/")
+        ));
+    }
+}
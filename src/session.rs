@@ -1,11 +1,52 @@
 use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
 use rusqlite::OptionalExtension;
+use serde::Deserialize;
+use std::collections::HashMap;
 use tokio_rusqlite::Connection;
 use tower_sessions::{
+    cookie::{
+        time::{self, format_description::well_known::Iso8601, Date, OffsetDateTime},
+        Cookie, CookieJar, Key,
+    },
     session::{Id, Record},
     session_store::{Error, Result, SessionStore},
 };
 
+/// Name of the session cookie, tower-sessions' default.
+pub const SESSION_COOKIE: &str = "id";
+
+/// Name of the session cookie of axum-sessions, which sessions were kept in before
+/// tower-sessions.
+pub const LEGACY_SESSION_COOKIE: &str = "sid";
+
+/// A session as axum-sessions stored it in the same table, through async-session: keyed by a
+/// hash of the cookie value, with values kept as JSON encoded strings.
+#[derive(Deserialize)]
+struct LegacySession {
+    #[serde(with = "time::serde::rfc3339::option")]
+    expiry: Option<OffsetDateTime>,
+    data: HashMap<String, String>,
+}
+
+/// The contents of a session of the axum-sessions format, see
+/// `SqliteSessionStore::take_legacy`.
+#[derive(Debug, PartialEq)]
+pub struct LegacyRecord {
+    pub data: HashMap<String, serde_json::Value>,
+    /// `None` for sessions that lasted as long as the browser kept the cookie.
+    pub expiry: Option<OffsetDateTime>,
+}
+
+/// Parses the end of the window in which sessions of the axum-sessions format are upgraded,
+/// a date such as `2025-01-31` that ends at midnight UTC.
+pub fn parse_legacy_sessions_until(s: &str) -> std::result::Result<OffsetDateTime, String> {
+    Ok(Date::parse(s, &Iso8601::DATE)
+        .map_err(|e| format!("expected a date such as 2025-01-31: {e}"))?
+        .midnight()
+        .assume_utc())
+}
+
 #[derive(Clone, Debug)]
 pub struct SqliteSessionStore {
     db: Connection,
@@ -32,7 +73,159 @@ impl SqliteSessionStore {
         Ok(())
     }
 
-    #[allow(dead_code)]
+    /// Loads the session whose private cookie has the value `cookie`, for checking sessions
+    /// outside of `SessionManagerLayer`, which never writes to the store or sets the cookie.
+    /// `None` unless the cookie was encrypted with `key` and its session exists and has not
+    /// expired (see `load`).
+    pub async fn load_by_cookie(&self, key: &Key, cookie: &str) -> anyhow::Result<Option<Record>> {
+        let mut jar = CookieJar::new();
+        jar.add_original(Cookie::new(SESSION_COOKIE, cookie.to_string()));
+        let Some(id) = jar
+            .private(key)
+            .get(SESSION_COOKIE)
+            .and_then(|cookie| cookie.value().parse::<Id>().ok())
+        else {
+            return Ok(None);
+        };
+        Ok(self.load(&id).await?)
+    }
+
+    /// Removes the session of the axum-sessions format whose signed cookie has the value
+    /// `cookie`, returning its contents unless it has expired. The session is removed either way,
+    /// so that it can only be carried over into a new session once.
+    pub async fn take_legacy(
+        &self,
+        key: &Key,
+        cookie: &str,
+    ) -> anyhow::Result<Option<LegacyRecord>> {
+        let mut jar = CookieJar::new();
+        jar.add_original(Cookie::new(LEGACY_SESSION_COOKIE, cookie.to_string()));
+        let Some(cookie) = jar.signed(key).get(LEGACY_SESSION_COOKIE) else {
+            return Ok(None);
+        };
+        // async-session stores sessions under the hash of the random bytes in the cookie.
+        let Ok(cookie_value) = general_purpose::STANDARD.decode(cookie.value()) else {
+            return Ok(None);
+        };
+        let id = general_purpose::STANDARD.encode(blake3::hash(&cookie_value).as_bytes());
+
+        let Some(value) = self
+            .db
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let value = tx
+                    .query_row(
+                        r#"select value from sessions
+                           where id = ?1 and json_type(value, '$.expiry_date') is null"#,
+                        (&id,),
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()?;
+                tx.execute(r#"delete from sessions where id = ?1"#, (&id,))?;
+                tx.commit()?;
+                Ok(value)
+            })
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let session: LegacySession = serde_json::from_str(&value)?;
+        if session
+            .expiry
+            .is_some_and(|expiry| expiry <= OffsetDateTime::now_utc())
+        {
+            return Ok(None);
+        }
+        Ok(Some(LegacyRecord {
+            data: session
+                .data
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), serde_json::from_str(value)?)))
+                .collect::<serde_json::Result<_>>()?,
+            expiry: session.expiry,
+        }))
+    }
+
+    /// Number of sessions of the axum-sessions format that have not been upgraded yet.
+    pub async fn count_legacy(&self) -> anyhow::Result<usize> {
+        Ok(self
+            .db
+            .call(|conn| {
+                Ok(conn.query_row(
+                    r#"select count(*) from sessions
+                       where json_type(value, '$.expiry_date') is null"#,
+                    [],
+                    |row| row.get(0),
+                ))
+            })
+            .await??)
+    }
+
+    /// Deletes the sessions of the axum-sessions format once they can no longer be upgraded.
+    /// Returns the number of sessions that were deleted.
+    pub async fn delete_legacy(&self) -> anyhow::Result<usize> {
+        Ok(self
+            .db
+            .call(|conn| {
+                Ok(conn.execute(
+                    r#"delete from sessions where json_type(value, '$.expiry_date') is null"#,
+                    [],
+                ))
+            })
+            .await??)
+    }
+
+    /// Moves the sessions of a renamed user over to the new username, so that they stay logged
+    /// in. Returns the number of sessions that were updated.
+    pub async fn rename_user(&self, from: String, to: String) -> anyhow::Result<usize> {
+        Ok(self
+            .db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"update sessions set value = json_set(value, '$.data.username', ?1)
+                       where json_extract(value, '$.data.username') = ?2"#,
+                    (&to, &from),
+                ))
+            })
+            .await??)
+    }
+
+    /// Deletes all sessions of a user, e.g. after the user was deleted. Returns the number of
+    /// sessions that were deleted.
+    pub async fn delete_user(&self, username: String) -> anyhow::Result<usize> {
+        Ok(self
+            .db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    r#"delete from sessions where json_extract(value, '$.data.username') = ?1"#,
+                    (&username,),
+                ))
+            })
+            .await??)
+    }
+
+    /// All sessions of a user, including expired ones that have not been deleted yet.
+    pub async fn user_sessions(&self, username: String) -> anyhow::Result<Vec<Record>> {
+        let values = self
+            .db
+            .call(move |conn| {
+                Ok(conn
+                    .prepare(
+                        r#"select value from sessions
+                           where json_extract(value, '$.data.username') = ?1"#,
+                    )?
+                    .query_map((&username,), |row| row.get::<_, String>(0))?
+                    .collect::<std::result::Result<Vec<_>, _>>())
+            })
+            .await??;
+
+        Ok(values
+            .iter()
+            .map(|value| serde_json::from_str(value))
+            .collect::<std::result::Result<_, _>>()?)
+    }
+
     pub async fn clear(&self) -> anyhow::Result<()> {
         self.db
             .call(|conn| Ok(conn.execute(r#"delete from sessions"#, [])))
@@ -44,6 +237,37 @@ impl SqliteSessionStore {
 
 #[async_trait]
 impl SessionStore for SqliteSessionStore {
+    /// Creates a new session record in the store, picking a new ID if the record's ID is already
+    /// taken so that an existing session is never overwritten.
+    async fn create(&self, session_record: &mut Record) -> Result<()> {
+        let session_value =
+            serde_json::to_string(session_record).map_err(|err| Error::Backend(err.to_string()))?;
+
+        loop {
+            let session_id = session_record.id.to_string();
+            let session_value = session_value.clone();
+
+            let n_inserted = self
+                .db
+                .call(|conn| {
+                    Ok(conn.execute(
+                        r#"insert into sessions (id, value) values(?1, ?2)
+                           on conflict(id) do nothing"#,
+                        (session_id, session_value),
+                    ))
+                })
+                .await
+                .map_err(|err| Error::Backend(err.to_string()))?
+                .map_err(|err| Error::Backend(err.to_string()))?;
+
+            if n_inserted == 1 {
+                return Ok(());
+            }
+
+            session_record.id = Id::default();
+        }
+    }
+
     /// Saves the provided session record to the store.
     ///
     /// This method is intended for updating the state of an existing session.
@@ -96,6 +320,13 @@ impl SessionStore for SqliteSessionStore {
         let session: Record =
             serde_json::from_str(&value).map_err(|err| Error::Backend(err.to_string()))?;
 
+        // The cookie outliving the session, e.g. one stolen from an idle session, must not
+        // bring it back.
+        if session.expiry_date <= OffsetDateTime::now_utc() {
+            self.delete(&session.id).await?;
+            return Ok(None);
+        }
+
         Ok(Some(session))
     }
 
@@ -120,8 +351,225 @@ impl SessionStore for SqliteSessionStore {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
-    use tower_sessions::cookie::time::OffsetDateTime;
+    use std::{collections::HashMap, sync::Arc};
+    use tower_sessions::{cookie::time::OffsetDateTime, Expiry, Session};
+
+    async fn count_sessions(store: &SqliteSessionStore) -> usize {
+        store
+            .db
+            .call(|conn| {
+                Ok(conn
+                    .query_row("select count(*) from sessions", [], |row| row.get(0))
+                    .unwrap())
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_create_does_not_overwrite() {
+        let db = Connection::open(":memory:").await.unwrap();
+        let store = SqliteSessionStore::new(db);
+        store.init().await.unwrap();
+
+        let mut first = Record {
+            id: Id::default(),
+            data: HashMap::default(),
+            expiry_date: OffsetDateTime::now_utc(),
+        };
+        store.create(&mut first).await.unwrap();
+
+        let mut second = first.clone();
+        store.create(&mut second).await.unwrap();
+
+        assert_ne!(first.id, second.id);
+        assert_eq!(count_sessions(&store).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_cycle_id_deletes_old_session() {
+        let db = Connection::open(":memory:").await.unwrap();
+        let store = SqliteSessionStore::new(db);
+        store.init().await.unwrap();
+
+        let session = Session::new(None, Arc::new(store.clone()), None);
+        session.insert("foo", 42).await.unwrap();
+        session.save().await.unwrap();
+        let old_id = session.id().unwrap();
+
+        session.cycle_id().await.unwrap();
+        session.save().await.unwrap();
+        let new_id = session.id().unwrap();
+
+        assert_ne!(old_id, new_id);
+        assert!(store.load(&old_id).await.unwrap().is_none());
+        assert!(store.load(&new_id).await.unwrap().is_some());
+        assert_eq!(count_sessions(&store).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_load_by_cookie() {
+        let db = Connection::open(":memory:").await.unwrap();
+        let store = SqliteSessionStore::new(db);
+        store.init().await.unwrap();
+
+        let mut record = Record {
+            id: Id::default(),
+            data: HashMap::default(),
+            expiry_date: OffsetDateTime::now_utc() + tower_sessions::cookie::time::Duration::HOUR,
+        };
+        store.create(&mut record).await.unwrap();
+
+        let key = Key::generate();
+        let mut jar = CookieJar::new();
+        jar.private_mut(&key)
+            .add(Cookie::new(SESSION_COOKIE, record.id.to_string()));
+        let cookie = jar.get(SESSION_COOKIE).unwrap().value().to_string();
+
+        assert!(store.load_by_cookie(&key, &cookie).await.unwrap().is_some());
+        assert!(store
+            .load_by_cookie(&Key::generate(), &cookie)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .load_by_cookie(&key, &record.id.to_string())
+            .await
+            .unwrap()
+            .is_none());
+
+        record.expiry_date = OffsetDateTime::now_utc();
+        store.save(&record).await.unwrap();
+        assert!(store.load_by_cookie(&key, &cookie).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_take_legacy() {
+        let db = Connection::open(":memory:").await.unwrap();
+        let store = SqliteSessionStore::new(db);
+        store.init().await.unwrap();
+
+        // A session as axum-sessions would have stored it, and its signed cookie.
+        let key = Key::generate();
+        let legacy_session = |expiry: &str| {
+            let cookie_value = general_purpose::STANDARD.encode(rand::random::<[u8; 32]>());
+            let id = general_purpose::STANDARD.encode(
+                blake3::hash(&general_purpose::STANDARD.decode(&cookie_value).unwrap()).as_bytes(),
+            );
+            let value = serde_json::json!({
+                "id": id,
+                "expiry": expiry,
+                "data": { "username": "\"j.doe\"", "logged_in": "true" },
+            });
+            let mut jar = CookieJar::new();
+            jar.signed_mut(&key)
+                .add(Cookie::new(LEGACY_SESSION_COOKIE, cookie_value));
+            let cookie = jar.get(LEGACY_SESSION_COOKIE).unwrap().value().to_string();
+            (id, value.to_string(), cookie)
+        };
+        let insert = |id: String, value: String| {
+            store.db.call(move |conn| {
+                Ok(conn
+                    .execute(
+                        "insert into sessions (id, value) values (?1, ?2)",
+                        (id, value),
+                    )
+                    .unwrap())
+            })
+        };
+
+        let (id, value, cookie) = legacy_session("2999-01-01T00:00:00.123456Z");
+        insert(id, value).await.unwrap();
+        let (id, value, expired_cookie) = legacy_session("2000-01-01T00:00:00Z");
+        insert(id, value).await.unwrap();
+        let mut record = Record {
+            id: Id::default(),
+            data: HashMap::default(),
+            expiry_date: OffsetDateTime::now_utc(),
+        };
+        store.create(&mut record).await.unwrap();
+        assert_eq!(store.count_legacy().await.unwrap(), 2);
+
+        // the cookie has to be signed with the session secret
+        assert!(store
+            .take_legacy(&Key::generate(), &cookie)
+            .await
+            .unwrap()
+            .is_none());
+        let legacy = store.take_legacy(&key, &cookie).await.unwrap().unwrap();
+        assert_eq!(legacy.data["username"], "j.doe");
+        assert_eq!(legacy.data["logged_in"], true);
+        assert_eq!(legacy.expiry.unwrap().year(), 2999);
+        // sessions are only taken once
+        assert!(store.take_legacy(&key, &cookie).await.unwrap().is_none());
+        assert!(store
+            .take_legacy(&key, &expired_cookie)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(store.count_legacy().await.unwrap(), 0);
+
+        let (id, value, _) = legacy_session("2999-01-01T00:00:00Z");
+        insert(id, value).await.unwrap();
+        assert_eq!(store.delete_legacy().await.unwrap(), 1);
+        assert_eq!(count_sessions(&store).await, 1);
+
+        assert_eq!(
+            parse_legacy_sessions_until("2025-01-31").unwrap(),
+            OffsetDateTime::from_unix_timestamp(1738281600).unwrap()
+        );
+        assert!(parse_legacy_sessions_until("31.01.2025").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_expired() {
+        let db = Connection::open(":memory:").await.unwrap();
+        let store = SqliteSessionStore::new(db);
+        store.init().await.unwrap();
+
+        // as with --session-idle-minutes, only much shorter
+        let session = Session::new(
+            None,
+            Arc::new(store.clone()),
+            Some(Expiry::OnInactivity(time::Duration::milliseconds(100))),
+        );
+        session.insert("username", "j.doe").await.unwrap();
+        session.save().await.unwrap();
+        let id = session.id().unwrap();
+        assert!(store.load(&id).await.unwrap().is_some());
+
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(store.load(&id).await.unwrap().is_none());
+        // and it is deleted rather than left for the cookie to bring back
+        assert_eq!(count_sessions(&store).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_rename_user() {
+        let db = Connection::open(":memory:").await.unwrap();
+        let store = SqliteSessionStore::new(db);
+        store.init().await.unwrap();
+
+        let session = Session::new(None, Arc::new(store.clone()), None);
+        session.insert("username", "j.doe").await.unwrap();
+        session.save().await.unwrap();
+        let other = Session::new(None, Arc::new(store.clone()), None);
+        other.insert("username", "foo").await.unwrap();
+        other.save().await.unwrap();
+
+        assert_eq!(
+            store
+                .rename_user("j.doe".to_string(), "jdoe".to_string())
+                .await
+                .unwrap(),
+            1
+        );
+
+        let record = store.load(&session.id().unwrap()).await.unwrap().unwrap();
+        assert_eq!(record.data["username"], "jdoe");
+        let record = store.load(&other.id().unwrap()).await.unwrap().unwrap();
+        assert_eq!(record.data["username"], "foo");
+    }
 
     #[tokio::test]
     async fn test_session_lifecycle() {
@@ -133,7 +581,7 @@ mod tests {
             let mut session = Record {
                 id: Id::default(),
                 data: HashMap::default(),
-                expiry_date: OffsetDateTime::now_utc(),
+                expiry_date: OffsetDateTime::now_utc() + time::Duration::HOUR,
             };
 
             store.create(&mut session).await.unwrap();
@@ -0,0 +1,141 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use tracing::{error, trace};
+
+/// A single-page app served in place of the built-in templates. Files that exist in the dist
+/// directory are served as-is, every other path without a file extension gets `index.html` so
+/// that the app can do its own routing.
+pub struct Spa {
+    dist: PathBuf,
+}
+
+impl Spa {
+    pub fn new(dist: PathBuf) -> Self {
+        Self { dist }
+    }
+}
+
+/// Maps a request path to a path relative to the dist directory, refusing anything that could
+/// escape it.
+fn relative_path(request_path: &str) -> Option<PathBuf> {
+    let path = Path::new(request_path.trim_start_matches('/'));
+    if request_path.contains('\\')
+        || !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(path.to_path_buf())
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript",
+        Some("css") => "text/css",
+        Some("json" | "map") => "application/json",
+        Some("wasm") => "application/wasm",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Bundlers put content-hashed files under `assets/`, so those can be cached forever. Anything
+/// else (most importantly `index.html`) must be revalidated to pick up new deployments.
+fn cache_control(path: &Path) -> &'static str {
+    if path.starts_with("assets") {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    }
+}
+
+async fn serve_file(path: &Path, full_path: &Path) -> Option<Response> {
+    let contents = tokio::fs::read(full_path).await.ok()?;
+    Some(
+        Response::builder()
+            .header(header::CONTENT_TYPE, content_type(path))
+            .header(header::CACHE_CONTROL, cache_control(path))
+            .body(Body::from(contents))
+            .expect("could not build response"),
+    )
+}
+
+pub async fn spa_handler(method: Method, uri: Uri, spa: State<Arc<Spa>>) -> Response {
+    trace!("spa_handler");
+
+    if method != Method::GET && method != Method::HEAD {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let Some(path) = relative_path(uri.path()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let full_path = spa.dist.join(&path);
+    if tokio::fs::metadata(&full_path)
+        .await
+        .is_ok_and(|metadata| metadata.is_file())
+    {
+        if let Some(response) = serve_file(&path, &full_path).await {
+            return response;
+        }
+    }
+
+    // Missing files (e.g. a stale hashed asset) and unknown API endpoints should not turn into
+    // an HTML page.
+    if path.extension().is_some() || path.starts_with("api") {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let index = Path::new("index.html");
+    match serve_file(index, &spa.dist.join(index)).await {
+        Some(response) => response,
+        None => {
+            error!("could not read index.html from {}", spa.dist.display());
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_path() {
+        [
+            ("/", Some("")),
+            ("/credentials", Some("credentials")),
+            ("/assets/index-abc123.js", Some("assets/index-abc123.js")),
+            ("/../etc/passwd", None),
+            ("/assets/../../etc/passwd", None),
+            ("/./index.html", None),
+            ("/..\\secret", None),
+        ]
+        .iter()
+        .for_each(|(request_path, expected)| {
+            assert_eq!(
+                relative_path(request_path),
+                expected.map(PathBuf::from),
+                "{request_path}"
+            );
+        });
+    }
+}
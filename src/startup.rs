@@ -0,0 +1,148 @@
+use crate::app::AppError;
+use rusqlite::ErrorCode;
+use std::{fmt, process::ExitCode};
+
+/// Why the server could not start, attached to startup errors as context so that `report` can
+/// tell service managers and scripts apart by exit code what went wrong, and hint at the fix.
+/// The exit codes follow sysexits.h; anything not categorized exits with 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupFailure {
+    /// `--rp-origin` or `--extra-allowed-origin` is not a URL, or does not match `--rp-id`.
+    RpOrigin,
+    /// A template, i.e. `errorTemplate` of the config file, could not be read or parsed.
+    Template,
+    /// The state directory the databases are kept in by default does not exist.
+    StateDirectory,
+    /// The databases could not be opened or migrated.
+    Database,
+    /// Another process holds a lock on the databases, e.g. a second instance migrating them.
+    DatabaseLocked,
+    /// The config file, seed file or session secret is invalid, or the RP ID changed.
+    Config,
+}
+
+impl StartupFailure {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            // EX_USAGE
+            StartupFailure::RpOrigin => 64,
+            // EX_DATAERR
+            StartupFailure::Template => 65,
+            // EX_CANTCREAT
+            StartupFailure::StateDirectory => 73,
+            // EX_IOERR
+            StartupFailure::Database => 74,
+            // EX_TEMPFAIL, so that service managers can tell it is worth restarting.
+            StartupFailure::DatabaseLocked => 75,
+            // EX_CONFIG
+            StartupFailure::Config => 78,
+        }
+    }
+
+    pub fn hint(self) -> &'static str {
+        match self {
+            StartupFailure::RpOrigin => {
+                "--rp-origin must be a full URL such as https://auth.example.com whose host is \
+                 --rp-id or a subdomain of it"
+            }
+            StartupFailure::Template => {
+                "fix the Liquid syntax of the template, or remove errorTemplate from the config \
+                 file to use the built-in one"
+            }
+            StartupFailure::StateDirectory => {
+                "create the directory, point --state-directory (STATE_DIRECTORY) at an existing \
+                 one, or let the service manager create it, e.g. with StateDirectory= in systemd"
+            }
+            StartupFailure::Database => {
+                "check that the database files and their directory exist and are writable by \
+                 the server, and that the disk is not full"
+            }
+            StartupFailure::DatabaseLocked => {
+                "another process is using the database, e.g. a second instance or a backup; \
+                 stop it or retry once it is done"
+            }
+            StartupFailure::Config => {
+                "check the files given on the command line and in the config file"
+            }
+        }
+    }
+
+    /// Tells a locked database apart from other database errors.
+    pub fn database(error: &anyhow::Error) -> Self {
+        let locked = error.chain().any(|cause| {
+            matches!(cause.downcast_ref::<AppError>(), Some(AppError::Busy))
+                || matches!(
+                    cause.downcast_ref::<rusqlite::Error>(),
+                    Some(rusqlite::Error::SqliteFailure(err, _))
+                        if matches!(err.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+                )
+        });
+        if locked {
+            StartupFailure::DatabaseLocked
+        } else {
+            StartupFailure::Database
+        }
+    }
+}
+
+impl fmt::Display for StartupFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StartupFailure::RpOrigin => "invalid RP origin",
+            StartupFailure::Template => "could not load templates",
+            StartupFailure::StateDirectory => "state directory is missing",
+            StartupFailure::Database => "could not open the databases",
+            StartupFailure::DatabaseLocked => "the databases are locked",
+            StartupFailure::Config => "invalid configuration",
+        })
+    }
+}
+
+/// Prints `error` along with the hint of its `StartupFailure`, returning the exit code to exit
+/// with.
+pub fn report(error: &anyhow::Error) -> ExitCode {
+    let failure = error.downcast_ref::<StartupFailure>();
+    eprintln!("Error: {error:?}");
+    match failure {
+        Some(failure) => {
+            eprintln!("\nHint: {}", failure.hint());
+            ExitCode::from(failure.exit_code())
+        }
+        None => ExitCode::FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use rusqlite::ffi;
+
+    #[test]
+    fn test_startup_failure() {
+        let busy = rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_BUSY), None);
+        assert_eq!(
+            StartupFailure::database(&anyhow::Error::new(busy)),
+            StartupFailure::DatabaseLocked
+        );
+        assert_eq!(
+            StartupFailure::database(&AppError::Busy.into()),
+            StartupFailure::DatabaseLocked
+        );
+        assert_eq!(
+            StartupFailure::database(&AppError::Io.into()),
+            StartupFailure::Database
+        );
+
+        // Context added on top of the failure does not hide it.
+        let error = Err::<(), _>(anyhow::anyhow!("relative URL without a base"))
+            .context(StartupFailure::RpOrigin)
+            .context("while starting")
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<StartupFailure>(),
+            Some(&StartupFailure::RpOrigin)
+        );
+        assert_eq!(StartupFailure::RpOrigin.exit_code(), 64);
+    }
+}
@@ -0,0 +1,51 @@
+use crate::{
+    app::SharedAppState, captcha::Captcha, ceremony_log::CeremonyLog, client_ip::ClientIpResolver,
+    config::Config, disk::DiskSpace, drain::Drain, forwarded_https::ForwardedHttps,
+    handlers::html::Templates, health::DatabaseHealth, notify::Notifier, origins::AllowedOrigins,
+    policy::Policy, pow::ProofOfWork, recovery::RecoveryTokens, self_test::SelfTest,
+    session::SqliteSessionStore, throttle::LoginThrottle, timing::RequestTimingConfig,
+};
+use axum::extract::FromRef;
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::{collections::HashMap, sync::Arc};
+use tower_sessions::cookie::Key;
+use webauthn_rs::{prelude::Url, Webauthn};
+
+/// Usernames mapped to their argon2 password hashes, as read from the password file.
+pub type Passwords = Arc<HashMap<String, String>>;
+
+/// Everything shared by handlers and middleware. Each field can be extracted on its own, e.g.
+/// `State(policy): State<Arc<Policy>>`, so handlers only name what they use.
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pub app: SharedAppState,
+    pub webauthn: Arc<Webauthn>,
+    pub allowed_origins: Arc<AllowedOrigins>,
+    pub session_store: SqliteSessionStore,
+    /// Encrypts the session cookie, for reading sessions without `SessionManagerLayer`.
+    pub session_key: Key,
+    pub templates: Arc<Templates>,
+    pub policy: Arc<Policy>,
+    pub config: Arc<Config>,
+    pub timing: RequestTimingConfig,
+    pub pow: Arc<ProofOfWork>,
+    pub throttle: Arc<LoginThrottle>,
+    /// `None` unless a CAPTCHA provider is configured.
+    pub captcha: Option<Arc<Captcha>>,
+    pub recovery: Arc<RecoveryTokens>,
+    /// `None` with `--metrics off`.
+    pub prometheus: Option<Arc<PrometheusHandle>>,
+    pub passwords: Passwords,
+    /// `None` unless approval notifications are configured.
+    pub notifier: Option<Arc<Notifier>>,
+    pub self_test: Arc<SelfTest>,
+    pub disk_space: Arc<DiskSpace>,
+    pub database_health: Arc<DatabaseHealth>,
+    pub drain: Arc<Drain>,
+    pub client_ip: Arc<ClientIpResolver>,
+    pub forwarded_https: Arc<ForwardedHttps>,
+    /// The RP origin joined with `--path-prefix`, for absolute links to the pages.
+    pub base_url: Arc<Url>,
+    /// `None` unless `--debug-ceremonies` is given.
+    pub ceremony_log: Option<Arc<CeremonyLog>>,
+}
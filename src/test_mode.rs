@@ -0,0 +1,215 @@
+use crate::{
+    app::{AppError, SharedAppState},
+    metadata::AuthenticatorInfo,
+    session::SqliteSessionStore,
+};
+use anyhow::{anyhow, Context};
+use axum::{extract::State, http::StatusCode, Json};
+use base64::{engine::general_purpose, Engine as _};
+use openssl::{ec::EcKey, pkey::PKey};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use tracing::{error, trace};
+use webauthn_authenticator_rs::{softtoken::SoftToken, AuthenticatorBackend};
+use webauthn_rs::{
+    prelude::{Passkey, Url, Uuid},
+    Webauthn,
+};
+
+/// Endpoints for end-to-end tests of the built-in pages (or customized templates), served under
+/// `/api/test` with `--test-mode`. They let anyone reset the database and create users, so they
+/// must never be exposed on a real deployment.
+pub struct TestMode {
+    app: SharedAppState,
+    store: SqliteSessionStore,
+    webauthn: Arc<Webauthn>,
+    rp_id: String,
+    origin: Url,
+}
+
+/// A credential in the form of the Chrome DevTools Protocol's `WebAuthn.Credential`, so that it
+/// can be added to a virtual authenticator (e.g. with Playwright's `WebAuthn.addCredential`),
+/// which then logs in with it like a real one would.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualCredential {
+    credential_id: String,
+    is_resident_credential: bool,
+    rp_id: String,
+    /// PKCS#8, as base64 like all binary fields.
+    private_key: String,
+    user_handle: String,
+    sign_count: u32,
+}
+
+// The parts of a serialized `SoftToken` needed to hand its credential to another authenticator.
+#[derive(Deserialize)]
+struct SoftTokenState {
+    tokens: HashMap<Vec<u8>, Vec<u8>>,
+    counter: u32,
+}
+
+impl TestMode {
+    pub fn new(
+        app: SharedAppState,
+        store: SqliteSessionStore,
+        webauthn: Arc<Webauthn>,
+        rp_id: String,
+        origin: Url,
+    ) -> Self {
+        Self {
+            app,
+            store,
+            webauthn,
+            rp_id,
+            origin,
+        }
+    }
+
+    /// Registers a credential for `user_id` with a software authenticator, returning it along
+    /// with its private key.
+    fn register(
+        &self,
+        user_id: Uuid,
+        username: &str,
+    ) -> anyhow::Result<(Passkey, VirtualCredential)> {
+        let (mut soft_token, _) =
+            SoftToken::new(true).map_err(|e| anyhow!("could not create authenticator: {e:?}"))?;
+        let (challenge, registration) = self
+            .webauthn
+            .start_passkey_registration(user_id, username, username, None)
+            .context("could not start registration")?;
+        let credential = soft_token
+            .perform_register(self.origin.clone(), challenge.public_key, 60_000)
+            .map_err(|e| anyhow!("authenticator refused to register: {e:?}"))?;
+        let passkey = self
+            .webauthn
+            .finish_passkey_registration(&credential, &registration)
+            .context("could not finish registration")?;
+
+        let state: SoftTokenState = serde_cbor_2::from_slice(
+            &soft_token
+                .to_cbor()
+                .map_err(|e| anyhow!("could not serialize authenticator: {e:?}"))?,
+        )?;
+        let (credential_id, key) = state
+            .tokens
+            .into_iter()
+            .next()
+            .context("authenticator holds no credential")?;
+        let private_key =
+            PKey::from_ec_key(EcKey::private_key_from_der(&key)?)?.private_key_to_pkcs8()?;
+
+        let credential = VirtualCredential {
+            credential_id: general_purpose::STANDARD.encode(credential_id),
+            is_resident_credential: false,
+            rp_id: self.rp_id.clone(),
+            private_key: general_purpose::STANDARD.encode(private_key),
+            user_handle: general_purpose::STANDARD.encode(user_id.as_bytes()),
+            sign_count: state.counter,
+        };
+        Ok((passkey, credential))
+    }
+}
+
+/// Deletes all users, credentials, sessions and audit log entries.
+pub async fn test_reset_handler(test_mode: State<Arc<TestMode>>) -> Result<StatusCode, AppError> {
+    trace!("test_reset_handler");
+
+    test_mode.app.read().await.reset().await?;
+    test_mode.store.clear().await.map_err(|e| {
+        error!("could not clear sessions: {e:#}");
+        AppError::UnknownError
+    })?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestUserRequestPayload {
+    username: String,
+    /// Name of a credential to register for the user.
+    credential_name: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TestUserResponsePayload {
+    credential: Option<VirtualCredential>,
+}
+
+/// Creates a user, unless it exists already, optionally with a credential for a virtual
+/// authenticator.
+pub async fn test_user_handler(
+    test_mode: State<Arc<TestMode>>,
+    payload: Json<TestUserRequestPayload>,
+) -> Result<Json<TestUserResponsePayload>, AppError> {
+    trace!("test_user_handler");
+
+    let app = test_mode.app.read().await;
+    let user = app
+        .get_user_with_credentials(payload.username.clone())
+        .await?;
+
+    let Some(name) = payload.0.credential_name else {
+        return Ok(Json(TestUserResponsePayload { credential: None }));
+    };
+    let (passkey, credential) = test_mode.register(user.id, &user.username).map_err(|e| {
+        error!("could not create test credential: {e:#}");
+        AppError::WebauthnFailed
+    })?;
+    app.add_credential(user.username, name, &passkey, AuthenticatorInfo::default())
+        .await?;
+
+    Ok(Json(TestUserResponsePayload {
+        credential: Some(credential),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::App;
+    use tokio::sync::RwLock;
+    use tokio_rusqlite::Connection;
+    use webauthn_rs::WebauthnBuilder;
+
+    #[tokio::test]
+    async fn test_register() {
+        let origin = Url::parse("https://auth.example.com").unwrap();
+        let webauthn = WebauthnBuilder::new("example.com", &origin)
+            .unwrap()
+            .build()
+            .unwrap();
+        let db = Connection::open(":memory:").await.unwrap();
+        let test_mode = TestMode::new(
+            Arc::new(RwLock::new(App::new(db.clone()))),
+            SqliteSessionStore::new(db),
+            Arc::new(webauthn),
+            "example.com".to_string(),
+            origin,
+        );
+
+        let user_id = Uuid::new_v4();
+        let (passkey, credential) = test_mode.register(user_id, "foo").unwrap();
+        assert_eq!(
+            general_purpose::STANDARD
+                .decode(&credential.credential_id)
+                .unwrap(),
+            passkey.cred_id().as_ref()
+        );
+        assert_eq!(
+            general_purpose::STANDARD
+                .decode(&credential.user_handle)
+                .unwrap(),
+            user_id.as_bytes()
+        );
+        assert!(PKey::private_key_from_pkcs8(
+            &general_purpose::STANDARD
+                .decode(&credential.private_key)
+                .unwrap()
+        )
+        .is_ok());
+        assert_eq!(credential.rp_id, "example.com");
+    }
+}
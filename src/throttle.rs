@@ -0,0 +1,137 @@
+use metrics::counter;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Failures are forgotten once there were none for this long, which is also how long a client
+/// address or username is refused after its last failure.
+pub const FAILURE_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Expired entries are only pruned once this many client addresses and usernames are tracked.
+const MAX_TRACKED: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Subject {
+    Client(IpAddr),
+    User(String),
+}
+
+impl Subject {
+    fn of(ip: Option<IpAddr>, username: Option<&str>) -> impl Iterator<Item = Subject> {
+        ip.map(Subject::Client)
+            .into_iter()
+            .chain(username.map(|username| Subject::User(username.to_string())))
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+/// Refuses logins from client addresses and for usernames with too many recent failures, with
+/// `--max-login-failures`. The proof-of-work makes every client pay once failures spike overall,
+/// this stops a single client from guessing at length, or many clients at a single account.
+#[derive(Debug, Default)]
+pub struct LoginThrottle {
+    /// `None` to never refuse.
+    max_failures: Option<u32>,
+    failures: Mutex<HashMap<Subject, Failures>>,
+}
+
+impl LoginThrottle {
+    pub fn new(max_failures: Option<u32>) -> Self {
+        Self {
+            max_failures,
+            failures: Mutex::default(),
+        }
+    }
+
+    /// Whether the client at `ip`, or anyone logging in as `username`, has to wait before trying
+    /// again.
+    pub fn is_throttled(&self, ip: Option<IpAddr>, username: Option<&str>) -> bool {
+        let Some(max_failures) = self.max_failures else {
+            return false;
+        };
+
+        let failures = self.failures.lock().expect("failures lock poisoned");
+        let throttled = Subject::of(ip, username).any(|subject| {
+            failures
+                .get(&subject)
+                .is_some_and(|f| f.count >= max_failures && f.last.elapsed() < FAILURE_TTL)
+        });
+        if throttled {
+            counter!("throttled_logins").increment(1);
+        }
+        throttled
+    }
+
+    pub fn record_failure(&self, ip: Option<IpAddr>, username: Option<&str>) {
+        if self.max_failures.is_none() {
+            return;
+        }
+
+        let mut failures = self.failures.lock().expect("failures lock poisoned");
+        if failures.len() >= MAX_TRACKED {
+            failures.retain(|_, f| f.last.elapsed() < FAILURE_TTL);
+        }
+        for subject in Subject::of(ip, username) {
+            let entry = failures.entry(subject).or_insert(Failures {
+                count: 0,
+                last: Instant::now(),
+            });
+            if entry.last.elapsed() >= FAILURE_TTL {
+                entry.count = 0;
+            }
+            entry.count += 1;
+            entry.last = Instant::now();
+        }
+    }
+
+    /// Forgets the failures of `username` once the user logged in. Those of the client address
+    /// are kept, or a client could start over by logging in to an account of its own.
+    pub fn record_success(&self, username: &str) {
+        self.failures
+            .lock()
+            .expect("failures lock poisoned")
+            .remove(&Subject::User(username.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_throttle() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let disabled = LoginThrottle::new(None);
+        (0..100).for_each(|_| disabled.record_failure(Some(ip), Some("foo")));
+        assert!(!disabled.is_throttled(Some(ip), Some("foo")));
+
+        let throttle = LoginThrottle::new(Some(3));
+        (0..2).for_each(|_| throttle.record_failure(Some(ip), Some("foo")));
+        assert!(!throttle.is_throttled(Some(ip), Some("foo")));
+        throttle.record_failure(Some(ip), Some("foo"));
+        assert!(throttle.is_throttled(Some(ip), None));
+        // the account is throttled for other clients too, and the client for other accounts
+        assert!(throttle.is_throttled(Some(other), Some("foo")));
+        assert!(throttle.is_throttled(None, Some("foo")));
+        assert!(throttle.is_throttled(Some(ip), Some("bar")));
+        assert!(!throttle.is_throttled(Some(other), Some("bar")));
+
+        throttle.record_success("foo");
+        assert!(!throttle.is_throttled(Some(other), Some("foo")));
+        assert!(throttle.is_throttled(Some(ip), Some("foo")));
+
+        // failures without a client address still count against the account
+        (0..3).for_each(|_| throttle.record_failure(None, Some("bar")));
+        assert!(throttle.is_throttled(Some(other), Some("bar")));
+    }
+}
@@ -0,0 +1,138 @@
+use axum::{body::Body, extract::State, http::Request, middleware::Next, response::Response};
+use metrics::histogram;
+use rand::Rng;
+use std::{
+    fmt::Write as _,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+tokio::task_local! {
+    static TIMINGS: Arc<Timings>;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RequestTimingConfig {
+    /// Requests taking at least this long are logged at WARN. `None` disables slow request
+    /// logging.
+    pub slow_threshold: Option<Duration>,
+    /// Probability (0.0 to 1.0) that any request is logged with its timing breakdown.
+    pub sample_rate: f64,
+}
+
+/// Time spent in each phase of handling a single request.
+#[derive(Default, Debug)]
+pub struct Timings {
+    phases: Mutex<Vec<(&'static str, Duration)>>,
+}
+
+impl Timings {
+    fn add(&self, phase: &'static str, duration: Duration) {
+        let mut phases = self.phases.lock().expect("timings lock poisoned");
+        match phases.iter_mut().find(|(p, _)| *p == phase) {
+            Some((_, total)) => *total += duration,
+            None => phases.push((phase, duration)),
+        }
+    }
+
+    /// Formats the breakdown as `phase=1.234ms ...`, attributing any untracked time to `other`.
+    fn breakdown(&self, total: Duration) -> String {
+        let phases = self.phases.lock().expect("timings lock poisoned");
+        let mut out = String::new();
+        let mut tracked = Duration::ZERO;
+        for (phase, duration) in phases.iter() {
+            tracked += *duration;
+            _ = write!(out, "{phase}={:.3}ms ", duration.as_secs_f64() * 1000.0);
+        }
+        _ = write!(
+            out,
+            "other={:.3}ms",
+            total.saturating_sub(tracked).as_secs_f64() * 1000.0
+        );
+        out
+    }
+}
+
+/// Attributes `duration` to `phase` for the request currently being handled. Outside of a
+/// request (e.g. at startup) this does nothing.
+pub fn record(phase: &'static str, duration: Duration) {
+    _ = TIMINGS.try_with(|timings| timings.add(phase, duration));
+}
+
+pub async fn measure<F: Future>(phase: &'static str, fut: F) -> F::Output {
+    let start = Instant::now();
+    let output = fut.await;
+    record(phase, start.elapsed());
+    output
+}
+
+pub fn measure_sync<T>(phase: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let output = f();
+    record(phase, start.elapsed());
+    output
+}
+
+/// Middleware that logs requests exceeding the slow request threshold, as well as a random
+/// sample of all requests, along with how long was spent in each phase.
+pub async fn log_request_timings(
+    config: State<RequestTimingConfig>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+
+    let timings = Arc::new(Timings::default());
+    let start = Instant::now();
+    let response = TIMINGS.scope(timings.clone(), next.run(req)).await;
+    let total = start.elapsed();
+    histogram!("request_duration_seconds").record(total);
+
+    let status = response.status().as_u16();
+    let total_ms = total.as_secs_f64() * 1000.0;
+    if config
+        .slow_threshold
+        .is_some_and(|threshold| total >= threshold)
+    {
+        warn!(
+            "slow request: {method} {path} {status} took {total_ms:.3}ms ({})",
+            timings.breakdown(total)
+        );
+    } else if config.sample_rate > 0.0 && rand::thread_rng().gen_bool(config.sample_rate.min(1.0)) {
+        info!(
+            "sampled request: {method} {path} {status} took {total_ms:.3}ms ({})",
+            timings.breakdown(total)
+        );
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_timings_breakdown() {
+        let timings = Arc::new(Timings::default());
+
+        TIMINGS
+            .scope(timings.clone(), async {
+                record("db", Duration::from_millis(2));
+                record("ceremony", Duration::from_millis(3));
+                record("db", Duration::from_millis(2));
+            })
+            .await;
+
+        // recording outside of a request is a no-op
+        record("db", Duration::from_millis(100));
+
+        assert_eq!(
+            timings.breakdown(Duration::from_millis(10)),
+            "db=4.000ms ceremony=3.000ms other=3.000ms"
+        );
+    }
+}
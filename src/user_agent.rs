@@ -0,0 +1,222 @@
+use axum::http::{header, HeaderMap};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+/// A coarse description of the client, derived from the User-Agent header and (when the browser
+/// sends them) User-Agent client hints.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub browser: Option<&'static str>,
+    pub platform: Option<String>,
+    pub model: Option<String>,
+}
+
+fn browser(user_agent: &str) -> Option<&'static str> {
+    // Order matters, since e.g. Edge also claims to be Chrome and Safari.
+    [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("FxiOS/", "Firefox"),
+        ("CriOS/", "Chrome"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+    ]
+    .into_iter()
+    .find(|(needle, _)| user_agent.contains(needle))
+    .map(|(_, name)| name)
+}
+
+fn platform(user_agent: &str) -> Option<&'static str> {
+    [
+        ("iPhone", "iPhone"),
+        ("iPad", "iPad"),
+        ("Android", "Android"),
+        ("CrOS", "ChromeOS"),
+        ("Macintosh", "macOS"),
+        ("Windows", "Windows"),
+        ("Linux", "Linux"),
+    ]
+    .into_iter()
+    .find(|(needle, _)| user_agent.contains(needle))
+    .map(|(_, name)| name)
+}
+
+/// Client hint values are sent as quoted strings, e.g. `"macOS"`.
+fn client_hint(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().trim_matches('"').to_string())
+        .filter(|value| !value.is_empty())
+}
+
+impl ClientInfo {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        Self {
+            browser: browser(user_agent),
+            platform: client_hint(headers, "sec-ch-ua-platform")
+                .or_else(|| platform(user_agent).map(String::from)),
+            model: client_hint(headers, "sec-ch-ua-model"),
+        }
+    }
+
+    /// A human friendly default name for a credential registered from this client, such as
+    /// "Chrome on macOS" or "iPhone".
+    pub fn suggested_credential_name(&self) -> Option<String> {
+        if let Some(model) = self.model.as_ref() {
+            return Some(model.clone());
+        }
+
+        match (self.browser, self.platform.as_deref()) {
+            // Mobile Apple devices are better known by the device than by the browser.
+            (_, Some(device @ ("iPhone" | "iPad"))) => Some(device.to_string()),
+            (Some(browser), Some(platform)) => Some(format!("{browser} on {platform}")),
+            (None, Some(platform)) => Some(platform.to_string()),
+            (Some(browser), None) => Some(browser.to_string()),
+            (None, None) => None,
+        }
+    }
+}
+
+/// What happens when a session is used from a client with a different fingerprint than it was
+/// first used from, given with `--session-binding`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SessionBinding {
+    #[default]
+    Off,
+    // Log the request but let it through.
+    Log,
+    // Reject the request as if the session was not logged in.
+    Enforce,
+}
+
+/// The browser family and platform of a client as told by its User-Agent header alone, which
+/// unlike client hints is sent with every request (including those a reverse proxy makes to
+/// `/api/validate` on its behalf). Versions are left out, so that browser updates do not change
+/// it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientFingerprint {
+    pub browser: Option<String>,
+    pub platform: Option<String>,
+}
+
+impl ClientFingerprint {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        Self {
+            browser: browser(user_agent).map(String::from),
+            platform: platform(user_agent).map(String::from),
+        }
+    }
+}
+
+impl Display for ClientFingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} on {}",
+            self.browser.as_deref().unwrap_or("unknown browser"),
+            self.platform.as_deref().unwrap_or("unknown platform")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_suggested_credential_name() {
+        [
+            (
+                headers(&[("user-agent", "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")]),
+                Some("Chrome on macOS"),
+            ),
+            (
+                headers(&[("user-agent", "Mozilla/5.0 (iPhone; CPU iPhone OS 17_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.1 Mobile/15E148 Safari/604.1")]),
+                Some("iPhone"),
+            ),
+            (
+                headers(&[("user-agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.0.0")]),
+                Some("Edge on Windows"),
+            ),
+            (
+                headers(&[("user-agent", "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0")]),
+                Some("Firefox on Linux"),
+            ),
+            (
+                headers(&[
+                    ("user-agent", "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Mobile Safari/537.36"),
+                    ("sec-ch-ua-model", "\"Pixel 7\""),
+                ]),
+                Some("Pixel 7"),
+            ),
+            (
+                headers(&[
+                    ("user-agent", "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36"),
+                    ("sec-ch-ua-platform", "\"Chrome OS\""),
+                    ("sec-ch-ua-model", "\"\""),
+                ]),
+                Some("Chrome on Chrome OS"),
+            ),
+            (headers(&[("user-agent", "curl/8.4.0")]), None),
+            (headers(&[]), None),
+        ]
+        .iter()
+        .for_each(|(headers, expected)| {
+            assert_eq!(
+                ClientInfo::from_headers(headers)
+                    .suggested_credential_name()
+                    .as_deref(),
+                *expected,
+                "{headers:?}"
+            );
+        });
+    }
+
+    #[test]
+    fn test_client_fingerprint() {
+        let fingerprint =
+            |user_agent| ClientFingerprint::from_headers(&headers(&[("user-agent", user_agent)]));
+        let chrome = fingerprint("Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36");
+        assert_eq!(chrome.to_string(), "Chrome on Linux");
+        // updating the browser keeps the fingerprint
+        assert_eq!(
+            chrome,
+            fingerprint("Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/121.0.0.0 Safari/537.36")
+        );
+        assert_ne!(
+            chrome,
+            fingerprint("Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0")
+        );
+        assert_ne!(
+            chrome,
+            fingerprint("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")
+        );
+        // switching browsers, platforms, or leaving the header out changes it
+        assert_ne!(chrome, ClientFingerprint::from_headers(&HeaderMap::new()));
+        assert_eq!(
+            ClientFingerprint::from_headers(&HeaderMap::new()).to_string(),
+            "unknown browser on unknown platform"
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+use unicode_normalization::UnicodeNormalization;
+
+/// A step of username normalization, given with `--normalize-username`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NormalizationStep {
+    /// Unicode compatibility normalization (NFKC), so that e.g. fullwidth letters match their
+    /// ASCII spelling.
+    Nfkc,
+    /// Drop the realm of `user@realm`.
+    StripRealm,
+    /// Lowercase the username, so that it matches regardless of case.
+    Lowercase,
+}
+
+/// How usernames are normalized before they are looked up, so that spellings differing only in
+/// e.g. case log in as the same user. Steps are always applied in the order NFKC, realm
+/// stripping, lowercasing, regardless of the order they were given in.
+#[derive(Debug, Clone, Default)]
+pub struct UsernameNormalization {
+    nfkc: bool,
+    strip_realm: bool,
+    lowercase: bool,
+}
+
+impl UsernameNormalization {
+    pub fn new(steps: &[NormalizationStep]) -> Self {
+        Self {
+            nfkc: steps.contains(&NormalizationStep::Nfkc),
+            strip_realm: steps.contains(&NormalizationStep::StripRealm),
+            lowercase: steps.contains(&NormalizationStep::Lowercase),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.nfkc || self.strip_realm || self.lowercase
+    }
+
+    pub fn normalize(&self, username: &str) -> String {
+        let mut username = if self.nfkc {
+            username.nfkc().collect()
+        } else {
+            username.to_string()
+        };
+        if self.strip_realm {
+            // A username consisting of just a realm is left alone rather than emptied.
+            if let Some((user, _)) = username
+                .rsplit_once('@')
+                .filter(|(user, _)| !user.is_empty())
+            {
+                username = user.to_string();
+            }
+        }
+        if self.lowercase {
+            username = username.to_lowercase();
+        }
+        username
+    }
+
+    /// Usernames that differ from the username they normalize to, grouped by the latter. Users
+    /// with these names can no longer log in, as they would be looked up by the normalized name.
+    pub fn unnormalized(
+        &self,
+        usernames: impl IntoIterator<Item = String>,
+    ) -> BTreeMap<String, Vec<String>> {
+        let mut normalized = BTreeMap::<String, Vec<String>>::new();
+        for username in usernames {
+            let key = self.normalize(&username);
+            if key != username {
+                normalized.entry(key).or_default().push(username);
+            }
+        }
+        normalized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let none = UsernameNormalization::default();
+        assert!(!none.is_enabled());
+        assert_eq!(none.normalize("J.Doe@EXAMPLE.com"), "J.Doe@EXAMPLE.com");
+
+        let all = UsernameNormalization::new(&[
+            NormalizationStep::Lowercase,
+            NormalizationStep::StripRealm,
+            NormalizationStep::Nfkc,
+        ]);
+        assert_eq!(all.normalize("J.Doe@EXAMPLE.com"), "j.doe");
+        assert_eq!(all.normalize("ＪＤｏｅ"), "jdoe");
+        assert_eq!(all.normalize("j@doe@example.com"), "j@doe");
+        assert_eq!(all.normalize("@example.com"), "@example.com");
+
+        assert_eq!(
+            all.unnormalized(["JDoe", "jdoe@example.com", "Alice", "bob"].map(String::from)),
+            BTreeMap::from([
+                ("alice".to_string(), vec!["Alice".to_string()]),
+                (
+                    "jdoe".to_string(),
+                    vec!["JDoe".to_string(), "jdoe@example.com".to_string()]
+                ),
+            ])
+        );
+    }
+}
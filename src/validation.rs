@@ -0,0 +1,105 @@
+use axum::http::Method;
+use serde::{de::DeserializeOwned, Deserialize};
+use webauthn_rs_proto::{PublicKeyCredential, RegisterPublicKeyCredential};
+
+// `RegisterEndRequestPayload`, but without the `#[serde(flatten)]` of `WithAttachment`, which
+// hides the path of errors inside the credential.
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct RegistrationPayload {
+    name: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+/// A WebAuthn payload checked by `--strict-validation`, so that custom frontends learn which
+/// part of a credential the server could not make sense of, rather than just seeing
+/// `422 Unprocessable Entity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Payload {
+    Registration,
+    Authentication,
+}
+
+impl Payload {
+    /// The payload expected by a request, if it finishes a ceremony.
+    pub fn of(method: &Method, path: &str) -> Option<Self> {
+        if method != Method::POST {
+            return None;
+        }
+        match path {
+            "/api/register" | "/api/kiosk/register" | "/api/enroll" | "/api/recover" => {
+                Some(Self::Registration)
+            }
+            "/api/authenticate" | "/api/step-up" => Some(Self::Authentication),
+            _ => None,
+        }
+    }
+
+    /// Describes where `body` does not match the webauthn-rs types, e.g.
+    /// `credential.response.clientDataJSON: invalid type: integer 1, expected ...`.
+    pub fn check(self, body: &[u8]) -> Result<(), String> {
+        match self {
+            Self::Registration => check::<RegistrationPayload>(body),
+            // `authenticatorAttachment` is ignored like any other unknown field.
+            Self::Authentication => check::<PublicKeyCredential>(body),
+        }
+    }
+}
+
+fn check<T: DeserializeOwned>(body: &[u8]) -> Result<(), String> {
+    let deserializer = &mut serde_json::Deserializer::from_slice(body);
+    match serde_path_to_error::deserialize::<_, T>(deserializer) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{}: {}", e.path(), e.inner())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert_eq!(
+            Payload::of(&Method::POST, "/api/step-up"),
+            Some(Payload::Authentication)
+        );
+        assert_eq!(Payload::of(&Method::GET, "/api/register"), None);
+        assert_eq!(Payload::of(&Method::POST, "/api/settings"), None);
+
+        let credential = r#"{
+            "id": "AA",
+            "rawId": "AA",
+            "response": {"attestationObject": "AA", "clientDataJSON": "AA"},
+            "type": "public-key",
+            "extensions": {}
+        }"#;
+        let registration = format!(r#"{{"name": "key", "credential": {credential}}}"#);
+        assert_eq!(Payload::Registration.check(registration.as_bytes()), Ok(()));
+
+        let registration =
+            registration.replace(r#""clientDataJSON": "AA""#, r#""clientDataJSON": 1"#);
+        assert!(Payload::Registration
+            .check(registration.as_bytes())
+            .unwrap_err()
+            .starts_with("credential.response.clientDataJSON: "));
+
+        let authentication = r#"{
+            "id": "AA",
+            "rawId": "AA",
+            "response": {"authenticatorData": "AA", "clientDataJSON": "AA", "signature": "AA"},
+            "type": "public-key",
+            "extensions": {},
+            "authenticatorAttachment": "platform"
+        }"#;
+        assert_eq!(
+            Payload::Authentication.check(authentication.as_bytes()),
+            Ok(())
+        );
+        let authentication = authentication.replace(r#""signature": "AA""#, r#""signature": true"#);
+        assert!(Payload::Authentication
+            .check(authentication.as_bytes())
+            .unwrap_err()
+            .starts_with("response.signature: "));
+    }
+}
@@ -0,0 +1,162 @@
+use crate::{http_client::HttpClient, secret::SecretSource};
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use http_body_util::Full;
+use hyper::{body::Bytes, header, Method, Request};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::PathBuf;
+use webauthn_rs::prelude::Url;
+use zeroize::Zeroizing;
+
+/// How to log in to Vault.
+pub enum VaultAuth {
+    /// A token read from a file, e.g. one written by Vault Agent.
+    TokenFile(PathBuf),
+    /// The AppRole auth method mounted at `approle/`.
+    AppRole {
+        role_id: String,
+        secret_id_file: PathBuf,
+    },
+}
+
+#[derive(Serialize)]
+struct AppRoleLogin<'a> {
+    role_id: &'a str,
+    secret_id: &'a str,
+}
+
+/// A field of a secret in HashiCorp Vault's KV secrets engine, either version 1 or 2. For
+/// version 2 the path includes `data/`, e.g. `secret/data/webauthn-tiny`.
+pub struct VaultSecret {
+    pub address: Url,
+    pub auth: VaultAuth,
+    pub path: String,
+    pub field: String,
+    pub client: HttpClient,
+}
+
+impl VaultSecret {
+    fn url(&self, path: &str) -> anyhow::Result<Url> {
+        Ok(self
+            .address
+            .join(&format!("/v1/{}", path.trim_start_matches('/')))?)
+    }
+
+    async fn request(&self, request: Request<Full<Bytes>>) -> anyhow::Result<Value> {
+        let (status, body) = self.client.send(request).await?;
+        if !status.is_success() {
+            bail!("vault responded with {status}");
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    async fn token(&self) -> anyhow::Result<SecretString> {
+        match &self.auth {
+            VaultAuth::TokenFile(path) => {
+                let token =
+                    Zeroizing::new(tokio::fs::read_to_string(path).await.with_context(|| {
+                        format!("could not read vault token {}", path.display())
+                    })?);
+                Ok(token.trim().into())
+            }
+            VaultAuth::AppRole {
+                role_id,
+                secret_id_file,
+            } => {
+                let secret_id = Zeroizing::new(
+                    tokio::fs::read_to_string(secret_id_file)
+                        .await
+                        .with_context(|| {
+                            format!("could not read secret ID {}", secret_id_file.display())
+                        })?,
+                );
+                let body = serde_json::to_vec(&AppRoleLogin {
+                    role_id,
+                    secret_id: secret_id.trim(),
+                })?;
+                let request = Request::builder()
+                    .method(Method::POST)
+                    .uri(self.url("auth/approle/login")?.as_str())
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Full::from(body))?;
+                let response = self
+                    .request(request)
+                    .await
+                    .context("approle login failed")?;
+                match response.pointer("/auth/client_token") {
+                    Some(Value::String(token)) => Ok(token.as_str().into()),
+                    _ => bail!("approle login response has no client token"),
+                }
+            }
+        }
+    }
+}
+
+/// Finds `field` in a KV read response, where version 2 nests the secret's data one level
+/// deeper than version 1.
+fn secret_field(response: &Value, field: &str) -> Option<String> {
+    let data = response.get("data")?;
+    let data = match data.get("metadata") {
+        Some(_) => data.get("data")?,
+        None => data,
+    };
+    data.get(field)?.as_str().map(String::from)
+}
+
+#[async_trait]
+impl SecretSource for VaultSecret {
+    fn describe(&self) -> String {
+        format!("vault secret {}#{}", self.path, self.field)
+    }
+
+    async fn fetch(&self) -> anyhow::Result<SecretString> {
+        let token = self.token().await?;
+        let mut token = header::HeaderValue::from_str(token.expose_secret())
+            .context("vault token is not a valid header value")?;
+        // Keeps the token out of debug output of the request.
+        token.set_sensitive(true);
+        let request = Request::builder()
+            .uri(self.url(&self.path)?.as_str())
+            .header("X-Vault-Token", token)
+            .body(Full::default())?;
+        let response = self.request(request).await?;
+        let secret = Zeroizing::new(
+            secret_field(&response, &self.field)
+                .with_context(|| format!("secret has no string field {}", self.field))?,
+        );
+        Ok(secret.as_str().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_secret_field() {
+        let kv1 = json!({ "data": { "session_secret": "foo" } });
+        let kv2 = json!({
+            "data": {
+                "data": { "session_secret": "bar" },
+                "metadata": { "version": 3 }
+            }
+        });
+
+        assert_eq!(
+            secret_field(&kv1, "session_secret"),
+            Some("foo".to_string())
+        );
+        assert_eq!(
+            secret_field(&kv2, "session_secret"),
+            Some("bar".to_string())
+        );
+        assert_eq!(secret_field(&kv2, "other"), None);
+        assert_eq!(
+            secret_field(&json!({ "errors": [] }), "session_secret"),
+            None
+        );
+    }
+}
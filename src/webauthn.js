@@ -0,0 +1,431 @@
+// Helpers for running WebAuthn ceremonies against the webauthn-tiny API, served
+// at /assets/webauthn.js for use by the built-in pages and custom templates.
+export const VERSION = "__VERSION__";
+// The path the server is served under with --path-prefix (e.g. "/auth"), or ""
+// at the root. API paths and the built-in pages are relative to it.
+export const PATH_PREFIX = "__PATH_PREFIX__";
+
+export class WebAuthnTinyError extends Error {
+  // `code` is one of:
+  // - "timed_out": the browser prompt timed out or the user cancelled it, or
+  //   the server's challenge expired
+  // - "aborted": the ceremony was aborted
+  // - "already_registered": the authenticator already has a credential for
+  //   this account
+  // - "not_supported": the browser or authenticator does not support the
+  //   requested options
+  // - "security": the origin is not allowed to use the relying party ID
+  // - "no_credentials": the user has no credentials to verify with
+  // - "captcha_required": the server asked for a CAPTCHA but no `solveCaptcha`
+  //   function was passed to `authenticate`
+  // - "too_many_failures": logins from the client or for the account failed
+  //   too often, and are refused until the `Retry-After` of the response
+  // - "proof_of_work_required", "conflict", "unauthorized", "forbidden",
+  //   "bad_request", "server_error": the server rejected the request (see
+  //   `status`)
+  constructor(code, message, { status, cause } = {}) {
+    super(message, { cause });
+    this.name = "WebAuthnTinyError";
+    this.code = code;
+    this.status = status;
+  }
+}
+
+export function base64urlEncode(buffer) {
+  const bytes = new Uint8Array(buffer);
+  let binary = "";
+  for (const byte of bytes) binary += String.fromCharCode(byte);
+  return btoa(binary)
+    .replace(/\+/g, "-")
+    .replace(/\//g, "_")
+    .replace(/=+$/, "");
+}
+
+export function base64urlDecode(string) {
+  const base64 = string.replace(/-/g, "+").replace(/_/g, "/");
+  const binary = atob(
+    base64.padEnd(base64.length + ((4 - (base64.length % 4)) % 4), "="),
+  );
+  return Uint8Array.from(binary, (c) => c.charCodeAt(0)).buffer;
+}
+
+function decodeDescriptors(descriptors) {
+  return descriptors?.map((descriptor) => ({
+    ...descriptor,
+    id: base64urlDecode(descriptor.id),
+  }));
+}
+
+function creationOptions({ publicKey }) {
+  return {
+    publicKey: {
+      ...publicKey,
+      challenge: base64urlDecode(publicKey.challenge),
+      user: { ...publicKey.user, id: base64urlDecode(publicKey.user.id) },
+      excludeCredentials: decodeDescriptors(publicKey.excludeCredentials),
+    },
+  };
+}
+
+function requestOptions({ publicKey, mediation }) {
+  const options = {
+    publicKey: {
+      ...publicKey,
+      challenge: base64urlDecode(publicKey.challenge),
+      allowCredentials: decodeDescriptors(publicKey.allowCredentials),
+    },
+  };
+  if (mediation) options.mediation = mediation;
+  return options;
+}
+
+function credentialToJSON(credential) {
+  const { response } = credential;
+  const json = {
+    id: credential.id,
+    rawId: base64urlEncode(credential.rawId),
+    type: credential.type,
+    authenticatorAttachment: credential.authenticatorAttachment,
+    extensions: credential.getClientExtensionResults(),
+    response: { clientDataJSON: base64urlEncode(response.clientDataJSON) },
+  };
+  if (response.attestationObject) {
+    json.response.attestationObject = base64urlEncode(
+      response.attestationObject,
+    );
+    json.response.transports = response.getTransports?.();
+  } else {
+    json.response.authenticatorData = base64urlEncode(
+      response.authenticatorData,
+    );
+    json.response.signature = base64urlEncode(response.signature);
+    if (response.userHandle) {
+      json.response.userHandle = base64urlEncode(response.userHandle);
+    }
+  }
+  return json;
+}
+
+function mapDOMException(error) {
+  const code = {
+    NotAllowedError: "timed_out",
+    AbortError: "aborted",
+    InvalidStateError: "already_registered",
+    NotSupportedError: "not_supported",
+    SecurityError: "security",
+  }[error?.name];
+  return code
+    ? new WebAuthnTinyError(code, error.message, { cause: error })
+    : error;
+}
+
+async function responseError(response) {
+  let message = response.statusText;
+  try {
+    message = (await response.json()).error ?? message;
+  } catch (_) {
+    // not every error response has a JSON body
+  }
+  const code =
+    {
+      400: "bad_request",
+      401: "unauthorized",
+      403: "forbidden",
+      408: "timed_out",
+      409: "conflict",
+      429: response.headers.has("Retry-After")
+        ? "too_many_failures"
+        : "proof_of_work_required",
+    }[response.status] ?? "server_error";
+  return new WebAuthnTinyError(code, message, { status: response.status });
+}
+
+async function request(path, { method, body, headers } = {}) {
+  const response = await fetch(PATH_PREFIX + path, {
+    method: method ?? (body === undefined ? "GET" : "POST"),
+    headers:
+      body === undefined
+        ? headers
+        : { "Content-Type": "application/json", ...headers },
+    body: body === undefined ? undefined : JSON.stringify(body),
+  });
+  if (!response.ok) throw await responseError(response);
+  return response;
+}
+
+// The server names the ceremony a start response belongs to, which is sent back
+// when finishing it.
+function ceremonyHeaders(startResponse) {
+  const id = startResponse.headers.get("X-Ceremony-Id");
+  return id ? { "X-Ceremony-Id": id } : {};
+}
+
+async function createCredential(options) {
+  try {
+    return credentialToJSON(
+      await navigator.credentials.create(creationOptions(options)),
+    );
+  } catch (error) {
+    throw mapDOMException(error);
+  }
+}
+
+async function getCredential(options) {
+  try {
+    return credentialToJSON(
+      await navigator.credentials.get(requestOptions(options)),
+    );
+  } catch (error) {
+    throw mapDOMException(error);
+  }
+}
+
+function leadingZeroBits(bytes) {
+  let bits = 0;
+  for (const byte of bytes) {
+    if (byte === 0) {
+      bits += 8;
+      continue;
+    }
+    return bits + Math.clz32(byte) - 24;
+  }
+  return bits;
+}
+
+export async function solveProofOfWork({ challenge, difficulty }) {
+  const encoder = new TextEncoder();
+  for (let nonce = 0; ; nonce++) {
+    const digest = await crypto.subtle.digest(
+      "SHA-256",
+      encoder.encode(`${challenge}${nonce}`),
+    );
+    if (leadingZeroBits(new Uint8Array(digest)) >= difficulty) {
+      return String(nonce);
+    }
+  }
+}
+
+function loadScript(src) {
+  return new Promise((resolve, reject) => {
+    const script = document.createElement("script");
+    script.src = src;
+    script.async = true;
+    script.addEventListener("load", resolve);
+    script.addEventListener("error", reject);
+    document.head.append(script);
+  });
+}
+
+// Renders the CAPTCHA widget requested by the server into `container` and
+// resolves to the response token once the user solved it. Meant to be passed
+// to `authenticate` as `solveCaptcha`.
+export async function renderCaptcha(
+  { provider, siteKey, scriptUrl },
+  container,
+) {
+  const api = () =>
+    provider === "hcaptcha" ? window.hcaptcha : window.turnstile;
+  if (!api()) await loadScript(scriptUrl);
+  return new Promise((resolve) => {
+    api().render(container, { sitekey: siteKey, callback: resolve });
+  });
+}
+
+// Registers a new credential for the logged in user. `name` is either the
+// credential name or a (possibly async) function receiving the server's
+// suggested name and returning the name to use, or null to cancel. Resolves to
+// whether a credential was registered.
+export async function register(name) {
+  const startResponse = await request("/api/register");
+  const startPayload = await startResponse.json();
+  const credentialName =
+    typeof name === "function"
+      ? await name(startPayload.suggestedName ?? "")
+      : name;
+  if (credentialName === null || credentialName === undefined) return false;
+  const credential = await createCredential(startPayload);
+  await request("/api/register", {
+    body: { name: credentialName, credential },
+    headers: ceremonyHeaders(startResponse),
+  });
+  return true;
+}
+
+// Registers a credential named `name` for another user from a kiosk, which
+// requires the logged in user to be a member of the kiosk group.
+export async function enroll(username, name) {
+  const startResponse = await request(
+    `/api/kiosk/register?${new URLSearchParams({ username })}`,
+  );
+  const credential = await createCredential(await startResponse.json());
+  await request("/api/kiosk/register", {
+    body: { name, credential },
+    headers: ceremonyHeaders(startResponse),
+  });
+}
+
+// Logs in the user the session was started for (see /api/authenticate/context).
+// Resolves to `{ noCredentials, mustReenroll }`: users without credentials are
+// logged in without a ceremony (unless they have to enroll one, see
+// `enrollFirstCredential`), and `mustReenroll` is set when the credential
+// used has expired. After repeated failures the server may require a CAPTCHA,
+// which `solveCaptcha` receives and resolves to the response token for (see
+// `renderCaptcha`).
+export async function authenticate({ solveCaptcha } = {}) {
+  const startResponse = await request("/api/authenticate");
+  if (startResponse.status === 204) {
+    return { noCredentials: true, mustReenroll: false };
+  }
+  const startPayload = await startResponse.json();
+  const headers = ceremonyHeaders(startResponse);
+  if (startPayload.proofOfWork) {
+    headers["X-Proof-Of-Work"] = await solveProofOfWork(
+      startPayload.proofOfWork,
+    );
+  }
+  if (startPayload.captcha) {
+    if (!solveCaptcha) {
+      throw new WebAuthnTinyError("captcha_required", "captcha required");
+    }
+    headers["X-Captcha-Response"] = await solveCaptcha(startPayload.captcha);
+  }
+  const credential = await getCredential(startPayload);
+  const endResponse = await request("/api/authenticate", {
+    body: credential,
+    headers,
+  });
+  const { mustReenroll } = await endResponse.json();
+  return { noCredentials: false, mustReenroll };
+}
+
+// Re-verifies a logged in user, e.g. for locations protected with `max_age`.
+export async function stepUp() {
+  const startResponse = await request("/api/step-up");
+  if (startResponse.status === 204) {
+    throw new WebAuthnTinyError("no_credentials", "no credentials to verify");
+  }
+  const startPayload = await startResponse.json();
+  await request("/api/step-up", {
+    body: await getCredential(startPayload),
+    headers: ceremonyHeaders(startResponse),
+  });
+}
+
+// Registers the first credential of a user sent to /enroll, which logs them in.
+// Resolves to whether they are logged in now, which they are not while the
+// credential is awaiting approval.
+export async function enrollFirstCredential(name) {
+  const startResponse = await request("/api/enroll");
+  const credential = await createCredential(await startResponse.json());
+  const endResponse = await request("/api/enroll", {
+    body: { name, credential },
+    headers: ceremonyHeaders(startResponse),
+  });
+  return endResponse.status !== 202;
+}
+
+// Logs in a user sent to /enroll with just their password, which is only
+// allowed during their grace period.
+export async function skipEnrollment() {
+  await request("/api/enroll/skip", { method: "POST" });
+}
+
+// Uses up one of the user's recovery codes, after which `recover` can be called
+// as if a recovery link had been opened.
+export async function redeemRecoveryCode(username, code) {
+  await request("/api/recover/code", { body: { username, code } });
+}
+
+// Replaces all credentials of the user a recovery link was issued for.
+export async function recover(name) {
+  const startResponse = await request("/api/recover");
+  const credential = await createCredential(await startResponse.json());
+  await request("/api/recover", {
+    body: { name, credential },
+    headers: ceremonyHeaders(startResponse),
+  });
+}
+
+// Resolves to whether the session is logged in, and as whom:
+// `{ username, loggedIn, authTime, groups }`.
+export async function whoami() {
+  return await (await request("/api/whoami")).json();
+}
+
+// Resolves to the logged in user's settings, an object of preferences stored
+// with `saveSettings`.
+export async function getSettings() {
+  return await (await request("/api/settings")).json();
+}
+
+// Replaces all of the logged in user's settings with `settings`.
+export async function saveSettings(settings) {
+  await request("/api/settings", { method: "PUT", body: settings });
+}
+
+// Resolves to a page of the logged in user's security events, newest first:
+// `{ data: [{ id, at, event, detail }], next }`. Pass `next` as `before` to get
+// the following page, until it is null.
+export async function getHistory({ before, limit } = {}) {
+  const params = new URLSearchParams();
+  if (before != null) params.set("before", before);
+  if (limit != null) params.set("limit", limit);
+  return await (await request(`/api/history?${params}`)).json();
+}
+
+// Sends a request for a privileged action, which the server refuses unless the
+// user logged in or stepped up recently, stepping up and retrying once if so.
+async function withFreshAuth(send) {
+  try {
+    return await send();
+  } catch (error) {
+    if (error.code !== "forbidden") throw error;
+    await stepUp();
+    return await send();
+  }
+}
+
+// Deletes one of the logged in user's credentials, identified by the `handle`
+// listed by /api/credentials.
+export async function deleteCredential(handle) {
+  await withFreshAuth(() =>
+    request(`/api/credentials/${handle}`, { method: "DELETE" }),
+  );
+}
+
+// Replaces the logged in user's recovery codes, resolving to the new codes,
+// which cannot be retrieved again.
+export async function regenerateRecoveryCodes() {
+  const response = await withFreshAuth(() =>
+    request("/api/recovery-codes", { method: "POST" }),
+  );
+  return (await response.json()).codes;
+}
+
+// Replaces the tags of one of the logged in user's credentials, e.g.
+// `["work", "backup"]`, resolving to the tags as stored.
+export async function setCredentialTags(handle, tags) {
+  return await (
+    await request(`/api/credentials/${handle}/tags`, {
+      method: "PUT",
+      body: tags,
+    })
+  ).json();
+}
+
+// Deletes the logged in user's account after confirming it with a step-up.
+export async function deleteAccount() {
+  await stepUp();
+  await request("/api/users/self", { method: "DELETE" });
+}
+
+// Resolves to everything the server stores about the logged in user.
+export async function exportData() {
+  return await (await request("/api/privacy/export")).json();
+}
+
+// Like deleteAccount, but also removes the user from the audit log.
+export async function eraseAccount() {
+  await stepUp();
+  await request("/api/privacy/erase", { method: "POST" });
+}